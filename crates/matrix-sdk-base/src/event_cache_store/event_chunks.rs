@@ -0,0 +1,135 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Types to persist the linked chunks of the timeline of a room.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::deserialized_responses::SyncTimelineEvent;
+
+/// A chunk of the timeline of a room, as it is persisted in the event cache
+/// store.
+///
+/// The chunks of a room form a doubly linked list, with the `previous` and
+/// `next` identifiers of each chunk.
+#[derive(Clone, Debug)]
+pub struct EventChunk {
+    /// The unique identifier of the chunk in the room.
+    pub id: u64,
+
+    /// The identifier of the previous chunk, if any.
+    pub previous: Option<u64>,
+
+    /// The identifier of the next chunk, if any.
+    pub next: Option<u64>,
+
+    /// The content of the chunk.
+    pub content: EventChunkContent,
+}
+
+/// The content of an [`EventChunk`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum EventChunkContent {
+    /// The chunk holds events, in the order of the timeline.
+    Events(Vec<SyncTimelineEvent>),
+
+    /// The chunk is a gap in the timeline, that can be filled by paginating.
+    Gap {
+        /// The token to use to paginate backwards from this gap.
+        prev_token: String,
+    },
+}
+
+/// Sort the given chunks of a room by following their links, from the first
+/// chunk to the last.
+///
+/// Every chain of linked chunks starts with a chunk that has no previous chunk,
+/// or whose previous chunk is missing. If the links are inconsistent, the
+/// chunks that can't be reached this way are still returned, after the others.
+///
+/// This is meant to help implementations of
+/// [`EventCacheStore::load_event_chunks`](super::EventCacheStore::load_event_chunks).
+pub fn sort_event_chunks(chunks: Vec<EventChunk>) -> Vec<EventChunk> {
+    let mut remaining: BTreeMap<u64, EventChunk> =
+        chunks.into_iter().map(|chunk| (chunk.id, chunk)).collect();
+    let mut sorted = Vec::with_capacity(remaining.len());
+
+    loop {
+        let first = remaining
+            .values()
+            .find(|chunk| {
+                chunk.previous.map_or(true, |previous| !remaining.contains_key(&previous))
+            })
+            .map(|chunk| chunk.id)
+            // Only cycles are left.
+            .or_else(|| remaining.keys().next().copied());
+
+        let Some(mut id) = first else {
+            break;
+        };
+
+        while let Some(chunk) = remaining.remove(&id) {
+            let next = chunk.next;
+            sorted.push(chunk);
+
+            match next {
+                Some(next) => id = next,
+                None => break,
+            }
+        }
+    }
+
+    sorted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sort_event_chunks, EventChunk, EventChunkContent};
+
+    fn gap(id: u64, previous: Option<u64>, next: Option<u64>) -> EventChunk {
+        EventChunk {
+            id,
+            previous,
+            next,
+            content: EventChunkContent::Gap { prev_token: format!("token_{id}") },
+        }
+    }
+
+    fn ids(chunks: &[EventChunk]) -> Vec<u64> {
+        chunks.iter().map(|chunk| chunk.id).collect()
+    }
+
+    #[test]
+    fn test_sort_event_chunks() {
+        let chunks = vec![gap(2, Some(0), Some(1)), gap(1, Some(2), None), gap(0, None, Some(2))];
+        assert_eq!(ids(&sort_event_chunks(chunks)), [0, 2, 1]);
+
+        assert!(sort_event_chunks(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn test_sort_event_chunks_with_inconsistent_links() {
+        // The previous chunk of 3 and the next chunk of 4 are missing, 5 and 6 form
+        // a cycle.
+        let chunks = vec![
+            gap(6, Some(5), Some(5)),
+            gap(4, Some(3), Some(7)),
+            gap(5, Some(6), Some(6)),
+            gap(3, Some(1), Some(4)),
+        ];
+        assert_eq!(ids(&sort_event_chunks(chunks)), [3, 4, 5, 6]);
+    }
+}
@@ -16,11 +16,52 @@
 
 use async_trait::async_trait;
 use ruma::{
-    api::client::media::get_content_thumbnail::v3::Method, events::room::MediaSource, mxc_uri, uint,
+    api::client::media::get_content_thumbnail::v3::Method, events::room::MediaSource, mxc_uri,
+    room_id, serde::Raw, uint, OwnedEventId, OwnedMxcUri,
 };
+use serde_json::json;
 
-use super::DynEventCacheStore;
-use crate::media::{MediaFormat, MediaRequest, MediaThumbnailSettings};
+use super::{DynEventCacheStore, EventChunk, EventChunkContent};
+use crate::{
+    deserialized_responses::SyncTimelineEvent,
+    media::{MediaFormat, MediaRequest, MediaThumbnailSettings},
+};
+
+/// Create a timeline event with the given event ID, to be stored in chunks.
+fn timeline_event(event_id: &str) -> SyncTimelineEvent {
+    SyncTimelineEvent::new(
+        Raw::from_json_string(
+            json!({
+                "type": "m.room.message",
+                "event_id": event_id,
+                "sender": "@alice:localhost",
+                "origin_server_ts": 0,
+                "content": { "msgtype": "m.text", "body": event_id },
+            })
+            .to_string(),
+        )
+        .unwrap(),
+    )
+}
+
+/// Summarize the given chunks as their identifiers, links, and the event IDs
+/// or token of their content, to be able to compare them.
+fn summarize_chunks(
+    chunks: &[EventChunk],
+) -> Vec<(u64, Option<u64>, Option<u64>, Result<Vec<OwnedEventId>, String>)> {
+    chunks
+        .iter()
+        .map(|chunk| {
+            let content = match &chunk.content {
+                EventChunkContent::Events(events) => {
+                    Ok(events.iter().filter_map(|event| event.event_id()).collect())
+                }
+                EventChunkContent::Gap { prev_token } => Err(prev_token.clone()),
+            };
+            (chunk.id, chunk.previous, chunk.next, content)
+        })
+        .collect()
+}
 
 /// `EventCacheStore` integration tests.
 ///
@@ -34,6 +75,21 @@ pub trait EventCacheStoreIntegrationTests {
 
     /// Test replacing a MXID.
     async fn test_replace_media_key(&self);
+
+    /// Test checking whether media are in the store.
+    async fn test_contains_media(&self);
+
+    /// Test marking media as recently accessed.
+    async fn test_warm_media(&self);
+
+    /// Test clearing the data of the session.
+    async fn test_clear_session_data(&self);
+
+    /// Test back-pagination tokens storage.
+    async fn test_gaps(&self);
+
+    /// Test linked chunks storage.
+    async fn test_event_chunks(&self);
 }
 
 #[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
@@ -178,6 +234,285 @@ impl EventCacheStoreIntegrationTests for DynEventCacheStore {
         // Finding with the new request does work.
         assert_eq!(self.get_media_content(&new_req).await.unwrap().unwrap(), b"hello");
     }
+
+    async fn test_contains_media(&self) {
+        let uri = mxc_uri!("mxc://localhost/media");
+        let request_file =
+            MediaRequest { source: MediaSource::Plain(uri.to_owned()), format: MediaFormat::File };
+        let request_thumbnail = MediaRequest {
+            source: MediaSource::Plain(uri.to_owned()),
+            format: MediaFormat::Thumbnail(MediaThumbnailSettings::new(
+                Method::Crop,
+                uint!(100),
+                uint!(100),
+            )),
+        };
+
+        assert!(!self.contains_media(&request_file).await.unwrap(), "unexpected media found");
+
+        self.add_media_content(&request_file, b"hello".to_vec())
+            .await
+            .expect("adding media failed");
+
+        assert!(self.contains_media(&request_file).await.unwrap(), "media not found");
+        assert!(
+            !self.contains_media(&request_thumbnail).await.unwrap(),
+            "thumbnail found with the file's request"
+        );
+
+        self.remove_media_content(&request_file).await.expect("removing media failed");
+        assert!(!self.contains_media(&request_file).await.unwrap(), "media found after removal");
+    }
+
+    async fn test_warm_media(&self) {
+        let uri = mxc_uri!("mxc://localhost/media");
+        let request_file =
+            MediaRequest { source: MediaSource::Plain(uri.to_owned()), format: MediaFormat::File };
+        let request_thumbnail = MediaRequest {
+            source: MediaSource::Plain(uri.to_owned()),
+            format: MediaFormat::Thumbnail(MediaThumbnailSettings::new(
+                Method::Crop,
+                uint!(100),
+                uint!(100),
+            )),
+        };
+        let other_request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/other").to_owned()),
+            format: MediaFormat::File,
+        };
+        let missing_uri = mxc_uri!("mxc://localhost/missing");
+
+        // Nothing to warm.
+        assert_eq!(self.warm_media(&[uri.to_owned()]).await.unwrap(), 0);
+
+        self.add_media_content(&request_file, b"file".to_vec()).await.expect("adding media failed");
+        self.add_media_content(&request_thumbnail, b"thumbnail".to_vec())
+            .await
+            .expect("adding thumbnail failed");
+        self.add_media_content(&other_request, b"other".to_vec())
+            .await
+            .expect("adding other media failed");
+
+        // All the formats of the media are warmed, and the missing media is ignored.
+        assert_eq!(
+            self.warm_media(&[uri.to_owned(), missing_uri.to_owned()]).await.unwrap(),
+            2,
+            "unexpected number of warmed media"
+        );
+
+        // The media are left as is.
+        assert_eq!(self.get_media_content(&request_file).await.unwrap().unwrap(), b"file");
+        assert_eq!(
+            self.get_media_content(&request_thumbnail).await.unwrap().unwrap(),
+            b"thumbnail"
+        );
+        assert_eq!(self.get_media_content(&other_request).await.unwrap().unwrap(), b"other");
+        assert!(
+            self.get_media_content(&MediaRequest {
+                source: MediaSource::Plain(missing_uri.to_owned()),
+                format: MediaFormat::File,
+            })
+            .await
+            .unwrap()
+            .is_none(),
+            "missing media found after warming"
+        );
+
+        // Add enough media to make the `MemoryStore`, which keeps the last 20 media,
+        // drop the least recently used one: the warmed media must outlive the other
+        // one.
+        for i in 0..18 {
+            let request = MediaRequest {
+                source: MediaSource::Plain(OwnedMxcUri::from(format!("mxc://localhost/new-{i}"))),
+                format: MediaFormat::File,
+            };
+            self.add_media_content(&request, b"new".to_vec()).await.expect("adding media failed");
+        }
+        assert!(
+            self.contains_media(&request_file).await.unwrap(),
+            "warmed media file removed before an unwarmed one"
+        );
+        assert!(
+            self.contains_media(&request_thumbnail).await.unwrap(),
+            "warmed thumbnail removed before an unwarmed one"
+        );
+    }
+
+    async fn test_clear_session_data(&self) {
+        let req = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+        let other_req = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://example.org/media").to_owned()),
+            format: MediaFormat::File,
+        };
+
+        self.add_media_content(&req, b"hello".to_vec()).await.expect("adding media failed");
+        self.add_media_content(&other_req, b"world".to_vec()).await.expect("adding media failed");
+
+        self.clear_session_data().await.expect("clearing the session data failed");
+
+        // All the media were removed.
+        assert!(self.get_media_content(&req).await.unwrap().is_none(), "media found after clear");
+        assert!(
+            self.get_media_content(&other_req).await.unwrap().is_none(),
+            "other media found after clear"
+        );
+
+        // The store is still usable.
+        self.add_media_content(&req, b"hello".to_vec()).await.expect("adding media failed");
+        assert_eq!(self.get_media_content(&req).await.unwrap().unwrap(), b"hello");
+    }
+
+    async fn test_gaps(&self) {
+        let room_id = room_id!("!room:localhost");
+        let other_room_id = room_id!("!other_room:localhost");
+
+        // There is no gap yet.
+        assert_eq!(self.load_gap(room_id).await.unwrap(), None);
+
+        // Let's save a gap.
+        self.save_gap(room_id, "token_1").await.expect("saving gap failed");
+        assert_eq!(self.load_gap(room_id).await.unwrap().as_deref(), Some("token_1"));
+
+        // Saving a gap again overwrites it.
+        self.save_gap(room_id, "token_2").await.expect("saving gap failed");
+        assert_eq!(self.load_gap(room_id).await.unwrap().as_deref(), Some("token_2"));
+
+        // The gaps of different rooms don't collide.
+        assert_eq!(self.load_gap(other_room_id).await.unwrap(), None);
+        self.save_gap(other_room_id, "other_token").await.expect("saving gap failed");
+        assert_eq!(self.load_gap(room_id).await.unwrap().as_deref(), Some("token_2"));
+        assert_eq!(self.load_gap(other_room_id).await.unwrap().as_deref(), Some("other_token"));
+
+        // Clearing a gap only clears the gap of the room.
+        self.clear_gap(room_id).await.expect("clearing gap failed");
+        assert_eq!(self.load_gap(room_id).await.unwrap(), None);
+        assert_eq!(self.load_gap(other_room_id).await.unwrap().as_deref(), Some("other_token"));
+
+        // Clearing a missing gap is fine.
+        self.clear_gap(room_id).await.expect("clearing missing gap failed");
+
+        // The gaps are removed with the data of the session.
+        self.clear_session_data().await.expect("clearing the session data failed");
+        assert_eq!(self.load_gap(other_room_id).await.unwrap(), None);
+    }
+
+    async fn test_event_chunks(&self) {
+        let room_id = room_id!("!room:localhost");
+        let other_room_id = room_id!("!other_room:localhost");
+
+        // There are no chunks yet.
+        assert!(self.load_event_chunks(room_id).await.unwrap().is_empty());
+
+        // Let's save chunks, not in the order of the timeline.
+        let chunks = [
+            EventChunk {
+                id: 2,
+                previous: Some(0),
+                next: None,
+                content: EventChunkContent::Events(vec![timeline_event("$ev3")]),
+            },
+            EventChunk {
+                id: 0,
+                previous: None,
+                next: Some(2),
+                content: EventChunkContent::Gap { prev_token: "token".to_owned() },
+            },
+            EventChunk {
+                id: 1,
+                previous: None,
+                next: None,
+                content: EventChunkContent::Events(vec![timeline_event("$other_ev")]),
+            },
+        ];
+        for chunk in chunks {
+            let room_id = if chunk.id == 1 { other_room_id } else { room_id };
+            self.save_event_chunk(room_id, chunk).await.expect("saving chunk failed");
+        }
+
+        let chunks = self.load_event_chunks(room_id).await.unwrap();
+        assert_eq!(
+            summarize_chunks(&chunks),
+            vec![
+                (0, None, Some(2), Err("token".to_owned())),
+                (2, Some(0), None, Ok(vec![OwnedEventId::try_from("$ev3").unwrap()])),
+            ]
+        );
+
+        // Saving a chunk again replaces it, with its links.
+        self.save_event_chunk(
+            room_id,
+            EventChunk {
+                id: 0,
+                previous: None,
+                next: Some(3),
+                content: EventChunkContent::Events(vec![
+                    timeline_event("$ev1"),
+                    timeline_event("$ev2"),
+                ]),
+            },
+        )
+        .await
+        .expect("saving chunk failed");
+        self.save_event_chunk(
+            room_id,
+            EventChunk {
+                id: 3,
+                previous: Some(0),
+                next: Some(2),
+                content: EventChunkContent::Gap { prev_token: "other_token".to_owned() },
+            },
+        )
+        .await
+        .expect("saving chunk failed");
+        self.save_event_chunk(
+            room_id,
+            EventChunk {
+                id: 2,
+                previous: Some(3),
+                next: None,
+                content: EventChunkContent::Events(vec![timeline_event("$ev3")]),
+            },
+        )
+        .await
+        .expect("saving chunk failed");
+
+        let chunks = self.load_event_chunks(room_id).await.unwrap();
+        assert_eq!(
+            summarize_chunks(&chunks),
+            vec![
+                (
+                    0,
+                    None,
+                    Some(3),
+                    Ok(vec![
+                        OwnedEventId::try_from("$ev1").unwrap(),
+                        OwnedEventId::try_from("$ev2").unwrap()
+                    ])
+                ),
+                (3, Some(0), Some(2), Err("other_token".to_owned())),
+                (2, Some(3), None, Ok(vec![OwnedEventId::try_from("$ev3").unwrap()])),
+            ]
+        );
+
+        // The chunks of different rooms don't collide.
+        let other_chunks = self.load_event_chunks(other_room_id).await.unwrap();
+        assert_eq!(
+            summarize_chunks(&other_chunks),
+            vec![(1, None, None, Ok(vec![OwnedEventId::try_from("$other_ev").unwrap()]))]
+        );
+
+        // Removing the events of a room only removes the chunks of the room.
+        self.remove_room_events(room_id).await.expect("removing room events failed");
+        assert!(self.load_event_chunks(room_id).await.unwrap().is_empty());
+        assert_eq!(self.load_event_chunks(other_room_id).await.unwrap().len(), 1);
+
+        // The chunks are removed with the data of the session.
+        self.clear_session_data().await.expect("clearing the session data failed");
+        assert!(self.load_event_chunks(other_room_id).await.unwrap().is_empty());
+    }
 }
 
 /// Macro building to allow your `EventCacheStore` implementation to run the
@@ -230,6 +565,41 @@ macro_rules! event_cache_store_integration_tests {
                     get_event_cache_store().await.unwrap().into_event_cache_store();
                 event_cache_store.test_replace_media_key().await;
             }
+
+            #[async_test]
+            async fn test_contains_media() {
+                let event_cache_store =
+                    get_event_cache_store().await.unwrap().into_event_cache_store();
+                event_cache_store.test_contains_media().await;
+            }
+
+            #[async_test]
+            async fn test_warm_media() {
+                let event_cache_store =
+                    get_event_cache_store().await.unwrap().into_event_cache_store();
+                event_cache_store.test_warm_media().await;
+            }
+
+            #[async_test]
+            async fn test_clear_session_data() {
+                let event_cache_store =
+                    get_event_cache_store().await.unwrap().into_event_cache_store();
+                event_cache_store.test_clear_session_data().await;
+            }
+
+            #[async_test]
+            async fn test_gaps() {
+                let event_cache_store =
+                    get_event_cache_store().await.unwrap().into_event_cache_store();
+                event_cache_store.test_gaps().await;
+            }
+
+            #[async_test]
+            async fn test_event_chunks() {
+                let event_cache_store =
+                    get_event_cache_store().await.unwrap().into_event_cache_store();
+                event_cache_store.test_event_chunks().await;
+            }
         }
     };
 }
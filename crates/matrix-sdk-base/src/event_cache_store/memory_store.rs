@@ -12,13 +12,17 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{num::NonZeroUsize, sync::RwLock as StdRwLock};
+use std::{
+    collections::{BTreeMap, HashMap},
+    num::NonZeroUsize,
+    sync::RwLock as StdRwLock,
+};
 
 use async_trait::async_trait;
 use matrix_sdk_common::ring_buffer::RingBuffer;
-use ruma::{MxcUri, OwnedMxcUri};
+use ruma::{MxcUri, OwnedMxcUri, OwnedRoomId, RoomId, ServerName};
 
-use super::{EventCacheStore, EventCacheStoreError, Result};
+use super::{sort_event_chunks, EventCacheStore, EventCacheStoreError, EventChunk, Result};
 use crate::media::{MediaRequest, UniqueKey as _};
 
 /// In-memory, non-persistent implementation of the `EventCacheStore`.
@@ -28,6 +32,8 @@ use crate::media::{MediaRequest, UniqueKey as _};
 #[derive(Debug)]
 pub struct MemoryStore {
     media: StdRwLock<RingBuffer<(OwnedMxcUri, String /* unique key */, Vec<u8>)>>,
+    gaps: StdRwLock<HashMap<OwnedRoomId, String>>,
+    event_chunks: StdRwLock<HashMap<OwnedRoomId, BTreeMap<u64, EventChunk>>>,
 }
 
 // SAFETY: `new_unchecked` is safe because 20 is not zero.
@@ -35,7 +41,11 @@ const NUMBER_OF_MEDIAS: NonZeroUsize = unsafe { NonZeroUsize::new_unchecked(20)
 
 impl Default for MemoryStore {
     fn default() -> Self {
-        Self { media: StdRwLock::new(RingBuffer::new(NUMBER_OF_MEDIAS)) }
+        Self {
+            media: StdRwLock::new(RingBuffer::new(NUMBER_OF_MEDIAS)),
+            gaps: Default::default(),
+            event_chunks: Default::default(),
+        }
     }
 }
 
@@ -85,6 +95,13 @@ impl EventCacheStore for MemoryStore {
         }))
     }
 
+    async fn contains_media(&self, request: &MediaRequest) -> Result<bool> {
+        let expected_key = request.unique_key();
+
+        let media = self.media.read().unwrap();
+        Ok(media.iter().any(|(_media_uri, media_key, _media_content)| media_key == &expected_key))
+    }
+
     async fn remove_media_content(&self, request: &MediaRequest) -> Result<()> {
         let expected_key = request.unique_key();
 
@@ -119,6 +136,108 @@ impl EventCacheStore for MemoryStore {
 
         Ok(())
     }
+
+    async fn remove_media_by_server(&self, server_name: &ServerName) -> Result<u64> {
+        let mut media = self.media.write().unwrap();
+        let positions = media
+            .iter()
+            .enumerate()
+            .filter_map(|(position, (media_uri, _media_key, _media_content))| {
+                (media_uri.server_name().ok() == Some(server_name)).then_some(position)
+            })
+            .collect::<Vec<_>>();
+        let count = positions.len();
+
+        // Iterate in reverse-order so that positions stay valid after first removals.
+        for position in positions.into_iter().rev() {
+            media.remove(position);
+        }
+
+        Ok(count as u64)
+    }
+
+    async fn clean_up_media_cache(&self) -> Result<u64> {
+        // The number of media is already bounded, there is no retention policy.
+        Ok(0)
+    }
+
+    async fn warm_media(&self, uris: &[OwnedMxcUri]) -> Result<u64> {
+        let mut media = self.media.write().unwrap();
+        let positions = media
+            .iter()
+            .enumerate()
+            .filter_map(|(position, (media_uri, _media_key, _media_content))| {
+                uris.contains(media_uri).then_some(position)
+            })
+            .collect::<Vec<_>>();
+        let count = positions.len();
+
+        // The most recently pushed media are the last ones to be dropped, so move the
+        // warmed ones to the end, keeping their order.
+        let warmed = positions
+            .into_iter()
+            .rev()
+            .filter_map(|position| media.remove(position))
+            .collect::<Vec<_>>();
+        for entry in warmed.into_iter().rev() {
+            media.push(entry);
+        }
+
+        Ok(count as u64)
+    }
+
+    async fn clear_session_data(&self) -> Result<()> {
+        self.media.write().unwrap().clear();
+        self.gaps.write().unwrap().clear();
+        self.event_chunks.write().unwrap().clear();
+
+        Ok(())
+    }
+
+    async fn save_gap(&self, room_id: &RoomId, prev_batch_token: &str) -> Result<()> {
+        self.gaps.write().unwrap().insert(room_id.to_owned(), prev_batch_token.to_owned());
+
+        Ok(())
+    }
+
+    async fn load_gap(&self, room_id: &RoomId) -> Result<Option<String>> {
+        Ok(self.gaps.read().unwrap().get(room_id).cloned())
+    }
+
+    async fn clear_gap(&self, room_id: &RoomId) -> Result<()> {
+        self.gaps.write().unwrap().remove(room_id);
+
+        Ok(())
+    }
+
+    async fn save_event_chunk(&self, room_id: &RoomId, chunk: EventChunk) -> Result<()> {
+        self.event_chunks
+            .write()
+            .unwrap()
+            .entry(room_id.to_owned())
+            .or_default()
+            .insert(chunk.id, chunk);
+
+        Ok(())
+    }
+
+    async fn load_event_chunks(&self, room_id: &RoomId) -> Result<Vec<EventChunk>> {
+        let chunks = self
+            .event_chunks
+            .read()
+            .unwrap()
+            .get(room_id)
+            .map(|chunks| chunks.values().cloned().collect())
+            .unwrap_or_default();
+
+        Ok(sort_event_chunks(chunks))
+    }
+
+    async fn remove_room_events(&self, room_id: &RoomId) -> Result<()> {
+        self.event_chunks.write().unwrap().remove(room_id);
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -24,6 +24,7 @@ use std::str::Utf8Error;
 #[cfg(any(test, feature = "testing"))]
 #[macro_use]
 pub mod integration_tests;
+mod event_chunks;
 mod memory_store;
 mod traits;
 
@@ -32,6 +33,7 @@ pub use matrix_sdk_store_encryption::Error as StoreEncryptionError;
 #[cfg(any(test, feature = "testing"))]
 pub use self::integration_tests::EventCacheStoreIntegrationTests;
 pub use self::{
+    event_chunks::{sort_event_chunks, EventChunk, EventChunkContent},
     memory_store::MemoryStore,
     traits::{DynEventCacheStore, EventCacheStore, IntoEventCacheStore},
 };
@@ -53,13 +55,30 @@ pub enum EventCacheStoreError {
     Unencrypted,
 
     /// The store failed to encrypt or decrypt some data.
+    ///
+    /// The data is probably corrupted, or was encrypted with another key, so
+    /// callers can remove it and fetch it again.
     #[error("Error encrypting or decrypting data from the event cache store: {0}")]
     Encryption(#[from] StoreEncryptionError),
 
+    /// The store failed to serialize or deserialize some data.
+    ///
+    /// Like with [`Encryption`][Self::Encryption], the data is probably
+    /// corrupted, so callers can remove it and fetch it again.
+    #[error("Error serializing or deserializing data from the event cache store: {0}")]
+    Serialization(Box<dyn std::error::Error + Send + Sync>),
+
     /// The store failed to encode or decode some data.
     #[error("Error encoding or decoding data from the event cache store: {0}")]
     Codec(#[from] Utf8Error),
 
+    /// The storage of the store is full, so the data could not be written.
+    ///
+    /// Callers can ask the user to free up some space, or skip caching the
+    /// data.
+    #[error("The storage of the event cache store is full")]
+    StorageFull,
+
     /// The database format has changed in a backwards incompatible way.
     #[error(
         "The database format of the event cache store changed in an incompatible way, \
@@ -79,6 +98,17 @@ impl EventCacheStoreError {
     {
         Self::Backend(Box::new(error))
     }
+
+    /// Create a new [`Serialization`][Self::Serialization] error.
+    ///
+    /// Shorthand for `EventCacheStoreError::Serialization(Box::new(error))`.
+    #[inline]
+    pub fn serialization<E>(error: E) -> Self
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        Self::Serialization(Box::new(error))
+    }
 }
 
 /// An `EventCacheStore` specific result type.
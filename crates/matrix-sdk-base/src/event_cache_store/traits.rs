@@ -16,9 +16,9 @@ use std::{fmt, sync::Arc};
 
 use async_trait::async_trait;
 use matrix_sdk_common::AsyncTraitDeps;
-use ruma::MxcUri;
+use ruma::{MxcUri, OwnedMxcUri, RoomId, ServerName};
 
-use super::EventCacheStoreError;
+use super::{EventCacheStoreError, EventChunk};
 use crate::media::MediaRequest;
 
 /// An abstract trait that can be used to implement different store backends
@@ -74,6 +74,16 @@ pub trait EventCacheStore: AsyncTraitDeps {
         request: &MediaRequest,
     ) -> Result<Option<Vec<u8>>, Self::Error>;
 
+    /// Whether a media file's content is in the media store.
+    ///
+    /// Unlike [`EventCacheStore::get_media_content`], this doesn't read the
+    /// content, and doesn't count as an access of the media.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The `MediaRequest` of the file.
+    async fn contains_media(&self, request: &MediaRequest) -> Result<bool, Self::Error>;
+
     /// Remove a media file's content from the media store.
     ///
     /// # Arguments
@@ -88,6 +98,108 @@ pub trait EventCacheStore: AsyncTraitDeps {
     ///
     /// * `uri` - The `MxcUri` of the media files.
     async fn remove_media_content_for_uri(&self, uri: &MxcUri) -> Result<(), Self::Error>;
+
+    /// Remove all the media files' content whose `MxcUri` points to the given
+    /// homeserver from the media store.
+    ///
+    /// Stores that don't keep the `MxcUri`s in plain text, like encrypted
+    /// stores, may not be able to support this and return an error instead.
+    ///
+    /// Returns the number of media files that were removed.
+    ///
+    /// # Arguments
+    ///
+    /// * `server_name` - The server name in the authority of the `MxcUri`s.
+    async fn remove_media_by_server(&self, server_name: &ServerName) -> Result<u64, Self::Error>;
+
+    /// Remove the media that should not be kept in the media store anymore,
+    /// according to the retention policy of the store, if any.
+    ///
+    /// Returns the number of media files that were removed.
+    async fn clean_up_media_cache(&self) -> Result<u64, Self::Error>;
+
+    /// Mark all the media files' content associated to the given `MxcUri`s as
+    /// recently accessed, so they are the last ones to be removed by the
+    /// retention policy of the store.
+    ///
+    /// This is meant to be called with the media of a room that is about to
+    /// be opened. The `MxcUri`s that are not in the media store are ignored.
+    ///
+    /// Returns the number of media files that were found.
+    ///
+    /// # Arguments
+    ///
+    /// * `uris` - The `MxcUri`s of the media files.
+    async fn warm_media(&self, uris: &[OwnedMxcUri]) -> Result<u64, Self::Error>;
+
+    /// Remove all the data of the session from the store, atomically.
+    ///
+    /// This is meant to be called when the session is logged out. Unlike
+    /// removing the store, the store stays usable for the next session, with
+    /// the same passphrase.
+    async fn clear_session_data(&self) -> Result<(), Self::Error>;
+
+    /// Save the back-pagination token of the gap at the start of the timeline
+    /// of a room.
+    ///
+    /// This replaces the previous token of the room, if any.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The ID of the room.
+    ///
+    /// * `prev_batch_token` - The token to use to paginate backwards from the
+    ///   start of the timeline of the room.
+    async fn save_gap(&self, room_id: &RoomId, prev_batch_token: &str) -> Result<(), Self::Error>;
+
+    /// Get the back-pagination token of the gap at the start of the timeline
+    /// of a room, if any.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The ID of the room.
+    async fn load_gap(&self, room_id: &RoomId) -> Result<Option<String>, Self::Error>;
+
+    /// Remove the back-pagination token of the gap at the start of the
+    /// timeline of a room.
+    ///
+    /// This should be called when the start of the timeline of the room was
+    /// reached.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The ID of the room.
+    async fn clear_gap(&self, room_id: &RoomId) -> Result<(), Self::Error>;
+
+    /// Save a chunk of the timeline of a room.
+    ///
+    /// This replaces the chunk with the same identifier in the room, if any.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The ID of the room.
+    ///
+    /// * `chunk` - The chunk to save.
+    async fn save_event_chunk(
+        &self,
+        room_id: &RoomId,
+        chunk: EventChunk,
+    ) -> Result<(), Self::Error>;
+
+    /// Get all the chunks of the timeline of a room, sorted by following their
+    /// links, like [`sort_event_chunks`](super::sort_event_chunks) does.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The ID of the room.
+    async fn load_event_chunks(&self, room_id: &RoomId) -> Result<Vec<EventChunk>, Self::Error>;
+
+    /// Remove all the chunks of the timeline of a room.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The ID of the room.
+    async fn remove_room_events(&self, room_id: &RoomId) -> Result<(), Self::Error>;
 }
 
 #[repr(transparent)]
@@ -128,6 +240,10 @@ impl<T: EventCacheStore> EventCacheStore for EraseEventCacheStoreError<T> {
         self.0.get_media_content(request).await.map_err(Into::into)
     }
 
+    async fn contains_media(&self, request: &MediaRequest) -> Result<bool, Self::Error> {
+        self.0.contains_media(request).await.map_err(Into::into)
+    }
+
     async fn remove_media_content(&self, request: &MediaRequest) -> Result<(), Self::Error> {
         self.0.remove_media_content(request).await.map_err(Into::into)
     }
@@ -135,6 +251,50 @@ impl<T: EventCacheStore> EventCacheStore for EraseEventCacheStoreError<T> {
     async fn remove_media_content_for_uri(&self, uri: &MxcUri) -> Result<(), Self::Error> {
         self.0.remove_media_content_for_uri(uri).await.map_err(Into::into)
     }
+
+    async fn remove_media_by_server(&self, server_name: &ServerName) -> Result<u64, Self::Error> {
+        self.0.remove_media_by_server(server_name).await.map_err(Into::into)
+    }
+
+    async fn clean_up_media_cache(&self) -> Result<u64, Self::Error> {
+        self.0.clean_up_media_cache().await.map_err(Into::into)
+    }
+
+    async fn warm_media(&self, uris: &[OwnedMxcUri]) -> Result<u64, Self::Error> {
+        self.0.warm_media(uris).await.map_err(Into::into)
+    }
+
+    async fn clear_session_data(&self) -> Result<(), Self::Error> {
+        self.0.clear_session_data().await.map_err(Into::into)
+    }
+
+    async fn save_gap(&self, room_id: &RoomId, prev_batch_token: &str) -> Result<(), Self::Error> {
+        self.0.save_gap(room_id, prev_batch_token).await.map_err(Into::into)
+    }
+
+    async fn load_gap(&self, room_id: &RoomId) -> Result<Option<String>, Self::Error> {
+        self.0.load_gap(room_id).await.map_err(Into::into)
+    }
+
+    async fn clear_gap(&self, room_id: &RoomId) -> Result<(), Self::Error> {
+        self.0.clear_gap(room_id).await.map_err(Into::into)
+    }
+
+    async fn save_event_chunk(
+        &self,
+        room_id: &RoomId,
+        chunk: EventChunk,
+    ) -> Result<(), Self::Error> {
+        self.0.save_event_chunk(room_id, chunk).await.map_err(Into::into)
+    }
+
+    async fn load_event_chunks(&self, room_id: &RoomId) -> Result<Vec<EventChunk>, Self::Error> {
+        self.0.load_event_chunks(room_id).await.map_err(Into::into)
+    }
+
+    async fn remove_room_events(&self, room_id: &RoomId) -> Result<(), Self::Error> {
+        self.0.remove_room_events(room_id).await.map_err(Into::into)
+    }
 }
 
 /// A type-erased [`EventCacheStore`].
@@ -1,10 +1,10 @@
 -- basic kv metadata like the database version and store cipher
-CREATE TABLE "kv" (
+CREATE TABLE IF NOT EXISTS "kv" (
     "key" TEXT PRIMARY KEY NOT NULL,
     "value" BLOB NOT NULL
 );
 
-CREATE TABLE "media" (
+CREATE TABLE IF NOT EXISTS "media" (
     "uri" BLOB NOT NULL,
     "format" BLOB NOT NULL,
     "data" BLOB NOT NULL,
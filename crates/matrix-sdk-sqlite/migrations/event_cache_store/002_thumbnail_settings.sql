@@ -0,0 +1,6 @@
+-- The settings of the thumbnails, to be able to list them even if the format
+-- key is hashed. They are NULL for files, and for the thumbnails that were
+-- added before this migration.
+ALTER TABLE "media" ADD COLUMN "thumbnail_width" INTEGER;
+ALTER TABLE "media" ADD COLUMN "thumbnail_height" INTEGER;
+ALTER TABLE "media" ADD COLUMN "thumbnail_method" TEXT;
@@ -0,0 +1,22 @@
+-- The material needed to decrypt the encrypted media, with the same keys as
+-- the `media` table.
+CREATE TABLE "media_keys" (
+    "uri" BLOB NOT NULL,
+    "format" BLOB NOT NULL,
+    "key_info" BLOB NOT NULL,
+
+    PRIMARY KEY ("uri", "format")
+);
+
+-- The keys are useless without the media, so remove them together.
+CREATE TRIGGER "media_keys_remove_with_media" AFTER DELETE ON "media"
+BEGIN
+    DELETE FROM "media_keys" WHERE "uri" = OLD."uri" AND "format" = OLD."format";
+END;
+
+-- Follow the media when its key is replaced.
+CREATE TRIGGER "media_keys_replace_with_media" AFTER UPDATE OF "uri", "format" ON "media"
+BEGIN
+    UPDATE "media_keys" SET "uri" = NEW."uri", "format" = NEW."format"
+    WHERE "uri" = OLD."uri" AND "format" = OLD."format";
+END;
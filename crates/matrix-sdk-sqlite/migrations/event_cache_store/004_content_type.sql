@@ -0,0 +1,4 @@
+-- The content type of the media, encoded like the data because it can leak
+-- information about the media. It is NULL when it is unknown, like for the
+-- media that were added before this migration.
+ALTER TABLE "media" ADD COLUMN "content_type" BLOB;
@@ -0,0 +1,60 @@
+-- The contents of the media, stored once for all the media with the same
+-- content. The hash is computed on the plain content, with a key derived from
+-- the store cipher in an encrypted store.
+CREATE TABLE "media_blobs" (
+    "hash" BLOB PRIMARY KEY NOT NULL,
+    "data" BLOB NOT NULL
+);
+
+-- The hashes of the existing contents can't be computed here, so they use
+-- unique placeholders, and will not be shared.
+INSERT INTO "media_blobs" ("hash", "data")
+    SELECT CAST('legacy-' || "rowid" AS BLOB), "data" FROM "media";
+
+CREATE TABLE "media_new" (
+    "uri" BLOB NOT NULL,
+    "format" BLOB NOT NULL,
+    "blob_hash" BLOB NOT NULL,
+    "last_access" INTEGER NOT NULL,
+    "thumbnail_width" INTEGER,
+    "thumbnail_height" INTEGER,
+    "thumbnail_method" TEXT,
+    "content_type" BLOB,
+
+    PRIMARY KEY ("uri", "format")
+);
+
+INSERT INTO "media_new" (
+    "rowid", "uri", "format", "blob_hash", "last_access",
+    "thumbnail_width", "thumbnail_height", "thumbnail_method", "content_type"
+)
+    SELECT "rowid", "uri", "format", CAST('legacy-' || "rowid" AS BLOB), "last_access",
+        "thumbnail_width", "thumbnail_height", "thumbnail_method", "content_type"
+    FROM "media";
+
+-- This also drops the triggers of the table.
+DROP TABLE "media";
+ALTER TABLE "media_new" RENAME TO "media";
+
+CREATE INDEX "media_blob_hash_idx" ON "media" ("blob_hash");
+
+-- Remove the content when the last media using it is removed. Note that this
+-- doesn't fire when a media is replaced with `INSERT OR REPLACE`, so the
+-- previous content must be removed manually in this case.
+CREATE TRIGGER "media_blobs_remove_unused" AFTER DELETE ON "media"
+BEGIN
+    DELETE FROM "media_blobs" WHERE "hash" = OLD."blob_hash"
+        AND NOT EXISTS (SELECT 1 FROM "media" WHERE "blob_hash" = OLD."blob_hash");
+END;
+
+-- Recreate the triggers of `003_media_keys.sql`.
+CREATE TRIGGER "media_keys_remove_with_media" AFTER DELETE ON "media"
+BEGIN
+    DELETE FROM "media_keys" WHERE "uri" = OLD."uri" AND "format" = OLD."format";
+END;
+
+CREATE TRIGGER "media_keys_replace_with_media" AFTER UPDATE OF "uri", "format" ON "media"
+BEGIN
+    UPDATE "media_keys" SET "uri" = NEW."uri", "format" = NEW."format"
+    WHERE "uri" = OLD."uri" AND "format" = OLD."format";
+END;
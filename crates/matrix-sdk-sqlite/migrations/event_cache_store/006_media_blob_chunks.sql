@@ -0,0 +1,15 @@
+-- The chunks of the media contents that are split in several rows, the first
+-- chunk is stored in `media_blobs`.
+CREATE TABLE "media_blob_chunks" (
+    "hash" BLOB NOT NULL,
+    "seq" INTEGER NOT NULL,
+    "data" BLOB NOT NULL,
+
+    PRIMARY KEY ("hash", "seq")
+);
+
+-- The chunks are useless without the first one, so remove them together.
+CREATE TRIGGER "media_blob_chunks_remove_with_blob" AFTER DELETE ON "media_blobs"
+BEGIN
+    DELETE FROM "media_blob_chunks" WHERE "hash" = OLD."hash";
+END;
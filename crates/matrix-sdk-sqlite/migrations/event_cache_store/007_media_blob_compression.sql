@@ -0,0 +1,3 @@
+-- Whether the content was compressed before being encoded. The existing
+-- contents are not compressed.
+ALTER TABLE "media_blobs" ADD COLUMN "compressed" BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,8 @@
+-- The kind of the format of the media, to be able to reconstruct it with the
+-- thumbnail settings even if the format key is hashed: `file`, `thumbnail` or
+-- `animated_thumbnail`. It is NULL for the media that were added before this
+-- migration, except for the files of an unencrypted store, whose format key
+-- is known.
+ALTER TABLE "media" ADD COLUMN "format_kind" TEXT;
+
+UPDATE "media" SET "format_kind" = 'file' WHERE "format" = CAST('file' AS BLOB);
@@ -0,0 +1,6 @@
+-- The back-pagination token of the gap at the start of the timeline of each
+-- room.
+CREATE TABLE "gaps" (
+    "room_id" BLOB PRIMARY KEY NOT NULL,
+    "prev_batch_token" BLOB NOT NULL
+);
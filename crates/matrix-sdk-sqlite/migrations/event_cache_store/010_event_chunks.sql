@@ -0,0 +1,11 @@
+-- The linked chunks of the timeline of each room.
+CREATE TABLE "event_chunks" (
+    "room_id" BLOB NOT NULL,
+    "chunk_id" INTEGER NOT NULL,
+    "previous" INTEGER,
+    "next" INTEGER,
+    -- The serialized content of the chunk, events or gap.
+    "content" BLOB NOT NULL,
+
+    PRIMARY KEY ("room_id", "chunk_id")
+);
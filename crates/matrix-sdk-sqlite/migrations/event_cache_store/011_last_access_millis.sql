@@ -0,0 +1,4 @@
+-- The last access of the media is now stored as a number of milliseconds
+-- since the Unix epoch, instead of seconds. The existing rows are converted so
+-- they keep their relative order with the new ones.
+UPDATE "media" SET "last_access" = "last_access" * 1000;
@@ -0,0 +1,2 @@
+-- The pinned media are never evicted from the cache.
+ALTER TABLE "media" ADD COLUMN "pinned" BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,24 @@
+-- The rooms that the media belong to, with the same keys as the `media` table.
+-- A media can belong to several rooms, or to none.
+CREATE TABLE "media_rooms" (
+    "room_id" BLOB NOT NULL,
+    "uri" BLOB NOT NULL,
+    "format" BLOB NOT NULL,
+
+    PRIMARY KEY ("room_id", "uri", "format")
+);
+
+CREATE INDEX "media_rooms_media_idx" ON "media_rooms" ("uri", "format");
+
+-- The associations are useless without the media, so remove them together.
+CREATE TRIGGER "media_rooms_remove_with_media" AFTER DELETE ON "media"
+BEGIN
+    DELETE FROM "media_rooms" WHERE "uri" = OLD."uri" AND "format" = OLD."format";
+END;
+
+-- Follow the media when its key is replaced.
+CREATE TRIGGER "media_rooms_replace_with_media" AFTER UPDATE OF "uri", "format" ON "media"
+BEGIN
+    UPDATE "media_rooms" SET "uri" = NEW."uri", "format" = NEW."format"
+    WHERE "uri" = OLD."uri" AND "format" = OLD."format";
+END;
@@ -0,0 +1,9 @@
+-- The metadata stored by the applications, separated from the `kv` table of
+-- the SDK so they can't collide.
+CREATE TABLE "custom_kv" (
+    "namespace" BLOB NOT NULL,
+    "key" BLOB NOT NULL,
+    "value" BLOB NOT NULL,
+
+    PRIMARY KEY ("namespace", "key")
+);
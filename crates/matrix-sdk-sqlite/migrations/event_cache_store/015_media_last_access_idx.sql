@@ -0,0 +1,4 @@
+-- The media are evicted and listed by order of last access, this avoids a full
+-- scan of the table followed by a sort. The index doesn't start with "pinned",
+-- so it can also be used when listing all the media.
+CREATE INDEX "media_last_access_idx" ON "media" ("last_access");
@@ -0,0 +1,3 @@
+-- The time when a media was removed, in milliseconds since the Unix epoch, in
+-- soft-delete mode. The removed media are kept until they are purged.
+ALTER TABLE "media" ADD COLUMN "deleted_at" INTEGER;
@@ -0,0 +1,7 @@
+-- The account namespace of the data of the stores sharing the database, keyed
+-- like the other keys. It is `NULL` for the stores without namespace.
+ALTER TABLE "media" ADD COLUMN "account" BLOB;
+ALTER TABLE "gaps" ADD COLUMN "account" BLOB;
+ALTER TABLE "event_chunks" ADD COLUMN "account" BLOB;
+
+CREATE INDEX "media_account_idx" ON "media" ("account");
@@ -0,0 +1,6 @@
+-- The media are evicted and listed by order of last access in the account
+-- namespace of a store, so the index of the last access starts with the
+-- namespace. It also replaces the index of the namespace.
+CREATE INDEX "media_account_last_access_idx" ON "media" ("account", "last_access");
+DROP INDEX "media_last_access_idx";
+DROP INDEX "media_account_idx";
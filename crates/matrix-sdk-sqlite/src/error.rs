@@ -30,6 +30,14 @@ pub enum OpenStoreError {
     #[error("Failed to create the database's parent directory")]
     CreateDir(#[source] io::Error),
 
+    /// Failed to create the DB file.
+    #[error("Failed to create the database file")]
+    CreateFile(#[source] io::Error),
+
+    /// The name of the database file is not a plain file name.
+    #[error("Invalid database file name: {0}")]
+    InvalidDatabaseFileName(String),
+
     /// Failed to create the DB pool.
     #[error(transparent)]
     CreatePool(#[from] CreatePoolError),
@@ -46,6 +54,62 @@ pub enum OpenStoreError {
     #[error("Invalid database version")]
     InvalidVersion,
 
+    /// The database was migrated by a newer version of the SDK, whose schema is
+    /// not supported.
+    #[error("Unsupported version of the database, found: {found}, supported: {supported}")]
+    UnsupportedDatabaseVersion {
+        /// The version of the database.
+        found: u8,
+        /// The latest version supported by this version of the SDK.
+        supported: u8,
+    },
+
+    /// The data of the database uses a storage format that is not supported,
+    /// it was probably created by a different major version of the SDK.
+    #[error("Unsupported storage format of the database, found: {found}, supported: {supported}")]
+    IncompatibleStorageFormat {
+        /// The storage format of the database.
+        found: u8,
+        /// The storage format supported by this version of the SDK.
+        supported: u8,
+    },
+
+    /// The envelope of the encrypted values of the database uses a different
+    /// codec than the one of the configuration.
+    #[error("Incompatible codec of the encrypted values, found: {found}, expected: {expected}")]
+    IncompatibleEnvelopeCodec {
+        /// The tag of the codec of the database.
+        found: u8,
+        /// The tag of the codec of the configuration.
+        expected: u8,
+    },
+
+    /// The integrity check of the database found a problem.
+    #[error("The database is corrupted: {0}")]
+    Corrupted(String),
+
+    /// Failed to check the integrity of the database.
+    #[error("Failed to check the integrity of the database")]
+    CheckIntegrity(#[source] rusqlite::Error),
+
+    /// The database must be migrated, which is not possible for a store opened
+    /// in read-only mode.
+    #[error(
+        "The database must be migrated before being opened in read-only mode, \
+         found: {found}, required: {required}"
+    )]
+    MigrationRequired {
+        /// The version of the database.
+        found: u8,
+        /// The version required by this version of the SDK.
+        required: u8,
+    },
+
+    /// The store cipher is missing, and can't be created for a store opened in
+    /// read-only mode.
+    #[error("The store cipher is missing and can't be created in read-only mode")]
+    MissingStoreCipher,
+
     /// Failed to apply migrations.
     #[error("Failed to run migrations")]
     Migration(#[from] Error),
@@ -58,6 +122,10 @@ pub enum OpenStoreError {
     #[error("Failed to initialize the store cipher")]
     InitCipher(#[from] matrix_sdk_store_encryption::Error),
 
+    /// The store cipher could not be decrypted with the given passphrase.
+    #[error("The passphrase of the store is incorrect")]
+    IncorrectPassphrase,
+
     /// Failed to load the store cipher from the DB.
     #[error("Failed to load the store cipher from the DB")]
     LoadCipher(#[source] rusqlite::Error),
@@ -69,6 +137,7 @@ pub enum OpenStoreError {
 
 #[derive(Debug, Error)]
 pub enum Error {
+    /// A failure of SQLite, like an I/O error or a constraint violation.
     #[error(transparent)]
     Sqlite(rusqlite::Error),
 
@@ -78,15 +147,29 @@ pub enum Error {
     #[error(transparent)]
     Pool(PoolError),
 
+    #[error("Timed out waiting for a connection of the pool")]
+    PoolTimeout,
+
+    /// A value failed to be serialized with MessagePack.
     #[error(transparent)]
     Encode(rmp_serde::encode::Error),
 
+    /// A value failed to be deserialized with MessagePack, it is probably
+    /// corrupted.
     #[error(transparent)]
     Decode(rmp_serde::decode::Error),
 
+    /// A value failed to be serialized or deserialized with JSON.
     #[error(transparent)]
     Json(#[from] serde_json::Error),
 
+    /// The envelope of an encrypted value failed to be serialized or
+    /// deserialized with the configured codec.
+    #[error("Failed to serialize or deserialize the envelope of an encrypted value")]
+    Codec(#[source] Box<dyn std::error::Error + Send + Sync>),
+
+    /// A value failed to be encrypted or decrypted with the store cipher, it
+    /// is probably corrupted or encrypted with another key.
     #[error(transparent)]
     Encryption(matrix_sdk_store_encryption::Error),
 
@@ -101,6 +184,78 @@ pub enum Error {
 
     #[error("Redaction failed: {0}")]
     Redaction(#[source] ruma::canonical_json::RedactionError),
+
+    #[error("The keys of the store are hashed and can't be queried")]
+    EncryptedKeysNotQueryable,
+
+    #[error("The media of an encrypted store can't be streamed")]
+    EncryptedMediaNotStreamable,
+
+    #[error("A compressed media can't be streamed")]
+    CompressedMediaNotStreamable,
+
+    /// The media was removed or replaced while its content was streamed.
+    #[error("The media was removed or replaced while it was streamed")]
+    MediaChangedWhileStreaming,
+
+    /// The content of a media doesn't match the hash it was stored with, it
+    /// was corrupted on disk.
+    #[error("The media content is corrupted")]
+    CorruptedMedia,
+
+    /// The requested range is not inside the media content.
+    #[error("Invalid range of {len} bytes at offset {offset} of a media content of {size} bytes")]
+    InvalidMediaRange {
+        /// The requested offset.
+        offset: usize,
+        /// The requested length.
+        len: usize,
+        /// The size of the media content.
+        size: usize,
+    },
+
+    /// The loader of the missing media failed.
+    #[error("Failed to load a missing media")]
+    MediaLoader(#[source] Box<dyn std::error::Error + Send + Sync>),
+
+    #[error("Failed to compress or decompress a media content")]
+    Compression(#[source] std::io::Error),
+
+    #[error("Failed to write or read an exported media cache")]
+    Export(#[source] std::io::Error),
+
+    /// The name of a file in the manifest of an exported media cache is not
+    /// the name of the file of its media, so it could point outside of the
+    /// export.
+    #[error("Invalid file name in an exported media cache: {0}")]
+    InvalidExportFileName(String),
+
+    /// The exported media cache was made with another
+    /// [account namespace](crate::SqliteEventCacheStoreConfig::account_namespace),
+    /// so its keys can't be used in this store.
+    #[error("The exported media cache is from another account namespace")]
+    ExportNamespaceMismatch,
+
+    #[error("The store is not encrypted with a passphrase")]
+    NotEncrypted,
+
+    /// The store cipher was given when the store was opened, it is not stored
+    /// in the database with a passphrase.
+    #[error("The store cipher is not stored in the database")]
+    StoreCipherNotStored,
+
+    /// The store cipher could not be decrypted with the given passphrase.
+    #[error("The passphrase of the store is incorrect")]
+    IncorrectPassphrase,
+
+    #[error("The storage of the database is full")]
+    StorageFull(#[source] rusqlite::Error),
+
+    #[error("The store was opened in read-only mode")]
+    ReadOnly,
+
+    #[error("Failed to get the size of the database files")]
+    DiskSize(#[source] std::io::Error),
 }
 
 macro_rules! impl_from {
@@ -113,7 +268,33 @@ macro_rules! impl_from {
     };
 }
 
-impl_from!(rusqlite::Error => Error::Sqlite);
+impl From<rusqlite::Error> for Error {
+    fn from(error: rusqlite::Error) -> Self {
+        // Other I/O errors are not included, because they are usually not fixed by
+        // freeing up space.
+        if error.sqlite_error_code() == Some(rusqlite::ErrorCode::DiskFull) {
+            Self::StorageFull(error)
+        } else {
+            Self::Sqlite(error)
+        }
+    }
+}
+
+impl Error {
+    /// Whether this is a transient error caused by another connection holding
+    /// a lock on the database, that can be retried.
+    pub(crate) fn is_busy(&self) -> bool {
+        let Self::Sqlite(error) = self else {
+            return false;
+        };
+
+        matches!(
+            error.sqlite_error_code(),
+            Some(rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked)
+        )
+    }
+}
+
 impl_from!(PoolError => Error::Pool);
 impl_from!(rmp_serde::encode::Error => Error::Encode);
 impl_from!(rmp_serde::decode::Error => Error::Decode);
@@ -143,6 +324,12 @@ impl From<Error> for EventCacheStoreError {
     fn from(e: Error) -> Self {
         match e {
             Error::Encryption(e) => EventCacheStoreError::Encryption(e),
+            Error::StorageFull(_) => EventCacheStoreError::StorageFull,
+            e @ (Error::Encode(_)
+            | Error::Decode(_)
+            | Error::Json(_)
+            | Error::Codec(_)
+            | Error::Compression(_)) => EventCacheStoreError::serialization(e),
             e => EventCacheStoreError::backend(e),
         }
     }
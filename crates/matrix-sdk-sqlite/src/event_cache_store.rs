@@ -1,15 +1,44 @@
-use std::{borrow::Cow, fmt, path::Path, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet},
+    fmt,
+    future::Future,
+    hash::Hasher,
+    num::NonZeroUsize,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex as StdMutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
 
 use async_trait::async_trait;
-use deadpool_sqlite::{Object as SqliteAsyncConn, Pool as SqlitePool, Runtime};
+use deadpool_sqlite::{
+    CreatePoolError, Hook, HookError, Object as SqliteAsyncConn, Pool as SqlitePool, PoolConfig,
+    Runtime,
+};
+use futures_core::Stream;
+use futures_util::{
+    future::{BoxFuture, FutureExt},
+    stream, TryStreamExt,
+};
 use matrix_sdk_base::{
-    event_cache_store::EventCacheStore,
-    media::{MediaRequest, UniqueKey},
+    event_cache_store::{sort_event_chunks, EventCacheStore, EventChunk, EventChunkContent},
+    media::{MediaFormat, MediaRequest, MediaThumbnailSettings, UniqueKey},
+};
+use matrix_sdk_store_encryption::{EncryptedValue, StoreCipher};
+use ruma::{
+    events::room::{EncryptedFile, JsonWebKey},
+    media::Method,
+    serde::Base64,
+    MxcUri, OwnedMxcUri, RoomId, ServerName, UInt,
 };
-use matrix_sdk_store_encryption::StoreCipher;
-use rusqlite::OptionalExtension;
+use rusqlite::{params_from_iter, DatabaseName, OptionalExtension, ToSql};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use tokio::fs;
-use tracing::debug;
+use tracing::{debug, field, instrument, warn, Span};
 
 use crate::{
     error::{Error, Result},
@@ -18,349 +47,11076 @@ use crate::{
 };
 
 mod keys {
+    // Entries in Key-value store
+    pub const STORAGE_FORMAT: &str = "sdk_storage_format";
+    pub const ENVELOPE_CODEC: &str = "envelope_codec";
+    pub const CREATED_AT: &str = "created_at";
+    pub const REENCODE_PROGRESS: &str = "reencode_progress";
+
     // Tables
     pub const MEDIA: &str = "media";
+    pub const MEDIA_KEYS: &str = "media_keys";
+    pub const MEDIA_ROOMS: &str = "media_rooms";
+    pub const MEDIA_BLOBS: &str = "media_blobs";
+    pub const MEDIA_BLOB_CHUNKS: &str = "media_blob_chunks";
+    pub const GAPS: &str = "gaps";
+    pub const EVENT_CHUNKS: &str = "event_chunks";
+    pub const CUSTOM_KV: &str = "custom_kv";
+    pub const ACCOUNT: &str = "account";
 }
 
+/// The query computing the total size of the media contents of the account
+/// namespace given as parameter in the cache.
+///
+/// The contents that are only used by media marked as deleted are not
+/// counted, since these media can't be evicted.
+const CACHE_SIZE_QUERY: &str = "SELECT \
+     (SELECT COALESCE(SUM(length(data)), 0) FROM media_blobs WHERE EXISTS \
+        (SELECT 1 FROM media WHERE media.blob_hash = media_blobs.hash \
+         AND media.deleted_at IS NULL AND media.account IS ?1)) + \
+     (SELECT COALESCE(SUM(length(data)), 0) FROM media_blob_chunks WHERE EXISTS \
+        (SELECT 1 FROM media WHERE media.blob_hash = media_blob_chunks.hash \
+         AND media.deleted_at IS NULL AND media.account IS ?1))";
+
+/// The query selecting the media of the account namespace given as parameter
+/// that can be evicted from the least recently accessed one, with the size of
+/// their content, in the order they are evicted.
+///
+/// The pinned media are never evicted.
+const LEAST_RECENTLY_ACCESSED_MEDIA_QUERY: &str =
+    "SELECT media.rowid, media.uri, media.format, media.blob_hash, \
+     length(media_blobs.data) + (SELECT COALESCE(SUM(length(data)), 0) \
+        FROM media_blob_chunks WHERE media_blob_chunks.hash = media_blobs.hash), \
+     media.last_access \
+     FROM media JOIN media_blobs ON media_blobs.hash = media.blob_hash \
+     WHERE NOT media.pinned AND media.deleted_at IS NULL AND media.account IS ? \
+     ORDER BY media.last_access ASC, media.rowid ASC";
+
 /// Identifier of the latest database version.
 ///
 /// This is used to figure whether the SQLite database requires a migration.
-/// Every new SQL migration should imply a bump of this number, and changes in
-/// the [`SqliteEventCacheStore::run_migrations`] function.
-const DATABASE_VERSION: u8 = 1;
+/// Every new SQL migration should imply a bump of this number, and a new entry
+/// in [`MIGRATIONS`].
+const DATABASE_VERSION: u8 = 18;
 
-/// A SQLite-based event cache store.
-#[derive(Clone)]
-pub struct SqliteEventCacheStore {
-    store_cipher: Option<Arc<StoreCipher>>,
-    pool: SqlitePool,
-}
+/// The SQL migrations of the database.
+///
+/// The migration at index `i` upgrades the database from the version `i` to the
+/// version `i + 1`.
+const MIGRATIONS: [&str; DATABASE_VERSION as usize] = [
+    include_str!("../migrations/event_cache_store/001_init.sql"),
+    include_str!("../migrations/event_cache_store/002_thumbnail_settings.sql"),
+    include_str!("../migrations/event_cache_store/003_media_keys.sql"),
+    include_str!("../migrations/event_cache_store/004_content_type.sql"),
+    include_str!("../migrations/event_cache_store/005_media_blobs.sql"),
+    include_str!("../migrations/event_cache_store/006_media_blob_chunks.sql"),
+    include_str!("../migrations/event_cache_store/007_media_blob_compression.sql"),
+    include_str!("../migrations/event_cache_store/008_format_kind.sql"),
+    include_str!("../migrations/event_cache_store/009_gaps.sql"),
+    include_str!("../migrations/event_cache_store/010_event_chunks.sql"),
+    include_str!("../migrations/event_cache_store/011_last_access_millis.sql"),
+    include_str!("../migrations/event_cache_store/012_media_pinned.sql"),
+    include_str!("../migrations/event_cache_store/013_media_rooms.sql"),
+    include_str!("../migrations/event_cache_store/014_custom_kv.sql"),
+    include_str!("../migrations/event_cache_store/015_media_last_access_idx.sql"),
+    include_str!("../migrations/event_cache_store/016_media_deleted_at.sql"),
+    include_str!("../migrations/event_cache_store/017_account_namespace.sql"),
+    include_str!("../migrations/event_cache_store/018_media_account_last_access_idx.sql"),
+];
 
-#[cfg(not(tarpaulin_include))]
-impl fmt::Debug for SqliteEventCacheStore {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("SqliteEventCacheStore").finish_non_exhaustive()
-    }
-}
+/// Identifier of the storage format of the data.
+///
+/// Contrary to [`DATABASE_VERSION`], this is not about the schema of the
+/// database but about changes of the format of the data that can't be
+/// migrated, like the envelope of encrypted values. It must be bumped when the
+/// data written by the SDK can't be read by the previous versions anymore, or
+/// vice versa. Opening a store with a different storage format fails with
+/// [`OpenStoreError::IncompatibleStorageFormat`].
+const STORAGE_FORMAT: u8 = 1;
 
-impl SqliteEventCacheStore {
-    /// Open the SQLite-based event cache store at the given path using the
-    /// given passphrase to encrypt private data.
-    pub async fn open(
-        path: impl AsRef<Path>,
-        passphrase: Option<&str>,
-    ) -> Result<Self, OpenStoreError> {
-        let pool = create_pool(path.as_ref()).await?;
+/// The maximum duration to wait for a connection in
+/// [`SqliteEventCacheStore::ping`].
+const PING_TIMEOUT: Duration = Duration::from_secs(1);
 
-        Self::open_with_pool(pool, passphrase).await
-    }
+/// The default name of the database file, in the directory of the store.
+const DATABASE_FILE_NAME: &str = "matrix-sdk-event-cache.sqlite3";
 
-    /// Open an SQLite-based event cache store using the given SQLite database
-    /// pool. The given passphrase will be used to encrypt private data.
-    pub async fn open_with_pool(
-        pool: SqlitePool,
-        passphrase: Option<&str>,
-    ) -> Result<Self, OpenStoreError> {
-        let conn = pool.get().await?;
-        let version = conn.db_version().await?;
-        run_migrations(&conn, version).await?;
+/// The algorithm used by the store cipher to encrypt the values, see
+/// [`SqliteEventCacheStore::cipher_algorithm`].
+const STORE_CIPHER_ALGORITHM: &str = "XChaCha20-Poly1305";
 
-        let store_cipher = match passphrase {
-            Some(p) => Some(Arc::new(conn.get_or_create_store_cipher(p).await?)),
-            None => None,
-        };
+/// The default Unix permissions of the directory of the store, when it is
+/// created.
+#[cfg(unix)]
+const DEFAULT_DIR_MODE: u32 = 0o700;
+
+/// The configuration of a [`SqliteEventCacheStore`].
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use matrix_sdk_sqlite::SqliteEventCacheStoreConfig;
+///
+/// // Report the media reads that take more than 100ms.
+/// let config = SqliteEventCacheStoreConfig::new()
+///     .slow_read_threshold(Duration::from_millis(100));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct SqliteEventCacheStoreConfig {
+    slow_read_threshold: Option<Duration>,
+    read_uncommitted: bool,
+    on_decode_failure: DecodeFailurePolicy,
+    verify_media_content: bool,
+    media_retention_policy: MediaRetentionPolicy,
+    busy_retry_policy: BusyRetryPolicy,
+    on_soft_limit_reached: Option<Callback<dyn Fn(u64) + Send + Sync>>,
+    on_media_evicted: Option<Callback<dyn Fn(&[EvictedMedia]) + Send + Sync>>,
+    on_migration_progress: Option<Callback<dyn Fn(u8, u8) + Send + Sync>>,
+    file_locking: FileLocking,
+    locking_mode: LockingMode,
+    mmap_size: Option<u64>,
+    synchronous: Option<Synchronous>,
+    busy_timeout: Option<Duration>,
+    wal_autocheckpoint: Option<u32>,
+    page_size: Option<u32>,
+    incremental_vacuum: Option<u32>,
+    cache_size: Option<i32>,
+    pool_max_size: Option<usize>,
+    acquire_timeout: Option<Duration>,
+    database_file_name: Option<String>,
+    hash_keys: bool,
+    account_namespace: Option<String>,
+    kdf_rounds: Option<u32>,
+    integrity_check: IntegrityCheck,
+    media_chunk_size: Option<usize>,
+    compress_media: bool,
+    clock: Option<Callback<dyn Fn() -> SystemTime + Send + Sync>>,
+    media_loader: Option<Callback<MediaLoader>>,
+    envelope_codec: Option<Arc<dyn EnvelopeCodec>>,
+    previous_envelope_codec: Option<Arc<dyn EnvelopeCodec>>,
+    deferred_access_times: Option<usize>,
+    soft_delete: bool,
+    #[cfg(feature = "sqlcipher")]
+    sqlcipher_key: Option<SqlCipherKey>,
+    #[cfg(unix)]
+    unix_mode: Option<u32>,
+}
 
-        Ok(Self { store_cipher, pool })
+impl SqliteEventCacheStoreConfig {
+    /// Create a new default `SqliteEventCacheStoreConfig`.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    fn encode_value(&self, value: Vec<u8>) -> Result<Vec<u8>> {
-        if let Some(key) = &self.store_cipher {
-            let encrypted = key.encrypt_value_data(value)?;
-            Ok(rmp_serde::to_vec_named(&encrypted)?)
-        } else {
-            Ok(value)
-        }
+    /// Set the duration above which a read of a media content is considered
+    /// slow.
+    ///
+    /// Slow reads are logged with a warning and counted in
+    /// [`EventCacheMetrics::slow_reads`]. Reads are not timed at all if this is
+    /// not set, which is the default.
+    #[must_use]
+    pub fn slow_read_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_read_threshold = Some(threshold);
+        self
     }
 
-    fn decode_value<'a>(&self, value: &'a [u8]) -> Result<Cow<'a, [u8]>> {
-        if let Some(key) = &self.store_cipher {
-            let encrypted = rmp_serde::from_slice(value)?;
-            let decrypted = key.decrypt_value_data(encrypted)?;
-            Ok(Cow::Owned(decrypted))
-        } else {
-            Ok(Cow::Borrowed(value))
-        }
+    /// Set whether the connections should use the `READ UNCOMMITTED`
+    /// isolation level, with `PRAGMA read_uncommitted`.
+    ///
+    /// This only has an effect on connections using SQLite's shared-cache
+    /// mode, which is not the case of the connections created by the store,
+    /// so this is only meant for embedders attaching their own tables to
+    /// connections that share their cache, and who accept to read data from
+    /// transactions that might be rolled back. Connections that don't share
+    /// their cache always read a consistent snapshot of the database in WAL
+    /// mode.
+    ///
+    /// Defaults to `false`.
+    #[must_use]
+    pub fn read_uncommitted(mut self, read_uncommitted: bool) -> Self {
+        self.read_uncommitted = read_uncommitted;
+        self
     }
 
-    fn encode_key(&self, table_name: &str, key: impl AsRef<[u8]>) -> Key {
-        let bytes = key.as_ref();
-        if let Some(store_cipher) = &self.store_cipher {
-            Key::Hashed(store_cipher.hash_key(table_name, bytes))
-        } else {
-            Key::Plain(bytes.to_owned())
-        }
+    /// Set what to do when the content of a media fails to be decoded in
+    /// [`EventCacheStore::get_media_content`].
+    ///
+    /// Defaults to [`DecodeFailurePolicy::Error`].
+    #[must_use]
+    pub fn on_decode_failure(mut self, policy: DecodeFailurePolicy) -> Self {
+        self.on_decode_failure = policy;
+        self
     }
 
-    async fn acquire(&self) -> Result<SqliteAsyncConn> {
-        Ok(self.pool.get().await?)
+    /// Set whether the content of a media is verified when it is read with
+    /// [`EventCacheStore::get_media_content`].
+    ///
+    /// The contents are stored under the SHA-256 hash of their plain content,
+    /// keyed in an encrypted store. When this is enabled, the hash is computed
+    /// again on the content that was read, and if it doesn't match, the
+    /// content was corrupted on disk: all the media using it are removed from
+    /// the cache, and the read fails with [`Error::CorruptedMedia`],
+    /// regardless of the [`DecodeFailurePolicy`].
+    ///
+    /// This is mostly useful for stores without passphrase, since the
+    /// encrypted values are authenticated, so a corrupted content already
+    /// fails to be decrypted. The contents stored by versions of the SDK that
+    /// didn't hash them are not verified.
+    ///
+    /// Defaults to `false`, since hashing the content makes every read slower.
+    #[must_use]
+    pub fn verify_media_content(mut self, verify: bool) -> Self {
+        self.verify_media_content = verify;
+        self
     }
-}
 
-async fn create_pool(path: &Path) -> Result<SqlitePool, OpenStoreError> {
-    fs::create_dir_all(path).await.map_err(OpenStoreError::CreateDir)?;
-    let cfg = deadpool_sqlite::Config::new(path.join("matrix-sdk-event-cache.sqlite3"));
-    Ok(cfg.create_pool(Runtime::Tokio1)?)
-}
+    /// Set how the writes that fail because the database is locked by
+    /// another connection are retried.
+    ///
+    /// This is on top of [`SqliteEventCacheStoreConfig::busy_timeout`], which
+    /// makes SQLite wait for the lock, but doesn't apply in every case, like
+    /// when a read transaction must be upgraded to a write transaction.
+    ///
+    /// Defaults to [`BusyRetryPolicy::default`].
+    #[must_use]
+    pub fn busy_retry_policy(mut self, policy: BusyRetryPolicy) -> Self {
+        self.busy_retry_policy = policy;
+        self
+    }
 
-/// Run migrations for the given version of the database.
-async fn run_migrations(conn: &SqliteAsyncConn, version: u8) -> Result<()> {
-    if version == 0 {
-        debug!("Creating database");
-    } else if version < DATABASE_VERSION {
-        debug!(version, new_version = DATABASE_VERSION, "Upgrading database");
-    } else {
-        return Ok(());
+    /// Set the policy that decides which media are kept in the cache.
+    ///
+    /// Defaults to a policy without any limit.
+    #[must_use]
+    pub fn media_retention_policy(mut self, policy: MediaRetentionPolicy) -> Self {
+        self.media_retention_policy = policy;
+        self
     }
 
-    if version < 1 {
-        // First turn on WAL mode, this can't be done in the transaction, it fails with
-        // the error message: "cannot change into wal mode from within a transaction".
-        conn.execute_batch("PRAGMA journal_mode = wal;").await?;
-        conn.with_transaction(|txn| {
-            txn.execute_batch(include_str!("../migrations/event_cache_store/001_init.sql"))?;
-            txn.set_db_version(1)
-        })
-        .await?;
+    /// Set the callback to call when the total size of the media cache goes
+    /// over [`MediaRetentionPolicy::soft_limit`].
+    ///
+    /// The callback is called with the total size of the media cache, after
+    /// the media content that made it go over the limit was added. It is only
+    /// called once until the size of the cache goes back under the limit, so
+    /// the application can react, like asking the user to free some space,
+    /// before media start being removed.
+    ///
+    /// The callback is called from an async context, so it must not block.
+    #[must_use]
+    pub fn on_soft_limit_reached(mut self, callback: impl Fn(u64) + Send + Sync + 'static) -> Self {
+        self.on_soft_limit_reached = Some(Callback(Arc::new(callback)));
+        self
     }
 
-    Ok(())
-}
+    /// Set a callback to call when media are evicted from the cache.
+    ///
+    /// The callback is called with the media that were removed because of
+    /// [`MediaRetentionPolicy::max_cache_size`] when a media is added, or
+    /// because of [`MediaRetentionPolicy::max_age`] when
+    /// [`EventCacheStore::clean_up_media_cache`] is called. It is only called
+    /// after the removal is committed, so it is never called for media that
+    /// are still in the cache.
+    #[must_use]
+    pub fn on_media_evicted(
+        mut self,
+        callback: impl Fn(&[EvictedMedia]) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_media_evicted = Some(Callback(Arc::new(callback)));
+        self
+    }
 
-#[async_trait]
-impl EventCacheStore for SqliteEventCacheStore {
-    type Error = Error;
+    /// Set a callback to call when the database is migrated to a newer
+    /// version when the store is opened.
+    ///
+    /// Migrations can take a while for a large media cache, so this allows to
+    /// show the progress of the upgrade. The callback is called before each
+    /// migration step, with the current version of the database and the
+    /// version it is migrated to. It is called outside of the transaction of
+    /// the migration, so it can take its time without blocking the database.
+    /// It is not called when the database is already up-to-date.
+    #[must_use]
+    pub fn on_migration_progress(
+        mut self,
+        callback: impl Fn(u8, u8) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_migration_progress = Some(Callback(Arc::new(callback)));
+        self
+    }
 
-    async fn add_media_content(&self, request: &MediaRequest, content: Vec<u8>) -> Result<()> {
-        let uri = self.encode_key(keys::MEDIA, request.source.unique_key());
-        let format = self.encode_key(keys::MEDIA, request.format.unique_key());
-        let data = self.encode_value(content)?;
+    /// Set how the database file is locked.
+    ///
+    /// This only has an effect on the pools created by the store, not on the
+    /// ones given to [`SqliteEventCacheStore::open_with_pool_and_config`].
+    ///
+    /// Defaults to [`FileLocking::Posix`].
+    #[must_use]
+    pub fn file_locking(mut self, file_locking: FileLocking) -> Self {
+        self.file_locking = file_locking;
+        self
+    }
 
-        let conn = self.acquire().await?;
-        conn.execute(
-            "INSERT OR REPLACE INTO media (uri, format, data, last_access) VALUES (?, ?, ?, CAST(strftime('%s') as INT))",
-            (uri, format, data),
-        )
-        .await?;
+    /// Set the locking mode of the connections, with `PRAGMA locking_mode`.
+    ///
+    /// With [`LockingMode::Exclusive`], the store uses a single connection for
+    /// both reads and writes, since it keeps the lock on the database until
+    /// it is closed.
+    ///
+    /// See [`LockingMode`] for the recommended settings for a database on a
+    /// network filesystem.
+    ///
+    /// Defaults to [`LockingMode::Normal`].
+    #[must_use]
+    pub fn locking_mode(mut self, locking_mode: LockingMode) -> Self {
+        self.locking_mode = locking_mode;
+        self
+    }
 
-        Ok(())
+    /// Set the maximum number of bytes of the database that are accessed
+    /// with memory-mapped I/O, with `PRAGMA mmap_size`.
+    ///
+    /// `0` disables memory-mapped I/O, which is recommended for a database on
+    /// a network filesystem, where the changes made through a mapping are not
+    /// reliably written back to the file.
+    ///
+    /// Defaults to SQLite's default, which depends on how SQLite was compiled,
+    /// and is usually `0`.
+    #[must_use]
+    pub fn mmap_size(mut self, bytes: u64) -> Self {
+        self.mmap_size = Some(bytes);
+        self
     }
 
-    async fn replace_media_key(
-        &self,
-        from: &MediaRequest,
-        to: &MediaRequest,
-    ) -> Result<(), Self::Error> {
-        let prev_uri = self.encode_key(keys::MEDIA, from.source.unique_key());
-        let prev_format = self.encode_key(keys::MEDIA, from.format.unique_key());
+    /// Set how often the connections wait for the writes to reach the disk,
+    /// with `PRAGMA synchronous`.
+    ///
+    /// See [`Synchronous`] for the durability of every setting.
+    ///
+    /// Defaults to SQLite's default, which is [`Synchronous::Full`].
+    #[must_use]
+    pub fn synchronous(mut self, synchronous: Synchronous) -> Self {
+        self.synchronous = Some(synchronous);
+        self
+    }
 
-        let new_uri = self.encode_key(keys::MEDIA, to.source.unique_key());
-        let new_format = self.encode_key(keys::MEDIA, to.format.unique_key());
+    /// Set how long a connection waits for the database to be unlocked by
+    /// another connection, before failing with a "database is locked" error.
+    ///
+    /// Defaults to 5 seconds, which is the default of the connections opened
+    /// with `rusqlite`.
+    #[must_use]
+    pub fn busy_timeout(mut self, timeout: Duration) -> Self {
+        self.busy_timeout = Some(timeout);
+        self
+    }
 
-        let conn = self.acquire().await?;
-        conn.execute(
-            r#"UPDATE media SET uri = ?, format = ?, last_access = CAST(strftime('%s') as INT)
-               WHERE uri = ? AND format = ?"#,
-            (new_uri, new_format, prev_uri, prev_format),
-        )
-        .await?;
+    /// Set the number of pages of the write-ahead log after which its content
+    /// is automatically written back to the database file.
+    ///
+    /// A lower threshold keeps the `-wal` file smaller, at the cost of more
+    /// frequent checkpoints, which can help for write-heavy workloads. A
+    /// threshold of zero disables the automatic checkpoints, they must then be
+    /// triggered with [`SqliteEventCacheStore::checkpoint`].
+    ///
+    /// Defaults to SQLite's default, which is 1000 pages.
+    #[must_use]
+    pub fn wal_autocheckpoint(mut self, pages: u32) -> Self {
+        self.wal_autocheckpoint = Some(pages);
+        self
+    }
 
-        Ok(())
+    /// Set the size in bytes of the pages of the database file.
+    ///
+    /// The page size can only be chosen when the database is created: it is
+    /// fixed when the first table is written, and it can't be changed
+    /// afterwards in WAL mode, which the store uses. So it is applied when
+    /// opening a new database, before switching it to WAL mode and creating
+    /// the schema, and it is ignored for an existing database, which keeps
+    /// the page size it was created with.
+    ///
+    /// It must be a power of two between 512 and 65536, SQLite ignores other
+    /// values.
+    ///
+    /// Defaults to SQLite's default, which is 4096 bytes.
+    #[must_use]
+    pub fn page_size(mut self, bytes: u32) -> Self {
+        self.page_size = Some(bytes);
+        self
     }
 
-    async fn get_media_content(&self, request: &MediaRequest) -> Result<Option<Vec<u8>>> {
-        let uri = self.encode_key(keys::MEDIA, request.source.unique_key());
-        let format = self.encode_key(keys::MEDIA, request.format.unique_key());
+    /// Enable the incremental vacuum of the database, to give the space freed
+    /// by removed media back to the filesystem without a full
+    /// [`SqliteEventCacheStore::vacuum`].
+    ///
+    /// Like the page size, this can only be chosen when the database is
+    /// created: SQLite's `PRAGMA auto_vacuum = INCREMENTAL` must be set before
+    /// the first table is created. So it is applied when opening a new
+    /// database, before switching it to WAL mode and creating the schema, and
+    /// an existing database keeps the mode it was created with, in which
+    /// case this option has no effect.
+    ///
+    /// After the operations that remove many media, like
+    /// [`EventCacheStore::clean_up_media_cache`] or
+    /// [`SqliteEventCacheStore::trim_to_count`], the free pages are released
+    /// with `PRAGMA incremental_vacuum` if there are more than
+    /// `free_pages_threshold` of them. Contrary to `VACUUM`, this only moves
+    /// the pages at the end of the file, so it is fast, but the database is
+    /// not defragmented.
+    ///
+    /// Defaults to no incremental vacuum: the free pages are reused for new
+    /// data, and only given back by [`SqliteEventCacheStore::vacuum`].
+    #[must_use]
+    pub fn incremental_vacuum(mut self, free_pages_threshold: u32) -> Self {
+        self.incremental_vacuum = Some(free_pages_threshold);
+        self
+    }
 
-        let conn = self.acquire().await?;
-        let data = conn
-            .with_transaction::<_, rusqlite::Error, _>(move |txn| {
-                // Update the last access.
-                // We need to do this first so the transaction is in write mode right away.
-                // See: https://sqlite.org/lang_transaction.html#read_transactions_versus_write_transactions
-                txn.execute(
-                    "UPDATE media SET last_access = CAST(strftime('%s') as INT) \
-                     WHERE uri = ? AND format = ?",
-                    (&uri, &format),
-                )?;
+    /// Set the maximum size of the page cache of every connection.
+    ///
+    /// Like SQLite's `PRAGMA cache_size`, a positive value is a number of
+    /// pages, and a negative value is a number of kibibytes, so it doesn't
+    /// depend on the page size.
+    ///
+    /// Defaults to SQLite's default, which is 2000 KiB.
+    #[must_use]
+    pub fn cache_size(mut self, size: i32) -> Self {
+        self.cache_size = Some(size);
+        self
+    }
 
-                txn.query_row::<Vec<u8>, _, _>(
-                    "SELECT data FROM media WHERE uri = ? AND format = ?",
-                    (&uri, &format),
-                    |row| row.get(0),
-                )
-                .optional()
-            })
-            .await?;
+    /// Set the maximum number of connections of the pool created by the store
+    /// for reads.
+    ///
+    /// SQLite only allows one writer at a time, so the writes use a separate
+    /// pool with a single connection, and this only limits the number of
+    /// concurrent reads, which is what most of the accesses to the media cache
+    /// are. A read-heavy application can set this to the number of concurrent
+    /// readers it expects.
+    ///
+    /// This only has an effect on the pools created by the store, not on the
+    /// ones given to [`SqliteEventCacheStore::open_with_pool_and_config`].
+    ///
+    /// Defaults to `deadpool`'s default, which is 4 times the number of CPUs.
+    #[must_use]
+    pub fn pool_max_size(mut self, max_size: usize) -> Self {
+        self.pool_max_size = Some(max_size);
+        self
+    }
 
-        data.map(|v| self.decode_value(&v).map(Into::into)).transpose()
+    /// Set the maximum duration to wait for a connection of the pool to be
+    /// free.
+    ///
+    /// When all the connections are used, for example by a slow write, the
+    /// accesses to the store wait for one to be returned to the pool. If it
+    /// takes longer than this, they fail with a timeout error instead, so the
+    /// callers can fall back to the network rather than wait indefinitely, and
+    /// the status of the pool is logged.
+    ///
+    /// Defaults to waiting indefinitely.
+    #[must_use]
+    pub fn acquire_timeout(mut self, timeout: Duration) -> Self {
+        self.acquire_timeout = Some(timeout);
+        self
     }
 
-    async fn remove_media_content(&self, request: &MediaRequest) -> Result<()> {
-        let uri = self.encode_key(keys::MEDIA, request.source.unique_key());
-        let format = self.encode_key(keys::MEDIA, request.format.unique_key());
+    /// Set the name of the database file, in the directory given when opening
+    /// the store.
+    ///
+    /// This allows to have several stores in the same directory, for example
+    /// one for each account, or next to the other stores of the SDK. The name
+    /// must be a plain file name, opening the store fails with
+    /// [`OpenStoreError::InvalidDatabaseFileName`] if it contains a path
+    /// separator or is `.` or `..`.
+    ///
+    /// Defaults to `matrix-sdk-event-cache.sqlite3`.
+    #[must_use]
+    pub fn database_file_name(mut self, file_name: impl Into<String>) -> Self {
+        self.database_file_name = Some(file_name.into());
+        self
+    }
 
-        let conn = self.acquire().await?;
-        conn.execute("DELETE FROM media WHERE uri = ? AND format = ?", (uri, format)).await?;
+    /// Set whether the keys are hashed in a store without passphrase.
+    ///
+    /// The keys of a store with a passphrase are always hashed with a key
+    /// derived from the store cipher. Without a passphrase, the keys are
+    /// stored as is by default, so anyone who can read the database can know
+    /// which media were accessed. When this is enabled, they are hashed with
+    /// SHA-256 instead. The hash is not secret, so it only prevents reading
+    /// the MXC URIs, not checking whether a given MXC URI is in the cache.
+    ///
+    /// Like in a store with a passphrase, the media can't be matched by the
+    /// plain MXC URI anymore, so
+    /// [`EventCacheStore::remove_media_by_server`] is not supported. The
+    /// setting is not persisted, changing it for an existing store makes the
+    /// data that was already stored unreachable.
+    ///
+    /// Defaults to `false`.
+    #[must_use]
+    pub fn hash_keys(mut self, hash_keys: bool) -> Self {
+        self.hash_keys = hash_keys;
+        self
+    }
 
-        Ok(())
+    /// Set the namespace of the data of this store, to share the database
+    /// with the stores of other accounts.
+    ///
+    /// The namespace is mixed into all the keys of the store, so the stores
+    /// opened with different namespaces can't see each other's media, gaps,
+    /// event chunks and custom values, and
+    /// [`EventCacheStore::clear_session_data`] only removes the data of the
+    /// namespace of the store. The data of any namespace can be removed with
+    /// [`SqliteEventCacheStore::clear_account_namespace`].
+    ///
+    /// The stores sharing a database also share its metadata, so they must use
+    /// the same passphrase. The retention policy and the maintenance methods,
+    /// like [`SqliteEventCacheStore::trim_to_count`], only apply to the media
+    /// of the namespace of the store, so every namespace has its own limits,
+    /// but the identical media contents of different namespaces are still only
+    /// stored once. A store without namespace sees none of the namespaced data,
+    /// but clearing its session data removes the data of all the namespaces.
+    ///
+    /// Like [`SqliteEventCacheStoreConfig::hash_keys`], the setting is not
+    /// persisted, changing it for an existing store makes the data that was
+    /// already stored unreachable.
+    ///
+    /// Defaults to no namespace.
+    #[must_use]
+    pub fn account_namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.account_namespace = Some(namespace.into());
+        self
     }
 
-    async fn remove_media_content_for_uri(&self, uri: &ruma::MxcUri) -> Result<()> {
-        let uri = self.encode_key(keys::MEDIA, uri);
+    /// Set the number of rounds of the key derivation of the passphrase, for
+    /// new stores.
+    ///
+    /// More rounds make it harder to brute-force the passphrase, but slower
+    /// to open the store. The number of rounds is persisted with the store
+    /// cipher, so existing stores are always opened with the number of rounds
+    /// they were created with, and this only affects the new stores, and
+    /// [`SqliteEventCacheStore::change_passphrase`].
+    ///
+    /// Defaults to the default of [`StoreCipher::export`].
+    #[must_use]
+    pub fn kdf_rounds(mut self, kdf_rounds: u32) -> Self {
+        self.kdf_rounds = Some(kdf_rounds);
+        self
+    }
 
-        let conn = self.acquire().await?;
-        conn.execute("DELETE FROM media WHERE uri = ?", (uri,)).await?;
+    /// Set whether the integrity of the database is checked when the store is
+    /// opened.
+    ///
+    /// If the check finds a problem, opening the store fails with
+    /// [`OpenStoreError::Corrupted`], instead of failing later on the first
+    /// query that reads the corrupted data. The check reads the whole
+    /// database, so it can be slow with a large database.
+    ///
+    /// Defaults to [`IntegrityCheck::None`].
+    #[must_use]
+    pub fn integrity_check(mut self, integrity_check: IntegrityCheck) -> Self {
+        self.integrity_check = integrity_check;
+        self
+    }
 
-        Ok(())
+    /// Set the size of the chunks the media contents are split into in the
+    /// database.
+    ///
+    /// Contents larger than this size are stored in several rows, to avoid a
+    /// single huge value in the database. The chunks of a media are always
+    /// written in the same transaction as the media itself, so an interrupted
+    /// write never leaves an incomplete media content that can be read. This
+    /// only affects the media added after the store is opened, the contents
+    /// that were already stored are read the same way.
+    ///
+    /// Defaults to `None`, to store every content in a single row.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is zero.
+    #[must_use]
+    pub fn media_chunk_size(mut self, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0, "Chunk size must be greater than zero");
+        self.media_chunk_size = Some(chunk_size);
+        self
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::{
-        sync::atomic::{AtomicU32, Ordering::SeqCst},
-        time::Duration,
-    };
+    /// Set whether the media contents are compressed in the database.
+    ///
+    /// The contents are compressed with zstd before being encrypted, and are
+    /// only stored compressed if it makes them smaller, which is mostly the
+    /// case for text-based media, like SVG images. Whether a content is
+    /// compressed is stored with it, so compressed and uncompressed contents
+    /// can be read regardless of this setting.
+    ///
+    /// Compressed contents can't be read with
+    /// [`SqliteEventCacheStore::get_media_content_stream`], and are read fully
+    /// by [`SqliteEventCacheStore::get_media_content_range`].
+    ///
+    /// Defaults to `false`.
+    #[must_use]
+    pub fn compress_media(mut self, compress_media: bool) -> Self {
+        self.compress_media = compress_media;
+        self
+    }
 
-    use matrix_sdk_base::{
-        event_cache_store::{EventCacheStore, EventCacheStoreError},
-        event_cache_store_integration_tests,
-        media::{MediaFormat, MediaRequest, MediaThumbnailSettings},
-    };
-    use matrix_sdk_test::async_test;
-    use once_cell::sync::Lazy;
-    use ruma::{events::room::MediaSource, media::Method, mxc_uri, uint};
-    use tempfile::{tempdir, TempDir};
+    /// Set the clock used to know the current time.
+    ///
+    /// This is used to record the last access of the media, and to compute
+    /// which media are too old to be kept according to
+    /// [`MediaRetentionPolicy::max_age`]. It is mostly useful in tests, to
+    /// not have to wait between accesses or for the media to expire.
+    ///
+    /// Defaults to [`SystemTime::now`].
+    #[must_use]
+    pub fn clock(mut self, clock: impl Fn() -> SystemTime + Send + Sync + 'static) -> Self {
+        self.clock = Some(Callback(Arc::new(clock)));
+        self
+    }
 
-    use super::SqliteEventCacheStore;
-    use crate::utils::SqliteAsyncConnExt;
+    /// Set a loader to call when a media is not in the cache, to make the
+    /// store a read-through cache.
+    ///
+    /// When [`EventCacheStore::get_media_content`] doesn't find the requested
+    /// media, it calls the loader, typically to download the media. If the
+    /// loader returns a content, it is stored with
+    /// [`EventCacheStore::add_media_content`] and returned, otherwise the miss
+    /// is returned. An error of the loader is returned as
+    /// [`Error::MediaLoader`], and nothing is stored.
+    ///
+    /// The loader is not called by the other reads of the store, like
+    /// [`SqliteEventCacheStore::get_media_content_stream`], and concurrent
+    /// misses of the same media each call the loader.
+    ///
+    /// Defaults to no loader.
+    #[must_use]
+    pub fn media_loader<F, Fut>(mut self, loader: F) -> Self
+    where
+        F: Fn(MediaRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Option<Vec<u8>>, MediaLoaderError>> + Send + 'static,
+    {
+        self.media_loader = Some(Callback(Arc::new(move |request| loader(request).boxed())));
+        self
+    }
 
-    static TMP_DIR: Lazy<TempDir> = Lazy::new(|| tempdir().unwrap());
-    static NUM: AtomicU32 = AtomicU32::new(0);
+    /// Set the codec used to serialize the envelope of the values encrypted
+    /// with the store cipher.
+    ///
+    /// The codec is persisted with the store cipher when the store is created,
+    /// and it can't be changed afterwards: opening an existing store with a
+    /// different codec fails with
+    /// [`OpenStoreError::IncompatibleEnvelopeCodec`]. This has no effect on
+    /// a store without a passphrase, unless the codec of the store is set
+    /// with [`SqliteEventCacheStoreConfig::previous_envelope_codec`].
+    ///
+    /// Defaults to [`MessagePackCodec`].
+    #[must_use]
+    pub fn envelope_codec(mut self, codec: impl EnvelopeCodec + 'static) -> Self {
+        self.envelope_codec = Some(Arc::new(codec));
+        self
+    }
 
-    async fn get_event_cache_store() -> Result<SqliteEventCacheStore, EventCacheStoreError> {
-        let name = NUM.fetch_add(1, SeqCst).to_string();
-        let tmpdir_path = TMP_DIR.path().join(name);
+    /// Set the codec that was used before the one of
+    /// [`SqliteEventCacheStoreConfig::envelope_codec`], to migrate an existing
+    /// store to a new codec.
+    ///
+    /// A store whose values use this codec can then be opened: the values are
+    /// written with the new codec, and read with the new codec or, if it fails,
+    /// with this one, so the codecs must reject the values of each other. The
+    /// existing values are rewritten with the new codec by
+    /// [`SqliteEventCacheStore::reencode_all`], after which this is not needed
+    /// anymore.
+    ///
+    /// Defaults to no previous codec.
+    #[must_use]
+    pub fn previous_envelope_codec(mut self, codec: impl EnvelopeCodec + 'static) -> Self {
+        self.previous_envelope_codec = Some(Arc::new(codec));
+        self
+    }
 
-        tracing::info!("using event cache store @ {}", tmpdir_path.to_str().unwrap());
+    /// The current time, according to the clock.
+    fn now(&self) -> SystemTime {
+        match &self.clock {
+            Some(clock) => (clock.0)(),
+            None => SystemTime::now(),
+        }
+    }
 
-        Ok(SqliteEventCacheStore::open(tmpdir_path.to_str().unwrap(), None).await.unwrap())
+    /// The codec of the envelope of the encrypted values.
+    fn codec(&self) -> &dyn EnvelopeCodec {
+        self.envelope_codec.as_deref().unwrap_or(&MessagePackCodec)
     }
 
-    event_cache_store_integration_tests!();
+    /// Defer the updates of the last access of the media.
+    ///
+    /// By default, every read of a media content also writes its last access
+    /// to the database, which must wait for the other writes. With this
+    /// setting, the accesses are kept in memory instead, and written together
+    /// in a single transaction once `max_pending` media are waiting, or when
+    /// [`SqliteEventCacheStore::flush_access_times`] is called.
+    ///
+    /// The order of the least recently accessed media is then only eventually
+    /// consistent: a media evicted because the cache is over
+    /// [`MediaRetentionPolicy::max_cache_size`] may have been accessed more
+    /// recently than what the database knows. The pending accesses are written
+    /// before [`EventCacheStore::clean_up_media_cache`] and
+    /// [`SqliteEventCacheStore::trim_to_count`], and they are lost if the store
+    /// is dropped before they are written, so `flush_access_times` should be
+    /// called before closing the store.
+    ///
+    /// Defaults to writing every access immediately.
+    #[must_use]
+    pub fn defer_access_times(mut self, max_pending: usize) -> Self {
+        self.deferred_access_times = Some(max_pending);
+        self
+    }
 
-    async fn get_event_cache_store_content_sorted_by_last_access(
-        event_cache_store: &SqliteEventCacheStore,
-    ) -> Vec<Vec<u8>> {
-        let sqlite_db = event_cache_store.acquire().await.expect("accessing sqlite db failed");
-        sqlite_db
-            .prepare("SELECT data FROM media ORDER BY last_access DESC", |mut stmt| {
-                stmt.query(())?.mapped(|row| row.get(0)).collect()
-            })
-            .await
-            .expect("querying media cache content by last access failed")
+    /// Set whether the removed media are only marked as deleted.
+    ///
+    /// When this is enabled, [`EventCacheStore::remove_media_content`] and the
+    /// other methods removing media only record the time of the removal: the
+    /// media are not returned by the store anymore, but they are kept in the
+    /// database, for auditing, until they are removed with
+    /// [`SqliteEventCacheStore::purge_deleted`]. Until then, they are not
+    /// evicted by the retention policy, so their contents are not counted in
+    /// [`SqliteEventCacheStore::media_cache_size`] either, unless they are
+    /// shared with media that are not deleted, but they still take space on
+    /// disk. Adding a media that was marked as deleted replaces it.
+    ///
+    /// Clearing the session data still removes the media immediately.
+    ///
+    /// Defaults to `false`, the media are removed immediately.
+    #[must_use]
+    pub fn soft_delete(mut self, soft_delete: bool) -> Self {
+        self.soft_delete = soft_delete;
+        self
     }
 
-    #[async_test]
-    async fn test_last_access() {
-        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
-        let uri = mxc_uri!("mxc://localhost/media");
-        let file_request =
-            MediaRequest { source: MediaSource::Plain(uri.to_owned()), format: MediaFormat::File };
-        let thumbnail_request = MediaRequest {
-            source: MediaSource::Plain(uri.to_owned()),
-            format: MediaFormat::Thumbnail(MediaThumbnailSettings::new(
-                Method::Crop,
-                uint!(100),
-                uint!(100),
-            )),
-        };
+    /// Set the key used by SQLCipher to encrypt the database.
+    ///
+    /// SQLCipher encrypts every page of the database, so it also hides the
+    /// keys, the indexes and the sizes of the rows, while the store cipher
+    /// set up with a passphrase only encrypts the values, and hashes the keys.
+    /// But all the reads and writes are slower, and the database can't be
+    /// read with the plain SQLite tools anymore. Both can be used together,
+    /// with the same passphrase or not, or independently.
+    ///
+    /// The key is applied with `PRAGMA key` to every connection, so opening
+    /// an encrypted database without the key, or with the wrong key, fails.
+    ///
+    /// Defaults to no key, which leaves the database file unencrypted.
+    #[cfg(feature = "sqlcipher")]
+    #[must_use]
+    pub fn sqlcipher_key(mut self, key: impl Into<String>) -> Self {
+        self.sqlcipher_key = Some(SqlCipherKey(key.into()));
+        self
+    }
 
-        let content: Vec<u8> = "hello world".into();
-        let thumbnail_content: Vec<u8> = "hello…".into();
+    /// Set the Unix permissions of the directory of the store and of the
+    /// database file, when they are created.
+    ///
+    /// The directory gets the given mode, and the database file gets the same
+    /// mode without the execute bits. SQLite creates the other files of the
+    /// database with the same permissions as the database file. The
+    /// permissions of the directories and files that already exist are not
+    /// changed.
+    ///
+    /// Defaults to `0o700` for the directory, since the database can contain
+    /// the plain content of the media of a store without passphrase, and to
+    /// the default permissions of SQLite, restricted by the umask, for the
+    /// database file.
+    #[cfg(unix)]
+    #[must_use]
+    pub fn unix_permissions(mut self, mode: u32) -> Self {
+        self.unix_mode = Some(mode);
+        self
+    }
 
-        // Add the media.
-        event_cache_store
-            .add_media_content(&file_request, content.clone())
-            .await
-            .expect("adding file failed");
+    /// The `PRAGMA` statements to run on every new connection.
+    fn connection_pragmas(&self) -> String {
+        let mut pragmas = String::new();
 
-        // Since the precision of the timestamp is in seconds, wait so the timestamps
-        // differ.
-        tokio::time::sleep(Duration::from_secs(3)).await;
+        // The key must be set before the database is read.
+        #[cfg(feature = "sqlcipher")]
+        if let Some(SqlCipherKey(key)) = &self.sqlcipher_key {
+            pragmas.push_str(&format!("PRAGMA key = '{}';", key.replace('\'', "''")));
+        }
 
-        event_cache_store
-            .add_media_content(&thumbnail_request, thumbnail_content.clone())
-            .await
-            .expect("adding thumbnail failed");
+        if self.read_uncommitted {
+            pragmas.push_str("PRAGMA read_uncommitted = true;");
+        }
 
-        // File's last access is older than thumbnail.
-        let contents =
-            get_event_cache_store_content_sorted_by_last_access(&event_cache_store).await;
+        if let Some(timeout) = self.busy_timeout {
+            pragmas.push_str(&format!("PRAGMA busy_timeout = {};", timeout.as_millis()));
+        }
 
-        assert_eq!(contents.len(), 2, "media cache contents length is wrong");
-        assert_eq!(contents[0], thumbnail_content, "thumbnail is not last access");
-        assert_eq!(contents[1], content, "file is not second-to-last access");
+        if let Some(pages) = self.wal_autocheckpoint {
+            pragmas.push_str(&format!("PRAGMA wal_autocheckpoint = {pages};"));
+        }
 
-        // Since the precision of the timestamp is in seconds, wait so the timestamps
-        // differ.
-        tokio::time::sleep(Duration::from_secs(3)).await;
+        if let Some(size) = self.cache_size {
+            pragmas.push_str(&format!("PRAGMA cache_size = {size};"));
+        }
 
-        // Access the file so its last access is more recent.
-        let _ = event_cache_store
-            .get_media_content(&file_request)
-            .await
-            .expect("getting file failed")
-            .expect("file is missing");
+        // The locking mode must be set before the database is read, so the WAL index
+        // is kept in memory instead of a shared memory file.
+        if self.locking_mode == LockingMode::Exclusive {
+            pragmas.push_str("PRAGMA locking_mode = EXCLUSIVE;");
+        }
 
-        // File's last access is more recent than thumbnail.
-        let contents =
-            get_event_cache_store_content_sorted_by_last_access(&event_cache_store).await;
+        if let Some(bytes) = self.mmap_size {
+            pragmas.push_str(&format!("PRAGMA mmap_size = {bytes};"));
+        }
 
-        assert_eq!(contents.len(), 2, "media cache contents length is wrong");
-        assert_eq!(contents[0], content, "file is not last access");
-        assert_eq!(contents[1], thumbnail_content, "thumbnail is not second-to-last access");
+        if let Some(synchronous) = self.synchronous {
+            pragmas.push_str(&format!("PRAGMA synchronous = {};", synchronous.pragma_value()));
+        }
+
+        pragmas
     }
 }
 
-#[cfg(test)]
-mod encrypted_tests {
-    use std::sync::atomic::{AtomicU32, Ordering::SeqCst};
+/// The key of SQLCipher, which must not be printed.
+#[cfg(feature = "sqlcipher")]
+#[derive(Clone)]
+struct SqlCipherKey(String);
 
-    use matrix_sdk_base::{
-        event_cache_store::EventCacheStoreError, event_cache_store_integration_tests,
-    };
-    use once_cell::sync::Lazy;
-    use tempfile::{tempdir, TempDir};
+#[cfg(feature = "sqlcipher")]
+impl fmt::Debug for SqlCipherKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SqlCipherKey").finish_non_exhaustive()
+    }
+}
 
-    use super::SqliteEventCacheStore;
+/// How the connections of a [`SqliteEventCacheStore`] lock the database.
+///
+/// See the [SQLite documentation] for the details of the locking modes.
+///
+/// # Network filesystems
+///
+/// SQLite relies on the locks of the filesystem, and on a shared memory file
+/// in WAL mode, which are both unreliable on network filesystems like NFS or
+/// SMB, and can lead to a corrupted database. If the database must live on
+/// such a filesystem, and is only opened by a single store at a time, the
+/// recommended settings are [`LockingMode::Exclusive`], which makes SQLite
+/// keep the WAL index in memory, and
+/// [`SqliteEventCacheStoreConfig::mmap_size`] set to `0`:
+///
+/// ```
+/// use matrix_sdk_sqlite::{LockingMode, SqliteEventCacheStoreConfig};
+///
+/// let config = SqliteEventCacheStoreConfig::new()
+///     .locking_mode(LockingMode::Exclusive)
+///     .mmap_size(0);
+/// ```
+///
+/// If the database must be shared by several processes,
+/// [`FileLocking::Dotfile`] can be used instead, at the cost of serializing
+/// all the accesses.
+///
+/// [SQLite documentation]: https://www.sqlite.org/pragma.html#pragma_locking_mode
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LockingMode {
+    /// Release the locks on the database at the end of every transaction, so
+    /// other connections can access it.
+    #[default]
+    Normal,
 
-    static TMP_DIR: Lazy<TempDir> = Lazy::new(|| tempdir().unwrap());
-    static NUM: AtomicU32 = AtomicU32::new(0);
+    /// Keep the locks on the database once they are acquired, until the store
+    /// is closed.
+    ///
+    /// No other store or process can access the database while it is open,
+    /// but fewer system calls are made, and no shared memory file is used in
+    /// WAL mode.
+    Exclusive,
+}
 
-    async fn get_event_cache_store() -> Result<SqliteEventCacheStore, EventCacheStoreError> {
-        let name = NUM.fetch_add(1, SeqCst).to_string();
-        let tmpdir_path = TMP_DIR.path().join(name);
+/// How often the connections of a [`SqliteEventCacheStore`] wait for the
+/// writes to reach the disk.
+///
+/// The store uses the WAL journal mode, except with the alternative
+/// [`FileLocking`] modes which use a rollback journal. See the [SQLite
+/// documentation] for the details.
+///
+/// [SQLite documentation]: https://www.sqlite.org/pragma.html#pragma_synchronous
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Synchronous {
+    /// Never wait for the writes to reach the disk.
+    ///
+    /// This is the fastest setting, but the database can get corrupted if the
+    /// operating system crashes or the power is lost in the middle of a write.
+    /// A crash of the application alone is safe.
+    Off,
 
-        tracing::info!("using event cache store @ {}", tmpdir_path.to_str().unwrap());
+    /// Wait for the writes to reach the disk only at the critical moments.
+    ///
+    /// In WAL mode, the database can't get corrupted, but the last
+    /// transactions before a power loss or a crash of the operating system
+    /// can be rolled back, which is usually acceptable for a cache. With a
+    /// rollback journal, the database can get corrupted in this case.
+    Normal,
 
-        Ok(SqliteEventCacheStore::open(
-            tmpdir_path.to_str().unwrap(),
-            Some("default_test_password"),
-        )
+    /// Wait for the writes to reach the disk after every transaction.
+    ///
+    /// No committed transaction is lost, at the cost of more calls to `fsync`.
+    Full,
+
+    /// Like [`Synchronous::Full`], and also wait for the removal of the
+    /// rollback journal to reach the disk.
+    ///
+    /// This only makes a difference with a rollback journal.
+    Extra,
+}
+
+impl Synchronous {
+    /// The value of `PRAGMA synchronous` for this setting.
+    fn pragma_value(self) -> &'static str {
+        match self {
+            Self::Off => "OFF",
+            Self::Normal => "NORMAL",
+            Self::Full => "FULL",
+            Self::Extra => "EXTRA",
+        }
+    }
+}
+
+/// How the integrity of the database of a [`SqliteEventCacheStore`] is checked
+/// when it is opened.
+///
+/// See the [SQLite documentation] for the details of the checks.
+///
+/// [SQLite documentation]: https://www.sqlite.org/pragma.html#pragma_integrity_check
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IntegrityCheck {
+    /// Don't check the integrity of the database.
+    #[default]
+    None,
+
+    /// Run `PRAGMA quick_check`, which skips the verification of the indexes
+    /// content.
+    Quick,
+
+    /// Run `PRAGMA integrity_check`, which is more thorough but slower.
+    Full,
+}
+
+impl IntegrityCheck {
+    /// The `PRAGMA` statement to run, if any.
+    fn pragma(self) -> Option<&'static str> {
+        match self {
+            Self::None => None,
+            Self::Quick => Some("PRAGMA quick_check"),
+            Self::Full => Some("PRAGMA integrity_check"),
+        }
+    }
+}
+
+/// How the database file of a [`SqliteEventCacheStore`] is locked.
+///
+/// The default POSIX advisory locks are unreliable on some filesystems, like
+/// NFS or some overlay filesystems used by containers, which can lead to
+/// spurious "database is locked" errors or to a corrupted database. The other
+/// variants select another [SQLite VFS] that doesn't rely on them.
+///
+/// The alternative VFSes are only available on Unix platforms, and none of
+/// them supports the shared memory needed by the WAL journal mode, so the
+/// database uses a rollback journal with them.
+///
+/// [SQLite VFS]: https://www.sqlite.org/vfs.html
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FileLocking {
+    /// Use POSIX advisory locks, with SQLite's default VFS.
+    #[default]
+    Posix,
+
+    /// Use a dot-file next to the database file as the lock, with SQLite's
+    /// `unix-dotfile` VFS.
+    ///
+    /// Only exclusive locks are supported, so concurrent reads are
+    /// serialized. A stale lock file left by a process that crashed while
+    /// holding the lock must be removed manually.
+    Dotfile,
+
+    /// Don't lock the database file at all, with SQLite's `unix-none` VFS.
+    ///
+    /// This is only safe if the database is never accessed concurrently by
+    /// several processes, or by several stores in the same process, otherwise
+    /// the database will get corrupted.
+    None,
+}
+
+impl FileLocking {
+    /// The name of the SQLite VFS to use, if it's not the default one.
+    fn vfs(self) -> Option<&'static str> {
+        match self {
+            Self::Posix => None,
+            Self::Dotfile => Some("unix-dotfile"),
+            Self::None => Some("unix-none"),
+        }
+    }
+}
+
+/// The policy that decides which media are kept in the cache of a
+/// [`SqliteEventCacheStore`].
+///
+/// The sizes are the ones of the media contents as they are stored in the
+/// database, so they are larger than the sizes of the plain media contents in
+/// an encrypted store, and identical contents are only counted once.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MediaRetentionPolicy {
+    /// The total size of the media cache, in bytes, above which the
+    /// [soft limit
+    /// callback](SqliteEventCacheStoreConfig::on_soft_limit_reached)
+    /// is called.
+    ///
+    /// Reaching this limit doesn't remove anything from the cache.
+    pub soft_limit: Option<u64>,
+
+    /// The maximum total size of the media cache, in bytes.
+    ///
+    /// When adding a media content makes the cache go over this size, the
+    /// least recently accessed media are removed until it fits again, except
+    /// the [reserved](SqliteEventCacheStore::reserve) ones and the one that
+    /// was just added.
+    pub max_cache_size: Option<u64>,
+
+    /// The maximum duration since the last access of a media, after which it
+    /// is removed by [`EventCacheStore::clean_up_media_cache`].
+    pub max_age: Option<Duration>,
+
+    /// The maximum size of a media content, in bytes.
+    ///
+    /// Contrary to the other sizes, this is compared to the size of the plain
+    /// media content. Larger media contents are not stored in the cache.
+    pub max_file_size: Option<usize>,
+
+    /// The maximum number of media in the cache.
+    ///
+    /// This is only applied by [`SqliteEventCacheStore::enforce_retention`],
+    /// not when adding a media.
+    pub max_items: Option<usize>,
+}
+
+/// The policy that decides how the writes of a [`SqliteEventCacheStore`] are
+/// retried when the database is busy or locked.
+///
+/// The delay between two attempts starts at `initial_backoff`, and is doubled
+/// after every attempt, up to `max_backoff`. The other errors are never
+/// retried.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BusyRetryPolicy {
+    /// The maximum number of times a write is retried, before its error is
+    /// returned.
+    ///
+    /// Zero disables the retries.
+    pub max_retries: u32,
+
+    /// The delay before the first retry.
+    pub initial_backoff: Duration,
+
+    /// The maximum delay between two retries.
+    pub max_backoff: Duration,
+}
+
+impl Default for BusyRetryPolicy {
+    /// 5 retries, waiting from 10 milliseconds to half a second, so a writer
+    /// that is stuck makes the write fail after about a second.
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(500),
+        }
+    }
+}
+
+/// The error of a loader set with
+/// [`SqliteEventCacheStoreConfig::media_loader`].
+pub type MediaLoaderError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A loader set with [`SqliteEventCacheStoreConfig::media_loader`].
+type MediaLoader = dyn Fn(MediaRequest) -> BoxFuture<'static, Result<Option<Vec<u8>>, MediaLoaderError>>
+    + Send
+    + Sync;
+
+/// A callback set in a [`SqliteEventCacheStoreConfig`].
+struct Callback<F: ?Sized>(Arc<F>);
+
+impl<F: ?Sized> Clone for Callback<F> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+impl<F: ?Sized> fmt::Debug for Callback<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Callback").finish_non_exhaustive()
+    }
+}
+
+/// What to do when the content of a media fails to be decoded, for example
+/// because it is corrupted or can't be decrypted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DecodeFailurePolicy {
+    /// Return the error.
+    #[default]
+    Error,
+
+    /// Behave as if the media was not in the cache.
+    TreatAsMiss,
+
+    /// Behave as if the media was not in the cache, and remove it from the
+    /// cache.
+    TreatAsMissAndDelete,
+}
+
+/// The error returned by an [`EnvelopeCodec`].
+pub type EnvelopeCodecError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The serialization format of the envelope of the values encrypted with the
+/// store cipher, which holds the ciphertext with the nonce and the version of
+/// the encryption.
+///
+/// This allows other tools to read the encrypted values of the database
+/// without having to parse MessagePack. See
+/// [`SqliteEventCacheStoreConfig::envelope_codec`].
+pub trait EnvelopeCodec: fmt::Debug + Send + Sync {
+    /// The identifier of this format, persisted in the database to detect when
+    /// a store is opened with the wrong codec.
+    ///
+    /// `0` is the identifier of [`MessagePackCodec`], it must not be used by
+    /// other codecs.
+    fn tag(&self) -> u8;
+
+    /// Serialize the given encrypted value.
+    fn encode(&self, value: &EncryptedValue) -> Result<Vec<u8>, EnvelopeCodecError>;
+
+    /// Deserialize an encrypted value serialized with
+    /// [`EnvelopeCodec::encode`].
+    fn decode(&self, data: &[u8]) -> Result<EncryptedValue, EnvelopeCodecError>;
+}
+
+/// The identifier of [`MessagePackCodec`], which is also the format of the
+/// stores created before the codec was persisted.
+const MESSAGE_PACK_CODEC_TAG: u8 = 0;
+
+/// The default [`EnvelopeCodec`], that serializes the envelope with
+/// MessagePack, with the names of the fields.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MessagePackCodec;
+
+impl EnvelopeCodec for MessagePackCodec {
+    fn tag(&self) -> u8 {
+        MESSAGE_PACK_CODEC_TAG
+    }
+
+    fn encode(&self, value: &EncryptedValue) -> Result<Vec<u8>, EnvelopeCodecError> {
+        Ok(rmp_serde::to_vec_named(value)?)
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<EncryptedValue, EnvelopeCodecError> {
+        Ok(rmp_serde::from_slice(data)?)
+    }
+}
+
+/// A snapshot of the metrics of a [`SqliteEventCacheStore`].
+///
+/// The metrics are not persisted, they are counted since the store was opened.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct EventCacheMetrics {
+    /// The number of reads of a media content that took longer than the
+    /// [slow read threshold](SqliteEventCacheStoreConfig::slow_read_threshold).
+    pub slow_reads: u64,
+
+    /// The number of requests of a media content.
+    ///
+    /// Every request is either a hit or a miss, except the ones that failed.
+    pub gets: u64,
+
+    /// The number of requests of a media content that returned it.
+    pub hits: u64,
+
+    /// The number of requests of a media content that was not in the cache,
+    /// or that was treated as a miss because it failed to be decoded.
+    pub misses: u64,
+
+    /// The number of media contents that were added, including the ones
+    /// that replaced an existing media.
+    pub adds: u64,
+
+    /// The number of media that were evicted according to the retention
+    /// policy, or removed by [`SqliteEventCacheStore::trim_to_count`].
+    pub evictions: u64,
+}
+
+/// The live counters behind [`EventCacheMetrics`].
+#[derive(Debug, Default)]
+struct Metrics {
+    slow_reads: AtomicU64,
+    gets: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    adds: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl Metrics {
+    fn snapshot(&self) -> EventCacheMetrics {
+        EventCacheMetrics {
+            slow_reads: self.slow_reads.load(Ordering::Relaxed),
+            gets: self.gets.load(Ordering::Relaxed),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            adds: self.adds.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The material needed to decrypt the content of an encrypted media.
+///
+/// It can be created from the [`EncryptedFile`] of an encrypted
+/// `MediaSource`. See [`SqliteEventCacheStore::add_media_key_info`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MediaKeyInfo {
+    /// The key to decrypt the media.
+    pub key: JsonWebKey,
+
+    /// The initialization vector used to encrypt the media.
+    pub iv: Base64,
+
+    /// The hashes of the encrypted media, to check its integrity before
+    /// decrypting it, by hash algorithm.
+    ///
+    /// This is empty for the key info stored before the hashes were.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub hashes: BTreeMap<String, Base64>,
+}
+
+impl From<&EncryptedFile> for MediaKeyInfo {
+    fn from(file: &EncryptedFile) -> Self {
+        Self { key: file.key.clone(), iv: file.iv.clone(), hashes: file.hashes.clone() }
+    }
+}
+
+/// A media content as it is stored in the database, without any decoding.
+///
+/// See [`SqliteEventCacheStore::get_media_content_raw`].
+#[derive(Clone, Debug)]
+pub struct RawStoredMedia {
+    /// The bytes as they are stored in the database.
+    ///
+    /// If [`RawStoredMedia::encrypted`] is `true`, this is the serialized
+    /// envelope produced by the store cipher, otherwise this is the plain
+    /// media content.
+    pub data: Vec<u8>,
+
+    /// Whether [`RawStoredMedia::data`] is encrypted with the store cipher.
+    pub encrypted: bool,
+
+    /// Whether the media content was compressed with zstd before being
+    /// encrypted.
+    pub compressed: bool,
+}
+
+/// What applying a [`MediaRetentionPolicy`] would remove from the cache.
+///
+/// See [`SqliteEventCacheStore::estimate_eviction`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EvictionEstimate {
+    /// The number of media that would be removed.
+    pub items: u64,
+
+    /// The number of bytes that would be freed, as the contents are stored in
+    /// the database.
+    ///
+    /// A content that is shared with a media that is kept is not counted.
+    pub bytes: u64,
+}
+
+/// Whether the database was migrated when a [`SqliteEventCacheStore`] was
+/// opened with [`SqliteEventCacheStore::open_with_config_and_outcome`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OpenOutcome {
+    /// The version of the database before it was migrated, `0` for a new
+    /// database, or `None` if it was already up-to-date.
+    pub migrated_from: Option<u8>,
+
+    /// The version of the database after it was opened.
+    pub migrated_to: u8,
+}
+
+/// What was removed by [`SqliteEventCacheStore::enforce_retention`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RetentionReport {
+    /// The number of media that were removed because they were not accessed
+    /// during [`MediaRetentionPolicy::max_age`].
+    pub expired: u64,
+
+    /// The number of media that were removed because they were larger than
+    /// [`MediaRetentionPolicy::max_file_size`].
+    pub too_large: u64,
+
+    /// The number of media that were removed because there were more than
+    /// [`MediaRetentionPolicy::max_items`].
+    pub over_count: u64,
+
+    /// The number of media that were removed because the cache was larger
+    /// than [`MediaRetentionPolicy::max_cache_size`].
+    pub over_size: u64,
+
+    /// The number of bytes that were freed, as the contents are stored in the
+    /// database.
+    pub bytes: u64,
+}
+
+/// What was removed by [`SqliteEventCacheStore::repair`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// The number of media whose content or content type failed to be
+    /// decoded or decrypted.
+    pub undecodable_media: u64,
+
+    /// The number of linked chunks of a room timeline whose content failed to
+    /// be decoded or decrypted.
+    pub undecodable_event_chunks: u64,
+
+    /// The number of back-pagination tokens that failed to be decrypted.
+    pub undecodable_gaps: u64,
+
+    /// The number of media with an empty content.
+    ///
+    /// They are only removed if it was requested.
+    pub empty_media: u64,
+
+    /// The number of rows that were not linked to the rest of the data
+    /// anymore: media without content, contents or chunks of contents without
+    /// media, and keys or room associations without media.
+    pub orphaned_rows: u64,
+}
+
+impl RepairReport {
+    /// Whether anything was removed.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// A media that was evicted from the cache.
+///
+/// See [`SqliteEventCacheStoreConfig::on_media_evicted`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EvictedMedia {
+    /// The key of the URI of the media, which is hashed in an encrypted store.
+    pub uri_key: Vec<u8>,
+
+    /// The key of the format of the media, which is hashed in an encrypted
+    /// store.
+    pub format_key: Vec<u8>,
+
+    /// The size of the content of the media, as it was stored in the
+    /// database.
+    ///
+    /// The content is kept in the cache if it is shared with another media.
+    pub size: u64,
+}
+
+/// A media in the cache, without its content.
+///
+/// See [`SqliteEventCacheStore::list_media`] and
+/// [`SqliteEventCacheStore::oldest_media`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MediaCacheEntry {
+    /// The key of the URI of the media, which is hashed in an encrypted store.
+    pub uri_key: Vec<u8>,
+
+    /// The key of the format of the media, which is hashed in an encrypted
+    /// store.
+    pub format_key: Vec<u8>,
+
+    /// The size of the content of the media, as it is stored in the database.
+    pub size: u64,
+
+    /// The last time the media was added or read.
+    pub last_access: SystemTime,
+}
+
+/// A row of the `media` table, as it is stored in the database.
+///
+/// See [`SqliteEventCacheStore::rowids_for_debugging`].
+#[cfg(any(test, feature = "debug-internals"))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MediaRowForDebugging {
+    /// The rowid of the row.
+    pub rowid: i64,
+
+    /// The `uri` column, which is hashed in an encrypted store.
+    pub uri: Vec<u8>,
+
+    /// The `format` column, which is hashed in an encrypted store.
+    pub format: Vec<u8>,
+
+    /// The `last_access` column, in milliseconds since the Unix epoch.
+    pub last_access: i64,
+}
+
+/// The number of media read from the database at once when going through the
+/// whole cache.
+const MEDIA_PAGE_SIZE: usize = 64;
+
+/// The tables and columns of the values encoded with
+/// [`SqliteEventCacheStore::encode_value`], in the order in which they are
+/// re-encoded by [`SqliteEventCacheStore::reencode_all`].
+const ENCODED_COLUMNS: [(&str, &str); 6] = [
+    (keys::MEDIA_BLOBS, "data"),
+    (keys::MEDIA, "content_type"),
+    (keys::MEDIA_KEYS, "key_info"),
+    (keys::GAPS, "prev_batch_token"),
+    (keys::EVENT_CHUNKS, "content"),
+    (keys::CUSTOM_KV, "value"),
+];
+
+/// The name of the manifest file of an exported media cache.
+const EXPORT_MANIFEST: &str = "manifest.json";
+
+/// The manifest of an exported media cache.
+///
+/// See [`SqliteEventCacheStore::export_to_dir`].
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ExportManifest {
+    /// The account namespace of the exported store, which is mixed into the
+    /// keys of the media.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    account_namespace: Option<String>,
+    /// The exported media, by the name of the file containing their content.
+    media: BTreeMap<String, ExportedMedia>,
+}
+
+/// The metadata of a media in an exported media cache.
+#[derive(Debug, Serialize, Deserialize)]
+struct ExportedMedia {
+    uri_key: Base64,
+    format_key: Base64,
+    /// The last access, in milliseconds since the Unix epoch.
+    #[serde(default)]
+    last_access_ms: Option<i64>,
+    /// The last access, in seconds since the Unix epoch, in exports made before
+    /// the milliseconds were stored.
+    #[serde(default, skip_serializing)]
+    last_access: Option<i64>,
+    size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    format_kind: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    thumbnail_width: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    thumbnail_height: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    thumbnail_method: Option<String>,
+}
+
+/// A media read from the database with all its columns.
+struct StoredMediaRow {
+    rowid: i64,
+    uri: Vec<u8>,
+    format: Vec<u8>,
+    last_access: i64,
+    content_type: Option<Vec<u8>>,
+    format_columns: FormatColumns,
+    data: Vec<u8>,
+    compressed: bool,
+}
+
+/// A media ready to be inserted in the database.
+struct EncodedMedia {
+    uri: Key,
+    format: Key,
+    hash: [u8; 32],
+    data: Vec<u8>,
+    compressed: bool,
+    content_type: Option<Vec<u8>>,
+    format_columns: FormatColumns,
+    /// The last access to set, instead of now.
+    last_access: Option<i64>,
+    /// The key of the account namespace of the store, if any.
+    account: Option<Key>,
+}
+
+/// The media that are currently reserved, with the number of live
+/// [`ReservationGuard`]s for each of them.
+type Reservations = Arc<StdMutex<BTreeMap<(Key, Key), usize>>>;
+
+/// The last accesses of the media that were not written to the database yet.
+///
+/// See [`SqliteEventCacheStoreConfig::defer_access_times`].
+type PendingAccesses = Arc<StdMutex<BTreeMap<(Key, Key), i64>>>;
+
+/// A guard marking a media as in use, exempting it from eviction until it is
+/// dropped.
+///
+/// See [`SqliteEventCacheStore::reserve`].
+#[derive(Debug)]
+#[must_use = "the media is released as soon as the guard is dropped"]
+pub struct ReservationGuard {
+    reservations: Reservations,
+    key: (Key, Key),
+}
+
+impl Drop for ReservationGuard {
+    fn drop(&mut self) {
+        let mut reservations = self.reservations.lock().unwrap();
+
+        if let Some(count) = reservations.get_mut(&self.key) {
+            *count -= 1;
+
+            if *count == 0 {
+                reservations.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// A SQLite-based event cache store.
+///
+/// # Concurrency
+///
+/// The store can be used from several tasks at once, and the database can be
+/// shared with other processes. Every eviction of media, when a media is added
+/// with a [`MediaRetentionPolicy::max_cache_size`], by
+/// [`EventCacheStore::clean_up_media_cache`] or by
+/// [`SqliteEventCacheStore::trim_to_count`], runs in a single transaction that
+/// takes the write lock of the database when it begins. The media to remove
+/// are therefore chosen from the latest data, and nothing can be added or
+/// accessed until they are removed, so the eviction never races with other
+/// writes: the media added by the same transaction and the
+/// [reserved](SqliteEventCacheStore::reserve) media are never evicted, and a
+/// media added just before an eviction is the most recently accessed one.
+///
+/// Reads don't take the write lock, they see the data as it was when they
+/// started, so they may return a media that is being evicted.
+///
+/// # Memory
+///
+/// [`EventCacheStore::get_media_content`] loads the whole content of a media
+/// in memory. In a store without passphrase, the content read from the
+/// database is returned as is. In an encrypted store, the data read from the
+/// database is dropped once the envelope of the encrypted value is
+/// deserialized, and the ciphertext is decrypted in place, so reading a media
+/// never holds much more than twice its size. The content of a media of a
+/// store without passphrase can be read without loading it fully with
+/// [`SqliteEventCacheStore::get_media_content_stream`] and
+/// [`SqliteEventCacheStore::get_media_content_range`].
+#[derive(Clone)]
+pub struct SqliteEventCacheStore {
+    store_cipher: Option<Arc<StoreCipher>>,
+    /// The pool used for reads.
+    pool: SqlitePool,
+    /// The pool used for writes. SQLite only allows one writer at a time, so
+    /// it has a single connection, unless it is the same pool as the one for
+    /// reads.
+    write_pool: SqlitePool,
+    config: SqliteEventCacheStoreConfig,
+    reservations: Reservations,
+    metrics: Arc<Metrics>,
+    /// Whether the total size of the media cache is above the soft limit of
+    /// the retention policy.
+    above_soft_limit: Arc<AtomicBool>,
+    /// Whether the media cache is disabled.
+    ///
+    /// See [`SqliteEventCacheStore::set_media_cache_enabled`].
+    media_cache_disabled: Arc<AtomicBool>,
+    /// Whether the store was opened in read-only mode.
+    ///
+    /// See [`SqliteEventCacheStore::open_read_only`].
+    read_only: bool,
+    /// The path of the database file, if the store opened it.
+    database_path: Option<PathBuf>,
+    pending_accesses: PendingAccesses,
+}
+
+/// Where the store cipher of a [`SqliteEventCacheStore`] comes from.
+enum CipherSource<'a> {
+    /// The store cipher is loaded from the database, or created and saved to
+    /// it, and it is encrypted with the given passphrase.
+    Passphrase(&'a str),
+    /// The given store cipher is used as is.
+    Cipher(Arc<StoreCipher>),
+}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Debug for SqliteEventCacheStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SqliteEventCacheStore").finish_non_exhaustive()
+    }
+}
+
+impl SqliteEventCacheStore {
+    /// Open the SQLite-based event cache store at the given path using the
+    /// given passphrase to encrypt private data.
+    pub async fn open(
+        path: impl AsRef<Path>,
+        passphrase: Option<&str>,
+    ) -> Result<Self, OpenStoreError> {
+        Self::open_with_config(path, passphrase, SqliteEventCacheStoreConfig::default()).await
+    }
+
+    /// Open the SQLite-based event cache store at the given path in read-only
+    /// mode, using the given passphrase to decrypt private data.
+    ///
+    /// This is meant for tools that inspect the cache of an application,
+    /// possibly while it is running. The connections are opened read-only, and
+    /// don't compete for the lock of the writer. The methods that modify the
+    /// store return [`Error::ReadOnly`] instead, and reading a media doesn't
+    /// update its last access.
+    ///
+    /// The store must already exist and be up-to-date: opening it fails with
+    /// [`OpenStoreError::MigrationRequired`] if it must be migrated, and with
+    /// [`OpenStoreError::MissingStoreCipher`] if a passphrase is given but the
+    /// store is not encrypted.
+    pub async fn open_read_only(
+        path: impl AsRef<Path>,
+        passphrase: Option<&str>,
+    ) -> Result<Self, OpenStoreError> {
+        Self::open_read_only_with_config(path, passphrase, SqliteEventCacheStoreConfig::default())
+            .await
+    }
+
+    /// Open the SQLite-based event cache store at the given path in read-only
+    /// mode, using the given passphrase to decrypt private data, and the given
+    /// configuration.
+    ///
+    /// This is the same as [`SqliteEventCacheStore::open_read_only`], for the
+    /// stores that were created with a custom configuration, like
+    /// [`SqliteEventCacheStoreConfig::database_file_name`] or
+    /// [`SqliteEventCacheStoreConfig::account_namespace`]. The settings that
+    /// only apply to writes are ignored.
+    pub async fn open_read_only_with_config(
+        path: impl AsRef<Path>,
+        passphrase: Option<&str>,
+        config: SqliteEventCacheStoreConfig,
+    ) -> Result<Self, OpenStoreError> {
+        let file_name = config.database_file_name.as_deref().unwrap_or(DATABASE_FILE_NAME);
+        if !is_plain_file_name(file_name) {
+            return Err(OpenStoreError::InvalidDatabaseFileName(file_name.to_owned()));
+        }
+
+        // `deadpool-sqlite` doesn't allow to choose the flags of the connections, but
+        // the `mode=ro` URI parameter is the same as `SQLITE_OPEN_READ_ONLY`.
+        let path = path.as_ref().join(file_name);
+        let mut params = vec![("mode", "ro")];
+        if let Some(vfs) = config.file_locking.vfs() {
+            params.push(("vfs", vfs));
+        }
+        let cfg = deadpool_sqlite::Config::new(sqlite_uri(&path, &params));
+        let pool = build_pool(cfg, &config)?;
+
+        let cipher = passphrase.map(CipherSource::Passphrase);
+        let (mut store, _) =
+            Self::open_with_pools(pool.clone(), pool, cipher, config, true).await?;
+        store.database_path = Some(path);
+
+        Ok(store)
+    }
+
+    /// Open the SQLite-based event cache store at the given path using the
+    /// given passphrase to encrypt private data, and the given configuration.
+    pub async fn open_with_config(
+        path: impl AsRef<Path>,
+        passphrase: Option<&str>,
+        config: SqliteEventCacheStoreConfig,
+    ) -> Result<Self, OpenStoreError> {
+        let cipher = passphrase.map(CipherSource::Passphrase);
+        Ok(Self::open_at_path(path.as_ref(), cipher, config).await?.0)
+    }
+
+    /// Open the SQLite-based event cache store at the given path using the
+    /// given passphrase to encrypt private data, and the given configuration,
+    /// and report whether the database was migrated.
+    ///
+    /// This is the same as [`SqliteEventCacheStore::open_with_config`], for
+    /// applications that show that their data is being upgraded, and need to
+    /// know when it is done. See also
+    /// [`SqliteEventCacheStoreConfig::on_migration_progress`] to follow the
+    /// progress of the migration.
+    pub async fn open_with_config_and_outcome(
+        path: impl AsRef<Path>,
+        passphrase: Option<&str>,
+        config: SqliteEventCacheStoreConfig,
+    ) -> Result<(Self, OpenOutcome), OpenStoreError> {
+        let cipher = passphrase.map(CipherSource::Passphrase);
+        Self::open_at_path(path.as_ref(), cipher, config).await
+    }
+
+    /// Open the SQLite-based event cache store at the given path, using the
+    /// given store cipher to encrypt private data.
+    ///
+    /// This allows to share the store cipher of another store, like the state
+    /// store, without deriving it again from the passphrase, which is slow.
+    /// The store cipher is used as is: it is not loaded from the database nor
+    /// saved to it, so the caller is responsible for persisting it, and must
+    /// always open the store with the same store cipher. Opening the store
+    /// with another store cipher, or with a passphrase, doesn't fail, but the
+    /// existing data can't be read anymore.
+    pub async fn open_with_cipher(
+        path: impl AsRef<Path>,
+        store_cipher: Arc<StoreCipher>,
+    ) -> Result<Self, OpenStoreError> {
+        let cipher = Some(CipherSource::Cipher(store_cipher));
+        let config = SqliteEventCacheStoreConfig::default();
+        Ok(Self::open_at_path(path.as_ref(), cipher, config).await?.0)
+    }
+
+    /// Open the SQLite-based event cache store at the given path, using the
+    /// given source of the store cipher, and the given configuration.
+    async fn open_at_path(
+        path: &Path,
+        cipher: Option<CipherSource<'_>>,
+        config: SqliteEventCacheStoreConfig,
+    ) -> Result<(Self, OpenOutcome), OpenStoreError> {
+        let (pool, write_pool) = if config.locking_mode == LockingMode::Exclusive {
+            // A second connection could never get the lock held by the first one.
+            let pool = create_pool(path, &config, Some(1)).await?;
+            (pool.clone(), pool)
+        } else {
+            let pool = create_pool(path, &config, config.pool_max_size).await?;
+            let write_pool = create_pool(path, &config, Some(1)).await?;
+            (pool, write_pool)
+        };
+        let file_name = config.database_file_name.as_deref().unwrap_or(DATABASE_FILE_NAME);
+        let database_path = path.join(file_name);
+
+        let (mut store, outcome) =
+            Self::open_with_pools(pool, write_pool, cipher, config, false).await?;
+        store.database_path = Some(database_path);
+
+        Ok((store, outcome))
+    }
+
+    /// Open an SQLite-based event cache store that only lives in memory, using
+    /// the given passphrase to encrypt private data.
+    ///
+    /// Nothing is written to disk, and all the data is lost when the store is
+    /// dropped. This is meant for tests and ephemeral sessions, like guest
+    /// sessions.
+    pub async fn open_in_memory(passphrase: Option<&str>) -> Result<Self, OpenStoreError> {
+        let config = SqliteEventCacheStoreConfig::default();
+
+        // Every connection to `:memory:` has its own database, so the pool must only
+        // have a single connection, for both reads and writes.
+        let mut cfg = deadpool_sqlite::Config::new(":memory:");
+        cfg.pool = Some(PoolConfig::new(1));
+        let pool = build_pool(cfg, &config)?;
+
+        let cipher = passphrase.map(CipherSource::Passphrase);
+        Ok(Self::open_with_pools(pool.clone(), pool, cipher, config, false).await?.0)
+    }
+
+    /// Open an SQLite-based event cache store that never caches any media.
+    ///
+    /// The store only lives in memory, like with
+    /// [`SqliteEventCacheStore::open_in_memory`], and its media cache is
+    /// disabled, as with [`SqliteEventCacheStore::set_media_cache_enabled`].
+    /// This is meant for applications that don't want any media to be
+    /// persisted, but still need an [`EventCacheStore`] implementation. The
+    /// media cache can still be enabled later, but the media are never
+    /// written to disk.
+    pub async fn open_disabled() -> Result<Self, OpenStoreError> {
+        let store = Self::open_in_memory(None).await?;
+        store.set_media_cache_enabled(false);
+
+        Ok(store)
+    }
+
+    /// Open an SQLite-based event cache store with the given configuration of
+    /// the SQLite database pools, using the given passphrase to encrypt
+    /// private data, and the given store configuration.
+    ///
+    /// This allows to control how the connections are opened. The connections
+    /// are opened with [URI filenames] enabled, so the path of the SQLite
+    /// configuration can be a URI to choose the VFS with the `vfs` parameter,
+    /// or the open mode with the `mode` and `cache` parameters, for example
+    /// `file:name?mode=memory&cache=shared` for an in-memory database that is
+    /// shared by all the connections using the same name.
+    ///
+    /// The store creates a pool for reads with the given configuration, and a
+    /// pool with a single connection for writes, or a single connection for
+    /// both with [`LockingMode::Exclusive`]. The settings of the store
+    /// configuration that apply to the connections, like
+    /// [`SqliteEventCacheStoreConfig::busy_timeout`], are applied to the
+    /// connections of both pools, but
+    /// [`SqliteEventCacheStoreConfig::file_locking`]
+    /// and [`SqliteEventCacheStoreConfig::database_file_name`] are ignored, the
+    /// path of the SQLite configuration is used as is.
+    ///
+    /// [URI filenames]: https://www.sqlite.org/uri.html
+    pub async fn open_with_sqlite_config(
+        sqlite_config: deadpool_sqlite::Config,
+        passphrase: Option<&str>,
+        config: SqliteEventCacheStoreConfig,
+    ) -> Result<Self, OpenStoreError> {
+        let mut read_config = sqlite_config.clone();
+        if read_config.pool.is_none() {
+            read_config.pool = config.pool_max_size.map(PoolConfig::new);
+        }
+        let mut write_config = sqlite_config;
+        write_config.pool = Some(PoolConfig::new(1));
+
+        let write_pool = build_pool(write_config, &config)?;
+        let pool = if config.locking_mode == LockingMode::Exclusive {
+            write_pool.clone()
+        } else {
+            build_pool(read_config, &config)?
+        };
+
+        let cipher = passphrase.map(CipherSource::Passphrase);
+        Ok(Self::open_with_pools(pool, write_pool, cipher, config, false).await?.0)
+    }
+
+    /// Open an SQLite-based event cache store using the given SQLite database
+    /// pool. The given passphrase will be used to encrypt private data.
+    pub async fn open_with_pool(
+        pool: SqlitePool,
+        passphrase: Option<&str>,
+    ) -> Result<Self, OpenStoreError> {
+        Self::open_with_pool_and_config(pool, passphrase, SqliteEventCacheStoreConfig::default())
+            .await
+    }
+
+    /// Open an SQLite-based event cache store using the given SQLite database
+    /// pool and configuration. The given passphrase will be used to encrypt
+    /// private data.
+    ///
+    /// The settings of the configuration that apply to the connections, like
+    /// [`SqliteEventCacheStoreConfig::read_uncommitted`], are not applied to
+    /// the connections of the given pool.
+    ///
+    /// The given pool is used for both reads and writes.
+    pub async fn open_with_pool_and_config(
+        pool: SqlitePool,
+        passphrase: Option<&str>,
+        config: SqliteEventCacheStoreConfig,
+    ) -> Result<Self, OpenStoreError> {
+        let cipher = passphrase.map(CipherSource::Passphrase);
+        Ok(Self::open_with_pools(pool.clone(), pool, cipher, config, false).await?.0)
+    }
+
+    /// Open an SQLite-based event cache store using the given SQLite database
+    /// pools for reads and writes, source of the store cipher, and
+    /// configuration.
+    ///
+    /// A store in read-only mode is not migrated, and the write pool is only
+    /// used to read the metadata of the store.
+    ///
+    /// Returns the store and whether it was migrated.
+    async fn open_with_pools(
+        pool: SqlitePool,
+        write_pool: SqlitePool,
+        cipher: Option<CipherSource<'_>>,
+        config: SqliteEventCacheStoreConfig,
+        read_only: bool,
+    ) -> Result<(Self, OpenOutcome), OpenStoreError> {
+        let conn = write_pool.get().await?;
+        check_integrity(&conn, config.integrity_check).await?;
+
+        let version = match conn.db_version().await {
+            // The schema is created and the version is set in the same transaction, but
+            // a database whose creation was interrupted with a version of the SDK that
+            // didn't do it has the tables of the first migration only. It is created
+            // with `IF NOT EXISTS`, so it can run again.
+            Err(OpenStoreError::MissingVersion) => {
+                warn!("The version of the database is missing, creating it again");
+                0
+            }
+            version => version?,
+        };
+
+        // An older version of the SDK doesn't know how to use the schema of a newer
+        // one.
+        if version > DATABASE_VERSION {
+            return Err(OpenStoreError::UnsupportedDatabaseVersion {
+                found: version,
+                supported: DATABASE_VERSION,
+            });
+        }
+
+        // Check the storage format before migrating, to leave incompatible stores
+        // untouched.
+        let storage_format = if version > 0 { load_storage_format(&conn).await? } else { None };
+        if let Some(found) = storage_format {
+            if found != STORAGE_FORMAT {
+                return Err(OpenStoreError::IncompatibleStorageFormat {
+                    found,
+                    supported: STORAGE_FORMAT,
+                });
+            }
+        }
+
+        if read_only {
+            if version < DATABASE_VERSION {
+                return Err(OpenStoreError::MigrationRequired {
+                    found: version,
+                    required: DATABASE_VERSION,
+                });
+            }
+        } else {
+            run_migrations(&conn, version, &config).await?;
+
+            // Stores created before the storage format was persisted use the first one.
+            if version > 0 && storage_format.is_none() {
+                conn.set_kv(keys::STORAGE_FORMAT, vec![STORAGE_FORMAT])
+                    .await
+                    .map_err(|error| OpenStoreError::Migration(error.into()))?;
+            }
+        }
+
+        // Check the codec before creating the store cipher, to leave incompatible
+        // stores untouched.
+        if cipher.is_some() {
+            let previous = config.previous_envelope_codec.as_ref().map(|codec| codec.tag());
+            check_envelope_codec(&conn, config.codec().tag(), previous, read_only).await?;
+        }
+
+        let store_cipher = match cipher {
+            Some(CipherSource::Passphrase(p)) if read_only => Some(Arc::new(
+                conn.get_store_cipher(p).await?.ok_or(OpenStoreError::MissingStoreCipher)?,
+            )),
+            Some(CipherSource::Passphrase(p)) => Some(Arc::new(
+                conn.get_or_create_store_cipher_with_kdf_rounds(p, config.kdf_rounds).await?,
+            )),
+            Some(CipherSource::Cipher(store_cipher)) => Some(store_cipher),
+            None => None,
+        };
+
+        let store = Self {
+            store_cipher,
+            pool,
+            write_pool,
+            config,
+            reservations: Default::default(),
+            metrics: Default::default(),
+            above_soft_limit: Default::default(),
+            media_cache_disabled: Default::default(),
+            read_only,
+            database_path: None,
+            pending_accesses: Default::default(),
+        };
+        let outcome = OpenOutcome {
+            migrated_from: (version < DATABASE_VERSION).then_some(version),
+            migrated_to: DATABASE_VERSION,
+        };
+
+        Ok((store, outcome))
+    }
+
+    /// Call the eviction callback with the given evicted media, if any.
+    fn notify_evicted(&self, evicted: &[EvictedMedia]) {
+        if evicted.is_empty() {
+            return;
+        }
+
+        self.metrics.evictions.fetch_add(evicted.len() as u64, Ordering::Relaxed);
+
+        if let Some(callback) = &self.config.on_media_evicted {
+            (callback.0)(evicted);
+        }
+    }
+
+    /// Call the soft limit callback if the given size of the cache just went
+    /// over the given soft limit.
+    fn check_soft_limit(&self, soft_limit: u64, cache_size: u64) {
+        let above_soft_limit = cache_size > soft_limit;
+        let was_above_soft_limit = self.above_soft_limit.swap(above_soft_limit, Ordering::SeqCst);
+
+        if above_soft_limit && !was_above_soft_limit {
+            debug!(cache_size, soft_limit, "The media cache went over the soft limit");
+
+            if let Some(callback) = &self.config.on_soft_limit_reached {
+                (callback.0)(cache_size);
+            }
+        }
+    }
+
+    /// The current time, as a number of milliseconds since the Unix epoch, like
+    /// the `last_access` column.
+    fn now(&self) -> i64 {
+        self.config.now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_millis() as i64)
+    }
+
+    /// The SQL statement removing the media matching the given condition.
+    ///
+    /// In soft-delete mode, the media are only marked as deleted now, the
+    /// time is part of the statement so the parameters of the condition are
+    /// the same in both modes.
+    fn remove_media_sql(&self, condition: &str) -> String {
+        if self.config.soft_delete {
+            format!(
+                "UPDATE media SET deleted_at = {} WHERE deleted_at IS NULL AND ({condition})",
+                self.now()
+            )
+        } else {
+            format!("DELETE FROM media WHERE {condition}")
+        }
+    }
+
+    /// Change the passphrase used to encrypt the store cipher.
+    ///
+    /// Only the store cipher is encrypted with the passphrase, the data is
+    /// encrypted with the keys of the store cipher, which don't change, so the
+    /// media don't need to be rewritten and this is fast, whatever the size of
+    /// the cache. The store must be opened with the new passphrase afterwards.
+    ///
+    /// Returns [`Error::NotEncrypted`] if the store was not created with a
+    /// passphrase, [`Error::StoreCipherNotStored`] if it was opened with
+    /// [`SqliteEventCacheStore::open_with_cipher`], since its store cipher is
+    /// not stored with a passphrase, and [`Error::IncorrectPassphrase`] if
+    /// `old` is not the current passphrase. Nothing is changed in these cases.
+    ///
+    /// # Arguments
+    ///
+    /// * `old` - The current passphrase.
+    ///
+    /// * `new` - The new passphrase.
+    pub async fn change_passphrase(&self, old: &str, new: &str) -> Result<()> {
+        let is_encrypted = self.is_encrypted();
+        let kdf_rounds = self.config.kdf_rounds;
+
+        self.retry_if_busy(|| {
+            let (old, new) = (old.to_owned(), new.to_owned());
+            async move {
+                let conn = self.acquire_write().await?;
+                // The store cipher is read in the transaction that writes it, so a concurrent
+                // change of the passphrase can't be overwritten.
+                conn.with_immediate_transaction::<_, Error, _>(move |txn| {
+                    let encrypted = txn
+                        .query_row("SELECT value FROM kv WHERE key = 'cipher'", (), |row| {
+                            row.get::<_, Vec<u8>>(0)
+                        })
+                        .optional()?;
+                    let Some(encrypted) = encrypted else {
+                        return Err(if is_encrypted {
+                            Error::StoreCipherNotStored
+                        } else {
+                            Error::NotEncrypted
+                        });
+                    };
+
+                    // The store cipher is authenticated, so it fails to be decrypted with the
+                    // wrong passphrase.
+                    let cipher =
+                        StoreCipher::import(&old, &encrypted).map_err(|error| match error {
+                            matrix_sdk_store_encryption::Error::Encryption(_) => {
+                                Error::IncorrectPassphrase
+                            }
+                            error => error.into(),
+                        })?;
+                    let export = match kdf_rounds {
+                        Some(kdf_rounds) => cipher.export_with_kdf_rounds(&new, kdf_rounds),
+                        #[cfg(not(test))]
+                        None => cipher.export(&new),
+                        #[cfg(test)]
+                        None => cipher._insecure_export_fast_for_testing(&new),
+                    };
+                    txn.set_kv("cipher", &export?)?;
+
+                    Ok(())
+                })
+                .await
+            }
+        })
+        .await
+    }
+
+    /// Enable or disable the media cache at runtime.
+    ///
+    /// When the media cache is disabled, the media contents that are added to
+    /// the store are dropped instead of being stored, and getting a media
+    /// content always returns `None`. The media that were already in the
+    /// cache are kept, and can be removed as usual, and they are returned
+    /// again when the media cache is enabled again.
+    ///
+    /// This setting is shared by all the clones of the store. The media cache
+    /// is enabled by default.
+    pub fn set_media_cache_enabled(&self, enabled: bool) {
+        self.media_cache_disabled.store(!enabled, Ordering::SeqCst);
+    }
+
+    /// Whether the media cache is enabled.
+    ///
+    /// See [`SqliteEventCacheStore::set_media_cache_enabled`].
+    pub fn is_media_cache_enabled(&self) -> bool {
+        !self.media_cache_disabled.load(Ordering::SeqCst)
+    }
+
+    /// Get a snapshot of the metrics of this store.
+    pub fn metrics(&self) -> EventCacheMetrics {
+        self.metrics.snapshot()
+    }
+
+    /// The path of the database file of this store.
+    ///
+    /// In WAL mode, SQLite also uses two other files next to this one, with
+    /// the `-wal` and `-shm` suffixes.
+    ///
+    /// Returns `None` for the stores that don't have a database file, like the
+    /// in-memory stores, and for the stores opened with a pool or an SQLite
+    /// configuration, whose path is not known by the store.
+    pub fn database_path(&self) -> Option<PathBuf> {
+        self.database_path.clone()
+    }
+
+    /// Whether this store was opened with a passphrase, so the values it
+    /// stores are encrypted and its keys are hashed.
+    ///
+    /// This doesn't take the encryption of the whole database with
+    /// [`SqliteEventCacheStoreConfig::sqlcipher_key`] into account.
+    pub fn is_encrypted(&self) -> bool {
+        self.store_cipher.is_some()
+    }
+
+    /// A description of the algorithm used to encrypt the values of this
+    /// store, for diagnostics.
+    ///
+    /// Returns `None` if the store is not encrypted.
+    pub fn cipher_algorithm(&self) -> Option<&'static str> {
+        self.store_cipher.is_some().then_some(STORE_CIPHER_ALGORITHM)
+    }
+
+    /// Store a custom value in the database of this store.
+    ///
+    /// This allows applications to keep small amounts of metadata along with
+    /// the cache, like the revision of their own format, without having to
+    /// open their own database. The entries of every namespace are separated
+    /// from each other, and from the metadata of the SDK. The value is
+    /// encrypted and the namespace and key are hashed with the store cipher,
+    /// if any.
+    ///
+    /// The custom values are not removed by
+    /// [`EventCacheStore::clear_session_data`].
+    ///
+    /// # Arguments
+    ///
+    /// * `namespace` - The namespace of the entry, like the name of the
+    ///   application.
+    ///
+    /// * `key` - The key of the entry in the namespace.
+    ///
+    /// * `value` - The value to store, which replaces the previous one.
+    pub async fn set_custom_kv(&self, namespace: &str, key: &str, value: Vec<u8>) -> Result<()> {
+        let namespace = self.encode_key(keys::CUSTOM_KV, namespace);
+        let key = self.encode_key(keys::CUSTOM_KV, key);
+        let value = self.encode_value(value)?;
+
+        self.retry_if_busy(|| {
+            let params = (namespace.clone(), key.clone(), value.clone());
+            async move {
+                let conn = self.acquire_write().await?;
+                conn.execute(
+                    "INSERT OR REPLACE INTO custom_kv (namespace, key, value) VALUES (?, ?, ?)",
+                    params,
+                )
+                .await?;
+
+                Ok(())
+            }
+        })
+        .await
+    }
+
+    /// Get a custom value stored with [`SqliteEventCacheStore::set_custom_kv`].
+    ///
+    /// # Arguments
+    ///
+    /// * `namespace` - The namespace of the entry.
+    ///
+    /// * `key` - The key of the entry in the namespace.
+    pub async fn get_custom_kv(&self, namespace: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        let namespace = self.encode_key(keys::CUSTOM_KV, namespace);
+        let key = self.encode_key(keys::CUSTOM_KV, key);
+
+        let conn = self.acquire().await?;
+        let value = conn
+            .query_row::<Vec<u8>, _, _>(
+                "SELECT value FROM custom_kv WHERE namespace = ? AND key = ?",
+                (namespace, key),
+                |row| row.get(0),
+            )
+            .await
+            .optional()?;
+
+        value.map(|value| Ok(self.decode_value(&value)?.into_owned())).transpose()
+    }
+
+    /// Remove a custom value stored with
+    /// [`SqliteEventCacheStore::set_custom_kv`].
+    ///
+    /// # Arguments
+    ///
+    /// * `namespace` - The namespace of the entry.
+    ///
+    /// * `key` - The key of the entry in the namespace.
+    pub async fn remove_custom_kv(&self, namespace: &str, key: &str) -> Result<()> {
+        let namespace = self.encode_key(keys::CUSTOM_KV, namespace);
+        let key = self.encode_key(keys::CUSTOM_KV, key);
+
+        self.retry_if_busy(|| {
+            let params = (namespace.clone(), key.clone());
+            async move {
+                let conn = self.acquire_write().await?;
+                conn.execute("DELETE FROM custom_kv WHERE namespace = ? AND key = ?", params)
+                    .await?;
+
+                Ok(())
+            }
+        })
+        .await
+    }
+
+    /// Get the time when the database of this store was created.
+    ///
+    /// Returns `None` for the databases created by a version of the SDK that
+    /// didn't record it.
+    pub async fn created_at(&self) -> Result<Option<SystemTime>> {
+        let conn = self.acquire().await?;
+        let Some(created_at) = conn.get_kv(keys::CREATED_AT).await? else {
+            return Ok(None);
+        };
+
+        Ok(<[u8; 8]>::try_from(created_at.as_slice()).ok().map(|millis| {
+            SystemTime::UNIX_EPOCH + Duration::from_millis(u64::from_be_bytes(millis))
+        }))
+    }
+
+    /// Get the total size of the files of the database on disk, in bytes.
+    ///
+    /// This is the sum of the sizes of the [database
+    /// file](Self::database_path) and of its `-wal` and `-shm` files, if they
+    /// exist. Returns 0 for the stores that don't have a database file.
+    pub async fn database_disk_size(&self) -> Result<u64> {
+        let Some(path) = &self.database_path else {
+            return Ok(0);
+        };
+
+        let mut size = 0;
+        for suffix in ["", "-wal", "-shm"] {
+            let mut file_path = path.clone().into_os_string();
+            file_path.push(suffix);
+
+            match fs::metadata(&file_path).await {
+                Ok(metadata) => size += metadata.len(),
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+                Err(error) => return Err(Error::DiskSize(error)),
+            }
+        }
+
+        Ok(size)
+    }
+
+    fn encode_value(&self, value: Vec<u8>) -> Result<Vec<u8>> {
+        if let Some(key) = &self.store_cipher {
+            let encrypted = key.encrypt_value_data(value)?;
+            self.config.codec().encode(&encrypted).map_err(|error| {
+                match error.downcast::<rmp_serde::encode::Error>() {
+                    Ok(error) => Error::Encode(*error),
+                    Err(error) => Error::Codec(error),
+                }
+            })
+        } else {
+            Ok(value)
+        }
+    }
+
+    fn decode_value<'a>(&self, value: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+        if let Some(key) = &self.store_cipher {
+            let encrypted = self.decode_envelope(value)?;
+            let decrypted = key.decrypt_value_data(encrypted)?;
+            Ok(Cow::Owned(decrypted))
+        } else {
+            Ok(Cow::Borrowed(value))
+        }
+    }
+
+    /// Deserialize the envelope of the given encrypted value, with the codec
+    /// of the store or the previous one.
+    fn decode_envelope(&self, value: &[u8]) -> Result<EncryptedValue> {
+        self.config
+            .codec()
+            .decode(value)
+            .or_else(|error| match &self.config.previous_envelope_codec {
+                // The value was not re-encoded with the new codec yet.
+                Some(previous) => previous.decode(value).map_err(|_| error),
+                None => Err(error),
+            })
+            .map_err(|error| match error.downcast::<rmp_serde::decode::Error>() {
+                Ok(error) => Error::Decode(*error),
+                Err(error) => Error::Codec(error),
+            })
+    }
+
+    fn encode_key(&self, table_name: &str, key: impl AsRef<[u8]>) -> Key {
+        match &self.config.account_namespace {
+            // Prefix the namespace with its length, so the boundary with the key is
+            // unambiguous, both can contain any byte.
+            Some(namespace) => {
+                let mut bytes = Vec::with_capacity(8 + namespace.len() + key.as_ref().len());
+                bytes.extend_from_slice(&(namespace.len() as u64).to_be_bytes());
+                bytes.extend_from_slice(namespace.as_bytes());
+                bytes.extend_from_slice(key.as_ref());
+                self.encode_key_bytes(table_name, &bytes)
+            }
+            None => self.encode_key_bytes(table_name, key.as_ref()),
+        }
+    }
+
+    /// The key of the account namespace of this store, stored with its data.
+    fn account_key(&self) -> Option<Key> {
+        self.config.account_namespace.as_ref().map(|namespace| self.namespace_key(namespace))
+    }
+
+    /// The key of the given account namespace.
+    fn namespace_key(&self, namespace: &str) -> Key {
+        self.encode_key_bytes(keys::ACCOUNT, namespace.as_bytes())
+    }
+
+    /// Hash the given key, if the keys of this store are hashed, without
+    /// mixing the account namespace.
+    fn encode_key_bytes(&self, table_name: &str, bytes: &[u8]) -> Key {
+        if let Some(store_cipher) = &self.store_cipher {
+            Key::Hashed(store_cipher.hash_key(table_name, bytes))
+        } else if self.config.hash_keys {
+            Key::Hashed(hash_key_without_cipher(table_name, bytes))
+        } else {
+            Key::Plain(bytes.to_owned())
+        }
+    }
+
+    /// Get a connection from the pool for reads.
+    async fn acquire(&self) -> Result<SqliteAsyncConn> {
+        self.get_connection(&self.pool).await
+    }
+
+    /// Get a connection from the pool for writes.
+    ///
+    /// Fails with [`Error::ReadOnly`] if the store was opened in read-only
+    /// mode.
+    async fn acquire_write(&self) -> Result<SqliteAsyncConn> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        self.get_connection(&self.write_pool).await
+    }
+
+    /// Get a connection from the given pool, waiting at most for the
+    /// [`SqliteEventCacheStoreConfig::acquire_timeout`].
+    async fn get_connection(&self, pool: &SqlitePool) -> Result<SqliteAsyncConn> {
+        get_connection_within(pool, self.config.acquire_timeout).await
+    }
+
+    /// Check that the store can be used, for a liveness probe.
+    ///
+    /// This gets a connection for reads and runs a trivial query on it. It
+    /// waits for the connection for a short time, or for the
+    /// [`SqliteEventCacheStoreConfig::acquire_timeout`] if it is shorter, and
+    /// fails with [`Error::PoolTimeout`] if no connection is free by then, for
+    /// example because the pool is stuck. It fails with the error of SQLite if
+    /// the query fails, for example because the database can't be opened
+    /// anymore.
+    pub async fn ping(&self) -> Result<()> {
+        let timeout = self.config.acquire_timeout.map_or(PING_TIMEOUT, |t| t.min(PING_TIMEOUT));
+        let conn = get_connection_within(&self.pool, Some(timeout)).await?;
+        conn.query_row("SELECT 1", (), |row| row.get::<_, i64>(0)).await?;
+
+        Ok(())
+    }
+
+    /// Run the given write operation, and run it again if it fails because
+    /// the database is busy, according to the
+    /// [`SqliteEventCacheStoreConfig::busy_retry_policy`].
+    ///
+    /// The operation must be safe to run again when it failed, which is the
+    /// case when it runs in a single statement or transaction.
+    async fn retry_if_busy<T, F, Fut>(&self, mut operation: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let BusyRetryPolicy { max_retries, initial_backoff, max_backoff } =
+            self.config.busy_retry_policy;
+        let mut backoff = initial_backoff;
+        let mut retries = 0;
+
+        loop {
+            match operation().await {
+                Err(error) if error.is_busy() && retries < max_retries => {
+                    retries += 1;
+                    debug!(retries, ?backoff, "The database is busy, retrying the write");
+
+                    tokio::time::sleep(backoff).await;
+                    backoff = backoff.saturating_mul(2).min(max_backoff);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Mark the given media as in use, until the returned guard is dropped.
+    ///
+    /// Reserved media are skipped when evicting media from the cache, so this
+    /// should be used when a media that was just fetched is about to be
+    /// displayed, to make sure it doesn't vanish in the meantime. It is still
+    /// possible to remove a reserved media explicitly with
+    /// [`EventCacheStore::remove_media_content`].
+    ///
+    /// Reservations only live in memory, they don't need the media to be in
+    /// the cache and can be nested.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The `MediaRequest` of the file.
+    pub fn reserve(&self, request: &MediaRequest) -> ReservationGuard {
+        let uri = self.encode_key(keys::MEDIA, request.source.unique_key());
+        let format = self.encode_key(keys::MEDIA, request.format.unique_key());
+        let key = (uri, format);
+
+        *self.reservations.lock().unwrap().entry(key.clone()).or_default() += 1;
+
+        ReservationGuard { reservations: self.reservations.clone(), key }
+    }
+
+    /// The keys of the media that are currently reserved, as they are stored in
+    /// the database.
+    fn reserved_keys(&self) -> BTreeSet<(Vec<u8>, Vec<u8>)> {
+        self.reservations
+            .lock()
+            .unwrap()
+            .keys()
+            .map(|(uri, format)| (uri.to_vec(), format.to_vec()))
+            .collect()
+    }
+
+    /// Get the total size of the media contents of the account namespace of
+    /// this store in the cache, in bytes.
+    ///
+    /// This is the size of the media contents as they are stored in the
+    /// database. In an encrypted store, it is larger than the total size of
+    /// the plain media contents, because of the overhead of the encryption.
+    /// Identical contents are only stored, and counted, once. The contents
+    /// that are only used by media marked as deleted, with
+    /// [`SqliteEventCacheStoreConfig::soft_delete`], are not counted.
+    pub async fn media_cache_size(&self) -> Result<u64> {
+        let account = self.account_key();
+        let conn = self.acquire().await?;
+        let size = conn.query_row(CACHE_SIZE_QUERY, (account,), |row| row.get(0)).await?;
+
+        Ok(size)
+    }
+
+    /// Get the number of media contents of the account namespace of this store
+    /// in the cache.
+    pub async fn media_cache_count(&self) -> Result<u64> {
+        let account = self.account_key();
+        let conn = self.acquire().await?;
+        let count = conn
+            .query_row(
+                "SELECT COUNT(*) FROM media WHERE deleted_at IS NULL AND account IS ?",
+                (account,),
+                |row| row.get(0),
+            )
+            .await?;
+
+        Ok(count)
+    }
+
+    /// Remove the media of the account namespace of this store that were
+    /// marked as deleted before the given time.
+    ///
+    /// The media are only marked as deleted with
+    /// [`SqliteEventCacheStoreConfig::soft_delete`], this removes them, with
+    /// their content, for good.
+    ///
+    /// Returns the number of media that were removed.
+    ///
+    /// # Arguments
+    ///
+    /// * `before` - The time before which the media must have been deleted to
+    ///   be removed.
+    pub async fn purge_deleted(&self, before: SystemTime) -> Result<u64> {
+        let before =
+            before.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_millis() as i64);
+        let account = self.account_key();
+
+        let removed = self
+            .retry_if_busy(|| {
+                let account = account.clone();
+                async move {
+                    let conn = self.acquire_write().await?;
+                    Ok(conn
+                        .execute(
+                            "DELETE FROM media WHERE deleted_at IS NOT NULL AND deleted_at < ? \
+                             AND account IS ?",
+                            (before, account),
+                        )
+                        .await?)
+                }
+            })
+            .await?;
+
+        if removed > 0 {
+            debug!(removed, "Purged the deleted media");
+            self.maybe_incremental_vacuum().await?;
+        }
+
+        Ok(removed as u64)
+    }
+
+    /// Remove the least recently accessed media of the account namespace of
+    /// this store until there are not more than `max_items` of them in the
+    /// cache.
+    ///
+    /// This is a limit on the number of media, regardless of their size,
+    /// unlike [`MediaRetentionPolicy::max_cache_size`]. The reserved and pinned
+    /// media are never removed, but they count towards the limit, so more
+    /// than `max_items` media can remain if there are not enough other media
+    /// to remove.
+    ///
+    /// Returns the number of media that were removed.
+    pub async fn trim_to_count(&self, max_items: usize) -> Result<u64> {
+        self.flush_access_times().await?;
+
+        let max_items = max_items.try_into().unwrap_or(u64::MAX);
+        let account = self.account_key();
+        let reserved = Arc::new(self.reserved_keys());
+
+        let removed = self
+            .retry_if_busy(|| {
+                let (account, reserved) = (account.clone(), reserved.clone());
+                async move {
+                    let conn = self.acquire_write().await?;
+                    Ok(conn
+                        .with_immediate_transaction(move |txn| {
+                            trim_media_to_count(txn, max_items, account.as_ref(), &reserved)
+                        })
+                        .await?)
+                }
+            })
+            .await?;
+
+        let count = removed.len() as u64;
+        if count > 0 {
+            debug!(removed = count, max_items, "Trimmed the media cache");
+            self.maybe_incremental_vacuum().await?;
+        }
+
+        self.notify_evicted(&removed);
+
+        Ok(count)
+    }
+
+    /// Apply all the limits of the given retention policy to the media of the
+    /// account namespace of this store in the cache.
+    ///
+    /// The limits are applied in a single transaction, in this order:
+    ///
+    /// 1. The media that were not accessed during
+    ///    [`max_age`](MediaRetentionPolicy::max_age) are removed.
+    /// 2. The media larger than
+    ///    [`max_file_size`](MediaRetentionPolicy::max_file_size) are removed.
+    ///    Since the plain sizes of the stored media are not known, their sizes
+    ///    as they are stored are compared instead, which are a bit larger in an
+    ///    encrypted store, and smaller for compressed media.
+    /// 3. The least recently accessed media are removed until there are not
+    ///    more than [`max_items`](MediaRetentionPolicy::max_items).
+    /// 4. The least recently accessed media are removed until the cache is not
+    ///    larger than [`max_cache_size`](MediaRetentionPolicy::max_cache_size).
+    ///
+    /// The pinned and [reserved](Self::reserve) media are never removed, but
+    /// they count towards the limits of the last two steps, so the cache can
+    /// stay over them if there are not enough other media. The
+    /// [`soft_limit`](MediaRetentionPolicy::soft_limit) is ignored.
+    ///
+    /// This is meant to be called periodically by a maintenance task, with the
+    /// policy of the configuration or a stricter one.
+    pub async fn enforce_retention(
+        &self,
+        policy: &MediaRetentionPolicy,
+    ) -> Result<RetentionReport> {
+        // Don't remove the media that were accessed recently.
+        self.flush_access_times().await?;
+
+        let limits = self.retention_limits(policy);
+        let account = self.account_key();
+        let reserved = Arc::new(self.reserved_keys());
+
+        let (report, removed) = self
+            .retry_if_busy(|| {
+                let (account, reserved) = (account.clone(), reserved.clone());
+                async move {
+                    let conn = self.acquire_write().await?;
+                    Ok(conn
+                        .with_immediate_transaction(move |txn| {
+                            enforce_retention(txn, limits, account.as_ref(), &reserved)
+                        })
+                        .await?)
+                }
+            })
+            .await?;
+
+        if !removed.is_empty() {
+            debug!(?report, "Enforced the media retention policy");
+            self.maybe_incremental_vacuum().await?;
+        }
+
+        self.notify_evicted(&removed);
+
+        Ok(report)
+    }
+
+    /// Compute what [`SqliteEventCacheStore::enforce_retention`] would remove
+    /// from the cache with the given retention policy, without removing
+    /// anything.
+    ///
+    /// The policy is applied exactly like `enforce_retention` does, in a
+    /// transaction that is rolled back afterwards, so it takes the write lock
+    /// of the database while it runs.
+    pub async fn estimate_eviction(
+        &self,
+        policy: &MediaRetentionPolicy,
+    ) -> Result<EvictionEstimate> {
+        self.flush_access_times().await?;
+
+        let limits = self.retention_limits(policy);
+        let account = self.account_key();
+        let reserved = Arc::new(self.reserved_keys());
+
+        let report = self
+            .retry_if_busy(|| {
+                let (account, reserved) = (account.clone(), reserved.clone());
+                async move {
+                    let conn = self.acquire_write().await?;
+                    Ok(conn
+                        .with_rolled_back_transaction(move |txn| {
+                            enforce_retention(txn, limits, account.as_ref(), &reserved)
+                                .map(|(report, _)| report)
+                        })
+                        .await?)
+                }
+            })
+            .await?;
+
+        Ok(EvictionEstimate {
+            items: report.expired + report.too_large + report.over_count + report.over_size,
+            bytes: report.bytes,
+        })
+    }
+
+    /// Convert the given retention policy to the limits applied by
+    /// [`enforce_retention`], at the current time.
+    fn retention_limits(&self, policy: &MediaRetentionPolicy) -> RetentionLimits {
+        let cutoff = policy.max_age.map(|max_age| {
+            self.now().saturating_sub(max_age.as_millis().try_into().unwrap_or(i64::MAX))
+        });
+
+        RetentionLimits {
+            cutoff,
+            max_file_size: policy.max_file_size.map(|size| size.try_into().unwrap_or(u64::MAX)),
+            max_items: policy.max_items.map(|max| max.try_into().unwrap_or(u64::MAX)),
+            max_cache_size: policy.max_cache_size,
+        }
+    }
+
+    /// Add the content of several media files in the media store, at once.
+    ///
+    /// This is equivalent to calling [`EventCacheStore::add_media_content`]
+    /// for every item, except that all the media are added in a single
+    /// transaction with the same prepared statement. This avoids waiting for
+    /// the database to be synced to disk after every media, which dominates
+    /// the time it takes to add small media, so it is much faster when adding
+    /// hundreds of them, like when warming up the cache.
+    ///
+    /// It is atomic: if one of the media can't be added, none of them are.
+    ///
+    /// # Arguments
+    ///
+    /// * `items` - The `MediaRequest`s of the files, with their content.
+    pub async fn add_media_content_batch(&self, items: &[(MediaRequest, Vec<u8>)]) -> Result<()> {
+        let media = items
+            .iter()
+            .filter_map(|(request, content)| {
+                self.encode_media(request, content.clone(), None).transpose()
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        if !media.is_empty() {
+            self.insert_media(media).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove the content of several media files from the media store, at
+    /// once.
+    ///
+    /// This is equivalent to calling [`EventCacheStore::remove_media_content`]
+    /// for every request, except that all the media are removed in a single
+    /// transaction with the same prepared statement, like
+    /// [`Self::add_media_content_batch`].
+    ///
+    /// It is atomic: if one of the media can't be removed, none of them are.
+    /// The requests of media that are not in the cache, or that appear several
+    /// times, are ignored.
+    ///
+    /// Returns the number of media that were removed.
+    ///
+    /// # Arguments
+    ///
+    /// * `requests` - The `MediaRequest`s of the files.
+    pub async fn remove_media_content_batch(&self, requests: &[MediaRequest]) -> Result<u64> {
+        if requests.is_empty() {
+            return Ok(0);
+        }
+
+        let keys = requests
+            .iter()
+            .map(|request| {
+                (
+                    self.encode_key(keys::MEDIA, request.source.unique_key()),
+                    self.encode_key(keys::MEDIA, request.format.unique_key()),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let sql = self.remove_media_sql("uri = ? AND format = ?");
+
+        self.retry_if_busy(|| {
+            let (keys, sql) = (keys.clone(), sql.clone());
+            async move {
+                let conn = self.acquire_write().await?;
+                let removed = conn
+                    .with_transaction::<_, rusqlite::Error, _>(move |txn| {
+                        let mut statement = txn.prepare_cached(&sql)?;
+                        let mut removed = 0;
+                        for (uri, format) in keys {
+                            removed += statement.execute((uri, format))? as u64;
+                        }
+
+                        Ok(removed)
+                    })
+                    .await?;
+
+                Ok(removed)
+            }
+        })
+        .await
+    }
+
+    /// Encode the given media to be inserted in the database.
+    ///
+    /// Returns `None` if the media must not be stored according to the
+    /// retention policy.
+    fn encode_media(
+        &self,
+        request: &MediaRequest,
+        content: Vec<u8>,
+        content_type: Option<&str>,
+    ) -> Result<Option<EncodedMedia>> {
+        self.encode_media_with_keys(
+            self.encode_key(keys::MEDIA, request.source.unique_key()),
+            self.encode_key(keys::MEDIA, request.format.unique_key()),
+            content,
+            content_type,
+            FormatColumns::new(&request.format),
+        )
+    }
+
+    /// Encode the given media with the given keys to be inserted in the
+    /// database.
+    ///
+    /// Returns `None` if the media must not be stored according to the
+    /// retention policy.
+    fn encode_media_with_keys(
+        &self,
+        uri: Key,
+        format: Key,
+        content: Vec<u8>,
+        content_type: Option<&str>,
+        format_columns: FormatColumns,
+    ) -> Result<Option<EncodedMedia>> {
+        if !self.is_media_cache_enabled() {
+            debug!("The media cache is disabled, the media content is not cached");
+            return Ok(None);
+        }
+
+        // Check the size of the plain content, the encrypted one is larger.
+        if let Some(max_file_size) = self.config.media_retention_policy.max_file_size {
+            if content.len() > max_file_size {
+                debug!(size = content.len(), "The media content is too large to be cached");
+                return Ok(None);
+            }
+        }
+
+        let hash = self.content_hash(&content);
+
+        // Compress before encrypting, encrypted data doesn't compress.
+        let (content, compressed) = if self.config.compress_media {
+            let compressed = zstd::encode_all(&*content, 0).map_err(Error::Compression)?;
+            if compressed.len() < content.len() {
+                (compressed, true)
+            } else {
+                (content, false)
+            }
+        } else {
+            (content, false)
+        };
+
+        Ok(Some(EncodedMedia {
+            uri,
+            format,
+            hash,
+            data: self.encode_value(content)?,
+            compressed,
+            content_type: content_type
+                .map(|content_type| self.encode_value(content_type.as_bytes().to_vec()))
+                .transpose()?,
+            format_columns,
+            last_access: None,
+            account: self.account_key(),
+        }))
+    }
+
+    /// Decode the given value read from the database, taking ownership of it.
+    ///
+    /// Contrary to [`Self::decode_value`], this doesn't copy a value of a store
+    /// without passphrase. In an encrypted store, the given value is dropped
+    /// once the envelope is deserialized, and the ciphertext is decrypted in
+    /// place, so the memory used to decode a value never goes much over twice
+    /// its size, while the envelope and the ciphertext both exist, and the
+    /// ciphertext and the plaintext are never both held.
+    fn decode_owned_value(&self, value: Vec<u8>) -> Result<Vec<u8>> {
+        let Some(key) = &self.store_cipher else {
+            return Ok(value);
+        };
+
+        let encrypted = self.decode_envelope(&value)?;
+        drop(value);
+
+        Ok(key.decrypt_value_data(encrypted)?)
+    }
+
+    /// Decode the given media content read from the database.
+    ///
+    /// See [`Self::decode_owned_value`] for the memory used to decode it. A
+    /// compressed content is also decompressed in a new buffer.
+    fn decode_media_content(&self, data: Vec<u8>, compressed: bool) -> Result<Vec<u8>> {
+        let content = self.decode_owned_value(data)?;
+
+        if compressed {
+            zstd::decode_all(&*content).map_err(Error::Compression)
+        } else {
+            Ok(content)
+        }
+    }
+
+    /// Compute the hash identifying the given media content in the
+    /// `media_blobs` table.
+    ///
+    /// In an encrypted store, the hash is keyed, to not allow to check whether
+    /// a known content is in the cache.
+    fn content_hash(&self, content: &[u8]) -> [u8; 32] {
+        if let Some(store_cipher) = &self.store_cipher {
+            store_cipher.hash_key(keys::MEDIA_BLOBS, content)
+        } else {
+            Sha256::digest(content).into()
+        }
+    }
+
+    /// Insert the given media in the database in a single transaction, and
+    /// apply the retention policy.
+    ///
+    /// Returns the number of media that were evicted.
+    async fn insert_media(&self, media: Vec<EncodedMedia>) -> Result<usize> {
+        let MediaRetentionPolicy { soft_limit, max_cache_size, .. } =
+            self.config.media_retention_policy;
+        let reserved = max_cache_size.map(|_| Arc::new(self.reserved_keys()));
+        let chunk_size = self.config.media_chunk_size;
+        let now = self.now();
+        let account = self.account_key();
+        // Shared between the attempts, to avoid copying the contents.
+        let media = Arc::new(media);
+
+        let (cache_size, evicted) = self
+            .retry_if_busy(|| {
+                let (media, account, reserved) = (media.clone(), account.clone(), reserved.clone());
+                async move {
+                    let conn = self.acquire_write().await?;
+                    Ok(conn
+                        .with_immediate_transaction::<_, rusqlite::Error, _>(move |txn| {
+                            insert_media_in_transaction(
+                                txn,
+                                &media,
+                                now,
+                                chunk_size,
+                                soft_limit,
+                                max_cache_size,
+                                account.as_ref(),
+                                reserved.as_deref(),
+                            )
+                        })
+                        .await?)
+                }
+            })
+            .await?;
+
+        self.metrics.adds.fetch_add(media.len() as u64, Ordering::Relaxed);
+        self.notify_evicted(&evicted);
+
+        if let (Some(soft_limit), Some(cache_size)) = (soft_limit, cache_size) {
+            self.check_soft_limit(soft_limit, cache_size);
+        }
+
+        Ok(evicted.len())
+    }
+
+    /// Add a media file's content in the media store, with its content type.
+    ///
+    /// The content type is encrypted like the content, if the store is
+    /// encrypted.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The `MediaRequest` of the file.
+    ///
+    /// * `content` - The content of the file.
+    ///
+    /// * `content_type` - The content type of the file, if it is known.
+    pub async fn add_media_content_with_content_type(
+        &self,
+        request: &MediaRequest,
+        content: Vec<u8>,
+        content_type: Option<&str>,
+    ) -> Result<()> {
+        if let Some(media) = self.encode_media(request, content, content_type)? {
+            self.insert_media(vec![media]).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Add a media file's content in the media store, and associate it with
+    /// the given room.
+    ///
+    /// This is like [`EventCacheStore::add_media_content`], except that the
+    /// media can then be removed with all the other media of the room with
+    /// [`SqliteEventCacheStore::remove_media_content_for_room`], for example
+    /// when the room is left. A media can be associated with several rooms by
+    /// adding it for each of them.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The ID of the room the media belongs to.
+    ///
+    /// * `request` - The `MediaRequest` of the file.
+    ///
+    /// * `content` - The content of the file.
+    pub async fn add_media_content_for_room(
+        &self,
+        room_id: &RoomId,
+        request: &MediaRequest,
+        content: Vec<u8>,
+    ) -> Result<()> {
+        let Some(media) = self.encode_media(request, content, None)? else {
+            return Ok(());
+        };
+        let (uri, format) = (media.uri.clone(), media.format.clone());
+        self.insert_media(vec![media]).await?;
+
+        let room_id = self.encode_key(keys::MEDIA_ROOMS, room_id);
+        self.retry_if_busy(|| {
+            let params = (room_id.clone(), uri.clone(), format.clone());
+            async move {
+                // The media may already have been evicted.
+                let conn = self.acquire_write().await?;
+                conn.execute(
+                    "INSERT OR IGNORE INTO media_rooms (room_id, uri, format) \
+                     SELECT ?1, uri, format FROM media WHERE uri = ?2 AND format = ?3",
+                    params,
+                )
+                .await?;
+
+                Ok(())
+            }
+        })
+        .await
+    }
+
+    /// Remove all the media associated with the given room with
+    /// [`SqliteEventCacheStore::add_media_content_for_room`].
+    ///
+    /// The media are removed even if they are also associated with other
+    /// rooms. The media that are not associated with any room are untouched.
+    ///
+    /// Returns the number of media that were removed.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The ID of the room.
+    pub async fn remove_media_content_for_room(&self, room_id: &RoomId) -> Result<u64> {
+        let room_id = self.encode_key(keys::MEDIA_ROOMS, room_id);
+        let sql = self.remove_media_sql(
+            "(uri, format) IN (SELECT uri, format FROM media_rooms WHERE room_id = ?)",
+        );
+
+        self.retry_if_busy(|| {
+            let (room_id, sql) = (room_id.clone(), sql.clone());
+            async move {
+                let conn = self.acquire_write().await?;
+                let removed = conn.execute(sql, (room_id,)).await?;
+
+                Ok(removed as u64)
+            }
+        })
+        .await
+    }
+
+    /// Get a media file's content out of the media store, with its content
+    /// type.
+    ///
+    /// The content type is `None` if it is unknown, like for the media that
+    /// were added with [`EventCacheStore::add_media_content`].
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The `MediaRequest` of the file.
+    pub async fn get_media_content_with_content_type(
+        &self,
+        request: &MediaRequest,
+    ) -> Result<Option<(Vec<u8>, Option<String>)>> {
+        self.get_media(request).await
+    }
+
+    /// Get a media file's content out of the media store, as a stream of
+    /// chunks.
+    ///
+    /// Contrary to [`EventCacheStore::get_media_content`], the content is
+    /// never loaded fully in memory, it is read from the database with
+    /// incremental I/O, one chunk at a time. A connection of the pool is only
+    /// held while a chunk is read, so the stream doesn't block the other
+    /// reads, nor the writes with [`LockingMode::Exclusive`]. If the media is
+    /// removed or replaced while it is streamed, reading the next chunk fails
+    /// with [`Error::MediaChangedWhileStreaming`].
+    ///
+    /// The content of the media of an encrypted store is encrypted as a
+    /// whole, so this returns an error with an encrypted store. Likewise, it
+    /// returns an error for a compressed content, see
+    /// [`SqliteEventCacheStoreConfig::compress_media`].
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The `MediaRequest` of the file.
+    ///
+    /// * `chunk_size` - The maximum size of the chunks of the stream, in bytes.
+    ///   The chunks are smaller at the end of the content, and at the end of
+    ///   every chunk of the database if the content was split with
+    ///   [`SqliteEventCacheStoreConfig::media_chunk_size`].
+    pub async fn get_media_content_stream(
+        &self,
+        request: &MediaRequest,
+        chunk_size: NonZeroUsize,
+    ) -> Result<Option<impl Stream<Item = Result<Vec<u8>>> + Send>> {
+        let chunk_size = chunk_size.get();
+
+        if self.store_cipher.is_some() {
+            return Err(Error::EncryptedMediaNotStreamable);
+        }
+
+        if !self.is_media_cache_enabled() {
+            return Ok(None);
+        }
+
+        let uri = self.encode_key(keys::MEDIA, request.source.unique_key());
+        let format = self.encode_key(keys::MEDIA, request.format.unique_key());
+
+        let (query_uri, query_format) = (uri.clone(), format.clone());
+        let segments = self
+            .acquire()
+            .await?
+            .with_transaction(move |txn| media_segments(txn, &query_uri, &query_format))
+            .await?;
+        let Some(MediaSegments { hash, segments, compressed }) = segments else {
+            return Ok(None);
+        };
+        if compressed {
+            return Err(Error::CompressedMediaNotStreamable);
+        }
+
+        self.update_last_access(uri, format).await?;
+
+        // The position of every chunk of the stream in the rows of the content.
+        let reads = segments
+            .into_iter()
+            .flat_map(|(table, rowid, len)| {
+                (0..len)
+                    .step_by(chunk_size)
+                    .map(move |offset| (table, rowid, len, offset, chunk_size.min(len - offset)))
+            })
+            .collect::<Vec<_>>();
+
+        let store = self.clone();
+        let chunks = stream::unfold(
+            (store, Arc::new(hash), reads.into_iter()),
+            |(store, hash, mut reads)| async move {
+                let (table, rowid, len, offset, chunk_len) = reads.next()?;
+                let chunk = store
+                    .read_content_row(hash.clone(), table, rowid, len, offset, chunk_len)
+                    .await;
+
+                Some((chunk, (store, hash, reads)))
+            },
+        );
+
+        Ok(Some(chunks))
+    }
+
+    /// Read a part of a row of the content with the given hash, in the given
+    /// table, with the given rowid and length.
+    ///
+    /// The row is checked to still contain the content in the same
+    /// transaction, because its rowid can be reused by another content once
+    /// the media is removed.
+    async fn read_content_row(
+        &self,
+        hash: Arc<Vec<u8>>,
+        table: &'static str,
+        rowid: i64,
+        len: usize,
+        offset: usize,
+        chunk_len: usize,
+    ) -> Result<Vec<u8>> {
+        let chunk = self
+            .acquire()
+            .await?
+            .with_transaction::<_, rusqlite::Error, _>(move |txn| {
+                let found = txn
+                    .prepare_cached(&format!(
+                        "SELECT hash, length(data) FROM {table} WHERE rowid = ?"
+                    ))?
+                    .query_row((rowid,), |row| {
+                        Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, usize>(1)?))
+                    })
+                    .optional()?;
+                if found.as_ref().map(|(found_hash, found_len)| (&found_hash[..], *found_len))
+                    != Some((&hash[..], len))
+                {
+                    return Ok(None);
+                }
+
+                let blob = txn.blob_open(DatabaseName::Main, table, "data", rowid, true)?;
+                let mut chunk = vec![0; chunk_len];
+                blob.read_at_exact(&mut chunk, offset)?;
+
+                Ok(Some(chunk))
+            })
+            .await?;
+
+        chunk.ok_or(Error::MediaChangedWhileStreaming)
+    }
+
+    /// Get a range of a media file's content out of the media store.
+    ///
+    /// Contrary to [`EventCacheStore::get_media_content`], only the requested
+    /// bytes are read from the database, with incremental I/O, so a player
+    /// can seek in a large media without loading it fully in memory.
+    ///
+    /// The content of the media of an encrypted store is encrypted as a
+    /// whole, and a compressed content can't be read partially, so in these
+    /// cases the whole content is read, and the range is extracted from it.
+    ///
+    /// Returns `None` if the media is not in the cache, and
+    /// [`Error::InvalidMediaRange`] if the range is not inside the content.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The `MediaRequest` of the file.
+    ///
+    /// * `offset` - The position of the first byte to read in the content.
+    ///
+    /// * `len` - The number of bytes to read.
+    pub async fn get_media_content_range(
+        &self,
+        request: &MediaRequest,
+        offset: usize,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>> {
+        let is_in_range = move |size: usize| offset.checked_add(len).is_some_and(|end| end <= size);
+
+        if self.store_cipher.is_none() && self.is_media_cache_enabled() {
+            let uri = self.encode_key(keys::MEDIA, request.source.unique_key());
+            let format = self.encode_key(keys::MEDIA, request.format.unique_key());
+
+            let conn = self.acquire().await?;
+            let (query_uri, query_format) = (uri.clone(), format.clone());
+            let range = conn
+                .with_transaction::<_, rusqlite::Error, _>(move |txn| {
+                    let Some(MediaSegments { segments, compressed, .. }) =
+                        media_segments(txn, &query_uri, &query_format)?
+                    else {
+                        return Ok(RangeRead::Missing);
+                    };
+                    if compressed {
+                        return Ok(RangeRead::Compressed);
+                    }
+
+                    let size = segments.iter().map(|(_, _, len)| len).sum();
+                    if !is_in_range(size) {
+                        return Ok(RangeRead::OutOfRange(size));
+                    }
+
+                    // Copy the part of every row that is in the range.
+                    let mut content = vec![0; len];
+                    let mut segment_start = 0;
+                    for (table, rowid, segment_len) in segments {
+                        let segment_end = segment_start + segment_len;
+                        let start = offset.max(segment_start);
+                        let end = (offset + len).min(segment_end);
+
+                        if start < end {
+                            let blob =
+                                txn.blob_open(DatabaseName::Main, table, "data", rowid, true)?;
+                            blob.read_at_exact(
+                                &mut content[start - offset..end - offset],
+                                start - segment_start,
+                            )?;
+                        }
+
+                        segment_start = segment_end;
+                    }
+
+                    Ok(RangeRead::Content(content))
+                })
+                .await?;
+            drop(conn);
+
+            match range {
+                RangeRead::Missing => return Ok(None),
+                RangeRead::Compressed => {}
+                RangeRead::OutOfRange(size) => {
+                    return Err(Error::InvalidMediaRange { offset, len, size });
+                }
+                RangeRead::Content(content) => {
+                    self.update_last_access(uri, format).await?;
+                    return Ok(Some(content));
+                }
+            }
+        }
+
+        let Some((mut content, _)) = self.get_media(request).await? else {
+            return Ok(None);
+        };
+        if !is_in_range(content.len()) {
+            return Err(Error::InvalidMediaRange { offset, len, size: content.len() });
+        }
+
+        content.truncate(offset + len);
+        content.drain(..offset);
+
+        Ok(Some(content))
+    }
+
+    /// Mark the given media as used now, without reading its content.
+    ///
+    /// This is useful to keep the media that are displayed from an in-memory
+    /// copy from being evicted as the least recently used ones. It does
+    /// nothing if the media is not in the cache.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The `MediaRequest` of the file.
+    pub async fn touch_media_content(&self, request: &MediaRequest) -> Result<()> {
+        let uri = self.encode_key(keys::MEDIA, request.source.unique_key());
+        let format = self.encode_key(keys::MEDIA, request.format.unique_key());
+
+        self.update_last_access(uri, format).await
+    }
+
+    /// Pin the given media, so it is never evicted from the cache.
+    ///
+    /// A pinned media is not removed by the [retention
+    /// policy](SqliteEventCacheStoreConfig::media_retention_policy), neither
+    /// when the cache is too large nor when the media expired, but it is
+    /// still removed explicitly, for example with
+    /// [`EventCacheStore::remove_media_content`] or
+    /// [`SqliteEventCacheStore::clear_all_media`]. It stays pinned if its
+    /// content is replaced. This is meant for media that must always be
+    /// available, like the avatar of the user.
+    ///
+    /// It does nothing if the media is not in the cache.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The `MediaRequest` of the file.
+    pub async fn pin_media(&self, request: &MediaRequest) -> Result<()> {
+        self.set_media_pinned(request, true).await
+    }
+
+    /// Unpin the given media, so it can be evicted from the cache again.
+    ///
+    /// See [`SqliteEventCacheStore::pin_media`].
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The `MediaRequest` of the file.
+    pub async fn unpin_media(&self, request: &MediaRequest) -> Result<()> {
+        self.set_media_pinned(request, false).await
+    }
+
+    /// Set whether the given media is pinned.
+    async fn set_media_pinned(&self, request: &MediaRequest, pinned: bool) -> Result<()> {
+        let uri = self.encode_key(keys::MEDIA, request.source.unique_key());
+        let format = self.encode_key(keys::MEDIA, request.format.unique_key());
+
+        self.retry_if_busy(|| {
+            let (uri, format) = (uri.clone(), format.clone());
+            async move {
+                self.acquire_write()
+                    .await?
+                    .execute(
+                        "UPDATE media SET pinned = ? WHERE uri = ? AND format = ?",
+                        (pinned, uri, format),
+                    )
+                    .await?;
+
+                Ok(())
+            }
+        })
+        .await
+    }
+
+    /// Set the last access of the media with the given keys to now.
+    async fn update_last_access(&self, uri: Key, format: Key) -> Result<()> {
+        // Reads are allowed in read-only mode, they are just not recorded.
+        if self.read_only {
+            return Ok(());
+        }
+
+        let now = self.now();
+
+        if let Some(max_pending) = self.config.deferred_access_times {
+            let accesses = {
+                let mut pending = self.pending_accesses.lock().unwrap();
+                pending.insert((uri, format), now);
+
+                if pending.len() < max_pending {
+                    return Ok(());
+                }
+
+                std::mem::take(&mut *pending)
+            };
+
+            return self.write_access_times(accesses).await;
+        }
+
+        self.retry_if_busy(|| {
+            let (uri, format) = (uri.clone(), format.clone());
+            async move {
+                self.acquire_write()
+                    .await?
+                    .execute(
+                        "UPDATE media SET last_access = ? WHERE uri = ? AND format = ?",
+                        (now, uri, format),
+                    )
+                    .await?;
+
+                Ok(())
+            }
+        })
+        .await
+    }
+
+    /// Write the last accesses of the media that were deferred with
+    /// [`SqliteEventCacheStoreConfig::defer_access_times`] to the database.
+    ///
+    /// This does nothing if there are no pending accesses.
+    pub async fn flush_access_times(&self) -> Result<()> {
+        let accesses = std::mem::take(&mut *self.pending_accesses.lock().unwrap());
+        self.write_access_times(accesses).await
+    }
+
+    /// Write the given last accesses of media in a single transaction.
+    ///
+    /// If it fails, the accesses are pending again, unless the media were
+    /// accessed again in the meantime.
+    async fn write_access_times(&self, accesses: BTreeMap<(Key, Key), i64>) -> Result<()> {
+        if accesses.is_empty() {
+            return Ok(());
+        }
+
+        // Shared between the attempts.
+        let shared_accesses = Arc::new(accesses);
+        let result = self
+            .retry_if_busy(|| {
+                let accesses = shared_accesses.clone();
+                async move {
+                    let conn = self.acquire_write().await?;
+                    conn.with_transaction::<_, rusqlite::Error, _>(move |txn| {
+                        let mut update = txn.prepare_cached(
+                            "UPDATE media SET last_access = ? WHERE uri = ? AND format = ?",
+                        )?;
+                        for ((uri, format), last_access) in accesses.iter() {
+                            update.execute((last_access, uri, format))?;
+                        }
+
+                        Ok(())
+                    })
+                    .await?;
+
+                    Ok(())
+                }
+            })
+            .await;
+
+        if result.is_err() {
+            let mut pending = self.pending_accesses.lock().unwrap();
+            for (key, last_access) in shared_accesses.iter() {
+                pending.entry(key.clone()).or_insert(*last_access);
+            }
+        }
+
+        result
+    }
+
+    /// Get the content and content type of a media from the database, and
+    /// update its last access.
+    async fn get_media(&self, request: &MediaRequest) -> Result<Option<(Vec<u8>, Option<String>)>> {
+        self.metrics.gets.fetch_add(1, Ordering::Relaxed);
+
+        let media = self.read_media(request).await?;
+        let counter = if media.is_some() { &self.metrics.hits } else { &self.metrics.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        Ok(media)
+    }
+
+    /// Get the content and content type of a media from the database, and
+    /// update its last access, without counting the request in the metrics.
+    async fn read_media(
+        &self,
+        request: &MediaRequest,
+    ) -> Result<Option<(Vec<u8>, Option<String>)>> {
+        if !self.is_media_cache_enabled() {
+            return Ok(None);
+        }
+
+        // Only time the read if it was requested.
+        let start = self.config.slow_read_threshold.map(|_| Instant::now());
+
+        let uri = self.encode_key(keys::MEDIA, request.source.unique_key());
+        let format = self.encode_key(keys::MEDIA, request.format.unique_key());
+        let key_hash = start.map(|_| key_hash_for_logs(&uri, &format));
+
+        let conn = self.acquire().await?;
+        let acquire_duration = start.map(|start| start.elapsed());
+
+        let (query_uri, query_format) = (uri.clone(), format.clone());
+        let data = conn
+            .with_transaction::<_, rusqlite::Error, _>(move |txn| {
+                let Some((hash, mut data, compressed, content_type)) = txn
+                    .query_row::<(Vec<u8>, Vec<u8>, bool, Option<Vec<u8>>), _, _>(
+                        "SELECT media_blobs.hash, media_blobs.data, media_blobs.compressed, \
+                         media.content_type \
+                         FROM media JOIN media_blobs ON media_blobs.hash = media.blob_hash \
+                         WHERE media.uri = ? AND media.format = ? AND media.deleted_at IS NULL",
+                        (query_uri, query_format),
+                        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+                    )
+                    .optional()?
+                else {
+                    return Ok(None);
+                };
+
+                append_blob_chunks(txn, &hash, &mut data)?;
+                Ok(Some((hash, data, compressed, content_type)))
+            })
+            .await?;
+        drop(conn);
+
+        if let (Some(threshold), Some(start)) = (self.config.slow_read_threshold, start) {
+            let duration = start.elapsed();
+
+            if duration > threshold {
+                self.metrics.slow_reads.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    key_hash = key_hash.as_deref(),
+                    size = data.as_ref().map_or(0, |(_, data, _, _)| data.len()),
+                    ?duration,
+                    ?acquire_duration,
+                    "Reading a media content from the cache was slow"
+                );
+            }
+        }
+
+        let span = Span::current();
+        if !span.is_disabled() {
+            span.record("key_hash", key_hash_for_logs(&uri, &format).as_str());
+            span.record("cache.hit", data.is_some());
+            if let Some((_, data, _, _)) = &data {
+                span.record("media.size", data.len());
+            }
+        }
+
+        let Some((hash, data, compressed, content_type)) = data else {
+            return Ok(None);
+        };
+
+        // Only the data is read from the read pool, updating the last access must
+        // wait for the writer.
+        self.update_last_access(uri, format).await?;
+
+        let decoded = self.decode_media_content(data, compressed);
+
+        // The legacy contents use placeholders instead of hashes.
+        if let Ok(content) = &decoded {
+            if self.config.verify_media_content
+                && hash.len() == 32
+                && self.content_hash(content)[..] != hash[..]
+            {
+                warn!("The media content doesn't match its hash, removing it");
+                self.remove_corrupted_media_content(hash).await?;
+                return Err(Error::CorruptedMedia);
+            }
+        }
+
+        let decoded = decoded.and_then(|content| {
+            let content_type = content_type
+                .map(|content_type| {
+                    self.decode_value(&content_type)
+                        .map(|content_type| String::from_utf8_lossy(&content_type).into_owned())
+                })
+                .transpose()?;
+            Ok((content, content_type))
+        });
+
+        match decoded {
+            Ok(media) => Ok(Some(media)),
+            Err(error) => match self.config.on_decode_failure {
+                DecodeFailurePolicy::Error => Err(error),
+                DecodeFailurePolicy::TreatAsMiss => {
+                    warn!("Failed to decode media content, treating it as a miss: {error}");
+                    Ok(None)
+                }
+                DecodeFailurePolicy::TreatAsMissAndDelete => {
+                    warn!("Failed to decode media content, removing it: {error}");
+                    self.remove_media_content(request).await?;
+                    Ok(None)
+                }
+            },
+        }
+    }
+
+    /// Remove the media content with the given hash, and all the media using
+    /// it, ignoring the soft-delete mode so it can't be shared again.
+    async fn remove_corrupted_media_content(&self, hash: Vec<u8>) -> Result<()> {
+        self.retry_if_busy(|| {
+            let hash = hash.clone();
+            async move {
+                let conn = self.acquire_write().await?;
+                conn.execute("DELETE FROM media WHERE blob_hash = ?", (hash,)).await?;
+                Ok(())
+            }
+        })
+        .await
+    }
+
+    /// Remove all the data of the given account namespace from the database,
+    /// atomically.
+    ///
+    /// This removes the media, gaps and event chunks stored by the stores
+    /// opened with [`SqliteEventCacheStoreConfig::account_namespace`] set to
+    /// the given namespace, like [`EventCacheStore::clear_session_data`] does
+    /// for the namespace of this store. It is meant to be called when the
+    /// account is logged out, and the data of the other namespaces is left
+    /// untouched. The removal is immediate, even in soft-delete mode.
+    ///
+    /// Returns the number of media that were removed.
+    pub async fn clear_account_namespace(&self, namespace: &str) -> Result<u64> {
+        let account = self.namespace_key(namespace);
+
+        let count = self
+            .retry_if_busy(|| {
+                let account = account.clone();
+                async move {
+                    let conn = self.acquire_write().await?;
+                    Ok(conn
+                        .with_transaction::<_, rusqlite::Error, _>(move |txn| {
+                            let count =
+                                txn.execute("DELETE FROM media WHERE account = ?", (&account,))?;
+                            txn.execute("DELETE FROM gaps WHERE account = ?", (&account,))?;
+                            txn.execute("DELETE FROM event_chunks WHERE account = ?", (&account,))?;
+
+                            Ok(count as u64)
+                        })
+                        .await?)
+                }
+            })
+            .await?;
+
+        debug!(removed = count, "Cleared the data of an account namespace");
+        self.maybe_incremental_vacuum().await?;
+
+        Ok(count)
+    }
+
+    /// Remove all the media contents of the account namespace of this store
+    /// from the cache.
+    ///
+    /// Like [`SqliteEventCacheStore::clear_account_namespace`], the removal is
+    /// immediate, even in soft-delete mode, including the media that were
+    /// already marked as deleted.
+    ///
+    /// The write-ahead log is truncated afterwards, so the space used by the
+    /// media is given back to the filesystem without waiting for the next
+    /// checkpoint.
+    pub async fn clear_all_media(&self) -> Result<()> {
+        let account = self.account_key();
+
+        self.retry_if_busy(|| {
+            let account = account.clone();
+            async move {
+                let conn = self.acquire_write().await?;
+                // The contents that are not used anymore are removed by a trigger.
+                conn.execute("DELETE FROM media WHERE account IS ?", (account,)).await?;
+
+                Ok(())
+            }
+        })
+        .await?;
+        self.checkpoint().await?;
+
+        self.above_soft_limit.store(false, Ordering::SeqCst);
+        self.maybe_incremental_vacuum().await?;
+
+        Ok(())
+    }
+
+    /// Get the sizes and methods of the thumbnails of the given `MxcUri` that
+    /// are in the cache.
+    ///
+    /// The settings of the thumbnails are stored in dedicated columns, because
+    /// they can't be recovered from the format key of an encrypted store. That
+    /// means that the thumbnails that were stored before they were added are
+    /// not listed. Thumbnails that only differ by whether they are animated
+    /// are only listed once.
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - The `MxcUri` of the media.
+    pub async fn cached_thumbnail_sizes(&self, uri: &MxcUri) -> Result<Vec<(u16, u16, Method)>> {
+        let uri = self.encode_key(keys::MEDIA, uri);
+
+        let conn = self.acquire().await?;
+        let sizes = conn
+            .prepare(
+                "SELECT DISTINCT thumbnail_width, thumbnail_height, thumbnail_method FROM media \
+                 WHERE uri = ? AND thumbnail_method IS NOT NULL AND deleted_at IS NULL",
+                move |mut stmt| {
+                    stmt.query((uri,))?
+                        .mapped(|row| {
+                            Ok((
+                                row.get::<_, u64>(0)?,
+                                row.get::<_, u64>(1)?,
+                                row.get::<_, String>(2)?,
+                            ))
+                        })
+                        .collect::<rusqlite::Result<Vec<_>>>()
+                },
+            )
+            .await?;
+
+        Ok(sizes
+            .into_iter()
+            .filter_map(|(width, height, method)| {
+                Some((width.try_into().ok()?, height.try_into().ok()?, Method::from(method)))
+            })
+            .collect())
+    }
+
+    /// Get the contents of all the formats of a media that are in the cache,
+    /// and update their last access.
+    ///
+    /// The formats are reconstructed from the dedicated columns, because they
+    /// can't be recovered from the format key of an encrypted store. That
+    /// means that the media that were stored before they were added are not
+    /// returned, except for the files of an unencrypted store.
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - The `MxcUri` of the media.
+    pub async fn get_media_content_for_uri(
+        &self,
+        uri: &MxcUri,
+    ) -> Result<Vec<(MediaFormat, Vec<u8>)>> {
+        if !self.is_media_cache_enabled() {
+            return Ok(Vec::new());
+        }
+
+        let uri = self.encode_key(keys::MEDIA, uri);
+
+        let conn = self.acquire().await?;
+        let query_uri = uri.clone();
+        let rows = conn
+            .with_transaction::<_, rusqlite::Error, _>(move |txn| {
+                let mut statement = txn.prepare_cached(
+                    "SELECT media.format_kind, media.thumbnail_width, media.thumbnail_height, \
+                     media.thumbnail_method, media_blobs.hash, media_blobs.data, \
+                     media_blobs.compressed, media.format \
+                     FROM media JOIN media_blobs ON media_blobs.hash = media.blob_hash \
+                     WHERE media.uri = ? AND media.format_kind IS NOT NULL \
+                     AND media.deleted_at IS NULL",
+                )?;
+                let mut rows = statement.query((query_uri,))?;
+                let mut media = Vec::new();
+
+                while let Some(row) = rows.next()? {
+                    let hash = row.get::<_, Vec<u8>>(4)?;
+                    let mut data = row.get::<_, Vec<u8>>(5)?;
+                    append_blob_chunks(txn, &hash, &mut data)?;
+
+                    let format_columns = FormatColumns {
+                        kind: row.get(0)?,
+                        thumbnail_width: row.get(1)?,
+                        thumbnail_height: row.get(2)?,
+                        thumbnail_method: row.get(3)?,
+                    };
+                    media.push((
+                        format_columns,
+                        data,
+                        row.get::<_, bool>(6)?,
+                        row.get::<_, Vec<u8>>(7)?,
+                    ));
+                }
+
+                Ok(media)
+            })
+            .await?;
+        drop(conn);
+
+        let mut media = Vec::with_capacity(rows.len());
+        let mut format_keys = Vec::with_capacity(rows.len());
+        for (format_columns, data, compressed, format_key) in rows {
+            let Some(format) = format_columns.to_format() else {
+                continue;
+            };
+            media.push((format, self.decode_media_content(data, compressed)?));
+            format_keys.push(format_key);
+        }
+
+        for format_key in format_keys {
+            self.update_last_access(uri.clone(), Key::Plain(format_key)).await?;
+        }
+
+        Ok(media)
+    }
+
+    /// Get the content of a media, or of the closest thumbnail of the same
+    /// media that can be downscaled to the requested one, and update its last
+    /// access.
+    ///
+    /// If the requested format is cached, its content is returned, like with
+    /// [`EventCacheStore::get_media_content`]. Otherwise, if a thumbnail is
+    /// requested, a cached thumbnail of the same media is returned if:
+    ///
+    /// * it uses the same resizing method, because a cropped thumbnail can't be
+    ///   scaled and vice versa,
+    /// * it is animated only if the requested thumbnail is animated,
+    /// * its width and its height are both greater than or equal to the
+    ///   requested ones.
+    ///
+    /// The smallest matching thumbnail is returned, with its format, so the
+    /// client can downscale it. The thumbnails that were stored before their
+    /// settings were saved in the database are never matched.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The `MediaRequest` of the media.
+    pub async fn get_media_content_best_match(
+        &self,
+        request: &MediaRequest,
+    ) -> Result<Option<(MediaFormat, Vec<u8>)>> {
+        if let Some((content, _)) = self.get_media(request).await? {
+            return Ok(Some((request.format.clone(), content)));
+        }
+
+        let MediaFormat::Thumbnail(settings) = &request.format else {
+            return Ok(None);
+        };
+        if !self.is_media_cache_enabled() {
+            return Ok(None);
+        }
+        let FormatColumns { kind, thumbnail_width, thumbnail_height, thumbnail_method } =
+            FormatColumns::new(&request.format);
+
+        let uri = self.encode_key(keys::MEDIA, request.source.unique_key());
+
+        let conn = self.acquire().await?;
+        let query_uri = uri.clone();
+        let best_match = conn
+            .with_transaction::<_, rusqlite::Error, _>(move |txn| {
+                let Some((format, width, height, hash, mut data, compressed)) = txn
+                    .query_row::<(Vec<u8>, u64, u64, Vec<u8>, Vec<u8>, bool), _, _>(
+                        "SELECT media.format, media.thumbnail_width, media.thumbnail_height, \
+                         media_blobs.hash, media_blobs.data, media_blobs.compressed \
+                         FROM media JOIN media_blobs ON media_blobs.hash = media.blob_hash \
+                         WHERE media.uri = ? AND media.format_kind = ? \
+                         AND media.deleted_at IS NULL AND media.thumbnail_method = ? \
+                         AND media.thumbnail_width >= ? AND media.thumbnail_height >= ? \
+                         ORDER BY media.thumbnail_width * media.thumbnail_height \
+                         LIMIT 1",
+                        (query_uri, kind, thumbnail_method, thumbnail_width, thumbnail_height),
+                        |row| {
+                            Ok((
+                                row.get(0)?,
+                                row.get(1)?,
+                                row.get(2)?,
+                                row.get(3)?,
+                                row.get(4)?,
+                                row.get(5)?,
+                            ))
+                        },
+                    )
+                    .optional()?
+                else {
+                    return Ok(None);
+                };
+
+                append_blob_chunks(txn, &hash, &mut data)?;
+                Ok(Some((format, width, height, data, compressed)))
+            })
+            .await?;
+        drop(conn);
+
+        let Some((format, width, height, data, compressed)) = best_match else {
+            return Ok(None);
+        };
+
+        self.update_last_access(uri, Key::Plain(format)).await?;
+
+        let content = self.decode_media_content(data, compressed)?;
+        let mut matched_settings = settings.clone();
+        // The dimensions were compared to the requested ones, so they are valid.
+        matched_settings.size.width = UInt::new_saturating(width);
+        matched_settings.size.height = UInt::new_saturating(height);
+
+        Ok(Some((MediaFormat::Thumbnail(matched_settings), content)))
+    }
+
+    /// Load the pages of the given media into the caches, without reading
+    /// their content.
+    ///
+    /// This can be called before the media are actually needed, for example
+    /// before the user scrolls to them in a timeline, so the actual reads are
+    /// faster.
+    ///
+    /// SQLite's page cache is per connection, so this mostly benefits from the
+    /// cache of the operating system, and from the page cache when the same
+    /// connection of the pool is reused.
+    ///
+    /// This doesn't update the last access time of the media, and media that
+    /// are not in the cache are ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `requests` - The `MediaRequest`s of the files to prefetch.
+    pub async fn prefetch(&self, requests: &[MediaRequest]) -> Result<()> {
+        let keys = requests
+            .iter()
+            .map(|request| {
+                (
+                    self.encode_key(keys::MEDIA, request.source.unique_key()),
+                    self.encode_key(keys::MEDIA, request.format.unique_key()),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let conn = self.acquire().await?;
+        conn.with_transaction::<_, rusqlite::Error, _>(move |txn| {
+            // Reading the last byte goes through all the overflow pages of the data,
+            // without returning the whole content.
+            let mut statement = txn.prepare_cached(
+                "SELECT substr(media_blobs.data, -1) FROM media \
+                 JOIN media_blobs ON media_blobs.hash = media.blob_hash \
+                 WHERE media.uri = ? AND media.format = ? AND media.deleted_at IS NULL",
+            )?;
+
+            let mut chunks_statement = txn.prepare_cached(
+                "SELECT substr(media_blob_chunks.data, -1) FROM media \
+                 JOIN media_blob_chunks ON media_blob_chunks.hash = media.blob_hash \
+                 WHERE media.uri = ? AND media.format = ? AND media.deleted_at IS NULL",
+            )?;
+
+            for (uri, format) in keys {
+                statement.query_row((&uri, &format), |_| Ok(())).optional()?;
+                for chunk in chunks_statement.query_map((uri, format), |_| Ok(()))? {
+                    chunk?;
+                }
+            }
+
+            Ok(())
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    /// Remove the data that is corrupted or inconsistent, to keep the rest of
+    /// the cache usable.
+    ///
+    /// This is meant to be called from a maintenance task, after partial
+    /// failures, like an interrupted import, or when reads fail to decode the
+    /// data. It removes:
+    ///
+    /// * the media, linked chunks and back-pagination tokens that fail to be
+    ///   decoded or decrypted, which are logged,
+    /// * the media with an empty content, if `remove_empty_media` is set,
+    /// * the rows that are not linked to the rest of the data anymore.
+    ///
+    /// Every media is read and decoded, so it can take a while for a large
+    /// cache. If anything was removed, the database is then
+    /// [vacuumed](Self::vacuum) to reclaim the space.
+    pub async fn repair(&self, remove_empty_media: bool) -> Result<RepairReport> {
+        // Decode every media, one page at a time.
+        let mut undecodable_media = Vec::new();
+        let mut after_rowid = 0;
+        loop {
+            let page = self.read_media_page(after_rowid, MEDIA_PAGE_SIZE, true).await?;
+            let Some(last) = page.last() else {
+                break;
+            };
+            after_rowid = last.rowid;
+
+            for row in page {
+                let decoded = self.decode_media_content(row.data, row.compressed).and_then(|_| {
+                    row.content_type
+                        .map(|content_type| self.decode_value(&content_type).map(drop))
+                        .transpose()
+                });
+                if decoded.is_err() {
+                    undecodable_media.push(row.rowid);
+                }
+            }
+        }
+
+        let conn = self.acquire().await?;
+        let (event_chunks, gaps) = conn
+            .with_transaction::<_, rusqlite::Error, _>(|txn| {
+                let event_chunks = txn
+                    .prepare("SELECT rowid, content FROM event_chunks")?
+                    .query_map((), |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?)))?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                let gaps = txn
+                    .prepare("SELECT rowid, prev_batch_token FROM gaps")?
+                    .query_map((), |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?)))?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+
+                Ok((event_chunks, gaps))
+            })
+            .await?;
+        drop(conn);
+
+        let undecodable_event_chunks = event_chunks
+            .into_iter()
+            .filter(|(_, content)| {
+                self.decode_value(content)
+                    .ok()
+                    .and_then(|content| serde_json::from_slice::<EventChunkContent>(&content).ok())
+                    .is_none()
+            })
+            .map(|(rowid, _)| rowid)
+            .collect::<Vec<_>>();
+        let undecodable_gaps = gaps
+            .into_iter()
+            .filter(|(_, token)| self.decode_value(token).is_err())
+            .map(|(rowid, _)| rowid)
+            .collect::<Vec<_>>();
+
+        let conn = self.acquire_write().await?;
+        let report = conn
+            .with_transaction::<_, rusqlite::Error, _>(move |txn| {
+                let delete_rows = |table: &str, rowids: &[i64]| -> rusqlite::Result<u64> {
+                    let mut delete =
+                        txn.prepare(&format!("DELETE FROM {table} WHERE rowid = ?"))?;
+                    let mut count = 0;
+                    for rowid in rowids {
+                        count += delete.execute((rowid,))? as u64;
+                    }
+                    Ok(count)
+                };
+
+                let mut removed = RepairReport {
+                    undecodable_media: delete_rows("media", &undecodable_media)?,
+                    undecodable_event_chunks: delete_rows(
+                        "event_chunks",
+                        &undecodable_event_chunks,
+                    )?,
+                    undecodable_gaps: delete_rows("gaps", &undecodable_gaps)?,
+                    ..Default::default()
+                };
+
+                if remove_empty_media {
+                    removed.empty_media = txn.execute(
+                        "DELETE FROM media WHERE blob_hash IN (SELECT hash FROM media_blobs \
+                         WHERE length(data) = 0 AND NOT EXISTS \
+                            (SELECT 1 FROM media_blob_chunks \
+                             WHERE media_blob_chunks.hash = media_blobs.hash))",
+                        (),
+                    )? as u64;
+                }
+
+                // The triggers already removed what was linked to the media removed above,
+                // so this only counts the rows that were already orphaned.
+                for statement in [
+                    "DELETE FROM media WHERE NOT EXISTS \
+                     (SELECT 1 FROM media_blobs WHERE media_blobs.hash = media.blob_hash)",
+                    "DELETE FROM media_blobs WHERE NOT EXISTS \
+                     (SELECT 1 FROM media WHERE media.blob_hash = media_blobs.hash)",
+                    "DELETE FROM media_blob_chunks WHERE NOT EXISTS \
+                     (SELECT 1 FROM media_blobs WHERE media_blobs.hash = media_blob_chunks.hash)",
+                    "DELETE FROM media_keys WHERE NOT EXISTS \
+                     (SELECT 1 FROM media \
+                      WHERE media.uri = media_keys.uri AND media.format = media_keys.format)",
+                    "DELETE FROM media_rooms WHERE NOT EXISTS \
+                     (SELECT 1 FROM media \
+                      WHERE media.uri = media_rooms.uri AND media.format = media_rooms.format)",
+                ] {
+                    removed.orphaned_rows += txn.execute(statement, ())? as u64;
+                }
+
+                Ok(removed)
+            })
+            .await?;
+        drop(conn);
+
+        if report.undecodable_media > 0
+            || report.undecodable_event_chunks > 0
+            || report.undecodable_gaps > 0
+        {
+            warn!(
+                media = report.undecodable_media,
+                event_chunks = report.undecodable_event_chunks,
+                gaps = report.undecodable_gaps,
+                "Removed data that failed to be decoded from the event cache store"
+            );
+        }
+
+        if !report.is_empty() {
+            debug!(?report, "Repaired the event cache store");
+            self.vacuum().await?;
+        }
+
+        Ok(report)
+    }
+
+    /// Rewrite all the values of the store with the codec of
+    /// [`SqliteEventCacheStoreConfig::envelope_codec`].
+    ///
+    /// This migrates a store opened with
+    /// [`SqliteEventCacheStoreConfig::previous_envelope_codec`]: every value
+    /// is decoded and encoded again, in batches, and the new codec is
+    /// persisted once they are all rewritten. Only the envelopes of the values
+    /// change, not their plain content or the keys. The progress is persisted
+    /// with every batch, so if this is interrupted, calling it again resumes
+    /// where it stopped, and calling it when the store already uses the new
+    /// codec does nothing. It fails if a value can't be decoded, the store can
+    /// be repaired with [`SqliteEventCacheStore::repair`] first in this
+    /// case.
+    ///
+    /// This has no effect on a store without a passphrase, whose values are
+    /// not encoded.
+    ///
+    /// Returns the number of values that were rewritten.
+    pub async fn reencode_all(&self) -> Result<u64> {
+        if self.store_cipher.is_none() {
+            return Ok(0);
+        }
+
+        let tag = self.config.codec().tag();
+        let conn = self.acquire().await?;
+        if conn.get_kv(keys::ENVELOPE_CODEC).await?.as_deref() == Some(&[tag][..]) {
+            return Ok(0);
+        }
+        let (mut step, mut after_rowid) = conn
+            .get_kv(keys::REENCODE_PROGRESS)
+            .await?
+            .as_deref()
+            .and_then(decode_reencode_progress)
+            .unwrap_or_default();
+        drop(conn);
+
+        let chunk_size = self.config.media_chunk_size;
+        let mut count = 0;
+
+        while let Some(&column) = ENCODED_COLUMNS.get(step) {
+            let conn = self.acquire().await?;
+            let page = conn
+                .with_transaction::<_, rusqlite::Error, _>(move |txn| {
+                    read_encoded_values(txn, column, after_rowid, MEDIA_PAGE_SIZE)
+                })
+                .await?;
+            drop(conn);
+
+            let Some(&(last_rowid, _)) = page.last() else {
+                step += 1;
+                after_rowid = 0;
+                continue;
+            };
+
+            let values = page
+                .into_iter()
+                .map(|(rowid, old)| {
+                    let new = self.encode_value(self.decode_value(&old)?.into_owned())?;
+                    Ok((rowid, old, new))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            // Shared between the attempts, to avoid copying the values.
+            let values = Arc::new(values);
+            let progress = encode_reencode_progress(step, last_rowid);
+
+            count += self
+                .retry_if_busy(|| {
+                    let (values, progress) = (values.clone(), progress.clone());
+                    async move {
+                        let conn = self.acquire_write().await?;
+                        Ok(conn
+                            .with_transaction::<_, rusqlite::Error, _>(move |txn| {
+                                let count =
+                                    write_reencoded_values(txn, column, &values, chunk_size)?;
+                                txn.set_kv(keys::REENCODE_PROGRESS, &progress)?;
+                                Ok(count)
+                            })
+                            .await?)
+                    }
+                })
+                .await?;
+            after_rowid = last_rowid;
+        }
+
+        self.retry_if_busy(|| async move {
+            let conn = self.acquire_write().await?;
+            conn.with_transaction::<_, rusqlite::Error, _>(move |txn| {
+                txn.set_kv(keys::ENVELOPE_CODEC, &[tag])?;
+                txn.execute("DELETE FROM kv WHERE key = ?", (keys::REENCODE_PROGRESS,))?;
+                Ok(())
+            })
+            .await?;
+
+            Ok(())
+        })
+        .await?;
+
+        debug!(count, codec = tag, "Re-encoded the values of the event cache store");
+
+        Ok(count)
+    }
+
+    /// Compact the database in place, to give the space freed by removed
+    /// media back to the filesystem.
+    ///
+    /// This uses SQLite's `VACUUM`, which rewrites the whole database, so it
+    /// can take a while for a large cache, and it prevents any other
+    /// connection from writing to the database in the meantime. It is meant to
+    /// be called from a background maintenance task, after large removals like
+    /// [`SqliteEventCacheStore::clear_all_media`].
+    ///
+    /// The write-ahead log is truncated afterwards, so the changes are written
+    /// to the database file right away.
+    pub async fn vacuum(&self) -> Result<()> {
+        // `VACUUM` can't run inside a transaction, so it is not wrapped in one.
+        self.retry_if_busy(|| async move {
+            let conn = self.acquire_write().await?;
+            conn.execute_batch("VACUUM;").await?;
+
+            Ok(())
+        })
+        .await?;
+
+        self.checkpoint().await
+    }
+
+    /// Release the free pages of the database if there are more than the
+    /// threshold of [`SqliteEventCacheStoreConfig::incremental_vacuum`].
+    async fn maybe_incremental_vacuum(&self) -> Result<()> {
+        let Some(threshold) = self.config.incremental_vacuum else {
+            return Ok(());
+        };
+
+        self.retry_if_busy(|| async move {
+            let conn = self.acquire_write().await?;
+            let free_pages =
+                conn.query_row("PRAGMA freelist_count", (), |row| row.get::<_, u64>(0)).await?;
+            if free_pages <= u64::from(threshold) {
+                return Ok(());
+            }
+
+            debug!(free_pages, "Releasing the free pages of the event cache store");
+            // Every step of the statement releases one page, so they must all be consumed.
+            conn.prepare("PRAGMA incremental_vacuum", |mut stmt| {
+                let mut rows = stmt.query(())?;
+                while rows.next()?.is_some() {}
+                Ok(())
+            })
+            .await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Write the content of the write-ahead log back to the database file, and
+    /// truncate the log.
+    ///
+    /// The `-wal` file next to the database grows with the writes until its
+    /// content is written to the database, which SQLite does automatically
+    /// according to [`SqliteEventCacheStoreConfig::wal_autocheckpoint`], but
+    /// the file is never shrunk. This is useful to call after bulk writes, like
+    /// [`SqliteEventCacheStore::import_from_dir`], or large removals, like
+    /// [`EventCacheStore::clear_session_data`], to give the space back to the
+    /// filesystem.
+    pub async fn checkpoint(&self) -> Result<()> {
+        self.retry_if_busy(|| async move {
+            let conn = self.acquire_write().await?;
+            conn.execute_batch("PRAGMA wal_checkpoint(TRUNCATE);").await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Close the store cleanly.
+    ///
+    /// The last accesses deferred with
+    /// [`SqliteEventCacheStoreConfig::defer_access_times`] are written, the
+    /// write-ahead log is [checkpointed](Self::checkpoint) so it doesn't take
+    /// space until the store is opened again, and the connections to the
+    /// database are closed.
+    ///
+    /// The store can't be used anymore afterwards, and neither can its clones,
+    /// since they share the same connections: their operations fail. The
+    /// connections that are in use by a clone are closed when they are
+    /// released.
+    pub async fn close(self) -> Result<()> {
+        self.flush_access_times().await?;
+        self.checkpoint().await?;
+
+        self.pool.close();
+        self.write_pool.close();
+
+        Ok(())
+    }
+
+    /// Write a compacted copy of the database to the given path.
+    ///
+    /// This uses SQLite's `VACUUM INTO`, so contrary to an in-place `VACUUM`,
+    /// the live database is not locked for writing and its size is unchanged.
+    /// The output is the smallest possible database with the same content,
+    /// which makes it suitable for backups or transfers.
+    ///
+    /// The destination must not exist or be an empty file.
+    ///
+    /// # Arguments
+    ///
+    /// * `dest` - The path of the file to write the copy to.
+    pub async fn vacuum_into(&self, dest: &Path) -> Result<()> {
+        let dest =
+            dest.to_str().ok_or_else(|| rusqlite::Error::InvalidPath(dest.to_owned()))?.to_owned();
+
+        let conn = self.acquire().await?;
+        conn.execute("VACUUM INTO ?", (dest,)).await?;
+
+        Ok(())
+    }
+
+    /// Get a stream of all the media of the account namespace of this store in
+    /// the cache, with their content.
+    ///
+    /// The media are read from the database in pages, so only a few media
+    /// contents are in memory at once, and the next page is only read when
+    /// the stream is polled. The media added while the stream is consumed may
+    /// or may not be yielded.
+    pub fn stream_all_media(
+        &self,
+    ) -> impl Stream<Item = Result<(MediaCacheEntry, Vec<u8>)>> + Send + '_ {
+        let pages = stream::try_unfold(Some(0), move |last_rowid| async move {
+            let Some(last_rowid) = last_rowid else {
+                return Ok(None);
+            };
+
+            let page = self.read_media_page(last_rowid, MEDIA_PAGE_SIZE, false).await?;
+            let next_rowid = match page.last() {
+                Some(last) if page.len() == MEDIA_PAGE_SIZE => Some(last.rowid),
+                _ => None,
+            };
+
+            let media = page
+                .into_iter()
+                .map(|row| {
+                    let size = row.data.len() as u64;
+                    let content = self.decode_media_content(row.data, row.compressed)?;
+                    let entry = MediaCacheEntry {
+                        uri_key: row.uri,
+                        format_key: row.format,
+                        size,
+                        last_access: SystemTime::UNIX_EPOCH
+                            + Duration::from_millis(row.last_access.try_into().unwrap_or_default()),
+                    };
+                    Ok((entry, content))
+                })
+                .collect::<Vec<Result<_>>>();
+
+            Ok::<_, Error>(Some((stream::iter(media), next_rowid)))
+        });
+
+        pages.try_flatten()
+    }
+
+    /// Read the media with a rowid greater than the given one from the
+    /// database, with all their columns, ordered by rowid.
+    ///
+    /// Only the media of the account namespace of this store are read, unless
+    /// `all_accounts` is set.
+    async fn read_media_page(
+        &self,
+        after_rowid: i64,
+        limit: usize,
+        all_accounts: bool,
+    ) -> Result<Vec<StoredMediaRow>> {
+        let account = self.account_key();
+        let conn = self.acquire().await?;
+        let page = conn
+            .with_transaction::<_, rusqlite::Error, _>(move |txn| {
+                let mut statement = txn.prepare_cached(
+                    "SELECT media.rowid, media.uri, media.format, media.last_access, \
+                     media.content_type, media.format_kind, media.thumbnail_width, \
+                     media.thumbnail_height, media.thumbnail_method, media_blobs.hash, \
+                     media_blobs.data, media_blobs.compressed \
+                     FROM media JOIN media_blobs ON media_blobs.hash = media.blob_hash \
+                     WHERE media.rowid > ? AND media.deleted_at IS NULL \
+                        AND (? OR media.account IS ?) \
+                     ORDER BY media.rowid LIMIT ?",
+                )?;
+                let mut rows = statement.query((after_rowid, all_accounts, account, limit))?;
+                let mut page = Vec::new();
+
+                while let Some(row) = rows.next()? {
+                    let hash = row.get::<_, Vec<u8>>(9)?;
+                    let mut data = row.get::<_, Vec<u8>>(10)?;
+                    append_blob_chunks(txn, &hash, &mut data)?;
+
+                    page.push(StoredMediaRow {
+                        rowid: row.get(0)?,
+                        uri: row.get(1)?,
+                        format: row.get(2)?,
+                        last_access: row.get(3)?,
+                        content_type: row.get(4)?,
+                        format_columns: FormatColumns {
+                            kind: row.get(5)?,
+                            thumbnail_width: row.get(6)?,
+                            thumbnail_height: row.get(7)?,
+                            thumbnail_method: row.get(8)?,
+                        },
+                        data,
+                        compressed: row.get(11)?,
+                    });
+                }
+
+                Ok(page)
+            })
+            .await?;
+
+        Ok(page)
+    }
+
+    /// Export the media of the account namespace of this store to the given
+    /// directory.
+    ///
+    /// Every media content is written to its own file, named after a hash of
+    /// the keys of the media, next to a `manifest.json` file with the
+    /// metadata of the media. The directory is created if it doesn't exist,
+    /// and existing files with the same names are overwritten. The export can
+    /// be imported with [`SqliteEventCacheStore::import_from_dir`].
+    ///
+    /// The media contents are written decrypted, so the export must be
+    /// treated as sensitive as the media themselves, even if the store is
+    /// encrypted. The keys of the media are written as they are stored,
+    /// hashed in an encrypted store, so the export of an encrypted store can
+    /// only be imported in a store with the same store cipher.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the directory to write the export to.
+    pub async fn export_to_dir(&self, path: &Path) -> Result<()> {
+        fs::create_dir_all(path).await.map_err(Error::Export)?;
+
+        let mut manifest = ExportManifest {
+            account_namespace: self.config.account_namespace.clone(),
+            ..Default::default()
+        };
+        let mut last_rowid = 0;
+
+        loop {
+            let page = self.read_media_page(last_rowid, MEDIA_PAGE_SIZE, false).await?;
+
+            let Some(last) = page.last() else {
+                break;
+            };
+            last_rowid = last.rowid;
+
+            for row in page {
+                let content = self.decode_media_content(row.data, row.compressed)?;
+                let content_type = row
+                    .content_type
+                    .map(|content_type| {
+                        self.decode_value(&content_type)
+                            .map(|content_type| String::from_utf8_lossy(&content_type).into_owned())
+                    })
+                    .transpose()?;
+
+                let file_name = export_file_name(&row.uri, &row.format);
+                fs::write(path.join(&file_name), &content).await.map_err(Error::Export)?;
+
+                let FormatColumns { kind, thumbnail_width, thumbnail_height, thumbnail_method } =
+                    row.format_columns;
+                manifest.media.insert(
+                    file_name,
+                    ExportedMedia {
+                        uri_key: Base64::new(row.uri),
+                        format_key: Base64::new(row.format),
+                        last_access_ms: Some(row.last_access),
+                        last_access: None,
+                        size: content.len() as u64,
+                        content_type,
+                        format_kind: kind,
+                        thumbnail_width,
+                        thumbnail_height,
+                        thumbnail_method,
+                    },
+                );
+            }
+        }
+
+        // The manifest is written last, so an interrupted export is not valid.
+        fs::write(path.join(EXPORT_MANIFEST), serde_json::to_vec_pretty(&manifest)?)
+            .await
+            .map_err(Error::Export)?;
+
+        Ok(())
+    }
+
+    /// Import a media cache exported with
+    /// [`SqliteEventCacheStore::export_to_dir`] from the given directory.
+    ///
+    /// The media keep the last access they had in the exported cache. They
+    /// replace the media with the same keys that are already in the cache,
+    /// and the media that are larger than
+    /// [`MediaRetentionPolicy::max_file_size`] are skipped. The media are
+    /// added in batches, so if the import fails, the media of the previous
+    /// batches stay in the cache.
+    ///
+    /// The account namespace is mixed into the keys of the media, so the
+    /// export can only be imported in a store with the same
+    /// [`SqliteEventCacheStoreConfig::account_namespace`]. The files of the
+    /// media must have the names given to them by the export, any other name
+    /// in the manifest is rejected before reading anything.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the directory containing the export.
+    pub async fn import_from_dir(&self, path: &Path) -> Result<()> {
+        /// The number of media added to the database at once.
+        const BATCH_SIZE: usize = 64;
+
+        let manifest = fs::read(path.join(EXPORT_MANIFEST)).await.map_err(Error::Export)?;
+        let manifest: ExportManifest = serde_json::from_slice(&manifest)?;
+
+        if manifest.account_namespace != self.config.account_namespace {
+            return Err(Error::ExportNamespaceMismatch);
+        }
+        if let Some(file_name) = manifest.media.iter().find_map(|(file_name, media)| {
+            let expected = export_file_name(media.uri_key.as_bytes(), media.format_key.as_bytes());
+            (!is_plain_file_name(file_name) || *file_name != expected).then_some(file_name)
+        }) {
+            return Err(Error::InvalidExportFileName(file_name.clone()));
+        }
+
+        let mut batch = Vec::with_capacity(BATCH_SIZE);
+        let mut entries = manifest.media.into_iter().peekable();
+
+        while let Some((file_name, media)) = entries.next() {
+            let content = fs::read(path.join(&file_name)).await.map_err(Error::Export)?;
+
+            let encoded = self.encode_media_with_keys(
+                Key::Plain(media.uri_key.into_inner()),
+                Key::Plain(media.format_key.into_inner()),
+                content,
+                media.content_type.as_deref(),
+                FormatColumns {
+                    kind: media.format_kind,
+                    thumbnail_width: media.thumbnail_width,
+                    thumbnail_height: media.thumbnail_height,
+                    thumbnail_method: media.thumbnail_method,
+                },
+            )?;
+            if let Some(mut encoded) = encoded {
+                encoded.last_access = media
+                    .last_access_ms
+                    .or_else(|| media.last_access.map(|secs| secs.saturating_mul(1000)));
+                batch.push(encoded);
+            }
+
+            if batch.len() == BATCH_SIZE || (entries.peek().is_none() && !batch.is_empty()) {
+                self.insert_media(std::mem::take(&mut batch)).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// List the media of the account namespace of this store in the cache,
+    /// from the least recently used to the most recently used.
+    ///
+    /// This only reads the metadata of the media, not their content, so it is
+    /// cheap enough to go through the whole cache to debug it or to implement
+    /// a custom eviction. In an encrypted store, the keys of the media are
+    /// hashed, so they don't reveal the MXC URIs.
+    ///
+    /// # Arguments
+    ///
+    /// * `limit` - The maximum number of media to return.
+    ///
+    /// * `offset` - The number of media to skip.
+    pub async fn list_media(&self, limit: usize, offset: usize) -> Result<Vec<MediaCacheEntry>> {
+        let account = self.account_key();
+        let conn = self.acquire().await?;
+        let entries = conn
+            .prepare(
+                "SELECT media.uri, media.format, media.last_access, \
+                 length(media_blobs.data) + (SELECT COALESCE(SUM(length(data)), 0) \
+                    FROM media_blob_chunks WHERE media_blob_chunks.hash = media_blobs.hash) \
+                 FROM media JOIN media_blobs ON media_blobs.hash = media.blob_hash \
+                 WHERE media.deleted_at IS NULL AND media.account IS ? \
+                 ORDER BY media.last_access ASC, media.rowid ASC LIMIT ? OFFSET ?",
+                move |mut stmt| {
+                    stmt.query((account, limit, offset))?
+                        .mapped(|row| {
+                            let last_access = row.get::<_, u64>(2)?;
+                            Ok(MediaCacheEntry {
+                                uri_key: row.get(0)?,
+                                format_key: row.get(1)?,
+                                last_access: SystemTime::UNIX_EPOCH
+                                    + Duration::from_millis(last_access),
+                                size: row.get(3)?,
+                            })
+                        })
+                        .collect()
+                },
+            )
+            .await?;
+
+        Ok(entries)
+    }
+
+    /// Get the given number of least recently used media in the cache, from
+    /// the least recently used.
+    ///
+    /// This is meant for custom evictions, that choose which media to remove,
+    /// for example with [`SqliteEventCacheStore::remove_media_content_batch`],
+    /// without listing the whole cache. The pending accesses of
+    /// [`SqliteEventCacheStoreConfig::defer_access_times`] are written first,
+    /// so the order is up-to-date.
+    ///
+    /// # Arguments
+    ///
+    /// * `n` - The maximum number of media to return.
+    pub async fn oldest_media(&self, n: usize) -> Result<Vec<MediaCacheEntry>> {
+        self.flush_access_times().await?;
+        self.list_media(n, 0).await
+    }
+
+    /// Get the rowids and the raw keys of all the rows of the `media` table,
+    /// ordered by rowid.
+    ///
+    /// This is only meant to debug the physical storage layout, and to write
+    /// precise tests.
+    #[cfg(any(test, feature = "debug-internals"))]
+    pub async fn rowids_for_debugging(&self) -> Result<Vec<MediaRowForDebugging>> {
+        let conn = self.acquire().await?;
+        let rows = conn
+            .prepare(
+                "SELECT rowid, uri, format, last_access FROM media ORDER BY rowid",
+                |mut stmt| {
+                    stmt.query(())?
+                        .mapped(|row| {
+                            Ok(MediaRowForDebugging {
+                                rowid: row.get(0)?,
+                                uri: row.get(1)?,
+                                format: row.get(2)?,
+                                last_access: row.get(3)?,
+                            })
+                        })
+                        .collect()
+                },
+            )
+            .await?;
+
+        Ok(rows)
+    }
+
+    /// Store the material needed to decrypt the content of the given media.
+    ///
+    /// This allows to keep the content of an encrypted media encrypted in the
+    /// cache, and to decrypt it on demand. The key info is encrypted with the
+    /// store cipher, if any.
+    ///
+    /// The key info is kept as long as the media content with the same request
+    /// is: it is removed with it and follows it when its key is replaced with
+    /// [`EventCacheStore::replace_media_key`].
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The `MediaRequest` of the file.
+    ///
+    /// * `key_info` - The material needed to decrypt the file.
+    pub async fn add_media_key_info(
+        &self,
+        request: &MediaRequest,
+        key_info: &MediaKeyInfo,
+    ) -> Result<()> {
+        let uri = self.encode_key(keys::MEDIA, request.source.unique_key());
+        let format = self.encode_key(keys::MEDIA, request.format.unique_key());
+        let key_info = self.encode_value(serde_json::to_vec(key_info)?)?;
+
+        self.retry_if_busy(|| {
+            let params = (uri.clone(), format.clone(), key_info.clone());
+            async move {
+                let conn = self.acquire_write().await?;
+                conn.execute(
+                    "INSERT OR REPLACE INTO media_keys (uri, format, key_info) VALUES (?, ?, ?)",
+                    params,
+                )
+                .await?;
+
+                Ok(())
+            }
+        })
+        .await
+    }
+
+    /// Get the material needed to decrypt the content of the given media.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The `MediaRequest` of the file.
+    pub async fn get_media_key_info(&self, request: &MediaRequest) -> Result<Option<MediaKeyInfo>> {
+        let uri = self.encode_key(keys::MEDIA, request.source.unique_key());
+        let format = self.encode_key(keys::MEDIA, request.format.unique_key());
+
+        let conn = self.acquire().await?;
+        let key_info = conn
+            .query_row::<Vec<u8>, _, _>(
+                "SELECT key_info FROM media_keys WHERE uri = ? AND format = ?",
+                (uri, format),
+                |row| row.get(0),
+            )
+            .await
+            .optional()?;
+
+        key_info
+            .map(|key_info| Ok(serde_json::from_slice(&self.decode_value(&key_info)?)?))
+            .transpose()
+    }
+
+    /// Get a media file's content with the material needed to decrypt it, and
+    /// update its last access.
+    ///
+    /// This is like [`EventCacheStore::get_media_content`] followed by
+    /// [`SqliteEventCacheStore::get_media_key_info`], in a single call. The
+    /// key info is `None` if it was not stored with
+    /// [`SqliteEventCacheStore::add_media_key_info`].
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The `MediaRequest` of the file.
+    pub async fn get_media_content_with_key_info(
+        &self,
+        request: &MediaRequest,
+    ) -> Result<Option<(Vec<u8>, Option<MediaKeyInfo>)>> {
+        let Some((content, _)) = self.get_media(request).await? else {
+            return Ok(None);
+        };
+        let key_info = self.get_media_key_info(request).await?;
+
+        Ok(Some((content, key_info)))
+    }
+
+    /// Get a media file's content as it is stored in the database, skipping
+    /// the decryption step.
+    ///
+    /// This is meant for transferring the cache to another device: if both
+    /// ends share the same store cipher, the ciphertext can be moved as-is,
+    /// otherwise the receiving end must decode it first.
+    ///
+    /// Contrary to [`EventCacheStore::get_media_content`], this doesn't update
+    /// the last access time of the media.
+    ///
+    /// # Security
+    ///
+    /// For an unencrypted store, the returned bytes are the plain media
+    /// content. For an encrypted store, they can only be decrypted with the
+    /// same [`StoreCipher`], so the caller must make sure that the cipher is
+    /// transferred through a secure channel, if at all. Note that the returned
+    /// value doesn't contain the media request, which the caller needs to keep
+    /// track of, since keys are hashed in encrypted stores.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The `MediaRequest` of the file.
+    pub async fn get_media_content_raw(
+        &self,
+        request: &MediaRequest,
+    ) -> Result<Option<RawStoredMedia>> {
+        if !self.is_media_cache_enabled() {
+            return Ok(None);
+        }
+
+        let uri = self.encode_key(keys::MEDIA, request.source.unique_key());
+        let format = self.encode_key(keys::MEDIA, request.format.unique_key());
+
+        let conn = self.acquire().await?;
+        let data = conn
+            .with_transaction::<_, rusqlite::Error, _>(move |txn| {
+                let Some((hash, mut data, compressed)) = txn
+                    .query_row::<(Vec<u8>, Vec<u8>, bool), _, _>(
+                        "SELECT media_blobs.hash, media_blobs.data, media_blobs.compressed \
+                         FROM media JOIN media_blobs ON media_blobs.hash = media.blob_hash \
+                         WHERE media.uri = ? AND media.format = ? AND media.deleted_at IS NULL",
+                        (uri, format),
+                        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                    )
+                    .optional()?
+                else {
+                    return Ok(None);
+                };
+
+                append_blob_chunks(txn, &hash, &mut data)?;
+                Ok(Some((data, compressed)))
+            })
+            .await?;
+
+        Ok(data.map(|(data, compressed)| RawStoredMedia {
+            data,
+            encrypted: self.store_cipher.is_some(),
+            compressed,
+        }))
+    }
+}
+
+async fn create_pool(
+    path: &Path,
+    config: &SqliteEventCacheStoreConfig,
+    max_size: Option<usize>,
+) -> Result<SqlitePool, OpenStoreError> {
+    let file_name = config.database_file_name.as_deref().unwrap_or(DATABASE_FILE_NAME);
+    if !is_plain_file_name(file_name) {
+        return Err(OpenStoreError::InvalidDatabaseFileName(file_name.to_owned()));
+    }
+
+    create_dir(path, config).await.map_err(OpenStoreError::CreateDir)?;
+    let path = path.join(file_name);
+    #[cfg(unix)]
+    if let Some(mode) = config.unix_mode {
+        create_database_file(&path, mode & 0o666).await.map_err(OpenStoreError::CreateFile)?;
+    }
+
+    let mut cfg = match config.file_locking.vfs() {
+        Some(vfs) => deadpool_sqlite::Config::new(sqlite_uri(&path, &[("vfs", vfs)])),
+        None => deadpool_sqlite::Config::new(path),
+    };
+    if let Some(max_size) = max_size {
+        cfg.pool = Some(PoolConfig::new(max_size));
+    }
+
+    build_pool(cfg, config)
+}
+
+/// Create the directory of the store, and its parents, if it doesn't exist.
+///
+/// On Unix, a new directory gets the permissions of the configuration,
+/// regardless of the umask.
+async fn create_dir(path: &Path, config: &SqliteEventCacheStoreConfig) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+
+        if fs::metadata(path).await.is_ok() {
+            return Ok(());
+        }
+
+        let mode = config.unix_mode.unwrap_or(DEFAULT_DIR_MODE);
+        fs::DirBuilder::new().recursive(true).mode(mode).create(path).await?;
+        fs::set_permissions(path, Permissions::from_mode(mode)).await
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = config;
+        fs::create_dir_all(path).await
+    }
+}
+
+/// Create an empty database file with the given permissions, regardless of
+/// the umask, if it doesn't exist.
+///
+/// SQLite handles an empty file like a missing database.
+#[cfg(unix)]
+async fn create_database_file(path: &Path, mode: u32) -> std::io::Result<()> {
+    use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+
+    match fs::OpenOptions::new().write(true).create_new(true).mode(mode).open(path).await {
+        Ok(_) => fs::set_permissions(path, Permissions::from_mode(mode)).await,
+        Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => Ok(()),
+        Err(error) => Err(error),
+    }
+}
+
+/// Whether the given name is a plain file name, that can't be used to access a
+/// file outside of the directory it is joined to.
+fn is_plain_file_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+
+    !name.contains(['/', '\\'])
+        && matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(component)), None) if component == name
+        )
+}
+
+/// The result of reading a range of a media content in
+/// [`SqliteEventCacheStore::get_media_content_range`].
+enum RangeRead {
+    /// The media is not in the cache.
+    Missing,
+    /// The content is compressed, so it must be read as a whole.
+    Compressed,
+    /// The range is not inside the content, which has the given size.
+    OutOfRange(usize),
+    /// The requested range of the content.
+    Content(Vec<u8>),
+}
+
+/// The rows containing the content of a media, see [`media_segments`].
+struct MediaSegments {
+    /// The hash of the content.
+    hash: Vec<u8>,
+    /// The rows containing the content, in order, as their table, rowid and
+    /// length.
+    segments: Vec<(&'static str, i64, usize)>,
+    /// Whether the content is compressed.
+    compressed: bool,
+}
+
+/// Get the rows containing the content of the media with the given keys.
+///
+/// Returns `None` if the media is not in the cache.
+fn media_segments(
+    txn: &rusqlite::Transaction<'_>,
+    uri: &Key,
+    format: &Key,
+) -> rusqlite::Result<Option<MediaSegments>> {
+    let Some((hash, rowid, len, compressed)) = txn
+        .query_row::<(Vec<u8>, i64, usize, bool), _, _>(
+            "SELECT media_blobs.hash, media_blobs.rowid, length(media_blobs.data), \
+             media_blobs.compressed \
+             FROM media JOIN media_blobs ON media_blobs.hash = media.blob_hash \
+             WHERE media.uri = ? AND media.format = ? AND media.deleted_at IS NULL",
+            (uri, format),
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )
+        .optional()?
+    else {
+        return Ok(None);
+    };
+
+    let mut segments = vec![(keys::MEDIA_BLOBS, rowid, len)];
+    let mut statement = txn.prepare_cached(
+        "SELECT rowid, length(data) FROM media_blob_chunks WHERE hash = ? ORDER BY seq",
+    )?;
+    for segment in statement
+        .query_map((&hash,), |row| Ok((keys::MEDIA_BLOB_CHUNKS, row.get(0)?, row.get(1)?)))?
+    {
+        segments.push(segment?);
+    }
+
+    Ok(Some(MediaSegments { hash, segments, compressed }))
+}
+
+/// Get a connection from the given pool, waiting at most for the given
+/// timeout, if any.
+async fn get_connection_within(
+    pool: &SqlitePool,
+    timeout: Option<Duration>,
+) -> Result<SqliteAsyncConn> {
+    let Some(timeout) = timeout else {
+        return Ok(pool.get().await?);
+    };
+
+    match tokio::time::timeout(timeout, pool.get()).await {
+        Ok(conn) => Ok(conn?),
+        Err(_) => {
+            warn!(
+                status = ?pool.status(),
+                ?timeout,
+                "Timed out waiting for a connection to the event cache store"
+            );
+            Err(Error::PoolTimeout)
+        }
+    }
+}
+
+/// Build a pool with the given pool configuration, applying the connections
+/// settings of the given store configuration.
+fn build_pool(
+    cfg: deadpool_sqlite::Config,
+    config: &SqliteEventCacheStoreConfig,
+) -> Result<SqlitePool, OpenStoreError> {
+    let mut builder = cfg.builder(Runtime::Tokio1).map_err(CreatePoolError::Config)?;
+
+    let pragmas = config.connection_pragmas();
+    if !pragmas.is_empty() {
+        let pragmas = Arc::new(pragmas);
+        builder = builder.post_create(Hook::async_fn(move |conn, _| {
+            let pragmas = pragmas.clone();
+            Box::pin(async move {
+                conn.interact(move |conn| conn.execute_batch(&pragmas))
+                    .await
+                    .unwrap()
+                    .map_err(HookError::Backend)
+            })
+        }));
+    }
+
+    Ok(builder.build().map_err(CreatePoolError::Build)?)
+}
+
+/// Insert the given media in the transaction, and evict the least recently
+/// accessed media of the given account namespace if its media go over
+/// `max_cache_size`.
+///
+/// `now` is the last access of the media that don't have one.
+///
+/// Returns the size of the cache, if it was computed because one of the limits
+/// is set, and the evicted media.
+#[allow(clippy::too_many_arguments)]
+fn insert_media_in_transaction(
+    txn: &rusqlite::Transaction<'_>,
+    media: &[EncodedMedia],
+    now: i64,
+    chunk_size: Option<usize>,
+    soft_limit: Option<u64>,
+    max_cache_size: Option<u64>,
+    account: Option<&Key>,
+    reserved: Option<&BTreeSet<(Vec<u8>, Vec<u8>)>>,
+) -> rusqlite::Result<(Option<u64>, Vec<EvictedMedia>)> {
+    let mut select_previous = txn.prepare_cached(
+        "SELECT blob_hash, pinned FROM media \
+         WHERE uri = ? AND format = ? AND deleted_at IS NULL",
+    )?;
+    let mut remove_deleted = txn.prepare_cached(
+        "DELETE FROM media WHERE uri = ? AND format = ? AND deleted_at IS NOT NULL",
+    )?;
+    let mut insert_blob = txn.prepare_cached(
+        "INSERT OR IGNORE INTO media_blobs (hash, data, compressed) VALUES (?, ?, ?)",
+    )?;
+    let mut insert_blob_chunk =
+        txn.prepare_cached("INSERT INTO media_blob_chunks (hash, seq, data) VALUES (?, ?, ?)")?;
+    let mut insert = txn.prepare_cached(
+        "INSERT OR REPLACE INTO media \
+         (uri, format, blob_hash, content_type, last_access, format_kind, \
+          thumbnail_width, thumbnail_height, thumbnail_method, pinned, account) \
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+    )?;
+    // The trigger removing unused contents doesn't fire on replacement.
+    let mut remove_blob_if_unused = txn.prepare_cached(
+        "DELETE FROM media_blobs WHERE hash = ?1 \
+         AND NOT EXISTS (SELECT 1 FROM media WHERE blob_hash = ?1)",
+    )?;
+
+    let mut rowids = Vec::with_capacity(media.len());
+    for EncodedMedia {
+        uri,
+        format,
+        hash,
+        data,
+        compressed,
+        content_type,
+        format_columns,
+        last_access,
+        account,
+    } in media
+    {
+        let previous = select_previous
+            .query_row((uri, format), |row| Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, bool>(1)?)))
+            .optional()?;
+        // A replaced media stays pinned.
+        let pinned = previous.as_ref().is_some_and(|(_, pinned)| *pinned);
+        // A media marked as deleted is not replaced, it is removed like with a
+        // hard delete, so the triggers remove its content if it is unused, and
+        // the data associated with it.
+        remove_deleted.execute((uri, format))?;
+
+        // The first chunk is stored with the hash, the next ones in their own table.
+        let mut chunks = match chunk_size {
+            Some(chunk_size) if data.len() > chunk_size => data.chunks(chunk_size),
+            _ => data.chunks(data.len().max(1)),
+        };
+        let first_chunk = chunks.next().unwrap_or_default();
+
+        // An identical content that is already stored is reused.
+        if insert_blob.execute((hash, first_chunk, compressed))? > 0 {
+            for (seq, chunk) in (1..).zip(chunks) {
+                insert_blob_chunk.execute((hash, seq, chunk))?;
+            }
+        }
+        insert.execute((
+            uri,
+            format,
+            hash,
+            content_type,
+            last_access.unwrap_or(now),
+            &format_columns.kind,
+            format_columns.thumbnail_width,
+            format_columns.thumbnail_height,
+            &format_columns.thumbnail_method,
+            pinned,
+            account,
+        ))?;
+        rowids.push(txn.last_insert_rowid());
+
+        if let Some((previous_hash, _)) = previous.filter(|(h, _)| h[..] != hash[..]) {
+            remove_blob_if_unused.execute((previous_hash,))?;
+        }
+    }
+
+    // Only compute the size of the cache if we need it.
+    if soft_limit.is_none() && max_cache_size.is_none() {
+        return Ok((None, Vec::new()));
+    }
+
+    let mut cache_size = txn.query_row(CACHE_SIZE_QUERY, (account,), |row| row.get::<_, u64>(0))?;
+    let mut evicted = Vec::new();
+
+    if let (Some(max_cache_size), Some(reserved)) = (max_cache_size, reserved) {
+        if cache_size > max_cache_size {
+            (cache_size, evicted) =
+                evict_media(txn, max_cache_size, cache_size, &rowids, account, reserved)?;
+        }
+    }
+
+    Ok((Some(cache_size), evicted))
+}
+
+/// Remove the least recently accessed media of the given account namespace
+/// until the total size of its media is not over `max_cache_size` anymore.
+///
+/// The media with the given `rowids`, which were just added, and the reserved
+/// media are never removed.
+///
+/// Returns the total size of the cache after the eviction, which can still be
+/// over `max_cache_size` if there is nothing left to remove, and the evicted
+/// media.
+fn evict_media(
+    txn: &rusqlite::Transaction<'_>,
+    max_cache_size: u64,
+    mut cache_size: u64,
+    rowids: &[i64],
+    account: Option<&Key>,
+    reserved: &BTreeSet<(Vec<u8>, Vec<u8>)>,
+) -> rusqlite::Result<(u64, Vec<EvictedMedia>)> {
+    let mut select = txn.prepare(LEAST_RECENTLY_ACCESSED_MEDIA_QUERY)?;
+    let mut delete = txn.prepare("DELETE FROM media WHERE rowid = ?")?;
+    let mut blob_used = txn.prepare(
+        "SELECT EXISTS (SELECT 1 FROM media \
+         WHERE blob_hash = ? AND deleted_at IS NULL AND account IS ?)",
+    )?;
+
+    let mut rows = select.query((account,))?;
+    let mut evicted = Vec::new();
+
+    while cache_size > max_cache_size {
+        let Some(row) = rows.next()? else {
+            break;
+        };
+
+        let rowid = row.get::<_, i64>(0)?;
+        let key = (row.get::<_, Vec<u8>>(1)?, row.get::<_, Vec<u8>>(2)?);
+        if rowids.contains(&rowid) || reserved.contains(&key) {
+            continue;
+        }
+
+        delete.execute((rowid,))?;
+
+        // The content is only removed from the size of the cache with the last media
+        // using it.
+        let hash = row.get::<_, Vec<u8>>(3)?;
+        let size = row.get::<_, u64>(4)?;
+        if !blob_used.query_row((hash, account), |row| row.get::<_, bool>(0))? {
+            cache_size -= size;
+        }
+
+        let (uri_key, format_key) = key;
+        evicted.push(EvictedMedia { uri_key, format_key, size });
+    }
+
+    debug!(evicted = evicted.len(), cache_size, max_cache_size, "Evicted media from the cache");
+
+    Ok((cache_size, evicted))
+}
+
+/// Remove the least recently accessed media of the given account namespace
+/// until there are not more than `max_items` of them in the cache.
+///
+/// The reserved and pinned media are never removed, but they are counted. The
+/// media are removed with a single statement, the reserved ones are excluded
+/// by their keys since they are only known in memory.
+///
+/// Returns the removed media.
+fn trim_media_to_count(
+    txn: &rusqlite::Transaction<'_>,
+    max_items: u64,
+    account: Option<&Key>,
+    reserved: &BTreeSet<(Vec<u8>, Vec<u8>)>,
+) -> rusqlite::Result<Vec<EvictedMedia>> {
+    let count = txn.query_row(
+        "SELECT COUNT(*) FROM media WHERE deleted_at IS NULL AND account IS ?",
+        (account,),
+        |row| row.get::<_, u64>(0),
+    )?;
+    let excess = count.saturating_sub(max_items);
+    if excess == 0 {
+        return Ok(Vec::new());
+    }
+
+    let not_reserved = if reserved.is_empty() {
+        String::new()
+    } else {
+        format!("AND (uri, format) NOT IN (VALUES {})", vec!["(?, ?)"; reserved.len()].join(", "))
+    };
+    // The size of the content is computed before the trigger removes it, if it
+    // is not used anymore.
+    let mut delete = txn.prepare(&format!(
+        "DELETE FROM media WHERE rowid IN \
+            (SELECT rowid FROM media \
+             WHERE NOT pinned AND deleted_at IS NULL AND account IS ? {not_reserved} \
+             ORDER BY last_access ASC, rowid ASC LIMIT ?) \
+         RETURNING uri, format, \
+            (SELECT length(media_blobs.data) + (SELECT COALESCE(SUM(length(data)), 0) \
+                FROM media_blob_chunks WHERE media_blob_chunks.hash = media_blobs.hash) \
+             FROM media_blobs WHERE media_blobs.hash = media.blob_hash)"
+    ))?;
+
+    let mut params: Vec<&dyn ToSql> = vec![&account];
+    for (uri, format) in reserved {
+        params.extend([uri as &dyn ToSql, format]);
+    }
+    params.push(&excess);
+
+    let removed = delete
+        .query_map(params_from_iter(params), |row| {
+            Ok(EvictedMedia { uri_key: row.get(0)?, format_key: row.get(1)?, size: row.get(2)? })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    Ok(removed)
+}
+
+/// The limits of a [`MediaRetentionPolicy`] applied by [`enforce_retention`].
+#[derive(Clone, Copy, Debug)]
+struct RetentionLimits {
+    /// The time before which the media must have been accessed to be removed,
+    /// in milliseconds since the Unix epoch.
+    cutoff: Option<i64>,
+    max_file_size: Option<u64>,
+    max_items: Option<u64>,
+    max_cache_size: Option<u64>,
+}
+
+/// Apply the given limits to the media of the given account namespace, in the
+/// order documented in [`SqliteEventCacheStore::enforce_retention`].
+///
+/// Returns the report and the removed media.
+fn enforce_retention(
+    txn: &rusqlite::Transaction<'_>,
+    limits: RetentionLimits,
+    account: Option<&Key>,
+    reserved: &BTreeSet<(Vec<u8>, Vec<u8>)>,
+) -> rusqlite::Result<(RetentionReport, Vec<EvictedMedia>)> {
+    let RetentionLimits { cutoff, max_file_size, max_items, max_cache_size } = limits;
+    let cache_size = || txn.query_row(CACHE_SIZE_QUERY, (account,), |row| row.get::<_, u64>(0));
+    let size_before = cache_size()?;
+    let mut report = RetentionReport::default();
+    let mut removed = Vec::new();
+
+    if let Some(cutoff) = cutoff {
+        let media = remove_expired_media(txn, cutoff, account, reserved)?;
+        report.expired = media.len() as u64;
+        removed.extend(media);
+    }
+
+    if let Some(max_file_size) = max_file_size {
+        let media = remove_large_media(txn, max_file_size, account, reserved)?;
+        report.too_large = media.len() as u64;
+        removed.extend(media);
+    }
+
+    if let Some(max_items) = max_items {
+        let media = trim_media_to_count(txn, max_items, account, reserved)?;
+        report.over_count = media.len() as u64;
+        removed.extend(media);
+    }
+
+    if let Some(max_cache_size) = max_cache_size {
+        let (_, media) = evict_media(txn, max_cache_size, cache_size()?, &[], account, reserved)?;
+        report.over_size = media.len() as u64;
+        removed.extend(media);
+    }
+
+    let size_after = cache_size()?;
+    report.bytes = size_before.saturating_sub(size_after);
+
+    Ok((report, removed))
+}
+
+/// Remove the media of the given account namespace whose content is larger
+/// than `max_file_size`, as it is stored in the database.
+///
+/// The reserved and pinned media are never removed.
+///
+/// Returns the removed media.
+fn remove_large_media(
+    txn: &rusqlite::Transaction<'_>,
+    max_file_size: u64,
+    account: Option<&Key>,
+    reserved: &BTreeSet<(Vec<u8>, Vec<u8>)>,
+) -> rusqlite::Result<Vec<EvictedMedia>> {
+    let mut select = txn.prepare(
+        "SELECT rowid, uri, format, size FROM \
+            (SELECT media.rowid, media.uri, media.format, \
+             length(media_blobs.data) + (SELECT COALESCE(SUM(length(data)), 0) \
+                FROM media_blob_chunks WHERE media_blob_chunks.hash = media_blobs.hash) AS size \
+             FROM media JOIN media_blobs ON media_blobs.hash = media.blob_hash \
+             WHERE NOT media.pinned AND media.deleted_at IS NULL AND media.account IS ?) \
+         WHERE size > ?",
+    )?;
+    let mut delete = txn.prepare("DELETE FROM media WHERE rowid = ?")?;
+
+    let mut rows = select.query((account, max_file_size))?;
+    let mut removed = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        let key = (row.get::<_, Vec<u8>>(1)?, row.get::<_, Vec<u8>>(2)?);
+        if reserved.contains(&key) {
+            continue;
+        }
+
+        delete.execute((row.get::<_, i64>(0)?,))?;
+
+        let (uri_key, format_key) = key;
+        removed.push(EvictedMedia { uri_key, format_key, size: row.get(3)? });
+    }
+
+    Ok(removed)
+}
+
+/// Remove the media of the given account namespace that were not accessed
+/// since the given cutoff, in milliseconds since the Unix epoch.
+///
+/// The reserved and pinned media are never removed.
+///
+/// Returns the removed media.
+fn remove_expired_media(
+    txn: &rusqlite::Transaction<'_>,
+    cutoff: i64,
+    account: Option<&Key>,
+    reserved: &BTreeSet<(Vec<u8>, Vec<u8>)>,
+) -> rusqlite::Result<Vec<EvictedMedia>> {
+    let mut select = txn.prepare(
+        "SELECT media.rowid, media.uri, media.format, \
+         length(media_blobs.data) + (SELECT COALESCE(SUM(length(data)), 0) \
+            FROM media_blob_chunks WHERE media_blob_chunks.hash = media_blobs.hash) \
+         FROM media JOIN media_blobs ON media_blobs.hash = media.blob_hash \
+         WHERE media.last_access < ? AND NOT media.pinned AND media.deleted_at IS NULL \
+         AND media.account IS ?",
+    )?;
+    let mut delete = txn.prepare("DELETE FROM media WHERE rowid = ?")?;
+
+    let mut rows = select.query((cutoff, account))?;
+    let mut removed = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        let key = (row.get::<_, Vec<u8>>(1)?, row.get::<_, Vec<u8>>(2)?);
+        if reserved.contains(&key) {
+            continue;
+        }
+
+        delete.execute((row.get::<_, i64>(0)?,))?;
+
+        let (uri_key, format_key) = key;
+        removed.push(EvictedMedia { uri_key, format_key, size: row.get(3)? });
+    }
+
+    Ok(removed)
+}
+
+/// Read the values of the given table and column, with their rowid, ordered
+/// by rowid, starting after the given rowid.
+///
+/// The media contents are read with all their chunks.
+fn read_encoded_values(
+    conn: &rusqlite::Connection,
+    (table, column): (&str, &str),
+    after_rowid: i64,
+    limit: usize,
+) -> rusqlite::Result<Vec<(i64, Vec<u8>)>> {
+    let mut statement = conn.prepare_cached(&format!(
+        "SELECT rowid, {column} FROM {table} \
+         WHERE rowid > ? AND {column} IS NOT NULL ORDER BY rowid LIMIT ?"
+    ))?;
+    let mut rows = statement.query((after_rowid, limit))?;
+    let mut values = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        let rowid = row.get::<_, i64>(0)?;
+        let mut value = row.get::<_, Vec<u8>>(1)?;
+
+        if table == keys::MEDIA_BLOBS {
+            let hash =
+                conn.query_row("SELECT hash FROM media_blobs WHERE rowid = ?", (rowid,), |row| {
+                    row.get::<_, Vec<u8>>(0)
+                })?;
+            append_blob_chunks(conn, &hash, &mut value)?;
+        }
+
+        values.push((rowid, value));
+    }
+
+    Ok(values)
+}
+
+/// Replace the values of the given table and column, read with
+/// [`read_encoded_values`], with their new encoding.
+///
+/// The values that changed since they were read are skipped, since they were
+/// written with the new encoding.
+///
+/// Returns the number of values that were replaced.
+fn write_reencoded_values(
+    txn: &rusqlite::Transaction<'_>,
+    column: (&str, &str),
+    values: &[(i64, Vec<u8>, Vec<u8>)],
+    chunk_size: Option<usize>,
+) -> rusqlite::Result<u64> {
+    let (table, column_name) = column;
+    let mut count = 0;
+
+    for (rowid, old, new) in values {
+        let current = read_encoded_values(txn, column, rowid - 1, 1)?;
+        let unchanged = current
+            .first()
+            .is_some_and(|(current_rowid, value)| current_rowid == rowid && value == old);
+        if !unchanged {
+            continue;
+        }
+
+        if table == keys::MEDIA_BLOBS {
+            // The content is split in chunks again, like when it is inserted.
+            let hash =
+                txn.query_row("SELECT hash FROM media_blobs WHERE rowid = ?", (rowid,), |row| {
+                    row.get::<_, Vec<u8>>(0)
+                })?;
+            let mut chunks = match chunk_size {
+                Some(chunk_size) if new.len() > chunk_size => new.chunks(chunk_size),
+                _ => new.chunks(new.len().max(1)),
+            };
+
+            txn.execute(
+                "UPDATE media_blobs SET data = ? WHERE rowid = ?",
+                (chunks.next().unwrap_or_default(), rowid),
+            )?;
+            txn.execute("DELETE FROM media_blob_chunks WHERE hash = ?", (&hash,))?;
+            for (seq, chunk) in (1..).zip(chunks) {
+                txn.execute(
+                    "INSERT INTO media_blob_chunks (hash, seq, data) VALUES (?, ?, ?)",
+                    (&hash, seq, chunk),
+                )?;
+            }
+        } else {
+            txn.execute(
+                &format!("UPDATE {table} SET {column_name} = ? WHERE rowid = ?"),
+                (new, rowid),
+            )?;
+        }
+
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+/// Encode the progress of [`SqliteEventCacheStore::reencode_all`]: the index
+/// of the column in [`ENCODED_COLUMNS`], and the last rowid that was
+/// re-encoded in it.
+fn encode_reencode_progress(step: usize, rowid: i64) -> Vec<u8> {
+    let mut progress = vec![step as u8];
+    progress.extend(rowid.to_be_bytes());
+    progress
+}
+
+/// Decode the progress encoded with [`encode_reencode_progress`].
+fn decode_reencode_progress(progress: &[u8]) -> Option<(usize, i64)> {
+    let (step, rowid) = progress.split_first()?;
+    Some((usize::from(*step), i64::from_be_bytes(rowid.try_into().ok()?)))
+}
+
+/// Append the chunks of the media content with the given hash that are not
+/// stored with the hash to its first chunk.
+fn append_blob_chunks(
+    conn: &rusqlite::Connection,
+    hash: &[u8],
+    data: &mut Vec<u8>,
+) -> rusqlite::Result<()> {
+    let mut statement =
+        conn.prepare_cached("SELECT data FROM media_blob_chunks WHERE hash = ? ORDER BY seq")?;
+
+    for chunk in statement.query_map((hash,), |row| row.get::<_, Vec<u8>>(0))? {
+        data.extend(chunk?);
+    }
+
+    Ok(())
+}
+
+/// Build the [URI filename] to open the database at the given path with the
+/// given query parameters, like the VFS.
+///
+/// The values of the parameters are not escaped.
+///
+/// [URI filename]: https://www.sqlite.org/uri.html
+fn sqlite_uri(path: &Path, params: &[(&str, &str)]) -> String {
+    let mut uri = String::from("file:");
+
+    for &byte in path.as_os_str().as_encoded_bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-._~".contains(&byte) {
+            uri.push(byte.into());
+        } else {
+            uri.push_str(&format!("%{byte:02X}"));
+        }
+    }
+
+    for (i, (name, value)) in params.iter().enumerate() {
+        uri.push(if i == 0 { '?' } else { '&' });
+        uri.push_str(name);
+        uri.push('=');
+        uri.push_str(value);
+    }
+
+    uri
+}
+
+/// The values of the columns describing the format of a media.
+///
+/// They allow to reconstruct the format of a media, because it can't be
+/// recovered from the format key of an encrypted store.
+#[derive(Debug, Default)]
+struct FormatColumns {
+    /// The `format_kind` column.
+    kind: Option<String>,
+    /// The `thumbnail_width` column.
+    thumbnail_width: Option<u64>,
+    /// The `thumbnail_height` column.
+    thumbnail_height: Option<u64>,
+    /// The `thumbnail_method` column.
+    thumbnail_method: Option<String>,
+}
+
+impl FormatColumns {
+    const FILE: &'static str = "file";
+    const THUMBNAIL: &'static str = "thumbnail";
+    const ANIMATED_THUMBNAIL: &'static str = "animated_thumbnail";
+
+    /// The values of the columns for the given media format.
+    fn new(format: &MediaFormat) -> Self {
+        match format {
+            MediaFormat::File => Self { kind: Some(Self::FILE.to_owned()), ..Default::default() },
+            MediaFormat::Thumbnail(settings) => {
+                let kind =
+                    if settings.animated { Self::ANIMATED_THUMBNAIL } else { Self::THUMBNAIL };
+
+                Self {
+                    kind: Some(kind.to_owned()),
+                    thumbnail_width: Some(settings.size.width.into()),
+                    thumbnail_height: Some(settings.size.height.into()),
+                    thumbnail_method: Some(settings.size.method.as_str().to_owned()),
+                }
+            }
+        }
+    }
+
+    /// Reconstruct the media format from the values of the columns.
+    ///
+    /// Returns `None` if the kind of the format is unknown, or if the
+    /// settings of a thumbnail are missing or invalid.
+    fn to_format(&self) -> Option<MediaFormat> {
+        let animated = match self.kind.as_deref()? {
+            Self::FILE => return Some(MediaFormat::File),
+            Self::THUMBNAIL => false,
+            Self::ANIMATED_THUMBNAIL => true,
+            _ => return None,
+        };
+
+        let mut settings = MediaThumbnailSettings::new(
+            Method::from(self.thumbnail_method.as_deref()?),
+            UInt::new(self.thumbnail_width?)?,
+            UInt::new(self.thumbnail_height?)?,
+        );
+        settings.animated = animated;
+
+        Some(MediaFormat::Thumbnail(settings))
+    }
+}
+
+/// Hash the given key of the given table, for a store without store cipher.
+fn hash_key_without_cipher(table_name: &str, key: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    // Prefix the table name with its length, so the boundary with the key is
+    // unambiguous.
+    hasher.update((table_name.len() as u64).to_be_bytes());
+    hasher.update(table_name);
+    hasher.update(key);
+
+    hasher.finalize().into()
+}
+
+/// Compute the name of the file containing the content of the media with the
+/// given keys in an exported media cache.
+fn export_file_name(uri: &[u8], format: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    // Prefix the URI with its length, so the boundary between the keys is
+    // unambiguous.
+    hasher.update((uri.len() as u64).to_be_bytes());
+    hasher.update(uri);
+    hasher.update(format);
+
+    hasher.finalize().iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Compute a short hash of the given media keys, to identify the media in the
+/// logs without leaking the plain keys of unencrypted stores.
+fn key_hash_for_logs(uri: &Key, format: &Key) -> String {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(uri);
+    hasher.write(format);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Load the storage format persisted in the database, if any.
+async fn load_storage_format(conn: &SqliteAsyncConn) -> Result<Option<u8>, OpenStoreError> {
+    match conn.get_kv(keys::STORAGE_FORMAT).await.map_err(OpenStoreError::LoadVersion)?.as_deref() {
+        Some([format]) => Ok(Some(*format)),
+        Some(_) => Err(OpenStoreError::InvalidVersion),
+        None => Ok(None),
+    }
+}
+
+/// Check that the envelope of the encrypted values of the database uses the
+/// codec with the given tag, and persist it if the database doesn't have one
+/// yet.
+async fn check_envelope_codec(
+    conn: &SqliteAsyncConn,
+    expected: u8,
+    previous: Option<u8>,
+    read_only: bool,
+) -> Result<(), OpenStoreError> {
+    let found = match conn
+        .get_kv(keys::ENVELOPE_CODEC)
+        .await
+        .map_err(OpenStoreError::LoadCipher)?
+        .as_deref()
+    {
+        Some([tag]) => *tag,
+        Some(_) => return Err(OpenStoreError::InvalidVersion),
+        None => {
+            // The values of a store whose cipher was created before the codec was
+            // persisted use MessagePack, a new store uses the given codec.
+            let has_cipher =
+                conn.get_kv("cipher").await.map_err(OpenStoreError::LoadCipher)?.is_some();
+            let tag = if has_cipher || read_only { MESSAGE_PACK_CODEC_TAG } else { expected };
+
+            if tag == expected && !read_only {
+                conn.set_kv(keys::ENVELOPE_CODEC, vec![tag])
+                    .await
+                    .map_err(OpenStoreError::SaveCipher)?;
+            }
+
+            tag
+        }
+    };
+
+    // The values of a store using the previous codec are re-encoded with
+    // `SqliteEventCacheStore::reencode_all`.
+    if found != expected && Some(found) != previous {
+        return Err(OpenStoreError::IncompatibleEnvelopeCodec { found, expected });
+    }
+
+    Ok(())
+}
+
+/// Check the integrity of the database with the given check.
+async fn check_integrity(
+    conn: &SqliteAsyncConn,
+    integrity_check: IntegrityCheck,
+) -> Result<(), OpenStoreError> {
+    let Some(pragma) = integrity_check.pragma() else {
+        return Ok(());
+    };
+
+    let result = conn
+        .prepare(pragma, |mut stmt| {
+            stmt.query_map((), |row| row.get::<_, String>(0))?.collect::<Result<Vec<_>, _>>()
+        })
+        .await;
+
+    match result {
+        Ok(messages) if messages == ["ok"] => Ok(()),
+        Ok(messages) => Err(OpenStoreError::Corrupted(messages.join("\n"))),
+        // A database can be so corrupted that the check itself fails.
+        Err(error)
+            if matches!(
+                error.sqlite_error_code(),
+                Some(rusqlite::ErrorCode::DatabaseCorrupt | rusqlite::ErrorCode::NotADatabase)
+            ) =>
+        {
+            Err(OpenStoreError::Corrupted(error.to_string()))
+        }
+        Err(error) => Err(OpenStoreError::CheckIntegrity(error)),
+    }
+}
+
+/// Run migrations for the given version of the database.
+async fn run_migrations(
+    conn: &SqliteAsyncConn,
+    version: u8,
+    config: &SqliteEventCacheStoreConfig,
+) -> Result<()> {
+    if version == 0 {
+        if let Some(page_size) = config.page_size {
+            // This must happen before anything is written to the database, including
+            // switching to WAL mode in `apply_migrations`, otherwise it has no effect.
+            conn.execute_batch(format!("PRAGMA page_size = {page_size};")).await?;
+        }
+
+        if config.incremental_vacuum.is_some() {
+            // Like the page size, this must happen before the first table is created.
+            conn.execute_batch("PRAGMA auto_vacuum = INCREMENTAL;").await?;
+        }
+    }
+
+    // The creation time is only known for the stores created after it was
+    // persisted.
+    let created_at =
+        config.now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+    let initial_values = [
+        (keys::CREATED_AT, created_at.to_be_bytes().to_vec()),
+        (keys::STORAGE_FORMAT, vec![STORAGE_FORMAT]),
+    ];
+
+    let on_progress = config.on_migration_progress.as_ref().map(|callback| &*callback.0);
+    apply_migrations(conn, version, &MIGRATIONS, on_progress, &initial_values).await
+}
+
+/// Apply the given migrations to a database at the given version.
+///
+/// Every migration runs in its own transaction, that also bumps the version of
+/// the database, so an interrupted upgrade resumes at the first migration that
+/// wasn't applied.
+///
+/// The given callback is called before every migration, with the current and
+/// the latest versions.
+///
+/// When the database is created, the given values are written to its
+/// key-value table in the transaction of the first migration, so a database
+/// is never left without them.
+async fn apply_migrations(
+    conn: &SqliteAsyncConn,
+    version: u8,
+    migrations: &[&'static str],
+    on_progress: Option<&(dyn Fn(u8, u8) + Send + Sync)>,
+    initial_values: &[(&'static str, Vec<u8>)],
+) -> Result<()> {
+    let latest_version = migrations.len();
+
+    if version == 0 {
+        debug!("Creating database");
+    } else if usize::from(version) < latest_version {
+        debug!(version, new_version = latest_version, "Upgrading database");
+    } else {
+        return Ok(());
+    }
+
+    if version < 1 {
+        // First turn on WAL mode, this can't be done in the transaction, it fails with
+        // the error message: "cannot change into wal mode from within a transaction".
+        conn.execute_batch("PRAGMA journal_mode = wal;").await?;
+    }
+
+    let mut initial_values = (version == 0).then(|| initial_values.to_vec());
+
+    for (new_version, &migration) in (1..).zip(migrations).skip(version.into()) {
+        if let Some(on_progress) = on_progress {
+            // The number of migrations always fits, the versions are `u8`s.
+            on_progress(new_version - 1, latest_version as u8);
+        }
+
+        let values = initial_values.take().unwrap_or_default();
+        conn.with_transaction(move |txn| {
+            txn.execute_batch(migration)?;
+            for (key, value) in values {
+                txn.set_kv(key, &value)?;
+            }
+            txn.set_db_version(new_version)
+        })
+        .await?;
+    }
+
+    Ok(())
+}
+
+#[async_trait]
+impl EventCacheStore for SqliteEventCacheStore {
+    type Error = Error;
+
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(
+            key_hash = field::Empty,
+            media.size = content.len(),
+            media.stored_size = field::Empty,
+            cache.evicted = field::Empty,
+        )
+    )]
+    async fn add_media_content(&self, request: &MediaRequest, content: Vec<u8>) -> Result<()> {
+        let Some(media) = self.encode_media(request, content, None)? else {
+            return Ok(());
+        };
+
+        let span = Span::current();
+        if !span.is_disabled() {
+            span.record("key_hash", key_hash_for_logs(&media.uri, &media.format).as_str());
+            span.record("media.stored_size", media.data.len());
+        }
+
+        let evicted = self.insert_media(vec![media]).await?;
+        span.record("cache.evicted", evicted);
+
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn replace_media_key(
+        &self,
+        from: &MediaRequest,
+        to: &MediaRequest,
+    ) -> Result<(), Self::Error> {
+        let prev_uri = self.encode_key(keys::MEDIA, from.source.unique_key());
+        let prev_format = self.encode_key(keys::MEDIA, from.format.unique_key());
+
+        let new_uri = self.encode_key(keys::MEDIA, to.source.unique_key());
+        let new_format = self.encode_key(keys::MEDIA, to.format.unique_key());
+        let format_columns = FormatColumns::new(&to.format);
+
+        let now = self.now();
+        self.retry_if_busy(|| {
+            let params = (
+                new_uri.clone(),
+                new_format.clone(),
+                now,
+                format_columns.kind.clone(),
+                format_columns.thumbnail_width,
+                format_columns.thumbnail_height,
+                format_columns.thumbnail_method.clone(),
+                prev_uri.clone(),
+                prev_format.clone(),
+            );
+            async move {
+                let conn = self.acquire_write().await?;
+                conn.execute(
+                    r#"UPDATE media SET uri = ?, format = ?, last_access = ?,
+                           format_kind = ?, thumbnail_width = ?, thumbnail_height = ?,
+                           thumbnail_method = ?
+                       WHERE uri = ? AND format = ?"#,
+                    params,
+                )
+                .await?;
+
+                Ok(())
+            }
+        })
+        .await
+    }
+
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(key_hash = field::Empty, cache.hit = field::Empty, media.size = field::Empty)
+    )]
+    async fn get_media_content(&self, request: &MediaRequest) -> Result<Option<Vec<u8>>> {
+        if let Some((content, _)) = self.get_media(request).await? {
+            return Ok(Some(content));
+        }
+
+        let Some(loader) = &self.config.media_loader else {
+            return Ok(None);
+        };
+        let Some(content) = (loader.0)(request.clone()).await.map_err(Error::MediaLoader)? else {
+            return Ok(None);
+        };
+        self.add_media_content(request, content.clone()).await?;
+
+        Ok(Some(content))
+    }
+
+    async fn contains_media(&self, request: &MediaRequest) -> Result<bool> {
+        if !self.is_media_cache_enabled() {
+            return Ok(false);
+        }
+
+        let uri = self.encode_key(keys::MEDIA, request.source.unique_key());
+        let format = self.encode_key(keys::MEDIA, request.format.unique_key());
+
+        let found = self
+            .acquire()
+            .await?
+            .query_row(
+                "SELECT 1 FROM media WHERE uri = ? AND format = ? AND deleted_at IS NULL LIMIT 1",
+                (uri, format),
+                |_| Ok(()),
+            )
+            .await
+            .optional()?;
+
+        Ok(found.is_some())
+    }
+
+    #[instrument(level = "debug", skip_all, fields(key_hash = field::Empty))]
+    async fn remove_media_content(&self, request: &MediaRequest) -> Result<()> {
+        let uri = self.encode_key(keys::MEDIA, request.source.unique_key());
+        let format = self.encode_key(keys::MEDIA, request.format.unique_key());
+
+        let span = Span::current();
+        if !span.is_disabled() {
+            span.record("key_hash", key_hash_for_logs(&uri, &format).as_str());
+        }
+
+        let sql = self.remove_media_sql("uri = ? AND format = ?");
+
+        self.retry_if_busy(|| {
+            let (uri, format, sql) = (uri.clone(), format.clone(), sql.clone());
+            async move {
+                let conn = self.acquire_write().await?;
+                conn.execute(sql, (uri, format)).await?;
+
+                Ok(())
+            }
+        })
+        .await
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn remove_media_content_for_uri(&self, uri: &MxcUri) -> Result<()> {
+        let uri = self.encode_key(keys::MEDIA, uri);
+        let sql = self.remove_media_sql("uri = ?");
+
+        self.retry_if_busy(|| {
+            let (uri, sql) = (uri.clone(), sql.clone());
+            async move {
+                let conn = self.acquire_write().await?;
+                conn.execute(sql, (uri,)).await?;
+
+                Ok(())
+            }
+        })
+        .await
+    }
+
+    /// Remove all the media files' content whose `MxcUri` points to the given
+    /// homeserver from the media store.
+    ///
+    /// This is only supported by stores whose keys are not hashed: the keys
+    /// of an encrypted store, or of a store with
+    /// [`SqliteEventCacheStoreConfig::hash_keys`], are hashed so the server
+    /// name can't be matched, and this returns
+    /// [`Error::EncryptedKeysNotQueryable`].
+    #[instrument(level = "debug", skip(self), fields(removed = field::Empty))]
+    async fn remove_media_by_server(&self, server_name: &ServerName) -> Result<u64> {
+        if self.store_cipher.is_some() || self.config.hash_keys {
+            return Err(Error::EncryptedKeysNotQueryable);
+        }
+
+        // The URIs are stored as is, so we can match on the prefix containing the
+        // authority.
+        let Key::Plain(prefix) = self.encode_key(keys::MEDIA, format!("mxc://{server_name}/"))
+        else {
+            return Err(Error::EncryptedKeysNotQueryable);
+        };
+        let sql = self.remove_media_sql("substr(uri, 1, ?) = ?");
+
+        let count = self
+            .retry_if_busy(|| {
+                let (prefix, sql) = (prefix.clone(), sql.clone());
+                async move {
+                    let conn = self.acquire_write().await?;
+                    Ok(conn.execute(sql, (prefix.len() as i64, prefix)).await?)
+                }
+            })
+            .await?;
+        Span::current().record("removed", count);
+
+        Ok(count as u64)
+    }
+
+    #[instrument(level = "debug", skip_all, fields(removed = field::Empty))]
+    async fn clean_up_media_cache(&self) -> Result<u64> {
+        let Some(max_age) = self.config.media_retention_policy.max_age else {
+            return Ok(0);
+        };
+
+        // Don't remove the media that were accessed recently.
+        self.flush_access_times().await?;
+
+        let cutoff = self.now().saturating_sub(max_age.as_millis().try_into().unwrap_or(i64::MAX));
+        let account = self.account_key();
+        let reserved = Arc::new(self.reserved_keys());
+
+        let removed = self
+            .retry_if_busy(|| {
+                let (account, reserved) = (account.clone(), reserved.clone());
+                async move {
+                    let conn = self.acquire_write().await?;
+                    Ok(conn
+                        .with_immediate_transaction(move |txn| {
+                            remove_expired_media(txn, cutoff, account.as_ref(), &reserved)
+                        })
+                        .await?)
+                }
+            })
+            .await?;
+
+        let count = removed.len() as u64;
+        if count > 0 {
+            debug!(removed = count, "Removed expired media from the cache");
+            self.maybe_incremental_vacuum().await?;
+        }
+        Span::current().record("removed", count);
+
+        self.notify_evicted(&removed);
+
+        Ok(count)
+    }
+
+    #[instrument(level = "debug", skip_all, fields(warmed = field::Empty))]
+    async fn warm_media(&self, uris: &[OwnedMxcUri]) -> Result<u64> {
+        // A pending access must not overwrite the new one when it is written.
+        self.flush_access_times().await?;
+
+        let now = self.now();
+        let uris = Arc::new(
+            uris.iter().map(|uri| self.encode_key(keys::MEDIA, &**uri)).collect::<Vec<_>>(),
+        );
+
+        let count = self
+            .retry_if_busy(|| {
+                let uris = uris.clone();
+                async move {
+                    let conn = self.acquire_write().await?;
+                    Ok(conn
+                        .with_transaction::<_, rusqlite::Error, _>(move |txn| {
+                            let mut update = txn.prepare_cached(
+                                "UPDATE media SET last_access = MAX(last_access, ?) \
+                                 WHERE uri = ? AND deleted_at IS NULL",
+                            )?;
+                            let mut count = 0;
+                            for uri in uris.iter() {
+                                count += update.execute((now, uri))?;
+                            }
+
+                            Ok(count)
+                        })
+                        .await?)
+                }
+            })
+            .await?;
+        Span::current().record("warmed", count);
+
+        Ok(count as u64)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn clear_session_data(&self) -> Result<()> {
+        if let Some(namespace) = &self.config.account_namespace {
+            self.clear_account_namespace(namespace).await?;
+            return Ok(());
+        }
+
+        // Only the data tables are cleared, the key-value table holds the
+        // metadata of the store, like the store cipher, that must survive the
+        // session.
+        self.retry_if_busy(|| async move {
+            let conn = self.acquire_write().await?;
+            conn.with_transaction::<_, rusqlite::Error, _>(|txn| {
+                txn.execute_batch(
+                    "DELETE FROM media_keys;
+                     DELETE FROM media;
+                     DELETE FROM media_blobs;
+                     DELETE FROM gaps;
+                     DELETE FROM event_chunks;",
+                )
+            })
+            .await?;
+
+            Ok(())
+        })
+        .await?;
+
+        self.above_soft_limit.store(false, Ordering::SeqCst);
+        self.maybe_incremental_vacuum().await?;
+
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn save_gap(&self, room_id: &RoomId, prev_batch_token: &str) -> Result<()> {
+        let room_id = self.encode_key(keys::GAPS, room_id);
+        let prev_batch_token = self.encode_value(prev_batch_token.as_bytes().to_vec())?;
+        let account = self.account_key();
+
+        self.retry_if_busy(|| {
+            let params = (room_id.clone(), prev_batch_token.clone(), account.clone());
+            async move {
+                let conn = self.acquire_write().await?;
+                conn.execute(
+                    "INSERT OR REPLACE INTO gaps (room_id, prev_batch_token, account) \
+                     VALUES (?, ?, ?)",
+                    params,
+                )
+                .await?;
+
+                Ok(())
+            }
+        })
+        .await
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn load_gap(&self, room_id: &RoomId) -> Result<Option<String>> {
+        let room_id = self.encode_key(keys::GAPS, room_id);
+
+        let conn = self.acquire().await?;
+        let prev_batch_token = conn
+            .query_row("SELECT prev_batch_token FROM gaps WHERE room_id = ?", (room_id,), |row| {
+                row.get::<_, Vec<u8>>(0)
+            })
+            .await
+            .optional()?;
+
+        prev_batch_token
+            .map(|token| {
+                let token = self.decode_value(&token)?;
+                Ok(String::from_utf8_lossy(&token).into_owned())
+            })
+            .transpose()
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn clear_gap(&self, room_id: &RoomId) -> Result<()> {
+        let room_id = self.encode_key(keys::GAPS, room_id);
+
+        self.retry_if_busy(|| {
+            let room_id = room_id.clone();
+            async move {
+                let conn = self.acquire_write().await?;
+                conn.execute("DELETE FROM gaps WHERE room_id = ?", (room_id,)).await?;
+
+                Ok(())
+            }
+        })
+        .await
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn save_event_chunk(&self, room_id: &RoomId, chunk: EventChunk) -> Result<()> {
+        let room_id = self.encode_key(keys::EVENT_CHUNKS, room_id);
+        let content = self.encode_value(serde_json::to_vec(&chunk.content)?)?;
+        let account = self.account_key();
+
+        self.retry_if_busy(|| {
+            let params = (
+                room_id.clone(),
+                chunk.id,
+                chunk.previous,
+                chunk.next,
+                content.clone(),
+                account.clone(),
+            );
+            async move {
+                let conn = self.acquire_write().await?;
+                conn.execute(
+                    "INSERT OR REPLACE INTO event_chunks \
+                     (room_id, chunk_id, previous, next, content, account) \
+                     VALUES (?, ?, ?, ?, ?, ?)",
+                    params,
+                )
+                .await?;
+
+                Ok(())
+            }
+        })
+        .await
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn load_event_chunks(&self, room_id: &RoomId) -> Result<Vec<EventChunk>> {
+        let room_id = self.encode_key(keys::EVENT_CHUNKS, room_id);
+
+        let conn = self.acquire().await?;
+        let rows = conn
+            .prepare(
+                "SELECT chunk_id, previous, next, content FROM event_chunks WHERE room_id = ?",
+                move |mut stmt| {
+                    stmt.query((room_id,))?
+                        .mapped(|row| {
+                            Ok((
+                                row.get::<_, u64>(0)?,
+                                row.get::<_, Option<u64>>(1)?,
+                                row.get::<_, Option<u64>>(2)?,
+                                row.get::<_, Vec<u8>>(3)?,
+                            ))
+                        })
+                        .collect::<rusqlite::Result<Vec<_>>>()
+                },
+            )
+            .await?;
+
+        let chunks = rows
+            .into_iter()
+            .map(|(id, previous, next, content)| {
+                let content = serde_json::from_slice(&self.decode_value(&content)?)?;
+                Ok(EventChunk { id, previous, next, content })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(sort_event_chunks(chunks))
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn remove_room_events(&self, room_id: &RoomId) -> Result<()> {
+        let room_id = self.encode_key(keys::EVENT_CHUNKS, room_id);
+
+        self.retry_if_busy(|| {
+            let room_id = room_id.clone();
+            async move {
+                let conn = self.acquire_write().await?;
+                conn.execute("DELETE FROM event_chunks WHERE room_id = ?", (room_id,)).await?;
+
+                Ok(())
+            }
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        num::NonZeroUsize,
+        path::Path,
+        sync::{
+            atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering::SeqCst},
+            Arc, Mutex as StdMutex,
+        },
+        time::{Duration, SystemTime},
+    };
+
+    use assert_matches::assert_matches;
+    use deadpool_sqlite::Runtime;
+    use futures_util::TryStreamExt;
+    use matrix_sdk_base::{
+        deserialized_responses::SyncTimelineEvent,
+        event_cache_store::{
+            EventCacheStore, EventCacheStoreError, EventChunk, EventChunkContent,
+            IntoEventCacheStore,
+        },
+        event_cache_store_integration_tests,
+        media::{MediaFormat, MediaRequest, MediaThumbnailSettings, UniqueKey},
+    };
+    use matrix_sdk_store_encryption::EncryptedValue;
+    use matrix_sdk_test::async_test;
+    use once_cell::sync::Lazy;
+    use ruma::{
+        events::room::{EncryptedFileInit, JsonWebKeyInit, MediaSource},
+        media::Method,
+        mxc_uri, room_id,
+        serde::{Base64, Raw},
+        server_name, uint, MxcUri, OwnedMxcUri,
+    };
+    use serde_json::json;
+    use tempfile::{tempdir, TempDir};
+
+    use super::{
+        apply_migrations, keys, sqlite_uri, BusyRetryPolicy, EnvelopeCodec, EnvelopeCodecError,
+        EventCacheMetrics, EvictedMedia, EvictionEstimate, FileLocking, IntegrityCheck,
+        LockingMode, MediaCacheEntry, MediaRetentionPolicy, MessagePackCodec, OpenOutcome,
+        RepairReport, RetentionReport, SqliteEventCacheStore, SqliteEventCacheStoreConfig,
+        Synchronous, DATABASE_VERSION, LEAST_RECENTLY_ACCESSED_MEDIA_QUERY, MEDIA_PAGE_SIZE,
+        MIGRATIONS, STORAGE_FORMAT,
+    };
+    use crate::{
+        error::Error,
+        utils::{SqliteAsyncConnExt, SqliteKeyValueStoreAsyncConnExt},
+        OpenStoreError,
+    };
+
+    static TMP_DIR: Lazy<TempDir> = Lazy::new(|| tempdir().unwrap());
+    static NUM: AtomicU32 = AtomicU32::new(0);
+
+    async fn get_event_cache_store() -> Result<SqliteEventCacheStore, EventCacheStoreError> {
+        get_event_cache_store_with_config(SqliteEventCacheStoreConfig::default()).await
+    }
+
+    async fn get_event_cache_store_with_config(
+        config: SqliteEventCacheStoreConfig,
+    ) -> Result<SqliteEventCacheStore, EventCacheStoreError> {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let tmpdir_path = TMP_DIR.path().join(name);
+
+        tracing::info!("using event cache store @ {}", tmpdir_path.to_str().unwrap());
+
+        Ok(SqliteEventCacheStore::open_with_config(tmpdir_path.to_str().unwrap(), None, config)
+            .await
+            .unwrap())
+    }
+
+    event_cache_store_integration_tests!();
+
+    async fn get_event_cache_store_content_sorted_by_last_access(
+        event_cache_store: &SqliteEventCacheStore,
+    ) -> Vec<Vec<u8>> {
+        let sqlite_db = event_cache_store.acquire().await.expect("accessing sqlite db failed");
+        sqlite_db
+            .prepare(
+                "SELECT media_blobs.data FROM media \
+                 JOIN media_blobs ON media_blobs.hash = media.blob_hash \
+                 ORDER BY media.last_access DESC",
+                |mut stmt| stmt.query(())?.mapped(|row| row.get(0)).collect(),
+            )
+            .await
+            .expect("querying media cache content by last access failed")
+    }
+
+    #[async_test]
+    async fn test_last_access() {
+        // The time only advances when the test says so, so no sleep is needed
+        // between the accesses.
+        let now_millis = Arc::new(AtomicU64::new(1_000_000));
+        let event_cache_store =
+            get_event_cache_store_with_config(SqliteEventCacheStoreConfig::new().clock({
+                let now_millis = now_millis.clone();
+                move || SystemTime::UNIX_EPOCH + Duration::from_millis(now_millis.load(SeqCst))
+            }))
+            .await
+            .expect("creating media cache failed");
+        let uri = mxc_uri!("mxc://localhost/media");
+        let file_request =
+            MediaRequest { source: MediaSource::Plain(uri.to_owned()), format: MediaFormat::File };
+        let thumbnail_request = MediaRequest {
+            source: MediaSource::Plain(uri.to_owned()),
+            format: MediaFormat::Thumbnail(MediaThumbnailSettings::new(
+                Method::Crop,
+                uint!(100),
+                uint!(100),
+            )),
+        };
+
+        let content: Vec<u8> = "hello world".into();
+        let thumbnail_content: Vec<u8> = "hello…".into();
+
+        // Add the media.
+        event_cache_store
+            .add_media_content(&file_request, content.clone())
+            .await
+            .expect("adding file failed");
+
+        now_millis.fetch_add(1, SeqCst);
+
+        event_cache_store
+            .add_media_content(&thumbnail_request, thumbnail_content.clone())
+            .await
+            .expect("adding thumbnail failed");
+
+        // File's last access is older than thumbnail.
+        let contents =
+            get_event_cache_store_content_sorted_by_last_access(&event_cache_store).await;
+
+        assert_eq!(contents.len(), 2, "media cache contents length is wrong");
+        assert_eq!(contents[0], thumbnail_content, "thumbnail is not last access");
+        assert_eq!(contents[1], content, "file is not second-to-last access");
+
+        now_millis.fetch_add(1, SeqCst);
+
+        // Access the file so its last access is more recent.
+        let _ = event_cache_store
+            .get_media_content(&file_request)
+            .await
+            .expect("getting file failed")
+            .expect("file is missing");
+
+        // File's last access is more recent than thumbnail.
+        let contents =
+            get_event_cache_store_content_sorted_by_last_access(&event_cache_store).await;
+
+        assert_eq!(contents.len(), 2, "media cache contents length is wrong");
+        assert_eq!(contents[0], content, "file is not last access");
+        assert_eq!(contents[1], thumbnail_content, "thumbnail is not second-to-last access");
+
+        // The last accesses are the ones of the clock.
+        let last_accesses = event_cache_store
+            .acquire()
+            .await
+            .unwrap()
+            .prepare("SELECT last_access FROM media ORDER BY last_access", |mut stmt| {
+                stmt.query(())?.mapped(|row| row.get::<_, i64>(0)).collect::<Result<Vec<_>, _>>()
+            })
+            .await
+            .unwrap();
+        assert_eq!(last_accesses, [1_000_001, 1_000_002]);
+    }
+
+    #[async_test]
+    async fn test_least_recently_accessed_media_query_uses_index() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+
+        let details = event_cache_store
+            .acquire()
+            .await
+            .unwrap()
+            .prepare(
+                format!("EXPLAIN QUERY PLAN {LEAST_RECENTLY_ACCESSED_MEDIA_QUERY}"),
+                |mut stmt| {
+                    stmt.query((None::<Vec<u8>>,))?
+                        .mapped(|row| row.get::<_, String>(3))
+                        .collect::<Result<Vec<_>, _>>()
+                },
+            )
+            .await
+            .unwrap();
+
+        assert!(
+            details
+                .iter()
+                .any(|detail| detail.contains("USING INDEX media_account_last_access_idx")),
+            "{details:?}"
+        );
+        assert!(!details.iter().any(|detail| detail.contains("TEMP B-TREE")), "{details:?}");
+    }
+
+    #[async_test]
+    async fn test_get_media_content_raw() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let uri = mxc_uri!("mxc://localhost/media");
+        let request =
+            MediaRequest { source: MediaSource::Plain(uri.to_owned()), format: MediaFormat::File };
+
+        assert!(event_cache_store.get_media_content_raw(&request).await.unwrap().is_none());
+
+        let content: Vec<u8> = "hello world".into();
+        event_cache_store.add_media_content(&request, content.clone()).await.unwrap();
+
+        let raw = event_cache_store.get_media_content_raw(&request).await.unwrap().unwrap();
+        assert!(!raw.encrypted);
+        assert_eq!(raw.data, content);
+    }
+
+    #[async_test]
+    async fn test_remove_media_by_server() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let request_file = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+        let request_thumbnail = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::Thumbnail(MediaThumbnailSettings::new(
+                Method::Crop,
+                uint!(100),
+                uint!(100),
+            )),
+        };
+        // The server name is a prefix of this one, it must not match.
+        let request_other_server = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost.org/media").to_owned()),
+            format: MediaFormat::File,
+        };
+
+        event_cache_store.add_media_content(&request_file, b"hello".to_vec()).await.unwrap();
+        event_cache_store.add_media_content(&request_thumbnail, b"world".to_vec()).await.unwrap();
+        event_cache_store.add_media_content(&request_other_server, b"foo".to_vec()).await.unwrap();
+
+        let removed =
+            event_cache_store.remove_media_by_server(server_name!("localhost")).await.unwrap();
+        assert_eq!(removed, 2);
+
+        assert!(event_cache_store.get_media_content(&request_file).await.unwrap().is_none());
+        assert!(event_cache_store.get_media_content(&request_thumbnail).await.unwrap().is_none());
+        assert!(event_cache_store
+            .get_media_content(&request_other_server)
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[async_test]
+    async fn test_metrics() {
+        let event_cache_store = get_event_cache_store_with_config(
+            SqliteEventCacheStoreConfig::new().media_retention_policy(MediaRetentionPolicy {
+                max_cache_size: Some(10),
+                ..Default::default()
+            }),
+        )
+        .await
+        .expect("creating media cache failed");
+        let request = |id: &str| MediaRequest {
+            source: MediaSource::Plain(OwnedMxcUri::from(format!("mxc://localhost/{id}"))),
+            format: MediaFormat::File,
+        };
+        assert_eq!(event_cache_store.metrics(), EventCacheMetrics::default());
+
+        event_cache_store.add_media_content(&request("a"), vec![1; 5]).await.unwrap();
+        event_cache_store.get_media_content(&request("a")).await.unwrap().unwrap();
+        event_cache_store.get_media_content(&request("a")).await.unwrap().unwrap();
+        assert!(event_cache_store.get_media_content(&request("b")).await.unwrap().is_none());
+
+        // Adding these evicts the first media.
+        event_cache_store
+            .add_media_content_batch(&[(request("b"), vec![2; 5]), (request("c"), vec![3; 5])])
+            .await
+            .unwrap();
+        assert!(event_cache_store.get_media_content(&request("a")).await.unwrap().is_none());
+
+        assert_eq!(
+            event_cache_store.metrics(),
+            EventCacheMetrics { slow_reads: 0, gets: 4, hits: 2, misses: 2, adds: 3, evictions: 1 }
+        );
+    }
+
+    #[async_test]
+    async fn test_slow_reads() {
+        let uri = mxc_uri!("mxc://localhost/media");
+        let request =
+            MediaRequest { source: MediaSource::Plain(uri.to_owned()), format: MediaFormat::File };
+
+        // Reads are not timed by default.
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        event_cache_store.add_media_content(&request, b"hello".to_vec()).await.unwrap();
+        event_cache_store.get_media_content(&request).await.unwrap();
+        assert_eq!(event_cache_store.metrics().slow_reads, 0);
+
+        // With a zero threshold, every read is slow.
+        let event_cache_store = get_event_cache_store_with_config(
+            SqliteEventCacheStoreConfig::new().slow_read_threshold(Duration::ZERO),
+        )
+        .await
+        .expect("creating media cache failed");
+        event_cache_store.add_media_content(&request, b"hello".to_vec()).await.unwrap();
+        event_cache_store.get_media_content(&request).await.unwrap();
+        event_cache_store.get_media_content(&request).await.unwrap();
+        assert_eq!(event_cache_store.metrics().slow_reads, 2);
+    }
+
+    #[async_test]
+    async fn test_vacuum() {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+        let database_path = path.join("matrix-sdk-event-cache.sqlite3");
+
+        let event_cache_store = SqliteEventCacheStore::open(&path, None).await.unwrap();
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+
+        // Add and remove a large media, and write it to the database file.
+        event_cache_store.add_media_content(&request, vec![0; 1024 * 1024]).await.unwrap();
+        event_cache_store.remove_media_content(&request).await.unwrap();
+        event_cache_store
+            .acquire()
+            .await
+            .unwrap()
+            .execute_batch("PRAGMA wal_checkpoint(TRUNCATE);")
+            .await
+            .unwrap();
+        let size_before = std::fs::metadata(&database_path).unwrap().len();
+
+        event_cache_store.vacuum().await.unwrap();
+
+        let size_after = std::fs::metadata(&database_path).unwrap().len();
+        assert!(size_after < size_before, "{size_after} is not smaller than {size_before}");
+    }
+
+    #[async_test]
+    async fn test_incremental_vacuum() {
+        async fn pragma(event_cache_store: &SqliteEventCacheStore, name: &str) -> u64 {
+            event_cache_store
+                .acquire()
+                .await
+                .unwrap()
+                .query_row(format!("PRAGMA {name}"), (), |row| row.get(0))
+                .await
+                .unwrap()
+        }
+
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+
+        // By default, the free pages are kept in the database.
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        assert_eq!(pragma(&event_cache_store, "auto_vacuum").await, 0);
+        event_cache_store.add_media_content(&request, vec![0; 1024 * 1024]).await.unwrap();
+        event_cache_store.clear_all_media().await.unwrap();
+        assert!(pragma(&event_cache_store, "freelist_count").await > 0);
+
+        // With the incremental vacuum, they are released after large removals.
+        let event_cache_store = get_event_cache_store_with_config(
+            SqliteEventCacheStoreConfig::new().incremental_vacuum(16),
+        )
+        .await
+        .expect("creating media cache failed");
+        // `INCREMENTAL` is 2.
+        assert_eq!(pragma(&event_cache_store, "auto_vacuum").await, 2);
+        event_cache_store.add_media_content(&request, vec![0; 1024 * 1024]).await.unwrap();
+        let page_count_before = pragma(&event_cache_store, "page_count").await;
+
+        event_cache_store.clear_all_media().await.unwrap();
+        assert_eq!(pragma(&event_cache_store, "freelist_count").await, 0);
+        let page_count_after = pragma(&event_cache_store, "page_count").await;
+        assert!(
+            page_count_after < page_count_before,
+            "{page_count_after} is not smaller than {page_count_before}"
+        );
+    }
+
+    #[async_test]
+    async fn test_checkpoint() {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+        let wal_path = path.join("matrix-sdk-event-cache.sqlite3-wal");
+
+        let event_cache_store = SqliteEventCacheStore::open_with_config(
+            &path,
+            None,
+            SqliteEventCacheStoreConfig::new().wal_autocheckpoint(0),
+        )
+        .await
+        .unwrap();
+
+        let conn = event_cache_store.acquire_write().await.unwrap();
+        let pages = conn
+            .query_row("PRAGMA wal_autocheckpoint", (), |row| row.get::<_, u32>(0))
+            .await
+            .unwrap();
+        assert_eq!(pages, 0);
+        drop(conn);
+
+        // Many small writes, that are not checkpointed automatically.
+        for i in 0..100u8 {
+            let request = MediaRequest {
+                source: MediaSource::Plain(OwnedMxcUri::from(format!("mxc://localhost/media{i}"))),
+                format: MediaFormat::File,
+            };
+            event_cache_store.add_media_content(&request, vec![i; 1024]).await.unwrap();
+        }
+        assert!(std::fs::metadata(&wal_path).unwrap().len() > 0);
+
+        event_cache_store.checkpoint().await.unwrap();
+        assert_eq!(std::fs::metadata(&wal_path).unwrap().len(), 0);
+    }
+
+    #[async_test]
+    async fn test_vacuum_into() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+
+        // Add and remove a large media, to leave a lot of free pages.
+        event_cache_store.add_media_content(&request, vec![0; 1024 * 1024]).await.unwrap();
+        event_cache_store.remove_media_content(&request).await.unwrap();
+
+        let source_size: u64 = event_cache_store
+            .acquire()
+            .await
+            .unwrap()
+            .query_row(
+                "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+                (),
+                |row| row.get(0),
+            )
+            .await
+            .unwrap();
+
+        let dest_dir = tempdir().unwrap();
+        let dest = dest_dir.path().join("compacted.sqlite3");
+        event_cache_store.vacuum_into(&dest).await.unwrap();
+
+        let dest_size = std::fs::metadata(&dest).unwrap().len();
+        assert!(dest_size < source_size, "{dest_size} is not smaller than {source_size}");
+
+        // The copy is a valid event cache store.
+        let copy = SqliteEventCacheStore::open_with_pool(
+            deadpool_sqlite::Config::new(&dest).create_pool(Runtime::Tokio1).unwrap(),
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(copy.get_media_content(&request).await.unwrap().is_none());
+    }
+
+    #[async_test]
+    async fn test_read_uncommitted() {
+        let read_uncommitted = |event_cache_store: SqliteEventCacheStore| async move {
+            event_cache_store
+                .acquire()
+                .await
+                .unwrap()
+                .query_row("PRAGMA read_uncommitted", (), |row| row.get::<_, bool>(0))
+                .await
+                .unwrap()
+        };
+
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        assert!(!read_uncommitted(event_cache_store).await);
+
+        let event_cache_store = get_event_cache_store_with_config(
+            SqliteEventCacheStoreConfig::new().read_uncommitted(true),
+        )
+        .await
+        .expect("creating media cache failed");
+        assert!(read_uncommitted(event_cache_store).await);
+    }
+
+    #[cfg(unix)]
+    #[async_test]
+    async fn test_file_locking() {
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+
+        for file_locking in [FileLocking::Dotfile, FileLocking::None] {
+            let event_cache_store = get_event_cache_store_with_config(
+                SqliteEventCacheStoreConfig::new().file_locking(file_locking),
+            )
+            .await
+            .expect("creating media cache failed");
+
+            event_cache_store.add_media_content(&request, b"media".to_vec()).await.unwrap();
+            assert_eq!(
+                event_cache_store.get_media_content(&request).await.unwrap().as_deref(),
+                Some(&b"media"[..])
+            );
+        }
+    }
+
+    #[async_test]
+    async fn test_is_encrypted() {
+        let event_cache_store = get_event_cache_store().await.unwrap();
+        assert!(!event_cache_store.is_encrypted());
+        assert_eq!(event_cache_store.cipher_algorithm(), None);
+    }
+
+    #[async_test]
+    async fn test_synchronous() {
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+
+        let event_cache_store = get_event_cache_store_with_config(
+            SqliteEventCacheStoreConfig::new().synchronous(Synchronous::Normal),
+        )
+        .await
+        .expect("creating media cache failed");
+
+        // `NORMAL` is 1.
+        for conn in [
+            event_cache_store.acquire().await.unwrap(),
+            event_cache_store.acquire_write().await.unwrap(),
+        ] {
+            let synchronous =
+                conn.query_row("PRAGMA synchronous", (), |row| row.get::<_, u8>(0)).await.unwrap();
+            assert_eq!(synchronous, 1);
+        }
+
+        event_cache_store.add_media_content(&request, b"media".to_vec()).await.unwrap();
+        assert_eq!(
+            event_cache_store.get_media_content(&request).await.unwrap(),
+            Some(b"media".to_vec())
+        );
+        event_cache_store.remove_media_content(&request).await.unwrap();
+        assert!(!event_cache_store.contains_media(&request).await.unwrap());
+    }
+
+    #[async_test]
+    async fn test_exclusive_locking_mode() {
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+        let room_id = room_id!("!room:localhost");
+
+        let event_cache_store = get_event_cache_store_with_config(
+            SqliteEventCacheStoreConfig::new().locking_mode(LockingMode::Exclusive).mmap_size(0),
+        )
+        .await
+        .expect("creating media cache failed");
+
+        let conn = event_cache_store.acquire().await.unwrap();
+        let locking_mode =
+            conn.query_row("PRAGMA locking_mode", (), |row| row.get::<_, String>(0)).await.unwrap();
+        assert_eq!(locking_mode, "exclusive");
+        let mmap_size =
+            conn.query_row("PRAGMA mmap_size", (), |row| row.get::<_, u64>(0)).await.unwrap();
+        assert_eq!(mmap_size, 0);
+        drop(conn);
+
+        // Reads and writes share the connection holding the lock.
+        event_cache_store.add_media_content(&request, b"media".to_vec()).await.unwrap();
+        assert_eq!(
+            event_cache_store.get_media_content(&request).await.unwrap().as_deref(),
+            Some(&b"media"[..])
+        );
+        event_cache_store.save_gap(room_id, "prev_batch").await.unwrap();
+        assert_eq!(
+            event_cache_store.load_gap(room_id).await.unwrap().as_deref(),
+            Some("prev_batch")
+        );
+
+        event_cache_store.remove_media_content(&request).await.unwrap();
+        assert!(!event_cache_store.contains_media(&request).await.unwrap());
+        assert_eq!(event_cache_store.clean_up_media_cache().await.unwrap(), 0);
+    }
+
+    #[cfg(unix)]
+    #[async_test]
+    async fn test_unix_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        // New directories are only accessible by their owner by default.
+        let path = TMP_DIR.path().join(NUM.fetch_add(1, SeqCst).to_string());
+        SqliteEventCacheStore::open(&path, None).await.unwrap();
+        assert_eq!(mode(&path), 0o700);
+
+        let path = TMP_DIR.path().join(NUM.fetch_add(1, SeqCst).to_string());
+        SqliteEventCacheStore::open_with_config(
+            &path,
+            None,
+            SqliteEventCacheStoreConfig::new().unix_permissions(0o750),
+        )
+        .await
+        .unwrap();
+        assert_eq!(mode(&path), 0o750);
+        assert_eq!(mode(&path.join("matrix-sdk-event-cache.sqlite3")), 0o640);
+    }
+
+    #[test]
+    fn test_sqlite_uri() {
+        assert_eq!(
+            sqlite_uri(Path::new("/tmp/some dir/db?.sqlite3"), &[("vfs", "unix-none")]),
+            "file:/tmp/some%20dir/db%3F.sqlite3?vfs=unix-none"
+        );
+        assert_eq!(
+            sqlite_uri(Path::new("/tmp/db.sqlite3"), &[("mode", "ro"), ("vfs", "unix-none")]),
+            "file:/tmp/db.sqlite3?mode=ro&vfs=unix-none"
+        );
+    }
+
+    #[async_test]
+    async fn test_database_path_and_disk_size() {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+
+        let event_cache_store = SqliteEventCacheStore::open(&path, None).await.unwrap();
+        let database_path = event_cache_store.database_path().unwrap();
+        assert!(database_path.ends_with("matrix-sdk-event-cache.sqlite3"));
+        assert!(database_path.exists());
+
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+        event_cache_store.add_media_content(&request, vec![1; 64 * 1024]).await.unwrap();
+
+        // The size includes the write-ahead log, where the media is written first.
+        let main_file_size = std::fs::metadata(&database_path).unwrap().len();
+        let disk_size = event_cache_store.database_disk_size().await.unwrap();
+        assert!(disk_size > main_file_size);
+        assert!(disk_size > 64 * 1024);
+
+        // The name of the file can be configured.
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let event_cache_store = SqliteEventCacheStore::open_with_config(
+            TMP_DIR.path().join(name),
+            None,
+            SqliteEventCacheStoreConfig::new().database_file_name("other.sqlite3"),
+        )
+        .await
+        .unwrap();
+        assert!(event_cache_store.database_path().unwrap().ends_with("other.sqlite3"));
+
+        // An in-memory store doesn't have any file.
+        let event_cache_store = SqliteEventCacheStore::open_in_memory(None).await.unwrap();
+        assert_eq!(event_cache_store.database_path(), None);
+        assert_eq!(event_cache_store.database_disk_size().await.unwrap(), 0);
+    }
+
+    #[async_test]
+    async fn test_open_read_only() {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+        let other_request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/other").to_owned()),
+            format: MediaFormat::File,
+        };
+
+        // The store is still in use by the application.
+        let event_cache_store =
+            SqliteEventCacheStore::open(&path, Some("passphrase")).await.unwrap();
+        event_cache_store.add_media_content(&request, b"hello".to_vec()).await.unwrap();
+
+        let read_only_store =
+            SqliteEventCacheStore::open_read_only(&path, Some("passphrase")).await.unwrap();
+
+        // Reads work, and don't update the last access.
+        let last_access_before = event_cache_store.list_media(1, 0).await.unwrap()[0].last_access;
+        assert_eq!(
+            read_only_store.get_media_content(&request).await.unwrap().as_deref(),
+            Some(&b"hello"[..])
+        );
+        assert!(read_only_store.contains_media(&request).await.unwrap());
+        assert_eq!(
+            event_cache_store.list_media(1, 0).await.unwrap()[0].last_access,
+            last_access_before
+        );
+
+        // Writes fail.
+        assert_matches!(
+            read_only_store.add_media_content(&other_request, b"world".to_vec()).await,
+            Err(Error::ReadOnly)
+        );
+        assert_matches!(read_only_store.remove_media_content(&request).await, Err(Error::ReadOnly));
+        assert!(event_cache_store.get_media_content(&request).await.unwrap().is_some());
+
+        // The passphrase is still checked.
+        assert_matches!(
+            SqliteEventCacheStore::open_read_only(&path, Some("wrong")).await,
+            Err(OpenStoreError::IncorrectPassphrase)
+        );
+    }
+
+    #[async_test]
+    async fn test_open_read_only_requires_migrations() {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+
+        // A store that doesn't exist can't be created.
+        SqliteEventCacheStore::open_read_only(&path, None).await.unwrap_err();
+
+        // A store that is not up-to-date is not migrated.
+        std::fs::create_dir_all(&path).unwrap();
+        let pool = deadpool_sqlite::Config::new(path.join("matrix-sdk-event-cache.sqlite3"))
+            .create_pool(Runtime::Tokio1)
+            .unwrap();
+        let conn = pool.get().await.unwrap();
+        apply_migrations(&conn, 0, &MIGRATIONS[..1], None, &[]).await.unwrap();
+        drop(conn);
+
+        assert_matches!(
+            SqliteEventCacheStore::open_read_only(&path, None).await,
+            Err(OpenStoreError::MigrationRequired { found: 1, required: DATABASE_VERSION })
+        );
+    }
+
+    #[async_test]
+    async fn test_open_partially_initialized() {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+
+        // The tables of the first migration exist, but the version was not set.
+        std::fs::create_dir_all(&path).unwrap();
+        let pool = deadpool_sqlite::Config::new(path.join("matrix-sdk-event-cache.sqlite3"))
+            .create_pool(Runtime::Tokio1)
+            .unwrap();
+        let conn = pool.get().await.unwrap();
+        conn.execute_batch(MIGRATIONS[0]).await.unwrap();
+        assert_matches!(conn.db_version().await, Err(OpenStoreError::MissingVersion));
+        drop(conn);
+        drop(pool);
+
+        let event_cache_store = SqliteEventCacheStore::open(&path, None).await.unwrap();
+        let conn = event_cache_store.acquire().await.unwrap();
+        assert_eq!(conn.db_version().await.unwrap(), DATABASE_VERSION);
+        drop(conn);
+
+        event_cache_store.add_media_content(&request, b"media".to_vec()).await.unwrap();
+        drop(event_cache_store);
+
+        // The next opening is a normal one.
+        let event_cache_store = SqliteEventCacheStore::open(&path, None).await.unwrap();
+        assert_eq!(
+            event_cache_store.get_media_content(&request).await.unwrap(),
+            Some(b"media".to_vec())
+        );
+    }
+
+    #[cfg(feature = "sqlcipher")]
+    #[async_test]
+    async fn test_sqlcipher_key() {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+
+        let event_cache_store = SqliteEventCacheStore::open_with_config(
+            &path,
+            None,
+            SqliteEventCacheStoreConfig::new().sqlcipher_key("it's a secret"),
+        )
+        .await
+        .unwrap();
+        event_cache_store.add_media_content(&request, b"media".to_vec()).await.unwrap();
+        drop(event_cache_store);
+
+        // The whole file is encrypted, even its header.
+        let header = std::fs::read(path.join("matrix-sdk-event-cache.sqlite3")).unwrap();
+        assert!(!header.starts_with(b"SQLite format 3"));
+
+        // The database can't be opened without the key, or with the wrong one.
+        SqliteEventCacheStore::open(&path, None).await.unwrap_err();
+        SqliteEventCacheStore::open_with_config(
+            &path,
+            None,
+            SqliteEventCacheStoreConfig::new().sqlcipher_key("wrong"),
+        )
+        .await
+        .unwrap_err();
+
+        let event_cache_store = SqliteEventCacheStore::open_with_config(
+            &path,
+            None,
+            SqliteEventCacheStoreConfig::new().sqlcipher_key("it's a secret"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            event_cache_store.get_media_content(&request).await.unwrap(),
+            Some(b"media".to_vec())
+        );
+    }
+
+    #[async_test]
+    async fn test_deferred_access_times() {
+        let now_millis = Arc::new(AtomicU64::new(1_000_000));
+        let event_cache_store = get_event_cache_store_with_config(
+            SqliteEventCacheStoreConfig::new().defer_access_times(2).clock({
+                let now_millis = now_millis.clone();
+                move || SystemTime::UNIX_EPOCH + Duration::from_millis(now_millis.load(SeqCst))
+            }),
+        )
+        .await
+        .expect("creating media cache failed");
+        let request = |id: &str| MediaRequest {
+            source: MediaSource::Plain(OwnedMxcUri::from(format!("mxc://localhost/{id}"))),
+            format: MediaFormat::File,
+        };
+        let last_accesses = || async {
+            event_cache_store
+                .acquire()
+                .await
+                .unwrap()
+                .prepare("SELECT last_access FROM media ORDER BY rowid", |mut stmt| {
+                    stmt.query(())?
+                        .mapped(|row| row.get::<_, i64>(0))
+                        .collect::<Result<Vec<_>, _>>()
+                })
+                .await
+                .unwrap()
+        };
+
+        event_cache_store.add_media_content(&request("a"), b"a".to_vec()).await.unwrap();
+        event_cache_store.add_media_content(&request("b"), b"b".to_vec()).await.unwrap();
+        now_millis.store(2_000_000, SeqCst);
+
+        // The access is not written yet.
+        event_cache_store.get_media_content(&request("a")).await.unwrap().unwrap();
+        assert_eq!(last_accesses().await, [1_000_000, 1_000_000]);
+
+        // It is written with the second one.
+        event_cache_store.get_media_content(&request("b")).await.unwrap().unwrap();
+        assert_eq!(last_accesses().await, [2_000_000, 2_000_000]);
+
+        // It can be forced.
+        now_millis.store(3_000_000, SeqCst);
+        event_cache_store.get_media_content(&request("a")).await.unwrap().unwrap();
+        assert_eq!(last_accesses().await, [2_000_000, 2_000_000]);
+        event_cache_store.flush_access_times().await.unwrap();
+        assert_eq!(last_accesses().await, [3_000_000, 2_000_000]);
+
+        // Flushing without pending accesses does nothing.
+        event_cache_store.flush_access_times().await.unwrap();
+        assert_eq!(last_accesses().await, [3_000_000, 2_000_000]);
+    }
+
+    #[async_test]
+    async fn test_close() {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+
+        let event_cache_store = SqliteEventCacheStore::open_with_config(
+            &path,
+            None,
+            SqliteEventCacheStoreConfig::new().defer_access_times(10),
+        )
+        .await
+        .unwrap();
+        event_cache_store.add_media_content(&request, vec![1; 64 * 1024]).await.unwrap();
+        event_cache_store.get_media_content(&request).await.unwrap().unwrap();
+        event_cache_store.close().await.unwrap();
+
+        // The write-ahead log was truncated, or removed with the last connection.
+        let wal_path = path.join("matrix-sdk-event-cache.sqlite3-wal");
+        let wal_size = std::fs::metadata(wal_path).map_or(0, |metadata| metadata.len());
+        assert_eq!(wal_size, 0);
+
+        let event_cache_store = SqliteEventCacheStore::open(&path, None).await.unwrap();
+        assert_eq!(
+            event_cache_store.get_media_content(&request).await.unwrap(),
+            Some(vec![1; 64 * 1024])
+        );
+    }
+
+    #[async_test]
+    async fn test_custom_kv() {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+        let event_cache_store =
+            SqliteEventCacheStore::open(&path, Some("passphrase")).await.unwrap();
+
+        assert_eq!(event_cache_store.get_custom_kv("app", "version").await.unwrap(), None);
+
+        event_cache_store.set_custom_kv("app", "version", b"1".to_vec()).await.unwrap();
+        event_cache_store.set_custom_kv("other", "version", b"2".to_vec()).await.unwrap();
+        assert_eq!(
+            event_cache_store.get_custom_kv("app", "version").await.unwrap().as_deref(),
+            Some(&b"1"[..])
+        );
+        assert_eq!(
+            event_cache_store.get_custom_kv("other", "version").await.unwrap().as_deref(),
+            Some(&b"2"[..])
+        );
+
+        // It doesn't clobber the version of the database.
+        let conn = event_cache_store.acquire().await.unwrap();
+        assert_eq!(conn.db_version().await.unwrap(), DATABASE_VERSION);
+        drop(conn);
+
+        // It survives the session.
+        event_cache_store.clear_session_data().await.unwrap();
+        drop(event_cache_store);
+        let event_cache_store =
+            SqliteEventCacheStore::open(&path, Some("passphrase")).await.unwrap();
+        assert_eq!(
+            event_cache_store.get_custom_kv("app", "version").await.unwrap().as_deref(),
+            Some(&b"1"[..])
+        );
+
+        event_cache_store.remove_custom_kv("app", "version").await.unwrap();
+        assert_eq!(event_cache_store.get_custom_kv("app", "version").await.unwrap(), None);
+        assert!(event_cache_store.get_custom_kv("other", "version").await.unwrap().is_some());
+    }
+
+    #[async_test]
+    async fn test_created_at() {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+        let created_at = SystemTime::UNIX_EPOCH + Duration::from_millis(1_000_000);
+
+        let event_cache_store = SqliteEventCacheStore::open_with_config(
+            &path,
+            None,
+            SqliteEventCacheStoreConfig::new().clock(move || created_at),
+        )
+        .await
+        .unwrap();
+        assert_eq!(event_cache_store.created_at().await.unwrap(), Some(created_at));
+        drop(event_cache_store);
+
+        // It is not changed when the store is opened again.
+        let event_cache_store = SqliteEventCacheStore::open(&path, None).await.unwrap();
+        assert_eq!(event_cache_store.created_at().await.unwrap(), Some(created_at));
+
+        // It is unknown for the stores created before it was persisted.
+        event_cache_store
+            .acquire()
+            .await
+            .unwrap()
+            .execute("DELETE FROM kv WHERE key = ?", (keys::CREATED_AT,))
+            .await
+            .unwrap();
+        assert_eq!(event_cache_store.created_at().await.unwrap(), None);
+    }
+
+    #[async_test]
+    async fn test_clear_session_data_keeps_metadata() {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+
+        let event_cache_store =
+            SqliteEventCacheStore::open(&path, Some("passphrase")).await.unwrap();
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+        event_cache_store.add_media_content(&request, b"media".to_vec()).await.unwrap();
+        event_cache_store.clear_session_data().await.unwrap();
+
+        let conn = event_cache_store.acquire().await.unwrap();
+        assert_eq!(conn.db_version().await.unwrap(), DATABASE_VERSION);
+        assert_eq!(conn.get_kv(keys::STORAGE_FORMAT).await.unwrap(), Some(vec![STORAGE_FORMAT]));
+        drop(conn);
+        drop(event_cache_store);
+
+        // The store can be reopened with the same passphrase.
+        let event_cache_store =
+            SqliteEventCacheStore::open(&path, Some("passphrase")).await.unwrap();
+        assert!(event_cache_store.get_media_content(&request).await.unwrap().is_none());
+    }
+
+    #[async_test]
+    async fn test_busy_retry_policy() {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+        let database_path = path.join("matrix-sdk-event-cache.sqlite3");
+        // Don't wait for the lock in SQLite, to only test the retries.
+        let config = SqliteEventCacheStoreConfig::new().busy_timeout(Duration::ZERO);
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+
+        let event_cache_store = SqliteEventCacheStore::open_with_config(
+            &path,
+            None,
+            config.clone().busy_retry_policy(BusyRetryPolicy {
+                max_retries: 20,
+                initial_backoff: Duration::from_millis(10),
+                max_backoff: Duration::from_millis(50),
+            }),
+        )
+        .await
+        .unwrap();
+
+        // Another connection holds the write lock for a while.
+        let lock_database = || {
+            let conn = rusqlite::Connection::open(&database_path).unwrap();
+            conn.execute_batch("BEGIN IMMEDIATE").unwrap();
+            conn
+        };
+        let conn = lock_database();
+        let unlock = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            conn.execute_batch("COMMIT").unwrap();
+        });
+
+        // The writes wait for the lock to be released instead of failing.
+        event_cache_store.add_media_content(&request, b"hello".to_vec()).await.unwrap();
+        unlock.join().unwrap();
+        assert!(event_cache_store.contains_media(&request).await.unwrap());
+
+        // So do the maintenance writes.
+        let conn = lock_database();
+        let unlock = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            conn.execute_batch("COMMIT").unwrap();
+        });
+        event_cache_store.clear_all_media().await.unwrap();
+        unlock.join().unwrap();
+        assert!(!event_cache_store.contains_media(&request).await.unwrap());
+        drop(event_cache_store);
+
+        // Without retries, the error is returned.
+        let event_cache_store = SqliteEventCacheStore::open_with_config(
+            &path,
+            None,
+            config.busy_retry_policy(BusyRetryPolicy { max_retries: 0, ..Default::default() }),
+        )
+        .await
+        .unwrap();
+
+        let _conn = lock_database();
+        let error = event_cache_store.remove_media_content(&request).await.unwrap_err();
+        assert!(error.is_busy(), "unexpected error: {error}");
+    }
+
+    #[async_test]
+    async fn test_page_size_and_cache_size() {
+        let pragma = |event_cache_store: SqliteEventCacheStore, pragma: &'static str| async move {
+            event_cache_store
+                .acquire()
+                .await
+                .unwrap()
+                .query_row(pragma, (), |row| row.get::<_, i64>(0))
+                .await
+                .unwrap()
+        };
+
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+        let event_cache_store = SqliteEventCacheStore::open_with_config(
+            &path,
+            None,
+            SqliteEventCacheStoreConfig::new().page_size(8192).cache_size(-4096),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(pragma(event_cache_store.clone(), "PRAGMA page_size").await, 8192);
+        assert_eq!(pragma(event_cache_store.clone(), "PRAGMA cache_size").await, -4096);
+
+        // The database is still in WAL mode.
+        let journal_mode = event_cache_store
+            .acquire()
+            .await
+            .unwrap()
+            .query_row("PRAGMA journal_mode", (), |row| row.get::<_, String>(0))
+            .await
+            .unwrap();
+        assert_eq!(journal_mode, "wal");
+        drop(event_cache_store);
+
+        // The page size of an existing database doesn't change.
+        let event_cache_store = SqliteEventCacheStore::open_with_config(
+            &path,
+            None,
+            SqliteEventCacheStoreConfig::new().page_size(16384),
+        )
+        .await
+        .unwrap();
+        assert_eq!(pragma(event_cache_store, "PRAGMA page_size").await, 8192);
+    }
+
+    #[async_test]
+    async fn test_busy_timeout() {
+        let busy_timeout = |event_cache_store: SqliteEventCacheStore| async move {
+            event_cache_store
+                .acquire()
+                .await
+                .unwrap()
+                .query_row("PRAGMA busy_timeout", (), |row| row.get::<_, u64>(0))
+                .await
+                .unwrap()
+        };
+
+        let event_cache_store = get_event_cache_store_with_config(
+            SqliteEventCacheStoreConfig::new().busy_timeout(Duration::from_secs(10)),
+        )
+        .await
+        .expect("creating media cache failed");
+        assert_eq!(busy_timeout(event_cache_store.clone()).await, 10_000);
+
+        // Concurrent writes and reads wait for each other instead of failing.
+        let tasks = (0..50)
+            .map(|i| {
+                let event_cache_store = event_cache_store.clone();
+                tokio::spawn(async move {
+                    let request = MediaRequest {
+                        source: MediaSource::Plain(OwnedMxcUri::from(format!(
+                            "mxc://localhost/{i}"
+                        ))),
+                        format: MediaFormat::File,
+                    };
+                    let content = format!("media {i}").into_bytes();
+
+                    event_cache_store.add_media_content(&request, content.clone()).await.unwrap();
+                    assert_eq!(
+                        event_cache_store.get_media_content(&request).await.unwrap(),
+                        Some(content)
+                    );
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+    }
+
+    #[async_test]
+    async fn test_acquire_timeout() {
+        let event_cache_store = get_event_cache_store_with_config(
+            SqliteEventCacheStoreConfig::new()
+                .pool_max_size(1)
+                .acquire_timeout(Duration::from_millis(50)),
+        )
+        .await
+        .expect("creating media cache failed");
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+        event_cache_store.add_media_content(&request, b"media".to_vec()).await.unwrap();
+
+        // The only connection for reads is held.
+        let conn = event_cache_store.acquire().await.unwrap();
+        assert_matches!(
+            event_cache_store.get_media_content(&request).await,
+            Err(Error::PoolTimeout)
+        );
+
+        // The reads work again once it is released.
+        drop(conn);
+        assert_eq!(
+            event_cache_store.get_media_content(&request).await.unwrap().as_deref(),
+            Some(&b"media"[..])
+        );
+    }
+
+    #[async_test]
+    async fn test_media_loader() {
+        let loads = Arc::new(AtomicU32::new(0));
+        let event_cache_store =
+            get_event_cache_store_with_config(SqliteEventCacheStoreConfig::new().media_loader({
+                let loads = loads.clone();
+                move |request: MediaRequest| {
+                    let loads = loads.clone();
+                    async move {
+                        loads.fetch_add(1, SeqCst);
+                        if request.uri() == mxc_uri!("mxc://localhost/missing") {
+                            Ok(None)
+                        } else {
+                            Ok(Some(b"loaded".to_vec()))
+                        }
+                    }
+                }
+            }))
+            .await
+            .expect("creating media cache failed");
+
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+
+        // The content is loaded on the first miss, and stored.
+        assert_eq!(
+            event_cache_store.get_media_content(&request).await.unwrap().as_deref(),
+            Some(&b"loaded"[..])
+        );
+        assert_eq!(loads.load(SeqCst), 1);
+        assert!(event_cache_store.contains_media(&request).await.unwrap());
+
+        // The next read is a hit.
+        assert_eq!(
+            event_cache_store.get_media_content(&request).await.unwrap().as_deref(),
+            Some(&b"loaded"[..])
+        );
+        assert_eq!(loads.load(SeqCst), 1);
+
+        // A miss of the loader is a miss of the store.
+        let missing = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/missing").to_owned()),
+            format: MediaFormat::File,
+        };
+        assert!(event_cache_store.get_media_content(&missing).await.unwrap().is_none());
+        assert_eq!(loads.load(SeqCst), 2);
+        assert!(!event_cache_store.contains_media(&missing).await.unwrap());
+    }
+
+    #[async_test]
+    async fn test_media_loader_error() {
+        let event_cache_store = get_event_cache_store_with_config(
+            SqliteEventCacheStoreConfig::new()
+                .media_loader(|_| async { Err("the server is unreachable".into()) }),
+        )
+        .await
+        .expect("creating media cache failed");
+
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+        assert_matches!(
+            event_cache_store.get_media_content(&request).await,
+            Err(Error::MediaLoader(error)) => {
+                assert_eq!(error.to_string(), "the server is unreachable");
+            }
+        );
+        assert!(!event_cache_store.contains_media(&request).await.unwrap());
+    }
+
+    #[async_test]
+    async fn test_open_outcome() {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+
+        // A new database is created from scratch.
+        let (event_cache_store, outcome) = SqliteEventCacheStore::open_with_config_and_outcome(
+            &path,
+            None,
+            SqliteEventCacheStoreConfig::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(outcome, OpenOutcome { migrated_from: Some(0), migrated_to: DATABASE_VERSION });
+        drop(event_cache_store);
+
+        // The database is already up-to-date.
+        let (_, outcome) = SqliteEventCacheStore::open_with_config_and_outcome(
+            &path,
+            None,
+            SqliteEventCacheStoreConfig::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(outcome, OpenOutcome { migrated_from: None, migrated_to: DATABASE_VERSION });
+    }
+
+    #[async_test]
+    async fn test_ping() {
+        let event_cache_store = get_event_cache_store_with_config(
+            SqliteEventCacheStoreConfig::new()
+                .pool_max_size(1)
+                .acquire_timeout(Duration::from_millis(50)),
+        )
+        .await
+        .expect("creating media cache failed");
+
+        event_cache_store.ping().await.unwrap();
+
+        // The only connection for reads is held.
+        let conn = event_cache_store.acquire().await.unwrap();
+        assert_matches!(event_cache_store.ping().await, Err(Error::PoolTimeout));
+
+        drop(conn);
+        event_cache_store.ping().await.unwrap();
+    }
+
+    #[async_test]
+    async fn test_pool_max_size() {
+        let event_cache_store =
+            get_event_cache_store_with_config(SqliteEventCacheStoreConfig::new().pool_max_size(2))
+                .await
+                .expect("creating media cache failed");
+        assert_eq!(event_cache_store.pool.status().max_size, 2);
+
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+        event_cache_store.add_media_content(&request, b"media".to_vec()).await.unwrap();
+
+        // More concurrent reads than connections wait for a connection to be free.
+        let tasks = (0..10)
+            .map(|_| {
+                let event_cache_store = event_cache_store.clone();
+                let request = request.clone();
+                tokio::spawn(async move {
+                    event_cache_store.get_media_content(&request).await.unwrap().unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for task in tasks {
+            assert_eq!(task.await.unwrap(), b"media");
+        }
+    }
+
+    #[async_test]
+    async fn test_reads_during_write() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+        event_cache_store.add_media_content(&request, b"media".to_vec()).await.unwrap();
+
+        // Start a write transaction and keep it open.
+        let write_conn = event_cache_store.acquire_write().await.unwrap();
+        write_conn.execute_batch("BEGIN IMMEDIATE; DELETE FROM media;").await.unwrap();
+
+        // The reads don't wait for the writer, and see the last committed state.
+        assert_eq!(event_cache_store.media_cache_count().await.unwrap(), 1);
+        assert_eq!(
+            event_cache_store.get_media_content_raw(&request).await.unwrap().unwrap().data,
+            b"media"
+        );
+
+        write_conn.execute_batch("ROLLBACK;").await.unwrap();
+    }
+
+    #[async_test]
+    async fn test_open_in_memory() {
+        let event_cache_store = SqliteEventCacheStore::open_in_memory(None).await.unwrap();
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+
+        event_cache_store.add_media_content(&request, b"media".to_vec()).await.unwrap();
+        assert_eq!(
+            event_cache_store.get_media_content(&request).await.unwrap().as_deref(),
+            Some(&b"media"[..])
+        );
+
+        // Another store has its own database.
+        let other_event_cache_store = SqliteEventCacheStore::open_in_memory(None).await.unwrap();
+        assert!(other_event_cache_store.get_media_content(&request).await.unwrap().is_none());
+    }
+
+    #[async_test]
+    async fn test_incompatible_storage_format() {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+
+        let event_cache_store = SqliteEventCacheStore::open(&path, None).await.unwrap();
+        let conn = event_cache_store.acquire().await.unwrap();
+        assert_eq!(conn.get_kv(keys::STORAGE_FORMAT).await.unwrap(), Some(vec![STORAGE_FORMAT]));
+
+        // Pretend the store was created by another version of the SDK.
+        conn.set_kv(keys::STORAGE_FORMAT, vec![STORAGE_FORMAT + 1]).await.unwrap();
+        drop(conn);
+        drop(event_cache_store);
+
+        assert_matches!(
+            SqliteEventCacheStore::open(&path, None).await,
+            Err(OpenStoreError::IncompatibleStorageFormat { found, supported }) => {
+                assert_eq!(found, STORAGE_FORMAT + 1);
+                assert_eq!(supported, STORAGE_FORMAT);
+            }
+        );
+    }
+
+    #[async_test]
+    async fn test_get_media_content_range() {
+        let event_cache_store = get_event_cache_store_with_config(
+            SqliteEventCacheStoreConfig::new().media_chunk_size(4),
+        )
+        .await
+        .expect("creating media cache failed");
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+
+        assert!(event_cache_store.get_media_content_range(&request, 0, 1).await.unwrap().is_none());
+
+        // The content is split in rows of 4 bytes.
+        event_cache_store.add_media_content(&request, b"0123456789".to_vec()).await.unwrap();
+
+        for (offset, len, expected) in [
+            (0, 3, &b"012"[..]),
+            (3, 4, b"3456"),
+            (1, 8, b"12345678"),
+            (6, 4, b"6789"),
+            (0, 10, b"0123456789"),
+            (10, 0, b""),
+        ] {
+            assert_eq!(
+                event_cache_store
+                    .get_media_content_range(&request, offset, len)
+                    .await
+                    .unwrap()
+                    .as_deref(),
+                Some(expected),
+                "range of {len} bytes at {offset}"
+            );
+        }
+
+        assert_matches!(
+            event_cache_store.get_media_content_range(&request, 8, 3).await,
+            Err(Error::InvalidMediaRange { offset: 8, len: 3, size: 10 })
+        );
+        assert_matches!(
+            event_cache_store.get_media_content_range(&request, usize::MAX, 2).await,
+            Err(Error::InvalidMediaRange { size: 10, .. })
+        );
+    }
+
+    #[async_test]
+    async fn test_get_media_content_range_compressed() {
+        let event_cache_store = get_event_cache_store_with_config(
+            SqliteEventCacheStoreConfig::new().compress_media(true),
+        )
+        .await
+        .expect("creating media cache failed");
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+
+        // A content that compresses well, so it is stored compressed.
+        let content = b"0123456789".repeat(100);
+        event_cache_store.add_media_content(&request, content.clone()).await.unwrap();
+
+        assert_eq!(
+            event_cache_store.get_media_content_range(&request, 995, 5).await.unwrap().as_deref(),
+            Some(&content[995..])
+        );
+        assert_matches!(
+            event_cache_store.get_media_content_range(&request, 995, 6).await,
+            Err(Error::InvalidMediaRange { size: 1000, .. })
+        );
+    }
+
+    #[async_test]
+    async fn test_get_media_content_stream() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+
+        assert!(event_cache_store
+            .get_media_content_stream(&request, NonZeroUsize::new(1024).unwrap())
+            .await
+            .unwrap()
+            .is_none());
+
+        // A content of a few MiB that is not a multiple of the chunk size.
+        let content = (0..3 * 1024 * 1024 + 100).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        event_cache_store.add_media_content(&request, content.clone()).await.unwrap();
+
+        let chunk_size = 64 * 1024;
+        let chunks = event_cache_store
+            .get_media_content_stream(&request, NonZeroUsize::new(chunk_size).unwrap())
+            .await
+            .unwrap()
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+
+        assert_eq!(chunks.len(), content.len().div_ceil(chunk_size));
+        assert!(chunks[..chunks.len() - 1].iter().all(|chunk| chunk.len() == chunk_size));
+        assert_eq!(chunks.last().unwrap().len(), 100);
+        assert_eq!(chunks.concat(), content);
+    }
+
+    #[async_test]
+    async fn test_get_media_content_stream_media_changed() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+        event_cache_store.add_media_content(&request, vec![1; 100]).await.unwrap();
+
+        let mut chunks = Box::pin(
+            event_cache_store
+                .get_media_content_stream(&request, NonZeroUsize::new(10).unwrap())
+                .await
+                .unwrap()
+                .unwrap(),
+        );
+        assert_eq!(chunks.try_next().await.unwrap().unwrap(), [1; 10]);
+
+        // The content is replaced by another one.
+        event_cache_store.add_media_content(&request, vec![2; 100]).await.unwrap();
+
+        assert_matches!(chunks.try_next().await, Err(Error::MediaChangedWhileStreaming));
+    }
+
+    #[async_test]
+    async fn test_get_media_content_stream_exclusive_locking_mode() {
+        let event_cache_store = get_event_cache_store_with_config(
+            SqliteEventCacheStoreConfig::new()
+                .locking_mode(LockingMode::Exclusive)
+                .acquire_timeout(Duration::from_secs(5)),
+        )
+        .await
+        .expect("creating media cache failed");
+        let request = |id: &str| MediaRequest {
+            source: MediaSource::Plain(OwnedMxcUri::from(format!("mxc://localhost/{id}"))),
+            format: MediaFormat::File,
+        };
+        event_cache_store.add_media_content(&request("a"), vec![1; 100]).await.unwrap();
+
+        let mut chunks = Box::pin(
+            event_cache_store
+                .get_media_content_stream(&request("a"), NonZeroUsize::new(10).unwrap())
+                .await
+                .unwrap()
+                .unwrap(),
+        );
+        assert_eq!(chunks.try_next().await.unwrap().unwrap(), [1; 10]);
+
+        // The single connection is not held between the chunks.
+        event_cache_store.add_media_content(&request("b"), vec![2; 10]).await.unwrap();
+
+        let rest = chunks.try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(rest.concat(), [1; 90]);
+    }
+
+    #[async_test]
+    async fn test_media_chunks() {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+        let config = SqliteEventCacheStoreConfig::new().media_chunk_size(1024);
+        let event_cache_store =
+            SqliteEventCacheStore::open_with_config(&path, None, config).await.unwrap();
+
+        let chunks_count = || async {
+            event_cache_store
+                .acquire()
+                .await
+                .unwrap()
+                .query_row("SELECT COUNT(*) FROM media_blob_chunks", (), |row| row.get::<_, u64>(0))
+                .await
+                .unwrap()
+        };
+
+        let small_request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/small").to_owned()),
+            format: MediaFormat::File,
+        };
+        let large_request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/large").to_owned()),
+            format: MediaFormat::File,
+        };
+        let small_content = vec![1; 100];
+        let large_content = (0..5000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+        // A content smaller than the chunk size is stored in a single row.
+        event_cache_store.add_media_content(&small_request, small_content.clone()).await.unwrap();
+        assert_eq!(chunks_count().await, 0);
+
+        // A larger content is split, with the first chunk stored with the hash.
+        event_cache_store.add_media_content(&large_request, large_content.clone()).await.unwrap();
+        assert_eq!(chunks_count().await, 4);
+        assert_eq!(event_cache_store.media_cache_size().await.unwrap(), 5100);
+
+        // Both are read back whole.
+        assert_eq!(
+            event_cache_store.get_media_content(&small_request).await.unwrap(),
+            Some(small_content)
+        );
+        assert_eq!(
+            event_cache_store.get_media_content(&large_request).await.unwrap(),
+            Some(large_content.clone())
+        );
+        let streamed = event_cache_store
+            .get_media_content_stream(&large_request, NonZeroUsize::new(4096).unwrap())
+            .await
+            .unwrap()
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(streamed.concat(), large_content);
+
+        // The chunks are removed with the content.
+        event_cache_store.remove_media_content(&large_request).await.unwrap();
+        assert_eq!(chunks_count().await, 0);
+        assert_eq!(event_cache_store.media_cache_size().await.unwrap(), 100);
+    }
+
+    #[async_test]
+    async fn test_compress_media() {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+
+        let plain_request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/plain").to_owned()),
+            format: MediaFormat::File,
+        };
+        let compressible_request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/compressible").to_owned()),
+            format: MediaFormat::File,
+        };
+        let tiny_request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/tiny").to_owned()),
+            format: MediaFormat::File,
+        };
+        let content = b"<svg></svg>".repeat(1000);
+
+        // Add a media without compression.
+        let event_cache_store = SqliteEventCacheStore::open(&path, None).await.unwrap();
+        event_cache_store.add_media_content(&plain_request, content[1..].to_vec()).await.unwrap();
+        drop(event_cache_store);
+
+        let config = SqliteEventCacheStoreConfig::new().compress_media(true);
+        let event_cache_store =
+            SqliteEventCacheStore::open_with_config(&path, None, config).await.unwrap();
+
+        // A compressible content is stored compressed.
+        event_cache_store.add_media_content(&compressible_request, content.clone()).await.unwrap();
+        let raw = event_cache_store.get_media_content_raw(&compressible_request).await.unwrap();
+        let raw = raw.unwrap();
+        assert!(raw.compressed);
+        assert!(raw.data.len() < content.len() / 10, "{} is not small enough", raw.data.len());
+        assert_eq!(
+            event_cache_store.get_media_content(&compressible_request).await.unwrap(),
+            Some(content.clone())
+        );
+        assert_matches!(
+            event_cache_store
+                .get_media_content_stream(&compressible_request, NonZeroUsize::new(1024).unwrap())
+                .await,
+            Err(Error::CompressedMediaNotStreamable)
+        );
+
+        // A content that doesn't get smaller is stored uncompressed.
+        event_cache_store.add_media_content(&tiny_request, vec![42]).await.unwrap();
+        let raw = event_cache_store.get_media_content_raw(&tiny_request).await.unwrap().unwrap();
+        assert!(!raw.compressed);
+        assert_eq!(raw.data, [42]);
+
+        // The uncompressed content added before can still be read.
+        let raw = event_cache_store.get_media_content_raw(&plain_request).await.unwrap().unwrap();
+        assert!(!raw.compressed);
+        assert_eq!(
+            event_cache_store.get_media_content(&plain_request).await.unwrap(),
+            Some(content[1..].to_vec())
+        );
+    }
+
+    #[async_test]
+    async fn test_list_media() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let requests = ["first", "second", "third"].map(|id| MediaRequest {
+            source: MediaSource::Plain(OwnedMxcUri::from(format!("mxc://localhost/{id}"))),
+            format: MediaFormat::File,
+        });
+
+        assert!(event_cache_store.list_media(10, 0).await.unwrap().is_empty());
+
+        for (i, request) in requests.iter().enumerate() {
+            event_cache_store.add_media_content(request, vec![0; i + 1]).await.unwrap();
+        }
+
+        // Make sure the access times are increasing.
+        let conn = event_cache_store.acquire().await.unwrap();
+        conn.execute("UPDATE media SET last_access = 1000 + rowid * 10", ()).await.unwrap();
+        drop(conn);
+
+        let entries = event_cache_store.list_media(10, 0).await.unwrap();
+        assert_eq!(entries.len(), 3);
+        for (i, (entry, request)) in entries.iter().zip(&requests).enumerate() {
+            assert_eq!(entry.uri_key, request.source.unique_key().as_bytes());
+            assert_eq!(entry.format_key, request.format.unique_key().as_bytes());
+            assert_eq!(entry.size, i as u64 + 1);
+        }
+        assert!(entries[0].last_access < entries[1].last_access);
+        assert!(entries[1].last_access < entries[2].last_access);
+
+        // Reading a media makes it the most recently used.
+        event_cache_store.get_media_content(&requests[0]).await.unwrap();
+        let entries = event_cache_store.list_media(10, 0).await.unwrap();
+        let sizes = entries.iter().map(|entry| entry.size).collect::<Vec<_>>();
+        assert_eq!(sizes, [2, 3, 1]);
+
+        // The list can be paginated.
+        let page = event_cache_store.list_media(1, 1).await.unwrap();
+        assert_eq!(page, entries[1..2]);
+    }
+
+    #[async_test]
+    async fn test_oldest_media() {
+        let now_millis = Arc::new(AtomicU64::new(1000));
+        let event_cache_store = get_event_cache_store_with_config(
+            SqliteEventCacheStoreConfig::new().defer_access_times(10).clock({
+                let now_millis = now_millis.clone();
+                move || SystemTime::UNIX_EPOCH + Duration::from_millis(now_millis.load(SeqCst))
+            }),
+        )
+        .await
+        .expect("creating media cache failed");
+        let requests = ["a", "b", "c", "d", "e"].map(|id| MediaRequest {
+            source: MediaSource::Plain(OwnedMxcUri::from(format!("mxc://localhost/{id}"))),
+            format: MediaFormat::File,
+        });
+
+        assert!(event_cache_store.oldest_media(3).await.unwrap().is_empty());
+
+        for (i, request) in requests.iter().enumerate() {
+            now_millis.fetch_add(10, SeqCst);
+            event_cache_store.add_media_content(request, vec![0; i + 1]).await.unwrap();
+        }
+
+        // Read `a` and `c` again, which are pending accesses that are written first.
+        for index in [2, 0] {
+            now_millis.fetch_add(10, SeqCst);
+            event_cache_store.get_media_content(&requests[index]).await.unwrap();
+        }
+
+        // The order is now `b`, `d`, `e`, `c`, `a`.
+        let oldest = event_cache_store.oldest_media(3).await.unwrap();
+        let keys = oldest.iter().map(|entry| entry.uri_key.clone()).collect::<Vec<_>>();
+        let expected = [1, 3, 4].map(|index| requests[index].source.unique_key().into_bytes());
+        assert_eq!(keys, expected);
+        let sizes = oldest.iter().map(|entry| entry.size).collect::<Vec<_>>();
+        assert_eq!(sizes, [2, 4, 5]);
+        let last_accesses = oldest.iter().map(|entry| entry.last_access).collect::<Vec<_>>();
+        assert_eq!(
+            last_accesses,
+            [1020, 1040, 1050].map(|millis| SystemTime::UNIX_EPOCH + Duration::from_millis(millis))
+        );
+
+        // Asking for more than the cache contains returns all the media.
+        assert_eq!(event_cache_store.oldest_media(10).await.unwrap().len(), 5);
+        assert!(event_cache_store.oldest_media(0).await.unwrap().is_empty());
+    }
+
+    #[async_test]
+    async fn test_contains_media_does_not_update_last_access() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+        let missing_request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/missing").to_owned()),
+            format: MediaFormat::File,
+        };
+        event_cache_store.add_media_content(&request, b"hello".to_vec()).await.unwrap();
+
+        let conn = event_cache_store.acquire().await.unwrap();
+        conn.execute("UPDATE media SET last_access = 1000", ()).await.unwrap();
+        drop(conn);
+
+        assert!(event_cache_store.contains_media(&request).await.unwrap());
+        assert!(!event_cache_store.contains_media(&missing_request).await.unwrap());
+
+        let last_access = event_cache_store
+            .acquire()
+            .await
+            .unwrap()
+            .query_row("SELECT last_access FROM media", (), |row| row.get::<_, i64>(0))
+            .await
+            .unwrap();
+        assert_eq!(last_access, 1000);
+    }
+
+    #[async_test]
+    async fn test_touch_media_content() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let old_request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/old").to_owned()),
+            format: MediaFormat::File,
+        };
+        let new_request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/new").to_owned()),
+            format: MediaFormat::File,
+        };
+        event_cache_store.add_media_content(&old_request, b"old".to_vec()).await.unwrap();
+        event_cache_store.add_media_content(&new_request, b"new".to_vec()).await.unwrap();
+
+        // Pretend the media were accessed a while ago, in order.
+        let conn = event_cache_store.acquire().await.unwrap();
+        conn.execute("UPDATE media SET last_access = 1000 + rowid", ()).await.unwrap();
+        drop(conn);
+        assert_eq!(
+            get_event_cache_store_content_sorted_by_last_access(&event_cache_store).await,
+            [b"new".to_vec(), b"old".to_vec()]
+        );
+
+        event_cache_store.touch_media_content(&old_request).await.unwrap();
+        assert_eq!(
+            get_event_cache_store_content_sorted_by_last_access(&event_cache_store).await,
+            [b"old".to_vec(), b"new".to_vec()]
+        );
+
+        // Touching a media that is not in the cache does nothing.
+        let missing_request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/missing").to_owned()),
+            format: MediaFormat::File,
+        };
+        event_cache_store.touch_media_content(&missing_request).await.unwrap();
+        assert_eq!(event_cache_store.media_cache_count().await.unwrap(), 2);
+    }
+
+    #[async_test]
+    async fn test_on_media_evicted() {
+        let evicted = Arc::new(StdMutex::new(Vec::new()));
+        let event_cache_store = get_event_cache_store_with_config(
+            SqliteEventCacheStoreConfig::new()
+                .media_retention_policy(MediaRetentionPolicy {
+                    max_cache_size: Some(10),
+                    max_age: Some(Duration::from_secs(60)),
+                    ..Default::default()
+                })
+                .on_media_evicted({
+                    let evicted = evicted.clone();
+                    move |media| evicted.lock().unwrap().extend_from_slice(media)
+                }),
+        )
+        .await
+        .expect("creating media cache failed");
+
+        let request = |id: &str| MediaRequest {
+            source: MediaSource::Plain(OwnedMxcUri::from(format!("mxc://localhost/{id}"))),
+            format: MediaFormat::File,
+        };
+
+        event_cache_store.add_media_content(&request("a"), vec![1; 6]).await.unwrap();
+        assert!(evicted.lock().unwrap().is_empty());
+
+        // Adding `b` goes over the maximum size, `a` is evicted.
+        event_cache_store.add_media_content(&request("b"), vec![2; 6]).await.unwrap();
+        assert_eq!(
+            *evicted.lock().unwrap(),
+            [EvictedMedia {
+                uri_key: b"mxc://localhost/a".to_vec(),
+                format_key: MediaFormat::File.unique_key().into_bytes(),
+                size: 6,
+            }]
+        );
+
+        // Expired media are reported too.
+        event_cache_store
+            .acquire()
+            .await
+            .unwrap()
+            .execute("UPDATE media SET last_access = 0", ())
+            .await
+            .unwrap();
+        assert_eq!(event_cache_store.clean_up_media_cache().await.unwrap(), 1);
+        let evicted = evicted.lock().unwrap();
+        assert_eq!(evicted.len(), 2);
+        assert_eq!(evicted[1].uri_key, b"mxc://localhost/b");
+    }
+
+    #[async_test]
+    async fn test_export_to_dir() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let uri = mxc_uri!("mxc://localhost/media");
+        let file_request =
+            MediaRequest { source: MediaSource::Plain(uri.to_owned()), format: MediaFormat::File };
+        let thumbnail_request = MediaRequest {
+            source: MediaSource::Plain(uri.to_owned()),
+            format: MediaFormat::Thumbnail(MediaThumbnailSettings::new(
+                Method::Scale,
+                uint!(100),
+                uint!(100),
+            )),
+        };
+        event_cache_store
+            .add_media_content_with_content_type(&file_request, b"file".to_vec(), Some("image/png"))
+            .await
+            .unwrap();
+        event_cache_store
+            .add_media_content(&thumbnail_request, b"thumbnail".to_vec())
+            .await
+            .unwrap();
+
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+        event_cache_store.export_to_dir(&path).await.unwrap();
+
+        let manifest: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(path.join("manifest.json")).unwrap()).unwrap();
+        let media = manifest["media"].as_object().unwrap();
+        assert_eq!(media.len(), 2);
+
+        let mut contents = media
+            .iter()
+            .map(|(file_name, media)| {
+                let content = std::fs::read(path.join(file_name)).unwrap();
+                assert_eq!(media["size"], content.len());
+                (content, media["content_type"].as_str().map(ToOwned::to_owned))
+            })
+            .collect::<Vec<_>>();
+        contents.sort();
+        assert_eq!(
+            contents,
+            [(b"file".to_vec(), Some("image/png".to_owned())), (b"thumbnail".to_vec(), None)]
+        );
+    }
+
+    #[async_test]
+    async fn test_import_from_dir() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let request = |id: &str| MediaRequest {
+            source: MediaSource::Plain(OwnedMxcUri::from(format!("mxc://localhost/{id}"))),
+            format: MediaFormat::File,
+        };
+        event_cache_store
+            .add_media_content_with_content_type(&request("a"), vec![1; 10], Some("image/png"))
+            .await
+            .unwrap();
+        event_cache_store.add_media_content(&request("b"), vec![2; 20]).await.unwrap();
+        event_cache_store.add_media_content(&request("c"), vec![3; 30]).await.unwrap();
+
+        // `b` is the least recently used, then `c`, then `a`.
+        let conn = event_cache_store.acquire().await.unwrap();
+        for (id, last_access) in [("a", 300), ("b", 100), ("c", 200)] {
+            let uri = format!("mxc://localhost/{id}").into_bytes();
+            conn.execute("UPDATE media SET last_access = ? WHERE uri = ?", (last_access, uri))
+                .await
+                .unwrap();
+        }
+        drop(conn);
+        let entries = event_cache_store.list_media(10, 0).await.unwrap();
+
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+        event_cache_store.export_to_dir(&path).await.unwrap();
+
+        event_cache_store.clear_all_media().await.unwrap();
+        assert_eq!(event_cache_store.media_cache_count().await.unwrap(), 0);
+
+        event_cache_store.import_from_dir(&path).await.unwrap();
+
+        // The last accesses are preserved.
+        assert_eq!(event_cache_store.list_media(10, 0).await.unwrap(), entries);
+        assert_eq!(
+            entries.iter().map(|entry| entry.last_access).collect::<Vec<_>>(),
+            [100, 200, 300].map(|millis| SystemTime::UNIX_EPOCH + Duration::from_millis(millis))
+        );
+        assert_eq!(
+            event_cache_store.get_media_content_with_content_type(&request("a")).await.unwrap(),
+            Some((vec![1; 10], Some("image/png".to_owned())))
+        );
+
+        // The media that are too large are skipped.
+        let other_event_cache_store = get_event_cache_store_with_config(
+            SqliteEventCacheStoreConfig::new().media_retention_policy(MediaRetentionPolicy {
+                max_file_size: Some(20),
+                ..Default::default()
+            }),
+        )
+        .await
+        .unwrap();
+        other_event_cache_store.import_from_dir(&path).await.unwrap();
+        let sizes = other_event_cache_store
+            .list_media(10, 0)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.size)
+            .collect::<Vec<_>>();
+        assert_eq!(sizes, [20, 10]);
+
+        // The export can't be imported in another account namespace.
+        let namespaced_event_cache_store = get_event_cache_store_with_config(
+            SqliteEventCacheStoreConfig::new().account_namespace("alice"),
+        )
+        .await
+        .unwrap();
+        assert_matches!(
+            namespaced_event_cache_store.import_from_dir(&path).await,
+            Err(Error::ExportNamespaceMismatch)
+        );
+    }
+
+    #[async_test]
+    async fn test_import_from_dir_rejects_other_file_names() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+        event_cache_store.add_media_content(&request, b"hello".to_vec()).await.unwrap();
+
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+        event_cache_store.export_to_dir(&path).await.unwrap();
+        let manifest_path = path.join("manifest.json");
+        let manifest: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&manifest_path).unwrap()).unwrap();
+        let (_, media) = manifest["media"].as_object().unwrap().iter().next().unwrap();
+
+        // A file outside of the export, or another file of the export.
+        std::fs::write(TMP_DIR.path().join("secret"), b"secret").unwrap();
+        for file_name in ["../secret", "manifest.json"] {
+            let mut manifest = manifest.clone();
+            manifest["media"] = serde_json::Value::Object(
+                [(file_name.to_owned(), media.clone())].into_iter().collect(),
+            );
+            std::fs::write(&manifest_path, serde_json::to_vec(&manifest).unwrap()).unwrap();
+
+            assert_matches!(
+                event_cache_store.import_from_dir(&path).await,
+                Err(Error::InvalidExportFileName(name)) if name == file_name
+            );
+        }
+    }
+
+    #[async_test]
+    async fn test_stream_all_media() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+
+        assert!(event_cache_store
+            .stream_all_media()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap()
+            .is_empty());
+
+        // More media than in a page.
+        let items = (0..MEDIA_PAGE_SIZE as u32 * 2 + 3)
+            .map(|i| {
+                let request = MediaRequest {
+                    source: MediaSource::Plain(OwnedMxcUri::from(format!("mxc://localhost/{i}"))),
+                    format: MediaFormat::File,
+                };
+                (request, i.to_be_bytes().to_vec())
+            })
+            .collect::<Vec<_>>();
+        event_cache_store.add_media_content_batch(&items).await.unwrap();
+
+        let mut media = event_cache_store.stream_all_media().try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(media.len(), items.len());
+
+        media.sort_by(|(a, _), (b, _)| a.uri_key.cmp(&b.uri_key));
+        let mut expected = items
+            .iter()
+            .map(|(request, content)| (request.source.unique_key().into_bytes(), content.clone()))
+            .collect::<Vec<_>>();
+        expected.sort();
+        for ((entry, content), (uri_key, expected_content)) in media.into_iter().zip(expected) {
+            assert_eq!(entry.uri_key, uri_key);
+            assert_eq!(entry.size, 4);
+            assert_eq!(content, expected_content);
+        }
+    }
+
+    #[async_test]
+    async fn test_event_chunks_after_reopen() {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+        let room_id = room_id!("!room:localhost");
+        let event = SyncTimelineEvent::new(
+            Raw::from_json_string(
+                json!({
+                    "type": "m.room.message",
+                    "event_id": "$ev",
+                    "sender": "@alice:localhost",
+                    "origin_server_ts": 0,
+                    "content": { "msgtype": "m.text", "body": "hello" },
+                })
+                .to_string(),
+            )
+            .unwrap(),
+        );
+
+        let event_cache_store = SqliteEventCacheStore::open(&path, None).await.unwrap();
+        // Save the chunks in the reverse order of the timeline.
+        for (id, previous, next) in [(2, Some(1), None), (1, Some(0), Some(2)), (0, None, Some(1))]
+        {
+            let content = if id == 1 {
+                EventChunkContent::Events(vec![event.clone()])
+            } else {
+                EventChunkContent::Gap { prev_token: format!("token_{id}") }
+            };
+            event_cache_store
+                .save_event_chunk(room_id, EventChunk { id, previous, next, content })
+                .await
+                .unwrap();
+        }
+        drop(event_cache_store);
+
+        let event_cache_store = SqliteEventCacheStore::open(&path, None).await.unwrap();
+        let chunks = event_cache_store.load_event_chunks(room_id).await.unwrap();
+
+        assert_eq!(
+            chunks.iter().map(|chunk| (chunk.id, chunk.previous, chunk.next)).collect::<Vec<_>>(),
+            [(0, None, Some(1)), (1, Some(0), Some(2)), (2, Some(1), None)]
+        );
+        assert_matches!(&chunks[0].content, EventChunkContent::Gap { prev_token } => {
+            assert_eq!(prev_token, "token_0");
+        });
+        assert_matches!(&chunks[1].content, EventChunkContent::Events(events) => {
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0].event_id().unwrap(), "$ev");
+        });
+        assert_matches!(&chunks[2].content, EventChunkContent::Gap { prev_token } => {
+            assert_eq!(prev_token, "token_2");
+        });
+    }
+
+    #[async_test]
+    async fn test_database_file_name() {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+
+        let first_store = SqliteEventCacheStore::open_with_config(
+            &path,
+            None,
+            SqliteEventCacheStoreConfig::new().database_file_name("first.sqlite3"),
+        )
+        .await
+        .unwrap();
+        let second_store = SqliteEventCacheStore::open_with_config(
+            &path,
+            None,
+            SqliteEventCacheStoreConfig::new().database_file_name("second.sqlite3"),
+        )
+        .await
+        .unwrap();
+
+        // The stores are independent.
+        first_store.add_media_content(&request, b"first".to_vec()).await.unwrap();
+        assert!(second_store.get_media_content(&request).await.unwrap().is_none());
+        second_store.add_media_content(&request, b"second".to_vec()).await.unwrap();
+        assert_eq!(first_store.get_media_content(&request).await.unwrap().unwrap(), b"first");
+        assert_eq!(second_store.get_media_content(&request).await.unwrap().unwrap(), b"second");
+
+        assert!(path.join("first.sqlite3").exists());
+        assert!(path.join("second.sqlite3").exists());
+        assert!(!path.join("matrix-sdk-event-cache.sqlite3").exists());
+
+        // The store can be opened in read-only mode with the same name.
+        let read_only_store = SqliteEventCacheStore::open_read_only_with_config(
+            &path,
+            None,
+            SqliteEventCacheStoreConfig::new().database_file_name("second.sqlite3"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(read_only_store.get_media_content(&request).await.unwrap().unwrap(), b"second");
+
+        // The name can't be used to access a file outside of the directory.
+        for file_name in ["", ".", "..", "../other.sqlite3", "dir/other.sqlite3", "/other.sqlite3"]
+        {
+            assert_matches!(
+                SqliteEventCacheStore::open_with_config(
+                    &path,
+                    None,
+                    SqliteEventCacheStoreConfig::new().database_file_name(file_name),
+                )
+                .await,
+                Err(OpenStoreError::InvalidDatabaseFileName(name)) => {
+                    assert_eq!(name, file_name);
+                }
+            );
+        }
+    }
+
+    #[async_test]
+    async fn test_open_with_sqlite_config() {
+        let name = format!("test_open_with_sqlite_config_{}", NUM.fetch_add(1, SeqCst));
+        let sqlite_config =
+            || deadpool_sqlite::Config::new(format!("file:{name}?mode=memory&cache=shared"));
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+
+        let event_cache_store = SqliteEventCacheStore::open_with_sqlite_config(
+            sqlite_config(),
+            None,
+            SqliteEventCacheStoreConfig::default(),
+        )
+        .await
+        .unwrap();
+        event_cache_store.add_media_content(&request, b"media".to_vec()).await.unwrap();
+
+        // Another store opened with the same named in-memory database shares the
+        // data.
+        let other_store = SqliteEventCacheStore::open_with_sqlite_config(
+            sqlite_config(),
+            None,
+            SqliteEventCacheStoreConfig::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(other_store.get_media_content(&request).await.unwrap().unwrap(), b"media");
+
+        // Nothing was written to disk.
+        assert!(!Path::new(&name).exists());
+        assert!(!Path::new(&format!("file:{name}?mode=memory&cache=shared")).exists());
+    }
+
+    #[async_test]
+    async fn test_storage_full() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+
+        // Limit the size of the database to its current size, to simulate a full
+        // storage.
+        let conn = event_cache_store.acquire_write().await.unwrap();
+        let page_count =
+            conn.query_row("PRAGMA page_count", (), |row| row.get::<_, u64>(0)).await.unwrap();
+        conn.execute_batch(format!("PRAGMA max_page_count = {page_count};")).await.unwrap();
+        drop(conn);
+
+        assert_matches!(
+            event_cache_store.add_media_content(&request, vec![0; 1024 * 1024]).await,
+            Err(Error::StorageFull(_))
+        );
+        // The error is kept when the error type is erased.
+        assert_matches!(
+            event_cache_store
+                .clone()
+                .into_event_cache_store()
+                .add_media_content(&request, vec![0; 1024 * 1024])
+                .await,
+            Err(EventCacheStoreError::StorageFull)
+        );
+
+        // Nothing was stored.
+        assert!(event_cache_store.get_media_content(&request).await.unwrap().is_none());
+    }
+
+    #[async_test]
+    async fn test_open_disabled() {
+        let event_cache_store = SqliteEventCacheStore::open_disabled().await.unwrap();
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+        assert!(!event_cache_store.is_media_cache_enabled());
+
+        // Adding media is a no-op.
+        event_cache_store.add_media_content(&request, b"media".to_vec()).await.unwrap();
+        event_cache_store
+            .add_media_content_batch(&[(request.clone(), b"media".to_vec())])
+            .await
+            .unwrap();
+        assert_eq!(event_cache_store.media_cache_count().await.unwrap(), 0);
+
+        // Getting media always misses.
+        assert!(event_cache_store.get_media_content(&request).await.unwrap().is_none());
+        assert!(event_cache_store.get_media_content_raw(&request).await.unwrap().is_none());
+
+        // The other methods are harmless.
+        event_cache_store.remove_media_content(&request).await.unwrap();
+        event_cache_store.remove_media_content_for_uri(request.uri()).await.unwrap();
+        assert_eq!(event_cache_store.clean_up_media_cache().await.unwrap(), 0);
+        event_cache_store.clear_session_data().await.unwrap();
+    }
+
+    #[async_test]
+    async fn test_set_media_cache_enabled() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let request = |name: &str| MediaRequest {
+            source: MediaSource::Plain(OwnedMxcUri::from(format!("mxc://localhost/{name}"))),
+            format: MediaFormat::File,
+        };
+        assert!(event_cache_store.is_media_cache_enabled());
+
+        event_cache_store.add_media_content(&request("kept"), b"kept".to_vec()).await.unwrap();
+
+        // Disable the media cache, from a clone of the store.
+        event_cache_store.clone().set_media_cache_enabled(false);
+        assert!(!event_cache_store.is_media_cache_enabled());
+
+        event_cache_store
+            .add_media_content(&request("dropped"), b"dropped".to_vec())
+            .await
+            .unwrap();
+        assert!(event_cache_store.get_media_content(&request("kept")).await.unwrap().is_none());
+        assert!(event_cache_store.get_media_content(&request("dropped")).await.unwrap().is_none());
+        assert_eq!(event_cache_store.media_cache_count().await.unwrap(), 1);
+
+        // The media that were in the cache are still there when it is enabled again.
+        event_cache_store.set_media_cache_enabled(true);
+        assert_eq!(
+            event_cache_store.get_media_content(&request("kept")).await.unwrap().unwrap(),
+            b"kept"
+        );
+        assert!(event_cache_store.get_media_content(&request("dropped")).await.unwrap().is_none());
+    }
+
+    #[async_test]
+    async fn test_hash_keys() {
+        let uri = mxc_uri!("mxc://localhost/media");
+        let request =
+            MediaRequest { source: MediaSource::Plain(uri.to_owned()), format: MediaFormat::File };
+        let stored_uris = |event_cache_store: SqliteEventCacheStore| async move {
+            let conn = event_cache_store.acquire().await.unwrap();
+            conn.prepare("SELECT uri FROM media", |mut stmt| {
+                stmt.query(())?.mapped(|row| row.get::<_, Vec<u8>>(0)).collect()
+            })
+            .await
+            .unwrap()
+        };
+
+        // By default, the keys are stored as is.
+        let event_cache_store = get_event_cache_store().await.unwrap();
+        event_cache_store.add_media_content(&request, b"media".to_vec()).await.unwrap();
+        assert_eq!(stored_uris(event_cache_store).await, vec![uri.as_bytes().to_vec()]);
+
+        // With the option, the keys are hashed.
+        let event_cache_store =
+            get_event_cache_store_with_config(SqliteEventCacheStoreConfig::new().hash_keys(true))
+                .await
+                .unwrap();
+        event_cache_store.add_media_content(&request, b"media".to_vec()).await.unwrap();
+
+        let uris = stored_uris(event_cache_store.clone()).await;
+        assert_eq!(uris.len(), 1);
+        assert_eq!(uris[0].len(), 32);
+        assert_ne!(uris[0], uri.as_bytes());
+
+        // The media can still be accessed with their keys.
+        assert_eq!(event_cache_store.get_media_content(&request).await.unwrap().unwrap(), b"media");
+        event_cache_store.remove_media_content_for_uri(uri).await.unwrap();
+        assert!(event_cache_store.get_media_content(&request).await.unwrap().is_none());
+
+        // But they can't be matched by server.
+        assert_matches!(
+            event_cache_store.remove_media_by_server(server_name!("localhost")).await,
+            Err(Error::EncryptedKeysNotQueryable)
+        );
+    }
+
+    #[async_test]
+    async fn test_apply_migrations() {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name).join("matrix-sdk-event-cache.sqlite3");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let pool = deadpool_sqlite::Config::new(&path).create_pool(Runtime::Tokio1).unwrap();
+        let conn = pool.get().await.unwrap();
+
+        // Create a database at the first version, the initial values are written with
+        // it.
+        apply_migrations(&conn, 0, &MIGRATIONS[..1], None, &[("initial", vec![1])]).await.unwrap();
+        assert_eq!(conn.db_version().await.unwrap(), 1);
+        assert_eq!(conn.get_kv("initial").await.unwrap(), Some(vec![1]));
+
+        let migrations = [
+            MIGRATIONS[0],
+            "CREATE TABLE foo (id INTEGER PRIMARY KEY);",
+            "ALTER TABLE foo ADD COLUMN bar TEXT;",
+        ];
+
+        // A failing migration is rolled back, but the previous ones are kept.
+        let failing_migrations = [migrations[0], migrations[1], "NOT SQL;"];
+        apply_migrations(&conn, 1, &failing_migrations, None, &[]).await.unwrap_err();
+        let version = conn.db_version().await.unwrap();
+        assert_eq!(version, 2);
+
+        // The upgrade resumes where it stopped.
+        apply_migrations(&conn, version, &migrations, None, &[]).await.unwrap();
+        assert_eq!(conn.db_version().await.unwrap(), 3);
+
+        let columns = conn
+            .prepare("SELECT name FROM pragma_table_info('foo')", |mut stmt| {
+                stmt.query_map((), |row| row.get::<_, String>(0))?.collect::<Result<Vec<_>, _>>()
+            })
+            .await
+            .unwrap();
+        assert_eq!(columns, ["id", "bar"]);
+
+        // An up-to-date database is left untouched.
+        apply_migrations(&conn, 3, &migrations, None, &[]).await.unwrap();
+        assert_eq!(conn.db_version().await.unwrap(), 3);
+    }
+
+    #[async_test]
+    async fn test_last_access_millis_migration() {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name).join("matrix-sdk-event-cache.sqlite3");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let pool = deadpool_sqlite::Config::new(&path).create_pool(Runtime::Tokio1).unwrap();
+        let conn = pool.get().await.unwrap();
+
+        // The last access used to be stored in seconds.
+        apply_migrations(&conn, 0, &MIGRATIONS[..10], None, &[]).await.unwrap();
+        conn.execute(
+            "INSERT INTO media (uri, format, blob_hash, last_access) \
+             VALUES (CAST('a' AS BLOB), CAST('file' AS BLOB), CAST('a' AS BLOB), 1000)",
+            (),
+        )
+        .await
+        .unwrap();
+
+        apply_migrations(&conn, 10, &MIGRATIONS, None, &[]).await.unwrap();
+
+        let last_access = conn
+            .query_row("SELECT last_access FROM media", (), |row| row.get::<_, i64>(0))
+            .await
+            .unwrap();
+        assert_eq!(last_access, 1_000_000);
+    }
+
+    #[async_test]
+    async fn test_migration_progress() {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name).join("matrix-sdk-event-cache.sqlite3");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let pool = deadpool_sqlite::Config::new(&path).create_pool(Runtime::Tokio1).unwrap();
+        let conn = pool.get().await.unwrap();
+
+        let calls = Arc::new(StdMutex::new(Vec::new()));
+        let on_progress = {
+            let calls = calls.clone();
+            move |version: u8, latest_version: u8| {
+                calls.lock().unwrap().push((version, latest_version));
+            }
+        };
+
+        let migrations = [
+            MIGRATIONS[0],
+            "CREATE TABLE foo (id INTEGER PRIMARY KEY);",
+            "ALTER TABLE foo ADD COLUMN bar TEXT;",
+            "ALTER TABLE foo ADD COLUMN baz TEXT;",
+        ];
+
+        // The callback is called before every step.
+        apply_migrations(&conn, 0, &migrations[..2], Some(&on_progress), &[]).await.unwrap();
+        assert_eq!(*calls.lock().unwrap(), [(0, 2), (1, 2)]);
+        calls.lock().unwrap().clear();
+
+        apply_migrations(&conn, 2, &migrations, Some(&on_progress), &[]).await.unwrap();
+        assert_eq!(*calls.lock().unwrap(), [(2, 4), (3, 4)]);
+        calls.lock().unwrap().clear();
+
+        // It is not called for an up-to-date database.
+        apply_migrations(&conn, 4, &migrations, Some(&on_progress), &[]).await.unwrap();
+        assert!(calls.lock().unwrap().is_empty());
+
+        // It is called when opening a new store.
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+        let config = SqliteEventCacheStoreConfig::new().on_migration_progress(on_progress);
+
+        SqliteEventCacheStore::open_with_config(&path, None, config.clone()).await.unwrap();
+        let expected = (0..DATABASE_VERSION).map(|v| (v, DATABASE_VERSION)).collect::<Vec<_>>();
+        assert_eq!(*calls.lock().unwrap(), expected);
+        calls.lock().unwrap().clear();
+
+        // But not when reopening it.
+        SqliteEventCacheStore::open_with_config(&path, None, config).await.unwrap();
+        assert!(calls.lock().unwrap().is_empty());
+    }
+
+    #[async_test]
+    async fn test_unsupported_database_version() {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+
+        let event_cache_store = SqliteEventCacheStore::open(&path, None).await.unwrap();
+        let conn = event_cache_store.acquire().await.unwrap();
+
+        // Pretend the database was migrated by a newer version of the SDK.
+        conn.set_kv("version", vec![DATABASE_VERSION + 1]).await.unwrap();
+        drop(conn);
+        drop(event_cache_store);
+
+        assert_matches!(
+            SqliteEventCacheStore::open(&path, None).await,
+            Err(OpenStoreError::UnsupportedDatabaseVersion { found, supported }) => {
+                assert_eq!(found, DATABASE_VERSION + 1);
+                assert_eq!(supported, DATABASE_VERSION);
+            }
+        );
+    }
+
+    #[async_test]
+    async fn test_integrity_check() {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+        let database_path = path.join("matrix-sdk-event-cache.sqlite3");
+
+        let event_cache_store = SqliteEventCacheStore::open(&path, None).await.unwrap();
+        for i in 0..100u8 {
+            let request = MediaRequest {
+                source: MediaSource::Plain(OwnedMxcUri::from(format!("mxc://localhost/media{i}"))),
+                format: MediaFormat::File,
+            };
+            event_cache_store.add_media_content(&request, vec![i; 4096]).await.unwrap();
+        }
+        // Write everything to the database file.
+        event_cache_store.vacuum().await.unwrap();
+        drop(event_cache_store);
+
+        // An intact database passes the check.
+        let config = SqliteEventCacheStoreConfig::new().integrity_check(IntegrityCheck::Full);
+        let event_cache_store =
+            SqliteEventCacheStore::open_with_config(&path, None, config.clone()).await.unwrap();
+        drop(event_cache_store);
+
+        // Truncate the database, like after an unclean shutdown.
+        let file = std::fs::OpenOptions::new().write(true).open(&database_path).unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len / 2).unwrap();
+        drop(file);
+
+        assert_matches!(
+            SqliteEventCacheStore::open_with_config(
+                &path,
+                None,
+                SqliteEventCacheStoreConfig::new().integrity_check(IntegrityCheck::Quick),
+            )
+            .await,
+            Err(OpenStoreError::Corrupted(_))
+        );
+        assert_matches!(
+            SqliteEventCacheStore::open_with_config(&path, None, config).await,
+            Err(OpenStoreError::Corrupted(_))
+        );
+    }
+
+    #[async_test]
+    async fn test_prefetch() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+        let missing_request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/missing").to_owned()),
+            format: MediaFormat::File,
+        };
+
+        event_cache_store.add_media_content(&request, vec![1; 64 * 1024]).await.unwrap();
+
+        let last_access = || async {
+            event_cache_store
+                .acquire()
+                .await
+                .unwrap()
+                .query_row("SELECT last_access FROM media", (), |row| row.get::<_, i64>(0))
+                .await
+                .unwrap()
+        };
+        let last_access_before = last_access().await;
+
+        // Wait so the timestamps would differ.
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        // Missing media are ignored.
+        event_cache_store.prefetch(&[request.clone(), missing_request]).await.unwrap();
+
+        // The last access was not updated.
+        assert_eq!(last_access().await, last_access_before);
+    }
+
+    #[async_test]
+    async fn test_cached_thumbnail_sizes() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let uri = mxc_uri!("mxc://localhost/media");
+        let thumbnail_request = |method, width, height| MediaRequest {
+            source: MediaSource::Plain(uri.to_owned()),
+            format: MediaFormat::Thumbnail(MediaThumbnailSettings::new(method, width, height)),
+        };
+
+        assert!(event_cache_store.cached_thumbnail_sizes(uri).await.unwrap().is_empty());
+
+        let file_request =
+            MediaRequest { source: MediaSource::Plain(uri.to_owned()), format: MediaFormat::File };
+        event_cache_store.add_media_content(&file_request, b"file".to_vec()).await.unwrap();
+        assert!(event_cache_store.cached_thumbnail_sizes(uri).await.unwrap().is_empty());
+
+        let requests = [
+            thumbnail_request(Method::Crop, uint!(100), uint!(100)),
+            thumbnail_request(Method::Scale, uint!(100), uint!(100)),
+            thumbnail_request(Method::Scale, uint!(640), uint!(480)),
+        ];
+        for request in &requests {
+            event_cache_store.add_media_content(request, b"thumbnail".to_vec()).await.unwrap();
+        }
+
+        // A thumbnail of another media.
+        let other_request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/other").to_owned()),
+            format: MediaFormat::Thumbnail(MediaThumbnailSettings::new(
+                Method::Crop,
+                uint!(32),
+                uint!(32),
+            )),
+        };
+        event_cache_store.add_media_content(&other_request, b"other".to_vec()).await.unwrap();
+
+        let mut sizes = event_cache_store.cached_thumbnail_sizes(uri).await.unwrap();
+        sizes.sort_by(|a, b| (a.0, a.1, a.2.as_str()).cmp(&(b.0, b.1, b.2.as_str())));
+        assert_eq!(
+            sizes,
+            vec![(100, 100, Method::Crop), (100, 100, Method::Scale), (640, 480, Method::Scale)]
+        );
+    }
+
+    #[async_test]
+    async fn test_get_media_content_for_uri() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let uri = mxc_uri!("mxc://localhost/media");
+        let thumbnail_settings = |method, width, height, animated| {
+            let mut settings = MediaThumbnailSettings::new(method, width, height);
+            settings.animated = animated;
+            MediaFormat::Thumbnail(settings)
+        };
+
+        assert!(event_cache_store.get_media_content_for_uri(uri).await.unwrap().is_empty());
+
+        let formats = [
+            (MediaFormat::File, b"file".to_vec()),
+            (thumbnail_settings(Method::Crop, uint!(96), uint!(96), false), b"crop".to_vec()),
+            (thumbnail_settings(Method::Scale, uint!(640), uint!(480), false), b"scale".to_vec()),
+            (thumbnail_settings(Method::Scale, uint!(640), uint!(480), true), b"animated".to_vec()),
+        ];
+        for (format, content) in &formats {
+            let request =
+                MediaRequest { source: MediaSource::Plain(uri.to_owned()), format: format.clone() };
+            event_cache_store.add_media_content(&request, content.clone()).await.unwrap();
+        }
+
+        // A media with another URI.
+        let other_request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/other").to_owned()),
+            format: MediaFormat::File,
+        };
+        event_cache_store.add_media_content(&other_request, b"other".to_vec()).await.unwrap();
+
+        let mut media = event_cache_store
+            .get_media_content_for_uri(uri)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(format, content)| (format.unique_key(), content))
+            .collect::<Vec<_>>();
+        media.sort();
+
+        let mut expected = formats
+            .into_iter()
+            .map(|(format, content)| (format.unique_key(), content))
+            .collect::<Vec<_>>();
+        expected.sort();
+
+        assert_eq!(media, expected);
+    }
+
+    #[async_test]
+    async fn test_get_media_content_for_uri_deferred_access_times() {
+        let now_millis = Arc::new(AtomicU64::new(1000));
+        let event_cache_store = get_event_cache_store_with_config(
+            SqliteEventCacheStoreConfig::new().defer_access_times(10).clock({
+                let now_millis = now_millis.clone();
+                move || SystemTime::UNIX_EPOCH + Duration::from_millis(now_millis.load(SeqCst))
+            }),
+        )
+        .await
+        .expect("creating media cache failed");
+        let uri = mxc_uri!("mxc://localhost/media");
+        let request = |uri: &MxcUri, format| MediaRequest {
+            source: MediaSource::Plain(uri.to_owned()),
+            format,
+        };
+        let thumbnail =
+            MediaFormat::Thumbnail(MediaThumbnailSettings::new(Method::Crop, uint!(96), uint!(96)));
+
+        event_cache_store
+            .add_media_content(&request(uri, MediaFormat::File), b"file".to_vec())
+            .await
+            .unwrap();
+        now_millis.fetch_add(10, SeqCst);
+        event_cache_store
+            .add_media_content(&request(uri, thumbnail), b"crop".to_vec())
+            .await
+            .unwrap();
+        now_millis.fetch_add(10, SeqCst);
+        event_cache_store
+            .add_media_content(
+                &request(mxc_uri!("mxc://localhost/other"), MediaFormat::File),
+                b"other".to_vec(),
+            )
+            .await
+            .unwrap();
+
+        now_millis.fetch_add(10, SeqCst);
+        assert_eq!(event_cache_store.get_media_content_for_uri(uri).await.unwrap().len(), 2);
+
+        // The accesses are deferred, like the other reads.
+        let last_accesses = |entries: Vec<MediaCacheEntry>| {
+            entries.into_iter().map(|entry| entry.last_access).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            last_accesses(event_cache_store.list_media(10, 0).await.unwrap()),
+            [1000, 1010, 1020].map(|millis| SystemTime::UNIX_EPOCH + Duration::from_millis(millis))
+        );
+        event_cache_store.flush_access_times().await.unwrap();
+        assert_eq!(
+            last_accesses(event_cache_store.list_media(10, 0).await.unwrap()),
+            [1020, 1030, 1030].map(|millis| SystemTime::UNIX_EPOCH + Duration::from_millis(millis))
+        );
+    }
+
+    #[async_test]
+    async fn test_get_media_content_best_match() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let uri = mxc_uri!("mxc://localhost/media");
+        let thumbnail_request = |method, width, height, animated| {
+            let mut settings = MediaThumbnailSettings::new(method, width, height);
+            settings.animated = animated;
+            MediaRequest {
+                source: MediaSource::Plain(uri.to_owned()),
+                format: MediaFormat::Thumbnail(settings),
+            }
+        };
+        let event_cache_store = &event_cache_store;
+        let best_match = |request| async move {
+            event_cache_store
+                .get_media_content_best_match(&request)
+                .await
+                .unwrap()
+                .map(|(format, content)| (format.unique_key(), content))
+        };
+
+        // Nothing is cached.
+        assert_eq!(
+            best_match(thumbnail_request(Method::Crop, uint!(96), uint!(96), false)).await,
+            None
+        );
+
+        let crop_128 = thumbnail_request(Method::Crop, uint!(128), uint!(128), false);
+        let crop_256 = thumbnail_request(Method::Crop, uint!(256), uint!(256), false);
+        let scale_640_480 = thumbnail_request(Method::Scale, uint!(640), uint!(480), false);
+        let animated_crop_512 = thumbnail_request(Method::Crop, uint!(512), uint!(512), true);
+        for (request, content) in [
+            (&crop_128, b"crop_128".to_vec()),
+            (&crop_256, b"crop_256".to_vec()),
+            (&scale_640_480, b"scale_640_480".to_vec()),
+            (&animated_crop_512, b"animated_512".to_vec()),
+        ] {
+            event_cache_store.add_media_content(request, content).await.unwrap();
+        }
+
+        // The exact format is returned when it is cached.
+        assert_eq!(
+            best_match(crop_256.clone()).await,
+            Some((crop_256.format.unique_key(), b"crop_256".to_vec()))
+        );
+
+        // The smallest larger thumbnail with the same method is returned.
+        assert_eq!(
+            best_match(thumbnail_request(Method::Crop, uint!(96), uint!(96), false)).await,
+            Some((crop_128.format.unique_key(), b"crop_128".to_vec()))
+        );
+        assert_eq!(
+            best_match(thumbnail_request(Method::Crop, uint!(200), uint!(100), false)).await,
+            Some((crop_256.format.unique_key(), b"crop_256".to_vec()))
+        );
+
+        // A cropped thumbnail doesn't match a scaled one, and vice versa.
+        assert_eq!(
+            best_match(thumbnail_request(Method::Scale, uint!(96), uint!(96), false)).await,
+            Some((scale_640_480.format.unique_key(), b"scale_640_480".to_vec()))
+        );
+        assert_eq!(
+            best_match(thumbnail_request(Method::Scale, uint!(300), uint!(300), false)).await,
+            Some((scale_640_480.format.unique_key(), b"scale_640_480".to_vec()))
+        );
+        assert_eq!(
+            best_match(thumbnail_request(Method::Crop, uint!(300), uint!(300), false)).await,
+            None
+        );
+
+        // Both dimensions must be greater than or equal to the requested ones.
+        assert_eq!(
+            best_match(thumbnail_request(Method::Scale, uint!(800), uint!(100), false)).await,
+            None
+        );
+        assert_eq!(
+            best_match(thumbnail_request(Method::Scale, uint!(100), uint!(600), false)).await,
+            None
+        );
+
+        // An animated thumbnail only matches an animated request.
+        assert_eq!(
+            best_match(thumbnail_request(Method::Crop, uint!(96), uint!(96), true)).await,
+            Some((animated_crop_512.format.unique_key(), b"animated_512".to_vec()))
+        );
+
+        // A file is never matched with a thumbnail.
+        let file_request =
+            MediaRequest { source: MediaSource::Plain(uri.to_owned()), format: MediaFormat::File };
+        assert_eq!(best_match(file_request).await, None);
+    }
+
+    #[async_test]
+    async fn test_soft_limit() {
+        let calls = Arc::new(StdMutex::new(Vec::new()));
+        let event_cache_store = get_event_cache_store_with_config(
+            SqliteEventCacheStoreConfig::new()
+                .media_retention_policy(MediaRetentionPolicy {
+                    soft_limit: Some(10),
+                    ..Default::default()
+                })
+                .on_soft_limit_reached({
+                    let calls = calls.clone();
+                    move |cache_size| calls.lock().unwrap().push(cache_size)
+                }),
+        )
+        .await
+        .expect("creating media cache failed");
+
+        let request = |id: &str| MediaRequest {
+            source: MediaSource::Plain(OwnedMxcUri::from(format!("mxc://localhost/{id}"))),
+            format: MediaFormat::File,
+        };
+
+        // Under the limit.
+        event_cache_store.add_media_content(&request("a"), vec![1; 6]).await.unwrap();
+        assert!(calls.lock().unwrap().is_empty());
+
+        // Over the limit, the callback is called.
+        event_cache_store.add_media_content(&request("b"), vec![2; 6]).await.unwrap();
+        assert_eq!(*calls.lock().unwrap(), [12]);
+
+        // Still over the limit, the callback is not called again.
+        event_cache_store.add_media_content(&request("c"), vec![3; 6]).await.unwrap();
+        assert_eq!(*calls.lock().unwrap(), [12]);
+
+        // Nothing was removed.
+        for id in ["a", "b", "c"] {
+            assert!(event_cache_store.get_media_content(&request(id)).await.unwrap().is_some());
+        }
+
+        // Go back under the limit, then over it again.
+        event_cache_store.remove_media_content(&request("b")).await.unwrap();
+        event_cache_store.remove_media_content(&request("c")).await.unwrap();
+        event_cache_store.add_media_content(&request("b"), vec![2; 1]).await.unwrap();
+        assert_eq!(*calls.lock().unwrap(), [12]);
+
+        event_cache_store.add_media_content(&request("c"), vec![3; 6]).await.unwrap();
+        assert_eq!(*calls.lock().unwrap(), [12, 13]);
+    }
+
+    #[async_test]
+    async fn test_max_cache_size() {
+        let event_cache_store = get_event_cache_store_with_config(
+            SqliteEventCacheStoreConfig::new().media_retention_policy(MediaRetentionPolicy {
+                max_cache_size: Some(10),
+                ..Default::default()
+            }),
+        )
+        .await
+        .expect("creating media cache failed");
+
+        let request = |id: &str| MediaRequest {
+            source: MediaSource::Plain(OwnedMxcUri::from(format!("mxc://localhost/{id}"))),
+            format: MediaFormat::File,
+        };
+        let set_last_access = |id: &str, last_access: i64| {
+            let uri = format!("mxc://localhost/{id}").into_bytes();
+            let event_cache_store = event_cache_store.clone();
+            async move {
+                event_cache_store
+                    .acquire()
+                    .await
+                    .unwrap()
+                    .execute("UPDATE media SET last_access = ? WHERE uri = ?", (last_access, uri))
+                    .await
+                    .unwrap();
+            }
+        };
+
+        event_cache_store.add_media_content(&request("a"), vec![1; 5]).await.unwrap();
+        event_cache_store.add_media_content(&request("b"), vec![2; 5]).await.unwrap();
+
+        // `a` was accessed more recently than `b`.
+        set_last_access("a", 200).await;
+        set_last_access("b", 100).await;
+
+        // Adding `c` only leaves room for two media, `b` is evicted.
+        event_cache_store.add_media_content(&request("c"), vec![3; 5]).await.unwrap();
+
+        assert!(event_cache_store.get_media_content(&request("a")).await.unwrap().is_some());
+        assert!(event_cache_store.get_media_content(&request("b")).await.unwrap().is_none());
+        assert!(event_cache_store.get_media_content(&request("c")).await.unwrap().is_some());
+
+        // A reserved media is not evicted, even if it is the least recently accessed.
+        let _guard = event_cache_store.reserve(&request("a"));
+        set_last_access("a", 100).await;
+        set_last_access("c", 200).await;
+        event_cache_store.add_media_content(&request("d"), vec![4; 5]).await.unwrap();
+
+        assert!(event_cache_store.get_media_content(&request("a")).await.unwrap().is_some());
+        assert!(event_cache_store.get_media_content(&request("c")).await.unwrap().is_none());
+        assert!(event_cache_store.get_media_content(&request("d")).await.unwrap().is_some());
+    }
+
+    #[async_test]
+    async fn test_clean_up_media_cache() {
+        let elapsed = Arc::new(AtomicU64::new(0));
+        let event_cache_store = get_event_cache_store_with_config(
+            SqliteEventCacheStoreConfig::new()
+                .media_retention_policy(MediaRetentionPolicy {
+                    max_age: Some(Duration::from_secs(60 * 60)),
+                    ..Default::default()
+                })
+                .clock({
+                    let elapsed = elapsed.clone();
+                    move || SystemTime::now() + Duration::from_secs(elapsed.load(SeqCst))
+                }),
+        )
+        .await
+        .expect("creating media cache failed");
+
+        let request = |id: &str| MediaRequest {
+            source: MediaSource::Plain(OwnedMxcUri::from(format!("mxc://localhost/{id}"))),
+            format: MediaFormat::File,
+        };
+
+        event_cache_store.add_media_content(&request("a"), b"a".to_vec()).await.unwrap();
+        event_cache_store.add_media_content(&request("b"), b"b".to_vec()).await.unwrap();
+
+        // Nothing is old enough to be removed yet.
+        assert_eq!(event_cache_store.clean_up_media_cache().await.unwrap(), 0);
+
+        // After two hours, all the media expired, except the reserved one.
+        elapsed.store(2 * 60 * 60, SeqCst);
+        let _guard = event_cache_store.reserve(&request("b"));
+        assert_eq!(event_cache_store.clean_up_media_cache().await.unwrap(), 1);
+
+        assert!(event_cache_store.get_media_content(&request("a")).await.unwrap().is_none());
+        assert!(event_cache_store.get_media_content(&request("b")).await.unwrap().is_some());
+    }
+
+    /// An [`EnvelopeCodec`] serializing the envelope as JSON.
+    #[derive(Debug)]
+    struct JsonCodec;
+
+    impl EnvelopeCodec for JsonCodec {
+        fn tag(&self) -> u8 {
+            1
+        }
+
+        fn encode(&self, value: &EncryptedValue) -> Result<Vec<u8>, EnvelopeCodecError> {
+            Ok(serde_json::to_vec(value)?)
+        }
+
+        fn decode(&self, data: &[u8]) -> Result<EncryptedValue, EnvelopeCodecError> {
+            Ok(serde_json::from_slice(data)?)
+        }
+    }
+
+    #[async_test]
+    async fn test_envelope_codec() {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+        let path = path.as_path();
+        let open = move |config: SqliteEventCacheStoreConfig| {
+            SqliteEventCacheStore::open_with_config(path, Some("passphrase"), config)
+        };
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+
+        let event_cache_store =
+            open(SqliteEventCacheStoreConfig::new().envelope_codec(JsonCodec)).await.unwrap();
+        event_cache_store.add_media_content(&request, b"media".to_vec()).await.unwrap();
+        event_cache_store.save_gap(room_id!("!room:localhost"), "token").await.unwrap();
+
+        // The encrypted values are serialized with the codec.
+        let raw = event_cache_store.get_media_content_raw(&request).await.unwrap().unwrap();
+        serde_json::from_slice::<EncryptedValue>(&raw.data).unwrap();
+        drop(event_cache_store);
+
+        // The values can be read back with the same codec.
+        let event_cache_store =
+            open(SqliteEventCacheStoreConfig::new().envelope_codec(JsonCodec)).await.unwrap();
+        assert_eq!(
+            event_cache_store.get_media_content(&request).await.unwrap(),
+            Some(b"media".to_vec())
+        );
+        assert_eq!(
+            event_cache_store.load_gap(room_id!("!room:localhost")).await.unwrap().as_deref(),
+            Some("token")
+        );
+        drop(event_cache_store);
+
+        // The store can't be opened with a different codec.
+        assert_matches!(
+            open(SqliteEventCacheStoreConfig::new()).await,
+            Err(OpenStoreError::IncompatibleEnvelopeCodec { found: 1, expected: 0 })
+        );
+    }
+
+    #[async_test]
+    async fn test_envelope_codec_of_existing_store() {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+
+        // Simulate a store created before the codec was persisted.
+        let event_cache_store =
+            SqliteEventCacheStore::open(&path, Some("passphrase")).await.unwrap();
+        event_cache_store
+            .acquire()
+            .await
+            .unwrap()
+            .execute("DELETE FROM kv WHERE key = ?", (keys::ENVELOPE_CODEC,))
+            .await
+            .unwrap();
+        drop(event_cache_store);
+
+        // Its values use MessagePack.
+        assert_matches!(
+            SqliteEventCacheStore::open_with_config(
+                &path,
+                Some("passphrase"),
+                SqliteEventCacheStoreConfig::new().envelope_codec(JsonCodec)
+            )
+            .await,
+            Err(OpenStoreError::IncompatibleEnvelopeCodec { found: 0, expected: 1 })
+        );
+        let conn = SqliteEventCacheStore::open(&path, Some("passphrase"))
+            .await
+            .unwrap()
+            .acquire()
+            .await
+            .unwrap();
+        assert_eq!(conn.get_kv(keys::ENVELOPE_CODEC).await.unwrap(), Some(vec![0]));
+    }
+
+    #[async_test]
+    async fn test_reencode_all() {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+        let path = path.as_path();
+        let open = move |config: SqliteEventCacheStoreConfig| {
+            SqliteEventCacheStore::open_with_config(
+                path,
+                Some("passphrase"),
+                config.media_chunk_size(4),
+            )
+        };
+        let request = |id: &str| MediaRequest {
+            source: MediaSource::Plain(OwnedMxcUri::from(format!("mxc://localhost/{id}"))),
+            format: MediaFormat::File,
+        };
+        let room_id = room_id!("!room:localhost");
+
+        // Write values in the old format.
+        let event_cache_store = open(SqliteEventCacheStoreConfig::new()).await.unwrap();
+        event_cache_store
+            .add_media_content_with_content_type(
+                &request("old"),
+                b"some old media content".to_vec(),
+                Some("text/plain"),
+            )
+            .await
+            .unwrap();
+        event_cache_store.save_gap(room_id, "prev_batch").await.unwrap();
+        event_cache_store.set_custom_kv("app", "key", b"value".to_vec()).await.unwrap();
+        drop(event_cache_store);
+
+        // Both formats can be read during the migration, new values use the new one.
+        let migrating_config = || {
+            SqliteEventCacheStoreConfig::new()
+                .envelope_codec(JsonCodec)
+                .previous_envelope_codec(MessagePackCodec)
+        };
+        let event_cache_store = open(migrating_config()).await.unwrap();
+        event_cache_store
+            .add_media_content(&request("new"), b"some new media".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            event_cache_store.get_media_content(&request("old")).await.unwrap().unwrap(),
+            b"some old media content"
+        );
+
+        // The two media contents, the content type, the gap and the custom value.
+        assert_eq!(event_cache_store.reencode_all().await.unwrap(), 5);
+        // It has nothing to do anymore.
+        assert_eq!(event_cache_store.reencode_all().await.unwrap(), 0);
+        drop(event_cache_store);
+
+        // The store can be opened without the previous codec, the data is intact.
+        let event_cache_store =
+            open(SqliteEventCacheStoreConfig::new().envelope_codec(JsonCodec)).await.unwrap();
+        assert_eq!(
+            event_cache_store.get_media_content_with_content_type(&request("old")).await.unwrap(),
+            Some((b"some old media content".to_vec(), Some("text/plain".to_owned())))
+        );
+        assert_eq!(
+            event_cache_store.get_media_content(&request("new")).await.unwrap().unwrap(),
+            b"some new media"
+        );
+        assert_eq!(
+            event_cache_store.load_gap(room_id).await.unwrap().as_deref(),
+            Some("prev_batch")
+        );
+        assert_eq!(
+            event_cache_store.get_custom_kv("app", "key").await.unwrap(),
+            Some(b"value".to_vec())
+        );
+        drop(event_cache_store);
+
+        // But not with the old codec anymore.
+        assert_matches!(
+            open(SqliteEventCacheStoreConfig::new()).await,
+            Err(OpenStoreError::IncompatibleEnvelopeCodec { found: 1, expected: 0 })
+        );
+    }
+
+    #[async_test]
+    async fn test_repair() {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+
+        let event_cache_store =
+            SqliteEventCacheStore::open(&path, Some("passphrase")).await.unwrap();
+        let request = |id: &str| MediaRequest {
+            source: MediaSource::Plain(OwnedMxcUri::from(format!("mxc://localhost/{id}"))),
+            format: MediaFormat::File,
+        };
+        event_cache_store
+            .add_media_content(&request("corrupted"), b"first".to_vec())
+            .await
+            .unwrap();
+        event_cache_store.add_media_content(&request("valid"), b"second".to_vec()).await.unwrap();
+
+        // Nothing to repair.
+        assert!(event_cache_store.repair(true).await.unwrap().is_empty());
+
+        // Corrupt the content of the first media, so it can't be decrypted anymore.
+        event_cache_store
+            .acquire()
+            .await
+            .unwrap()
+            .execute(
+                "UPDATE media_blobs SET data = X'00' \
+                 WHERE hash = (SELECT blob_hash FROM media ORDER BY rowid LIMIT 1)",
+                (),
+            )
+            .await
+            .unwrap();
+        assert!(event_cache_store.get_media_content(&request("corrupted")).await.is_err());
+
+        let report = event_cache_store.repair(true).await.unwrap();
+        assert_eq!(report, RepairReport { undecodable_media: 1, ..Default::default() });
+
+        // Only the corrupted media was removed.
+        assert!(!event_cache_store.contains_media(&request("corrupted")).await.unwrap());
+        assert_eq!(
+            event_cache_store.get_media_content(&request("valid")).await.unwrap(),
+            Some(b"second".to_vec())
+        );
+        assert_eq!(event_cache_store.media_cache_count().await.unwrap(), 1);
+        assert!(event_cache_store.repair(true).await.unwrap().is_empty());
+    }
+
+    #[async_test]
+    async fn test_account_namespaces() {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+        let open = |namespace: &str| {
+            SqliteEventCacheStore::open_with_config(
+                &path,
+                None,
+                SqliteEventCacheStoreConfig::new().account_namespace(namespace),
+            )
+        };
+        let alice = open("alice").await.unwrap();
+        let bob = open("bob").await.unwrap();
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+        let room_id = room_id!("!room:localhost");
+
+        // The same keys don't collide.
+        alice.add_media_content(&request, b"alice".to_vec()).await.unwrap();
+        bob.add_media_content(&request, b"bob".to_vec()).await.unwrap();
+        alice.save_gap(room_id, "alice_token").await.unwrap();
+        bob.save_gap(room_id, "bob_token").await.unwrap();
+
+        assert_eq!(alice.get_media_content(&request).await.unwrap(), Some(b"alice".to_vec()));
+        assert_eq!(bob.get_media_content(&request).await.unwrap(), Some(b"bob".to_vec()));
+        assert_eq!(alice.load_gap(room_id).await.unwrap().as_deref(), Some("alice_token"));
+        assert_eq!(bob.load_gap(room_id).await.unwrap().as_deref(), Some("bob_token"));
+
+        // The media are only listed in their namespace.
+        let alice_media = alice.list_media(10, 0).await.unwrap();
+        assert_eq!(alice_media.len(), 1);
+        assert_eq!(alice_media[0].size, 5);
+        let bob_media = bob.stream_all_media().try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(bob_media.len(), 1);
+        assert_eq!(bob_media[0].1, b"bob");
+
+        // Removing a media only removes it from its namespace.
+        bob.remove_media_content(&request).await.unwrap();
+        assert!(alice.contains_media(&request).await.unwrap());
+        bob.add_media_content(&request, b"bob".to_vec()).await.unwrap();
+
+        // Clearing the session data of a namespace leaves the other one intact.
+        bob.clear_session_data().await.unwrap();
+        assert!(!bob.contains_media(&request).await.unwrap());
+        assert_eq!(bob.load_gap(room_id).await.unwrap(), None);
+        assert_eq!(alice.get_media_content(&request).await.unwrap(), Some(b"alice".to_vec()));
+        assert_eq!(alice.load_gap(room_id).await.unwrap().as_deref(), Some("alice_token"));
+
+        // Any namespace can be cleared from another store.
+        bob.add_media_content(&request, b"bob".to_vec()).await.unwrap();
+        assert_eq!(bob.clear_account_namespace("alice").await.unwrap(), 1);
+        assert!(!alice.contains_media(&request).await.unwrap());
+        assert_eq!(alice.load_gap(room_id).await.unwrap(), None);
+        assert_eq!(bob.get_media_content(&request).await.unwrap(), Some(b"bob".to_vec()));
+
+        // A store without namespace doesn't see the namespaced data.
+        let event_cache_store = SqliteEventCacheStore::open(&path, None).await.unwrap();
+        assert!(!event_cache_store.contains_media(&request).await.unwrap());
+        assert!(event_cache_store.list_media(10, 0).await.unwrap().is_empty());
+
+        // The boundary between the namespace and the key is unambiguous.
+        let first = open("a\0b").await.unwrap();
+        let second = open("a").await.unwrap();
+        first.set_custom_kv("c", "key", b"first".to_vec()).await.unwrap();
+        assert_eq!(second.get_custom_kv("b\0c", "key").await.unwrap(), None);
+    }
+
+    #[async_test]
+    async fn test_maintenance_in_account_namespace() {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+        let open = |namespace: &str| {
+            SqliteEventCacheStore::open_with_config(
+                &path,
+                None,
+                SqliteEventCacheStoreConfig::new().account_namespace(namespace),
+            )
+        };
+        let alice = open("alice").await.unwrap();
+        let bob = open("bob").await.unwrap();
+        let request = |id: &str| MediaRequest {
+            source: MediaSource::Plain(OwnedMxcUri::from(format!("mxc://localhost/{id}"))),
+            format: MediaFormat::File,
+        };
+
+        for id in ["a", "b"] {
+            alice.add_media_content(&request(id), b"alice".to_vec()).await.unwrap();
+        }
+        for id in ["a", "b", "c"] {
+            bob.add_media_content(&request(id), b"bob".to_vec()).await.unwrap();
+        }
+
+        // Trimming the media of a namespace only counts and removes its media.
+        assert_eq!(bob.trim_to_count(1).await.unwrap(), 2);
+        assert_eq!(bob.list_media(10, 0).await.unwrap().len(), 1);
+        assert_eq!(alice.list_media(10, 0).await.unwrap().len(), 2);
+
+        // The retention policy only applies to the media of the namespace.
+        let policy = MediaRetentionPolicy { max_items: Some(1), ..Default::default() };
+        let report = alice.enforce_retention(&policy).await.unwrap();
+        assert_eq!(report.over_count, 1);
+        assert_eq!(alice.list_media(10, 0).await.unwrap().len(), 1);
+        assert!(bob.contains_media(&request("c")).await.unwrap());
+
+        let policy = MediaRetentionPolicy { max_cache_size: Some(0), ..Default::default() };
+        let report = bob.enforce_retention(&policy).await.unwrap();
+        assert_eq!(report.over_size, 1);
+        assert_eq!(alice.list_media(10, 0).await.unwrap().len(), 1);
+
+        // Clearing the media of a namespace leaves the other one intact.
+        bob.add_media_content(&request("c"), b"bob".to_vec()).await.unwrap();
+        alice.clear_all_media().await.unwrap();
+        assert!(alice.list_media(10, 0).await.unwrap().is_empty());
+        assert_eq!(bob.get_media_content(&request("c")).await.unwrap(), Some(b"bob".to_vec()));
+    }
+
+    #[async_test]
+    async fn test_verify_media_content() {
+        let event_cache_store = get_event_cache_store_with_config(
+            SqliteEventCacheStoreConfig::new().verify_media_content(true),
+        )
+        .await
+        .expect("creating media cache failed");
+        let request = |id: &str| MediaRequest {
+            source: MediaSource::Plain(OwnedMxcUri::from(format!("mxc://localhost/{id}"))),
+            format: MediaFormat::File,
+        };
+
+        // The first two media share the same content.
+        event_cache_store.add_media_content(&request("a"), b"media".to_vec()).await.unwrap();
+        event_cache_store.add_media_content(&request("b"), b"media".to_vec()).await.unwrap();
+        event_cache_store.add_media_content(&request("valid"), b"valid".to_vec()).await.unwrap();
+        assert_eq!(
+            event_cache_store.get_media_content(&request("a")).await.unwrap(),
+            Some(b"media".to_vec())
+        );
+
+        // Flip a bit of the shared content.
+        event_cache_store
+            .acquire()
+            .await
+            .unwrap()
+            .execute(
+                "UPDATE media_blobs SET data = X'6C65646961' \
+                 WHERE hash = (SELECT blob_hash FROM media ORDER BY rowid LIMIT 1)",
+                (),
+            )
+            .await
+            .unwrap();
+
+        assert_matches!(
+            event_cache_store.get_media_content(&request("a")).await,
+            Err(Error::CorruptedMedia)
+        );
+
+        // All the media using the corrupted content were removed.
+        assert!(!event_cache_store.contains_media(&request("a")).await.unwrap());
+        assert!(!event_cache_store.contains_media(&request("b")).await.unwrap());
+        assert_eq!(
+            event_cache_store.get_media_content(&request("valid")).await.unwrap(),
+            Some(b"valid".to_vec())
+        );
+
+        // The content can be cached again.
+        event_cache_store.add_media_content(&request("a"), b"media".to_vec()).await.unwrap();
+        assert_eq!(
+            event_cache_store.get_media_content(&request("a")).await.unwrap(),
+            Some(b"media".to_vec())
+        );
+    }
+
+    #[async_test]
+    async fn test_pinned_media() {
+        let event_cache_store = get_event_cache_store_with_config(
+            SqliteEventCacheStoreConfig::new().media_retention_policy(MediaRetentionPolicy {
+                max_cache_size: Some(10),
+                max_age: Some(Duration::from_secs(60)),
+                ..Default::default()
+            }),
+        )
+        .await
+        .expect("creating media cache failed");
+
+        let request = |id: &str| MediaRequest {
+            source: MediaSource::Plain(OwnedMxcUri::from(format!("mxc://localhost/{id}"))),
+            format: MediaFormat::File,
+        };
+
+        event_cache_store.add_media_content(&request("a"), vec![1; 5]).await.unwrap();
+        event_cache_store.add_media_content(&request("b"), vec![2; 5]).await.unwrap();
+        event_cache_store.pin_media(&request("a")).await.unwrap();
+
+        // Pinning a media that is not in the cache does nothing.
+        event_cache_store.pin_media(&request("missing")).await.unwrap();
+
+        // All the media expired, only the pinned one is kept.
+        let set_all_expired = || async {
+            event_cache_store
+                .acquire()
+                .await
+                .unwrap()
+                .execute("UPDATE media SET last_access = 0", ())
+                .await
+                .unwrap();
+        };
+        set_all_expired().await;
+        assert_eq!(event_cache_store.clean_up_media_cache().await.unwrap(), 1);
+        assert!(event_cache_store.contains_media(&request("a")).await.unwrap());
+        assert!(!event_cache_store.contains_media(&request("b")).await.unwrap());
+
+        // The pinned media is not evicted when the cache is too large, even
+        // if it is the least recently accessed one, and if it is replaced.
+        event_cache_store.add_media_content(&request("a"), vec![3; 5]).await.unwrap();
+        set_all_expired().await;
+        event_cache_store.add_media_content(&request("c"), vec![4; 5]).await.unwrap();
+        event_cache_store.add_media_content(&request("d"), vec![5; 5]).await.unwrap();
+        assert!(event_cache_store.contains_media(&request("a")).await.unwrap());
+        assert!(!event_cache_store.contains_media(&request("c")).await.unwrap());
+        assert!(event_cache_store.contains_media(&request("d")).await.unwrap());
+
+        // An unpinned media can be evicted again.
+        event_cache_store.unpin_media(&request("a")).await.unwrap();
+        set_all_expired().await;
+        assert_eq!(event_cache_store.clean_up_media_cache().await.unwrap(), 2);
+        assert_eq!(event_cache_store.media_cache_count().await.unwrap(), 0);
+
+        // A pinned media can still be removed explicitly.
+        event_cache_store.add_media_content(&request("a"), vec![1; 5]).await.unwrap();
+        event_cache_store.pin_media(&request("a")).await.unwrap();
+        event_cache_store.remove_media_content(&request("a")).await.unwrap();
+        assert!(!event_cache_store.contains_media(&request("a")).await.unwrap());
+    }
+
+    #[async_test]
+    async fn test_estimate_eviction() {
+        let request = |id: &str| MediaRequest {
+            source: MediaSource::Plain(OwnedMxcUri::from(format!("mxc://localhost/{id}"))),
+            format: MediaFormat::File,
+        };
+        let now_millis = Arc::new(AtomicU64::new(1_000_000));
+        let event_cache_store = get_event_cache_store_with_config(
+            SqliteEventCacheStoreConfig::new().defer_access_times(100).clock({
+                let now_millis = now_millis.clone();
+                move || SystemTime::UNIX_EPOCH + Duration::from_millis(now_millis.load(SeqCst))
+            }),
+        )
+        .await
+        .expect("creating media cache failed");
+
+        // Media accessed one millisecond apart, 10 seconds ago. `a` and `b` share the
+        // same content, `c` is larger than the others and `e` is pinned.
+        for (id, content) in [
+            ("a", vec![1; 10]),
+            ("b", vec![1; 10]),
+            ("c", vec![2; 100]),
+            ("d", vec![3; 10]),
+            ("e", vec![4; 10]),
+            ("f", vec![5; 10]),
+        ] {
+            event_cache_store.add_media_content(&request(id), content).await.unwrap();
+            now_millis.fetch_add(1, SeqCst);
+        }
+        event_cache_store.pin_media(&request("e")).await.unwrap();
+        now_millis.store(1_010_000, SeqCst);
+
+        // An empty policy removes nothing.
+        let estimate =
+            event_cache_store.estimate_eviction(&MediaRetentionPolicy::default()).await.unwrap();
+        assert_eq!(estimate, EvictionEstimate::default());
+
+        let policy = MediaRetentionPolicy {
+            max_age: Some(Duration::from_millis(9_999)),
+            max_file_size: Some(50),
+            max_items: Some(3),
+            max_cache_size: Some(15),
+            ..Default::default()
+        };
+
+        // A reserved media is not counted.
+        let guard = event_cache_store.reserve(&request("f"));
+        let reserved_estimate = event_cache_store.estimate_eviction(&policy).await.unwrap();
+        assert_eq!(reserved_estimate, EvictionEstimate { items: 4, bytes: 120 });
+        drop(guard);
+
+        // The pending accesses are taken into account.
+        event_cache_store.get_media_content(&request("a")).await.unwrap();
+
+        let estimate = event_cache_store.estimate_eviction(&policy).await.unwrap();
+        assert_eq!(estimate, EvictionEstimate { items: 5, bytes: 130 });
+
+        // Nothing was removed.
+        assert_eq!(event_cache_store.media_cache_count().await.unwrap(), 6);
+
+        // The estimate matches what is actually removed.
+        let size_before = event_cache_store.media_cache_size().await.unwrap();
+        let report = event_cache_store.enforce_retention(&policy).await.unwrap();
+        assert_eq!(
+            estimate,
+            EvictionEstimate {
+                items: report.expired + report.too_large + report.over_count + report.over_size,
+                bytes: report.bytes,
+            }
+        );
+        assert_eq!(
+            event_cache_store.media_cache_size().await.unwrap(),
+            size_before - estimate.bytes
+        );
+        assert!(event_cache_store.contains_media(&request("e")).await.unwrap());
+    }
+
+    #[async_test]
+    async fn test_media_cache_size_and_count() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        assert_eq!(event_cache_store.media_cache_size().await.unwrap(), 0);
+        assert_eq!(event_cache_store.media_cache_count().await.unwrap(), 0);
+
+        let request = |id: &str| MediaRequest {
+            source: MediaSource::Plain(OwnedMxcUri::from(format!("mxc://localhost/{id}"))),
+            format: MediaFormat::File,
+        };
+        event_cache_store.add_media_content(&request("a"), vec![0; 3]).await.unwrap();
+        event_cache_store.add_media_content(&request("b"), vec![0; 4]).await.unwrap();
+
+        assert_eq!(event_cache_store.media_cache_size().await.unwrap(), 7);
+        assert_eq!(event_cache_store.media_cache_count().await.unwrap(), 2);
+
+        // Replacing a media content doesn't count it twice.
+        event_cache_store.add_media_content(&request("a"), vec![0; 1]).await.unwrap();
+        assert_eq!(event_cache_store.media_cache_size().await.unwrap(), 5);
+        assert_eq!(event_cache_store.media_cache_count().await.unwrap(), 2);
+    }
+
+    #[async_test]
+    async fn test_max_file_size() {
+        let event_cache_store = get_event_cache_store_with_config(
+            SqliteEventCacheStoreConfig::new().media_retention_policy(MediaRetentionPolicy {
+                max_file_size: Some(5),
+                ..Default::default()
+            }),
+        )
+        .await
+        .expect("creating media cache failed");
+
+        let request = |id: &str| MediaRequest {
+            source: MediaSource::Plain(OwnedMxcUri::from(format!("mxc://localhost/{id}"))),
+            format: MediaFormat::File,
+        };
+
+        event_cache_store.add_media_content(&request("small"), vec![0; 5]).await.unwrap();
+        event_cache_store.add_media_content(&request("large"), vec![0; 10]).await.unwrap();
+
+        assert!(event_cache_store.get_media_content(&request("small")).await.unwrap().is_some());
+        assert!(event_cache_store.get_media_content(&request("large")).await.unwrap().is_none());
+    }
+
+    #[async_test]
+    async fn test_clear_all_media() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let request = |id: &str| MediaRequest {
+            source: MediaSource::Plain(OwnedMxcUri::from(format!("mxc://localhost/{id}"))),
+            format: MediaFormat::File,
+        };
+
+        for id in ["a", "b", "c"] {
+            event_cache_store
+                .add_media_content(&request(id), id.as_bytes().to_vec())
+                .await
+                .unwrap();
+        }
+        assert_eq!(event_cache_store.media_cache_count().await.unwrap(), 3);
+
+        event_cache_store.clear_all_media().await.unwrap();
+
+        assert_eq!(event_cache_store.media_cache_count().await.unwrap(), 0);
+        for id in ["a", "b", "c"] {
+            assert!(event_cache_store.get_media_content(&request(id)).await.unwrap().is_none());
+        }
+    }
+
+    #[async_test]
+    async fn test_add_media_content_batch() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let items = (0..200)
+            .map(|i| {
+                let request = MediaRequest {
+                    source: MediaSource::Plain(OwnedMxcUri::from(format!("mxc://localhost/{i}"))),
+                    format: MediaFormat::File,
+                };
+                (request, format!("media {i}").into_bytes())
+            })
+            .collect::<Vec<_>>();
+
+        event_cache_store.add_media_content_batch(&items).await.unwrap();
+
+        assert_eq!(event_cache_store.media_cache_count().await.unwrap(), 200);
+        for (request, content) in &items {
+            assert_eq!(
+                event_cache_store.get_media_content(request).await.unwrap().as_ref(),
+                Some(content)
+            );
+        }
+    }
+
+    #[async_test]
+    async fn test_media_content_for_room() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let request = |id: &str| MediaRequest {
+            source: MediaSource::Plain(OwnedMxcUri::from(format!("mxc://localhost/{id}"))),
+            format: MediaFormat::File,
+        };
+        let room_a = room_id!("!a:localhost");
+        let room_b = room_id!("!b:localhost");
+
+        event_cache_store
+            .add_media_content_for_room(room_a, &request("a"), b"a".to_vec())
+            .await
+            .unwrap();
+        event_cache_store
+            .add_media_content_for_room(room_b, &request("b"), b"b".to_vec())
+            .await
+            .unwrap();
+        event_cache_store.add_media_content(&request("none"), b"none".to_vec()).await.unwrap();
+
+        // A media can belong to several rooms.
+        event_cache_store
+            .add_media_content_for_room(room_a, &request("shared"), b"shared".to_vec())
+            .await
+            .unwrap();
+        event_cache_store
+            .add_media_content_for_room(room_b, &request("shared"), b"shared".to_vec())
+            .await
+            .unwrap();
+
+        assert_eq!(event_cache_store.remove_media_content_for_room(room_a).await.unwrap(), 2);
+        assert!(!event_cache_store.contains_media(&request("a")).await.unwrap());
+        assert!(!event_cache_store.contains_media(&request("shared")).await.unwrap());
+        assert!(event_cache_store.contains_media(&request("b")).await.unwrap());
+        assert!(event_cache_store.contains_media(&request("none")).await.unwrap());
+
+        // The association is removed with the media.
+        assert_eq!(event_cache_store.remove_media_content_for_room(room_a).await.unwrap(), 0);
+        event_cache_store.remove_media_content(&request("b")).await.unwrap();
+        let associations: u64 = event_cache_store
+            .acquire()
+            .await
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM media_rooms", (), |row| row.get(0))
+            .await
+            .unwrap();
+        assert_eq!(associations, 0);
+    }
+
+    #[async_test]
+    async fn test_remove_media_content_batch() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let request = |id: &str| MediaRequest {
+            source: MediaSource::Plain(OwnedMxcUri::from(format!("mxc://localhost/{id}"))),
+            format: MediaFormat::File,
+        };
+        let items = ["a", "b", "c"]
+            .into_iter()
+            .map(|id| (request(id), id.as_bytes().to_vec()))
+            .collect::<Vec<_>>();
+        event_cache_store.add_media_content_batch(&items).await.unwrap();
+
+        // The missing and duplicate requests are ignored.
+        let removed = event_cache_store
+            .remove_media_content_batch(&[
+                request("a"),
+                request("missing"),
+                request("b"),
+                request("a"),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(removed, 2);
+
+        assert!(!event_cache_store.contains_media(&request("a")).await.unwrap());
+        assert!(!event_cache_store.contains_media(&request("b")).await.unwrap());
+        assert!(event_cache_store.contains_media(&request("c")).await.unwrap());
+        assert_eq!(event_cache_store.remove_media_content_batch(&[]).await.unwrap(), 0);
+    }
+
+    #[async_test]
+    async fn test_soft_delete() {
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+
+        // The media that are marked as deleted are counted too.
+        async fn count_rows(event_cache_store: &SqliteEventCacheStore) -> u64 {
+            event_cache_store
+                .acquire()
+                .await
+                .unwrap()
+                .query_row("SELECT COUNT(*) FROM media", (), |row| row.get(0))
+                .await
+                .unwrap()
+        }
+
+        // By default, the media are removed immediately.
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        event_cache_store.add_media_content(&request, b"hello".to_vec()).await.unwrap();
+        event_cache_store.remove_media_content(&request).await.unwrap();
+        assert!(event_cache_store.get_media_content(&request).await.unwrap().is_none());
+        assert_eq!(count_rows(&event_cache_store).await, 0);
+
+        // In soft-delete mode, they are hidden but kept.
+        let event_cache_store =
+            get_event_cache_store_with_config(SqliteEventCacheStoreConfig::new().soft_delete(true))
+                .await
+                .expect("creating media cache failed");
+        event_cache_store.add_media_content(&request, b"hello".to_vec()).await.unwrap();
+        event_cache_store.remove_media_content(&request).await.unwrap();
+        assert!(event_cache_store.get_media_content(&request).await.unwrap().is_none());
+        assert!(!event_cache_store.contains_media(&request).await.unwrap());
+        assert!(event_cache_store.list_media(10, 0).await.unwrap().is_empty());
+        assert!(event_cache_store
+            .stream_all_media()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(event_cache_store.media_cache_count().await.unwrap(), 0);
+        assert_eq!(count_rows(&event_cache_store).await, 1);
+
+        // They are not exported either.
+        let path = TMP_DIR.path().join(NUM.fetch_add(1, SeqCst).to_string());
+        event_cache_store.export_to_dir(&path).await.unwrap();
+        let manifest: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(path.join("manifest.json")).unwrap()).unwrap();
+        assert!(manifest["media"].as_object().unwrap().is_empty());
+
+        // Adding the media again replaces the deleted one.
+        event_cache_store.add_media_content(&request, b"hello".to_vec()).await.unwrap();
+        assert_eq!(event_cache_store.get_media_content(&request).await.unwrap().unwrap(), b"hello");
+        event_cache_store.remove_media_content_for_uri(request.uri()).await.unwrap();
+        assert!(event_cache_store.get_media_content(&request).await.unwrap().is_none());
+        assert_eq!(count_rows(&event_cache_store).await, 1);
+
+        // The media deleted after the given time are not purged.
+        assert_eq!(event_cache_store.purge_deleted(SystemTime::UNIX_EPOCH).await.unwrap(), 0);
+        assert_eq!(count_rows(&event_cache_store).await, 1);
+
+        let later = SystemTime::now() + Duration::from_secs(60);
+        assert_eq!(event_cache_store.purge_deleted(later).await.unwrap(), 1);
+        assert_eq!(count_rows(&event_cache_store).await, 0);
+        assert_eq!(event_cache_store.media_cache_size().await.unwrap(), 0);
+
+        // Clearing all the media doesn't keep them, deleted or not.
+        let other = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/other").to_owned()),
+            format: MediaFormat::File,
+        };
+        event_cache_store.add_media_content(&request, b"hello".to_vec()).await.unwrap();
+        event_cache_store.add_media_content(&other, b"world".to_vec()).await.unwrap();
+        event_cache_store.remove_media_content(&request).await.unwrap();
+        event_cache_store.clear_all_media().await.unwrap();
+        assert!(event_cache_store.get_media_content(&other).await.unwrap().is_none());
+        assert_eq!(count_rows(&event_cache_store).await, 0);
+    }
+
+    #[async_test]
+    async fn test_soft_deleted_media_added_again() {
+        let event_cache_store =
+            get_event_cache_store_with_config(SqliteEventCacheStoreConfig::new().soft_delete(true))
+                .await
+                .expect("creating media cache failed");
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+
+        event_cache_store.add_media_content(&request, b"hello".to_vec()).await.unwrap();
+        event_cache_store.pin_media(&request).await.unwrap();
+        event_cache_store.remove_media_content(&request).await.unwrap();
+
+        // The media added again with another content doesn't keep anything of the
+        // deleted one.
+        event_cache_store.add_media_content(&request, b"world".to_vec()).await.unwrap();
+        assert_eq!(event_cache_store.get_media_content(&request).await.unwrap().unwrap(), b"world");
+        let blobs = event_cache_store
+            .acquire()
+            .await
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM media_blobs", (), |row| row.get::<_, u64>(0))
+            .await
+            .unwrap();
+        assert_eq!(blobs, 1);
+
+        // It is not pinned anymore.
+        assert_eq!(event_cache_store.trim_to_count(0).await.unwrap(), 1);
+        assert!(!event_cache_store.contains_media(&request).await.unwrap());
+    }
+
+    #[async_test]
+    async fn test_soft_delete_with_max_cache_size() {
+        let policy = MediaRetentionPolicy { max_cache_size: Some(15), ..Default::default() };
+        let event_cache_store = get_event_cache_store_with_config(
+            SqliteEventCacheStoreConfig::new().soft_delete(true).media_retention_policy(policy),
+        )
+        .await
+        .expect("creating media cache failed");
+        let request = |id: &str| MediaRequest {
+            source: MediaSource::Plain(OwnedMxcUri::from(format!("mxc://localhost/{id}"))),
+            format: MediaFormat::File,
+        };
+
+        event_cache_store.add_media_content(&request("a"), vec![1; 6]).await.unwrap();
+        event_cache_store.remove_media_content(&request("a")).await.unwrap();
+
+        // The content of the deleted media is not counted, so it doesn't make the
+        // other media be evicted.
+        event_cache_store.add_media_content(&request("b"), vec![2; 6]).await.unwrap();
+        event_cache_store.add_media_content(&request("c"), vec![3; 6]).await.unwrap();
+        assert_eq!(event_cache_store.media_cache_size().await.unwrap(), 12);
+        assert!(event_cache_store.contains_media(&request("b")).await.unwrap());
+        assert!(event_cache_store.contains_media(&request("c")).await.unwrap());
+        assert_eq!(
+            event_cache_store.estimate_eviction(&policy).await.unwrap(),
+            EvictionEstimate::default()
+        );
+
+        // The live media are still evicted when the cache is too large.
+        event_cache_store.add_media_content(&request("d"), vec![4; 6]).await.unwrap();
+        assert_eq!(event_cache_store.media_cache_size().await.unwrap(), 12);
+        assert!(!event_cache_store.contains_media(&request("b")).await.unwrap());
+        assert!(event_cache_store.contains_media(&request("d")).await.unwrap());
+
+        // The deleted media is kept until it is purged.
+        let later = SystemTime::now() + Duration::from_secs(60);
+        assert_eq!(event_cache_store.purge_deleted(later).await.unwrap(), 1);
+    }
+
+    #[async_test]
+    async fn test_trim_to_count() {
+        let now_millis = Arc::new(AtomicU64::new(1_000_000));
+        let event_cache_store =
+            get_event_cache_store_with_config(SqliteEventCacheStoreConfig::new().clock({
+                let now_millis = now_millis.clone();
+                move || SystemTime::UNIX_EPOCH + Duration::from_millis(now_millis.load(SeqCst))
+            }))
+            .await
+            .expect("creating media cache failed");
+        let request = |id: &str| MediaRequest {
+            source: MediaSource::Plain(OwnedMxcUri::from(format!("mxc://localhost/{id}"))),
+            format: MediaFormat::File,
+        };
+
+        for id in ["a", "b", "c", "d", "e"] {
+            event_cache_store
+                .add_media_content(&request(id), id.as_bytes().to_vec())
+                .await
+                .unwrap();
+            now_millis.fetch_add(1, SeqCst);
+        }
+
+        // Accessing a media makes it the most recently accessed one.
+        event_cache_store.get_media_content(&request("a")).await.unwrap().unwrap();
+
+        // Nothing is removed if the cache is small enough.
+        assert_eq!(event_cache_store.trim_to_count(5).await.unwrap(), 0);
+
+        assert_eq!(event_cache_store.trim_to_count(2).await.unwrap(), 3);
+        assert_eq!(event_cache_store.media_cache_count().await.unwrap(), 2);
+        assert!(event_cache_store.contains_media(&request("a")).await.unwrap());
+        assert!(event_cache_store.contains_media(&request("e")).await.unwrap());
+
+        // The reserved and pinned media are counted but kept.
+        event_cache_store.add_media_content(&request("f"), b"f".to_vec()).await.unwrap();
+        event_cache_store.pin_media(&request("a")).await.unwrap();
+        let guard = event_cache_store.reserve(&request("e"));
+        assert_eq!(event_cache_store.trim_to_count(2).await.unwrap(), 1);
+        assert!(!event_cache_store.contains_media(&request("f")).await.unwrap());
+        drop(guard);
+
+        assert_eq!(event_cache_store.trim_to_count(0).await.unwrap(), 1);
+        assert_eq!(event_cache_store.media_cache_count().await.unwrap(), 1);
+        assert!(event_cache_store.contains_media(&request("a")).await.unwrap());
+    }
+
+    #[async_test]
+    async fn test_enforce_retention() {
+        fn request(id: &str) -> MediaRequest {
+            MediaRequest {
+                source: MediaSource::Plain(OwnedMxcUri::from(format!("mxc://localhost/{id}"))),
+                format: MediaFormat::File,
+            }
+        }
+
+        // Media accessed one millisecond apart, with "b" larger than the others and "d"
+        // pinned, all of them 10 seconds ago.
+        async fn store_with_media() -> SqliteEventCacheStore {
+            let now_millis = Arc::new(AtomicU64::new(1_000_000));
+            let event_cache_store =
+                get_event_cache_store_with_config(SqliteEventCacheStoreConfig::new().clock({
+                    let now_millis = now_millis.clone();
+                    move || SystemTime::UNIX_EPOCH + Duration::from_millis(now_millis.load(SeqCst))
+                }))
+                .await
+                .expect("creating media cache failed");
+
+            for (id, size) in [("a", 10), ("b", 100), ("c", 10), ("d", 10), ("e", 10)] {
+                event_cache_store
+                    .add_media_content(&request(id), vec![id.as_bytes()[0]; size])
+                    .await
+                    .unwrap();
+                now_millis.fetch_add(1, SeqCst);
+            }
+            event_cache_store.pin_media(&request("d")).await.unwrap();
+
+            now_millis.store(1_010_000, SeqCst);
+            event_cache_store
+        }
+
+        async fn remaining(event_cache_store: &SqliteEventCacheStore) -> Vec<&'static str> {
+            let mut remaining = Vec::new();
+            for id in ["a", "b", "c", "d", "e"] {
+                if event_cache_store.contains_media(&request(id)).await.unwrap() {
+                    remaining.push(id);
+                }
+            }
+            remaining
+        }
+
+        // An empty policy removes nothing.
+        let event_cache_store = store_with_media().await;
+        let report =
+            event_cache_store.enforce_retention(&MediaRetentionPolicy::default()).await.unwrap();
+        assert_eq!(report, RetentionReport::default());
+        assert_eq!(remaining(&event_cache_store).await, ["a", "b", "c", "d", "e"]);
+
+        // Only the age.
+        let event_cache_store = store_with_media().await;
+        let policy = MediaRetentionPolicy {
+            max_age: Some(Duration::from_millis(9_999)),
+            ..Default::default()
+        };
+        let report = event_cache_store.enforce_retention(&policy).await.unwrap();
+        assert_eq!(report, RetentionReport { expired: 1, bytes: 10, ..Default::default() });
+        assert_eq!(remaining(&event_cache_store).await, ["b", "c", "d", "e"]);
+
+        // Only the size of the files.
+        let event_cache_store = store_with_media().await;
+        let policy = MediaRetentionPolicy { max_file_size: Some(50), ..Default::default() };
+        let report = event_cache_store.enforce_retention(&policy).await.unwrap();
+        assert_eq!(report, RetentionReport { too_large: 1, bytes: 100, ..Default::default() });
+        assert_eq!(remaining(&event_cache_store).await, ["a", "c", "d", "e"]);
+
+        // Only the count.
+        let event_cache_store = store_with_media().await;
+        let policy = MediaRetentionPolicy { max_items: Some(3), ..Default::default() };
+        let report = event_cache_store.enforce_retention(&policy).await.unwrap();
+        assert_eq!(report, RetentionReport { over_count: 2, bytes: 110, ..Default::default() });
+        assert_eq!(remaining(&event_cache_store).await, ["c", "d", "e"]);
+
+        // Only the size of the cache.
+        let event_cache_store = store_with_media().await;
+        let policy = MediaRetentionPolicy { max_cache_size: Some(30), ..Default::default() };
+        let report = event_cache_store.enforce_retention(&policy).await.unwrap();
+        assert_eq!(report, RetentionReport { over_size: 2, bytes: 110, ..Default::default() });
+        assert_eq!(remaining(&event_cache_store).await, ["c", "d", "e"]);
+
+        // All of them, applied in order. The pinned media is kept even if the cache is
+        // still too large.
+        let event_cache_store = store_with_media().await;
+        let policy = MediaRetentionPolicy {
+            max_age: Some(Duration::from_millis(9_999)),
+            max_file_size: Some(50),
+            max_items: Some(2),
+            max_cache_size: Some(5),
+            ..Default::default()
+        };
+        let report = event_cache_store.enforce_retention(&policy).await.unwrap();
+        assert_eq!(
+            report,
+            RetentionReport { expired: 1, too_large: 1, over_count: 1, over_size: 1, bytes: 130 }
+        );
+        assert_eq!(remaining(&event_cache_store).await, ["d"]);
+        assert_eq!(event_cache_store.media_cache_size().await.unwrap(), 10);
+    }
+
+    #[async_test]
+    async fn test_warm_media_last_access() {
+        let now_millis = Arc::new(AtomicU64::new(1_000_000));
+        let event_cache_store =
+            get_event_cache_store_with_config(SqliteEventCacheStoreConfig::new().clock({
+                let now_millis = now_millis.clone();
+                move || SystemTime::UNIX_EPOCH + Duration::from_millis(now_millis.load(SeqCst))
+            }))
+            .await
+            .expect("creating media cache failed");
+        let uri = |id: &str| OwnedMxcUri::from(format!("mxc://localhost/{id}"));
+        let request = |id: &str| MediaRequest {
+            source: MediaSource::Plain(uri(id)),
+            format: MediaFormat::File,
+        };
+
+        for id in ["a", "b", "c"] {
+            event_cache_store
+                .add_media_content(&request(id), id.as_bytes().to_vec())
+                .await
+                .unwrap();
+        }
+
+        now_millis.store(2_000_000, SeqCst);
+        assert_eq!(event_cache_store.warm_media(&[uri("a"), uri("c"), uri("d")]).await.unwrap(), 2);
+
+        let mut last_accesses = event_cache_store
+            .list_media(10, 0)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| {
+                let uri = String::from_utf8(entry.uri_key).unwrap();
+                let last_access = entry.last_access.duration_since(SystemTime::UNIX_EPOCH).unwrap();
+                (uri, last_access.as_millis())
+            })
+            .collect::<Vec<_>>();
+        last_accesses.sort();
+        assert_eq!(
+            last_accesses,
+            [
+                ("mxc://localhost/a".to_owned(), 2_000_000),
+                ("mxc://localhost/b".to_owned(), 1_000_000),
+                ("mxc://localhost/c".to_owned(), 2_000_000),
+            ]
+        );
+
+        // The warmed media are the last ones to be evicted.
+        assert_eq!(event_cache_store.trim_to_count(2).await.unwrap(), 1);
+        assert!(!event_cache_store.contains_media(&request("b")).await.unwrap());
+    }
+
+    #[async_test]
+    async fn test_concurrent_eviction() {
+        let event_cache_store = get_event_cache_store_with_config(
+            SqliteEventCacheStoreConfig::new().media_retention_policy(MediaRetentionPolicy {
+                max_cache_size: Some(10 * 1024),
+                max_age: Some(Duration::from_secs(60)),
+                ..Default::default()
+            }),
+        )
+        .await
+        .expect("creating media cache failed");
+        let request = |id: usize| MediaRequest {
+            source: MediaSource::Plain(OwnedMxcUri::from(format!("mxc://localhost/{id}"))),
+            format: MediaFormat::File,
+        };
+        let done = AtomicBool::new(false);
+
+        let adder = async {
+            for id in 0..100 {
+                event_cache_store.add_media_content(&request(id), vec![1; 1024]).await.unwrap();
+
+                // The media that was just added is the most recently accessed one, and it
+                // fits in the limits, so it can't have been evicted.
+                assert!(event_cache_store.contains_media(&request(id)).await.unwrap());
+            }
+            done.store(true, SeqCst);
+        };
+        let evictor = async {
+            while !done.load(SeqCst) {
+                event_cache_store.clean_up_media_cache().await.unwrap();
+                event_cache_store.trim_to_count(5).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        };
+        tokio::join!(adder, evictor);
+
+        assert!(event_cache_store.media_cache_count().await.unwrap() <= 10);
+        assert!(event_cache_store.contains_media(&request(99)).await.unwrap());
+    }
+
+    #[async_test]
+    async fn test_content_type() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let request = |id: &str| MediaRequest {
+            source: MediaSource::Plain(OwnedMxcUri::from(format!("mxc://localhost/{id}"))),
+            format: MediaFormat::File,
+        };
+
+        event_cache_store
+            .add_media_content_with_content_type(&request("a"), b"a".to_vec(), Some("image/png"))
+            .await
+            .unwrap();
+        event_cache_store.add_media_content(&request("b"), b"b".to_vec()).await.unwrap();
+
+        assert_eq!(
+            event_cache_store.get_media_content_with_content_type(&request("a")).await.unwrap(),
+            Some((b"a".to_vec(), Some("image/png".to_owned())))
+        );
+        assert_eq!(
+            event_cache_store.get_media_content_with_content_type(&request("b")).await.unwrap(),
+            Some((b"b".to_vec(), None))
+        );
+        assert_eq!(
+            event_cache_store.get_media_content_with_content_type(&request("c")).await.unwrap(),
+            None
+        );
+
+        // Replacing the content without a content type forgets the previous one.
+        event_cache_store.add_media_content(&request("a"), b"a".to_vec()).await.unwrap();
+        assert_eq!(
+            event_cache_store.get_media_content_with_content_type(&request("a")).await.unwrap(),
+            Some((b"a".to_vec(), None))
+        );
+    }
+
+    #[async_test]
+    async fn test_media_deduplication() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let uri = mxc_uri!("mxc://localhost/media");
+        let file_request =
+            MediaRequest { source: MediaSource::Plain(uri.to_owned()), format: MediaFormat::File };
+        let thumbnail_request = MediaRequest {
+            source: MediaSource::Plain(uri.to_owned()),
+            format: MediaFormat::Thumbnail(MediaThumbnailSettings::new(
+                Method::Crop,
+                uint!(100),
+                uint!(100),
+            )),
+        };
+        let count_blobs = || async {
+            event_cache_store
+                .acquire()
+                .await
+                .unwrap()
+                .query_row("SELECT COUNT(*) FROM media_blobs", (), |row| row.get::<_, u64>(0))
+                .await
+                .unwrap()
+        };
+
+        // The same content is only stored once.
+        event_cache_store.add_media_content(&file_request, b"avatar".to_vec()).await.unwrap();
+        event_cache_store.add_media_content(&thumbnail_request, b"avatar".to_vec()).await.unwrap();
+        assert_eq!(count_blobs().await, 1);
+        assert_eq!(event_cache_store.media_cache_size().await.unwrap(), 6);
+
+        // The content is kept as long as a media uses it.
+        event_cache_store.remove_media_content(&file_request).await.unwrap();
+        assert_eq!(count_blobs().await, 1);
+        assert_eq!(
+            event_cache_store.get_media_content(&thumbnail_request).await.unwrap().as_deref(),
+            Some(&b"avatar"[..])
+        );
+
+        // Replacing the content of the last media using it removes it.
+        event_cache_store.add_media_content(&thumbnail_request, b"thumb".to_vec()).await.unwrap();
+        assert_eq!(count_blobs().await, 1);
+        assert_eq!(event_cache_store.media_cache_size().await.unwrap(), 5);
+
+        // Removing the last media using a content removes it.
+        event_cache_store.remove_media_content(&thumbnail_request).await.unwrap();
+        assert_eq!(count_blobs().await, 0);
+    }
+
+    #[async_test]
+    async fn test_remove_media_content_for_uri_plain_and_encrypted() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let uri = mxc_uri!("mxc://localhost/media");
+
+        // Both sources use the `MxcUri` as their key, so the media added with one
+        // can be found and removed with the other.
+        let plain_request =
+            MediaRequest { source: MediaSource::Plain(uri.to_owned()), format: MediaFormat::File };
+        let encrypted_request = MediaRequest {
+            source: MediaSource::Encrypted(Box::new(
+                EncryptedFileInit {
+                    url: uri.to_owned(),
+                    key: JsonWebKeyInit {
+                        kty: "oct".to_owned(),
+                        key_ops: vec!["encrypt".to_owned(), "decrypt".to_owned()],
+                        alg: "A256CTR".to_owned(),
+                        k: Base64::new(vec![0; 32]),
+                        ext: true,
+                    }
+                    .into(),
+                    iv: Base64::new(vec![1; 16]),
+                    hashes: Default::default(),
+                    v: "v2".to_owned(),
+                }
+                .into(),
+            )),
+            format: MediaFormat::Thumbnail(MediaThumbnailSettings::new(
+                Method::Crop,
+                uint!(100),
+                uint!(100),
+            )),
+        };
+
+        event_cache_store.add_media_content(&plain_request, b"plain".to_vec()).await.unwrap();
+        event_cache_store
+            .add_media_content(&encrypted_request, b"encrypted".to_vec())
+            .await
+            .unwrap();
+
+        event_cache_store.remove_media_content_for_uri(uri).await.unwrap();
+
+        assert!(event_cache_store.get_media_content(&plain_request).await.unwrap().is_none());
+        assert!(event_cache_store.get_media_content(&encrypted_request).await.unwrap().is_none());
+        assert_eq!(event_cache_store.media_cache_count().await.unwrap(), 0);
+    }
+
+    #[async_test]
+    async fn test_rowids_for_debugging() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let uri = mxc_uri!("mxc://localhost/media");
+        let file_request =
+            MediaRequest { source: MediaSource::Plain(uri.to_owned()), format: MediaFormat::File };
+        let thumbnail_request = MediaRequest {
+            source: MediaSource::Plain(uri.to_owned()),
+            format: MediaFormat::Thumbnail(MediaThumbnailSettings::new(
+                Method::Crop,
+                uint!(100),
+                uint!(100),
+            )),
+        };
+
+        event_cache_store.add_media_content(&file_request, b"file".to_vec()).await.unwrap();
+        event_cache_store.add_media_content(&thumbnail_request, b"thumb".to_vec()).await.unwrap();
+
+        let rows = event_cache_store.rowids_for_debugging().await.unwrap();
+        assert_eq!(rows.len(), 2);
+        assert!(rows[0].rowid < rows[1].rowid);
+        assert_eq!(rows[0].uri, uri.as_bytes());
+        assert_eq!(rows[0].format, b"file");
+        assert_eq!(rows[1].uri, uri.as_bytes());
+        assert_eq!(rows[1].format, b"crop_100x100");
+    }
+
+    #[async_test]
+    async fn test_reserve() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let uri = mxc_uri!("mxc://localhost/media");
+        let request =
+            MediaRequest { source: MediaSource::Plain(uri.to_owned()), format: MediaFormat::File };
+
+        let guard = event_cache_store.reserve(&request);
+        let nested_guard = event_cache_store.reserve(&request);
+        assert_eq!(event_cache_store.reservations.lock().unwrap().get(&guard.key), Some(&2));
+
+        // The media is still reserved while a guard is alive.
+        drop(guard);
+        assert_eq!(event_cache_store.reservations.lock().unwrap().get(&nested_guard.key), Some(&1));
+
+        drop(nested_guard);
+        assert!(event_cache_store.reservations.lock().unwrap().is_empty());
+    }
+}
+
+#[cfg(test)]
+mod encrypted_tests {
+    use std::{
+        collections::BTreeMap,
+        num::NonZeroUsize,
+        sync::{
+            atomic::{AtomicU32, Ordering::SeqCst},
+            Arc,
+        },
+    };
+
+    use assert_matches::assert_matches;
+    use matrix_sdk_base::{
+        deserialized_responses::SyncTimelineEvent,
+        event_cache_store::{EventCacheStore, EventCacheStoreError, EventChunk, EventChunkContent},
+        event_cache_store_integration_tests,
+        media::{MediaFormat, MediaRequest},
+    };
+    use matrix_sdk_store_encryption::StoreCipher;
+    use matrix_sdk_test::async_test;
+    use once_cell::sync::Lazy;
+    use ruma::{
+        events::room::{EncryptedFile, EncryptedFileInit, JsonWebKeyInit, MediaSource},
+        mxc_uri, room_id,
+        serde::{Base64, Raw},
+        server_name,
+    };
+    use serde_json::json;
+    use tempfile::{tempdir, TempDir};
+
+    use super::{
+        DecodeFailurePolicy, MediaKeyInfo, SqliteEventCacheStore, SqliteEventCacheStoreConfig,
+    };
+    use crate::{
+        error::Error,
+        utils::{SqliteAsyncConnExt, SqliteKeyValueStoreAsyncConnExt},
+        OpenStoreError,
+    };
+
+    static TMP_DIR: Lazy<TempDir> = Lazy::new(|| tempdir().unwrap());
+    static NUM: AtomicU32 = AtomicU32::new(0);
+
+    async fn get_event_cache_store() -> Result<SqliteEventCacheStore, EventCacheStoreError> {
+        get_event_cache_store_with_config(SqliteEventCacheStoreConfig::default()).await
+    }
+
+    async fn get_event_cache_store_with_config(
+        config: SqliteEventCacheStoreConfig,
+    ) -> Result<SqliteEventCacheStore, EventCacheStoreError> {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let tmpdir_path = TMP_DIR.path().join(name);
+
+        tracing::info!("using event cache store @ {}", tmpdir_path.to_str().unwrap());
+
+        Ok(SqliteEventCacheStore::open_with_config(
+            tmpdir_path.to_str().unwrap(),
+            Some("default_test_password"),
+            config,
+        )
         .await
         .unwrap())
     }
 
+    /// Add a media whose content can't be decrypted to the given store.
+    async fn add_corrupted_media(event_cache_store: &SqliteEventCacheStore) -> MediaRequest {
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+        event_cache_store.add_media_content(&request, b"hello".to_vec()).await.unwrap();
+
+        event_cache_store
+            .acquire()
+            .await
+            .unwrap()
+            .execute("UPDATE media_blobs SET data = ?", (b"garbage".to_vec(),))
+            .await
+            .unwrap();
+
+        request
+    }
+
+    async fn count_media(event_cache_store: &SqliteEventCacheStore) -> u64 {
+        event_cache_store
+            .acquire()
+            .await
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM media", (), |row| row.get(0))
+            .await
+            .unwrap()
+    }
+
     event_cache_store_integration_tests!();
+
+    #[async_test]
+    async fn test_get_media_content_raw() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let uri = mxc_uri!("mxc://localhost/media");
+        let request =
+            MediaRequest { source: MediaSource::Plain(uri.to_owned()), format: MediaFormat::File };
+
+        let content: Vec<u8> = "hello world".into();
+        event_cache_store.add_media_content(&request, content.clone()).await.unwrap();
+
+        let raw = event_cache_store.get_media_content_raw(&request).await.unwrap().unwrap();
+        assert!(raw.encrypted);
+        assert_ne!(raw.data, content);
+
+        // The raw data can be decoded with the same store cipher.
+        assert_eq!(event_cache_store.decode_value(&raw.data).unwrap().as_ref(), content);
+    }
+
+    #[async_test]
+    async fn test_remove_media_by_server() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+
+        assert_matches!(
+            event_cache_store.remove_media_by_server(server_name!("localhost")).await,
+            Err(Error::EncryptedKeysNotQueryable)
+        );
+    }
+
+    #[async_test]
+    async fn test_decode_failure_policy() {
+        // The error is returned by default.
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let request = add_corrupted_media(&event_cache_store).await;
+        assert_matches!(event_cache_store.get_media_content(&request).await, Err(Error::Decode(_)));
+        assert_eq!(count_media(&event_cache_store).await, 1);
+
+        let event_cache_store = get_event_cache_store_with_config(
+            SqliteEventCacheStoreConfig::new().on_decode_failure(DecodeFailurePolicy::TreatAsMiss),
+        )
+        .await
+        .expect("creating media cache failed");
+        let request = add_corrupted_media(&event_cache_store).await;
+        assert!(event_cache_store.get_media_content(&request).await.unwrap().is_none());
+        assert_eq!(count_media(&event_cache_store).await, 1);
+
+        let event_cache_store = get_event_cache_store_with_config(
+            SqliteEventCacheStoreConfig::new()
+                .on_decode_failure(DecodeFailurePolicy::TreatAsMissAndDelete),
+        )
+        .await
+        .expect("creating media cache failed");
+        let request = add_corrupted_media(&event_cache_store).await;
+        assert!(event_cache_store.get_media_content(&request).await.unwrap().is_none());
+        assert_eq!(count_media(&event_cache_store).await, 0);
+    }
+
+    #[async_test]
+    async fn test_is_encrypted() {
+        let event_cache_store = get_event_cache_store().await.unwrap();
+        assert!(event_cache_store.is_encrypted());
+        assert_eq!(event_cache_store.cipher_algorithm(), Some("XChaCha20-Poly1305"));
+    }
+
+    #[async_test]
+    async fn test_error_classes() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+
+        // A content that can't be deserialized.
+        let request = add_corrupted_media(&event_cache_store).await;
+        let error = event_cache_store.get_media_content(&request).await.unwrap_err();
+        assert_matches!(error, Error::Decode(_));
+        assert_matches!(EventCacheStoreError::from(error), EventCacheStoreError::Serialization(_));
+
+        // A content encrypted with another key.
+        let other_cipher = StoreCipher::new().unwrap();
+        let encrypted = other_cipher.encrypt_value_data(b"hello".to_vec()).unwrap();
+        event_cache_store
+            .acquire()
+            .await
+            .unwrap()
+            .execute(
+                "UPDATE media_blobs SET data = ?",
+                (rmp_serde::to_vec_named(&encrypted).unwrap(),),
+            )
+            .await
+            .unwrap();
+        let error = event_cache_store.get_media_content(&request).await.unwrap_err();
+        assert_matches!(error, Error::Encryption(_));
+        assert_matches!(EventCacheStoreError::from(error), EventCacheStoreError::Encryption(_));
+
+        // A failure of SQLite.
+        event_cache_store.acquire().await.unwrap().execute_batch("DROP TABLE media").await.unwrap();
+        let error = event_cache_store.get_media_content(&request).await.unwrap_err();
+        assert_matches!(error, Error::Sqlite(_));
+        assert_matches!(EventCacheStoreError::from(error), EventCacheStoreError::Backend(_));
+    }
+
+    #[async_test]
+    async fn test_media_cache_size() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+        event_cache_store.add_media_content(&request, vec![0; 10]).await.unwrap();
+
+        // The encrypted content is larger than the plain one.
+        assert!(event_cache_store.media_cache_size().await.unwrap() > 10);
+        assert_eq!(event_cache_store.media_cache_count().await.unwrap(), 1);
+    }
+
+    #[async_test]
+    async fn test_content_type() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+
+        event_cache_store
+            .add_media_content_with_content_type(&request, b"media".to_vec(), Some("image/png"))
+            .await
+            .unwrap();
+
+        // The content type is encrypted.
+        let raw: Vec<u8> = event_cache_store
+            .acquire()
+            .await
+            .unwrap()
+            .query_row("SELECT content_type FROM media", (), |row| row.get(0))
+            .await
+            .unwrap();
+        assert_ne!(raw, b"image/png");
+
+        assert_eq!(
+            event_cache_store.get_media_content_with_content_type(&request).await.unwrap(),
+            Some((b"media".to_vec(), Some("image/png".to_owned())))
+        );
+    }
+
+    #[async_test]
+    async fn test_large_media_round_trip() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+
+        // A content of a few MiB, that is decrypted in place.
+        let content = (0..16 * 1024 * 1024 + 7).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        event_cache_store.add_media_content(&request, content.clone()).await.unwrap();
+
+        let read = event_cache_store.get_media_content(&request).await.unwrap().unwrap();
+        assert_eq!(read.len(), content.len());
+        assert!(read == content);
+    }
+
+    #[async_test]
+    async fn test_incorrect_passphrase() {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+
+        let event_cache_store = SqliteEventCacheStore::open(&path, Some("secret")).await.unwrap();
+        drop(event_cache_store);
+
+        assert_matches!(
+            SqliteEventCacheStore::open(&path, Some("not the secret")).await,
+            Err(OpenStoreError::IncorrectPassphrase)
+        );
+        SqliteEventCacheStore::open(&path, Some("secret")).await.unwrap();
+    }
+
+    #[async_test]
+    async fn test_open_with_cipher() {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+        let store_cipher = Arc::new(StoreCipher::new().unwrap());
+
+        let first =
+            SqliteEventCacheStore::open_with_cipher(&path, store_cipher.clone()).await.unwrap();
+        let second =
+            SqliteEventCacheStore::open_with_cipher(&path, store_cipher.clone()).await.unwrap();
+        assert!(first.is_encrypted());
+
+        let first_request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/first").to_owned()),
+            format: MediaFormat::File,
+        };
+        let second_request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/second").to_owned()),
+            format: MediaFormat::File,
+        };
+        first.add_media_content(&first_request, b"first".to_vec()).await.unwrap();
+        second.add_media_content(&second_request, b"second".to_vec()).await.unwrap();
+
+        // Each store reads the data of the other one.
+        assert_eq!(
+            second.get_media_content(&first_request).await.unwrap().as_deref(),
+            Some(&b"first"[..])
+        );
+        assert_eq!(
+            first.get_media_content(&second_request).await.unwrap().as_deref(),
+            Some(&b"second"[..])
+        );
+
+        // The store cipher is not saved in the database.
+        assert!(first.acquire().await.unwrap().get_kv("cipher").await.unwrap().is_none());
+        assert_matches!(
+            first.change_passphrase("old", "new").await,
+            Err(Error::StoreCipherNotStored)
+        );
+
+        // The data can't be read with another store cipher.
+        let other =
+            SqliteEventCacheStore::open_with_cipher(&path, Arc::new(StoreCipher::new().unwrap()))
+                .await
+                .unwrap();
+        assert!(other.get_media_content(&first_request).await.unwrap().is_none());
+    }
+
+    #[async_test]
+    async fn test_get_media_content_range() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+
+        // The range is extracted from the decrypted content.
+        event_cache_store.add_media_content(&request, b"0123456789".to_vec()).await.unwrap();
+        assert_eq!(
+            event_cache_store.get_media_content_range(&request, 6, 4).await.unwrap().as_deref(),
+            Some(&b"6789"[..])
+        );
+        assert_matches!(
+            event_cache_store.get_media_content_range(&request, 6, 5).await,
+            Err(Error::InvalidMediaRange { offset: 6, len: 5, size: 10 })
+        );
+    }
+
+    #[async_test]
+    async fn test_get_media_content_stream() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+        event_cache_store.add_media_content(&request, vec![1, 2, 3]).await.unwrap();
+
+        assert_matches!(
+            event_cache_store
+                .get_media_content_stream(&request, NonZeroUsize::new(1024).unwrap())
+                .await,
+            Err(Error::EncryptedMediaNotStreamable)
+        );
+    }
+
+    #[async_test]
+    async fn test_export_to_dir() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+        event_cache_store.add_media_content(&request, b"hello".to_vec()).await.unwrap();
+
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+        event_cache_store.export_to_dir(&path).await.unwrap();
+
+        // The content is exported decrypted.
+        let manifest: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(path.join("manifest.json")).unwrap()).unwrap();
+        let media = manifest["media"].as_object().unwrap();
+        assert_eq!(media.len(), 1);
+        let file_name = media.keys().next().unwrap();
+        assert_eq!(std::fs::read(path.join(file_name)).unwrap(), b"hello");
+    }
+
+    #[async_test]
+    async fn test_event_chunks_after_reopen() {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+        let room_id = room_id!("!room:localhost");
+        let event = SyncTimelineEvent::new(
+            Raw::from_json_string(
+                json!({
+                    "type": "m.room.message",
+                    "event_id": "$ev",
+                    "sender": "@alice:localhost",
+                    "origin_server_ts": 0,
+                    "content": { "msgtype": "m.text", "body": "hello" },
+                })
+                .to_string(),
+            )
+            .unwrap(),
+        );
+
+        let event_cache_store =
+            SqliteEventCacheStore::open(&path, Some("passphrase")).await.unwrap();
+        // Save the chunks in the reverse order of the timeline.
+        for (id, previous, next) in [(2, Some(1), None), (1, Some(0), Some(2)), (0, None, Some(1))]
+        {
+            let content = if id == 1 {
+                EventChunkContent::Events(vec![event.clone()])
+            } else {
+                EventChunkContent::Gap { prev_token: format!("token_{id}") }
+            };
+            event_cache_store
+                .save_event_chunk(room_id, EventChunk { id, previous, next, content })
+                .await
+                .unwrap();
+        }
+        drop(event_cache_store);
+
+        let event_cache_store =
+            SqliteEventCacheStore::open(&path, Some("passphrase")).await.unwrap();
+        let chunks = event_cache_store.load_event_chunks(room_id).await.unwrap();
+
+        assert_eq!(
+            chunks.iter().map(|chunk| (chunk.id, chunk.previous, chunk.next)).collect::<Vec<_>>(),
+            [(0, None, Some(1)), (1, Some(0), Some(2)), (2, Some(1), None)]
+        );
+        assert_matches!(&chunks[0].content, EventChunkContent::Gap { prev_token } => {
+            assert_eq!(prev_token, "token_0");
+        });
+        assert_matches!(&chunks[1].content, EventChunkContent::Events(events) => {
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0].event_id().unwrap(), "$ev");
+        });
+        assert_matches!(&chunks[2].content, EventChunkContent::Gap { prev_token } => {
+            assert_eq!(prev_token, "token_2");
+        });
+    }
+
+    #[async_test]
+    async fn test_kdf_rounds() {
+        let kdf_rounds = |event_cache_store: SqliteEventCacheStore| async move {
+            let cipher =
+                event_cache_store.acquire().await.unwrap().get_kv("cipher").await.unwrap().unwrap();
+            let cipher: serde_json::Value = rmp_serde::from_slice(&cipher).unwrap();
+            cipher["kdf_info"]["Pbkdf2ToChaCha20Poly1305"]["rounds"].as_u64().unwrap()
+        };
+
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+
+        // A new store uses the configured number of rounds.
+        let event_cache_store = SqliteEventCacheStore::open_with_config(
+            &path,
+            Some("secret"),
+            SqliteEventCacheStoreConfig::new().kdf_rounds(1234),
+        )
+        .await
+        .unwrap();
+        assert_eq!(kdf_rounds(event_cache_store).await, 1234);
+
+        // An existing store keeps the number of rounds it was created with.
+        let event_cache_store = SqliteEventCacheStore::open_with_config(
+            &path,
+            Some("secret"),
+            SqliteEventCacheStoreConfig::new().kdf_rounds(5678),
+        )
+        .await
+        .unwrap();
+        assert_eq!(kdf_rounds(event_cache_store).await, 1234);
+
+        // A store created with the default number of rounds can be opened with a
+        // custom one.
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+        let event_cache_store = SqliteEventCacheStore::open(&path, Some("secret")).await.unwrap();
+        let default_kdf_rounds = kdf_rounds(event_cache_store).await;
+
+        let event_cache_store = SqliteEventCacheStore::open_with_config(
+            &path,
+            Some("secret"),
+            SqliteEventCacheStoreConfig::new().kdf_rounds(1234),
+        )
+        .await
+        .unwrap();
+        assert_eq!(kdf_rounds(event_cache_store).await, default_kdf_rounds);
+    }
+
+    #[async_test]
+    async fn test_change_passphrase() {
+        let name = NUM.fetch_add(1, SeqCst).to_string();
+        let path = TMP_DIR.path().join(name);
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+
+        let event_cache_store = SqliteEventCacheStore::open(&path, Some("old")).await.unwrap();
+        event_cache_store.add_media_content(&request, b"media".to_vec()).await.unwrap();
+
+        // The old passphrase must be the current one.
+        assert_matches!(
+            event_cache_store.change_passphrase("wrong", "new").await,
+            Err(Error::IncorrectPassphrase)
+        );
+        event_cache_store.change_passphrase("old", "new").await.unwrap();
+        drop(event_cache_store);
+
+        assert_matches!(
+            SqliteEventCacheStore::open(&path, Some("old")).await,
+            Err(OpenStoreError::IncorrectPassphrase)
+        );
+
+        // The media can still be read with the new passphrase.
+        let event_cache_store = SqliteEventCacheStore::open(&path, Some("new")).await.unwrap();
+        assert_eq!(
+            event_cache_store.get_media_content(&request).await.unwrap().as_deref(),
+            Some(&b"media"[..])
+        );
+
+        // A store without passphrase has no passphrase to change.
+        let path = TMP_DIR.path().join(NUM.fetch_add(1, SeqCst).to_string());
+        let event_cache_store = SqliteEventCacheStore::open(&path, None).await.unwrap();
+        assert_matches!(
+            event_cache_store.change_passphrase("old", "new").await,
+            Err(Error::NotEncrypted)
+        );
+    }
+
+    #[async_test]
+    async fn test_media_key_info() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+        let new_request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/new-media").to_owned()),
+            format: MediaFormat::File,
+        };
+        let key_info = MediaKeyInfo {
+            key: JsonWebKeyInit {
+                kty: "oct".to_owned(),
+                key_ops: vec!["encrypt".to_owned(), "decrypt".to_owned()],
+                alg: "A256CTR".to_owned(),
+                k: Base64::new(vec![0; 32]),
+                ext: true,
+            }
+            .into(),
+            iv: Base64::new(vec![1; 16]),
+            hashes: Default::default(),
+        };
+
+        assert!(event_cache_store.get_media_key_info(&request).await.unwrap().is_none());
+
+        event_cache_store.add_media_content(&request, b"ciphertext".to_vec()).await.unwrap();
+        event_cache_store.add_media_key_info(&request, &key_info).await.unwrap();
+
+        let stored = event_cache_store.get_media_key_info(&request).await.unwrap().unwrap();
+        assert_eq!(stored.key.k, key_info.key.k);
+        assert_eq!(stored.iv, key_info.iv);
+
+        // The key info is encrypted.
+        let raw: Vec<u8> = event_cache_store
+            .acquire()
+            .await
+            .unwrap()
+            .query_row("SELECT key_info FROM media_keys", (), |row| row.get(0))
+            .await
+            .unwrap();
+        assert_ne!(raw, serde_json::to_vec(&key_info).unwrap());
+
+        // The key info follows the media.
+        event_cache_store.replace_media_key(&request, &new_request).await.unwrap();
+        assert!(event_cache_store.get_media_key_info(&request).await.unwrap().is_none());
+        assert!(event_cache_store.get_media_key_info(&new_request).await.unwrap().is_some());
+
+        // The key info is removed with the media.
+        event_cache_store.remove_media_content(&new_request).await.unwrap();
+        assert!(event_cache_store.get_media_key_info(&new_request).await.unwrap().is_none());
+    }
+
+    #[async_test]
+    async fn test_media_content_with_key_info() {
+        let event_cache_store = get_event_cache_store().await.expect("creating media cache failed");
+        let uri = mxc_uri!("mxc://localhost/media");
+        let file: EncryptedFile = EncryptedFileInit {
+            url: uri.to_owned(),
+            key: JsonWebKeyInit {
+                kty: "oct".to_owned(),
+                key_ops: vec!["encrypt".to_owned(), "decrypt".to_owned()],
+                alg: "A256CTR".to_owned(),
+                k: Base64::new(vec![0; 32]),
+                ext: true,
+            }
+            .into(),
+            iv: Base64::new(vec![1; 16]),
+            hashes: BTreeMap::from([("sha256".to_owned(), Base64::new(vec![2; 32]))]),
+            v: "v2".to_owned(),
+        }
+        .into();
+        let request = MediaRequest {
+            source: MediaSource::Encrypted(Box::new(file.clone())),
+            format: MediaFormat::File,
+        };
+
+        assert!(event_cache_store
+            .get_media_content_with_key_info(&request)
+            .await
+            .unwrap()
+            .is_none());
+
+        // The content can be returned without its key info.
+        event_cache_store.add_media_content(&request, b"ciphertext".to_vec()).await.unwrap();
+        assert_matches!(
+            event_cache_store.get_media_content_with_key_info(&request).await.unwrap(),
+            Some((content, None)) => {
+                assert_eq!(content, b"ciphertext");
+            }
+        );
+
+        event_cache_store.add_media_key_info(&request, &MediaKeyInfo::from(&file)).await.unwrap();
+        assert_matches!(
+            event_cache_store.get_media_content_with_key_info(&request).await.unwrap(),
+            Some((content, Some(key_info))) => {
+                assert_eq!(content, b"ciphertext");
+                assert_eq!(key_info.key.k, file.key.k);
+                assert_eq!(key_info.iv, file.iv);
+                assert_eq!(key_info.hashes, file.hashes);
+            }
+        );
+    }
 }
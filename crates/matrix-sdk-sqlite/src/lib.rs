@@ -28,8 +28,16 @@ mod utils;
 #[cfg(feature = "crypto-store")]
 pub use self::crypto_store::SqliteCryptoStore;
 pub use self::error::OpenStoreError;
+#[cfg(all(feature = "event-cache", feature = "debug-internals"))]
+pub use self::event_cache_store::MediaRowForDebugging;
 #[cfg(feature = "event-cache")]
-pub use self::event_cache_store::SqliteEventCacheStore;
+pub use self::event_cache_store::{
+    BusyRetryPolicy, DecodeFailurePolicy, EnvelopeCodec, EnvelopeCodecError, EventCacheMetrics,
+    EvictedMedia, EvictionEstimate, FileLocking, IntegrityCheck, LockingMode, MediaCacheEntry,
+    MediaKeyInfo, MediaLoaderError, MediaRetentionPolicy, MessagePackCodec, OpenOutcome,
+    RawStoredMedia, RepairReport, ReservationGuard, RetentionReport, SqliteEventCacheStore,
+    SqliteEventCacheStoreConfig, Synchronous,
+};
 #[cfg(feature = "state-store")]
 pub use self::state_store::SqliteStateStore;
 
@@ -19,7 +19,9 @@ use async_trait::async_trait;
 use deadpool_sqlite::Object as SqliteAsyncConn;
 use itertools::Itertools;
 use matrix_sdk_store_encryption::StoreCipher;
-use rusqlite::{limits::Limit, OptionalExtension, Params, Row, Statement, Transaction};
+use rusqlite::{
+    limits::Limit, OptionalExtension, Params, Row, Statement, Transaction, TransactionBehavior,
+};
 
 use crate::{
     error::{Error, Result},
@@ -93,6 +95,26 @@ pub(crate) trait SqliteAsyncConnExt {
         E: From<rusqlite::Error> + Send + 'static,
         F: FnOnce(&Transaction<'_>) -> Result<T, E> + Send + 'static;
 
+    /// Like [`Self::with_transaction`], but the transaction takes the write
+    /// lock of the database when it begins, with `BEGIN IMMEDIATE`, instead of
+    /// on its first write.
+    ///
+    /// This makes sure that the reads of the transaction see the latest data,
+    /// and that no other connection can write until it is committed.
+    async fn with_immediate_transaction<T, E, F>(&self, f: F) -> Result<T, E>
+    where
+        T: Send + 'static,
+        E: From<rusqlite::Error> + Send + 'static,
+        F: FnOnce(&Transaction<'_>) -> Result<T, E> + Send + 'static;
+
+    /// Like [`Self::with_immediate_transaction`], but the transaction is always
+    /// rolled back, to see the effects of some changes without applying them.
+    async fn with_rolled_back_transaction<T, E, F>(&self, f: F) -> Result<T, E>
+    where
+        T: Send + 'static,
+        E: From<rusqlite::Error> + Send + 'static,
+        F: FnOnce(&Transaction<'_>) -> Result<T, E> + Send + 'static;
+
     async fn chunk_large_query_over<Query, Res>(
         &self,
         mut keys_to_chunk: Vec<Key>,
@@ -163,6 +185,38 @@ impl SqliteAsyncConnExt for SqliteAsyncConn {
         .unwrap()
     }
 
+    async fn with_immediate_transaction<T, E, F>(&self, f: F) -> Result<T, E>
+    where
+        T: Send + 'static,
+        E: From<rusqlite::Error> + Send + 'static,
+        F: FnOnce(&Transaction<'_>) -> Result<T, E> + Send + 'static,
+    {
+        self.interact(move |conn| {
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let result = f(&txn)?;
+            txn.commit()?;
+            Ok(result)
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn with_rolled_back_transaction<T, E, F>(&self, f: F) -> Result<T, E>
+    where
+        T: Send + 'static,
+        E: From<rusqlite::Error> + Send + 'static,
+        F: FnOnce(&Transaction<'_>) -> Result<T, E> + Send + 'static,
+    {
+        self.interact(move |conn| {
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let result = f(&txn)?;
+            txn.rollback()?;
+            Ok(result)
+        })
+        .await
+        .unwrap()
+    }
+
     /// Chunk a large query over some keys.
     ///
     /// Imagine there is a _dynamic_ query that runs potentially large number of
@@ -322,21 +376,58 @@ pub(crate) trait SqliteKeyValueStoreAsyncConnExt: SqliteAsyncConnExt {
         }
     }
 
+    /// Get the [`StoreCipher`] of the database, if there is one.
+    async fn get_store_cipher(
+        &self,
+        passphrase: &str,
+    ) -> Result<Option<StoreCipher>, OpenStoreError> {
+        let Some(encrypted) = self.get_kv("cipher").await.map_err(OpenStoreError::LoadCipher)?
+        else {
+            return Ok(None);
+        };
+
+        // The store cipher is authenticated, so it fails to be decrypted with the
+        // wrong passphrase.
+        let cipher = StoreCipher::import(passphrase, &encrypted).map_err(|error| match error {
+            matrix_sdk_store_encryption::Error::Encryption(_) => {
+                OpenStoreError::IncorrectPassphrase
+            }
+            error => error.into(),
+        })?;
+
+        Ok(Some(cipher))
+    }
+
     /// Get the [`StoreCipher`] of the database or create it.
     async fn get_or_create_store_cipher(
         &self,
         passphrase: &str,
     ) -> Result<StoreCipher, OpenStoreError> {
-        let encrypted_cipher = self.get_kv("cipher").await.map_err(OpenStoreError::LoadCipher)?;
+        self.get_or_create_store_cipher_with_kdf_rounds(passphrase, None).await
+    }
 
-        let cipher = if let Some(encrypted) = encrypted_cipher {
-            StoreCipher::import(passphrase, &encrypted)?
+    /// Get the [`StoreCipher`] of the database or create it, with the given
+    /// number of key derivation rounds.
+    ///
+    /// The number of rounds is only used to create a new store cipher, an
+    /// existing store cipher is always decrypted with the number of rounds it
+    /// was created with.
+    async fn get_or_create_store_cipher_with_kdf_rounds(
+        &self,
+        passphrase: &str,
+        kdf_rounds: Option<u32>,
+    ) -> Result<StoreCipher, OpenStoreError> {
+        let cipher = if let Some(cipher) = self.get_store_cipher(passphrase).await? {
+            cipher
         } else {
             let cipher = StoreCipher::new()?;
-            #[cfg(not(test))]
-            let export = cipher.export(passphrase);
-            #[cfg(test)]
-            let export = cipher._insecure_export_fast_for_testing(passphrase);
+            let export = match kdf_rounds {
+                Some(kdf_rounds) => cipher.export_with_kdf_rounds(passphrase, kdf_rounds),
+                #[cfg(not(test))]
+                None => cipher.export(passphrase),
+                #[cfg(test)]
+                None => cipher._insecure_export_fast_for_testing(passphrase),
+            };
             self.set_kv("cipher", export?).await.map_err(OpenStoreError::SaveCipher)?;
             cipher
         };
@@ -25,7 +25,7 @@ use base64::{
 };
 use blake3::{derive_key, Hash};
 use chacha20poly1305::{
-    aead::{Aead, Error as EncryptionError},
+    aead::{Aead, AeadInPlace, Error as EncryptionError},
     Key as ChachaKey, KeyInit, XChaCha20Poly1305, XNonce,
 };
 use hmac::Hmac;
@@ -148,6 +148,29 @@ impl StoreCipher {
         self.export_kdf(passphrase, KDF_ROUNDS)
     }
 
+    /// Encrypt the store cipher using the given passphrase with a custom
+    /// number of key derivation rounds, and export it.
+    ///
+    /// This is the same as [`StoreCipher::export`], except that more rounds
+    /// make it harder to brute-force the passphrase, and fewer rounds make it
+    /// faster to import the store cipher, e.g. on low-power devices. The
+    /// number of rounds is stored in the export, so [`StoreCipher::import`]
+    /// doesn't need to know it.
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - The passphrase that should be used to encrypt the store
+    ///   cipher.
+    ///
+    /// * `kdf_rounds` - The number of rounds of the PBKDF2 key derivation.
+    pub fn export_with_kdf_rounds(
+        &self,
+        passphrase: &str,
+        kdf_rounds: u32,
+    ) -> Result<Vec<u8>, Error> {
+        self.export_kdf(passphrase, kdf_rounds)
+    }
+
     /// Encrypt the store cipher using the given key and export it.
     ///
     /// This method can be used to persist the `StoreCipher` in an unencrypted
@@ -576,7 +599,9 @@ impl StoreCipher {
     ///
     /// * `value` - The EncryptedValue of a value that should be decrypted.
     ///
-    /// The method will return the raw decrypted value
+    /// The method will return the raw decrypted value. The value is decrypted
+    /// in the buffer of the ciphertext, so a large value is never held twice
+    /// in memory.
     ///
     /// # Examples
     ///
@@ -604,7 +629,10 @@ impl StoreCipher {
 
         let cipher = XChaCha20Poly1305::new(self.inner.encryption_key());
         let nonce = XNonce::from_slice(&value.nonce);
-        Ok(cipher.decrypt(nonce, value.ciphertext.as_ref())?)
+        let mut data = value.ciphertext;
+        cipher.decrypt_in_place(nonce, b"", &mut data)?;
+
+        Ok(data)
     }
 
     /// Expand the given passphrase into a KEY_SIZE long key.
@@ -814,7 +842,7 @@ struct EncryptedStoreCipher {
 mod tests {
     use serde_json::{json, Value};
 
-    use super::{Error, StoreCipher};
+    use super::{EncryptedStoreCipher, Error, KdfInfo, StoreCipher};
     use crate::{EncryptedValue, EncryptedValueBase64, EncryptedValueBase64DecodeError};
 
     #[test]
@@ -822,6 +850,26 @@ mod tests {
         StoreCipher::new().unwrap();
     }
 
+    #[test]
+    fn exporting_store_cipher_with_kdf_rounds() -> Result<(), Error> {
+        let passphrase = "it's a secret to everybody";
+        let store_cipher = StoreCipher::new()?;
+
+        let encrypted = store_cipher.export_with_kdf_rounds(passphrase, 500)?;
+        let decrypted = StoreCipher::import(passphrase, &encrypted)?;
+
+        assert_eq!(store_cipher.inner.encryption_key, decrypted.inner.encryption_key);
+        assert_eq!(store_cipher.inner.mac_key_seed, decrypted.inner.mac_key_seed);
+
+        let encrypted: EncryptedStoreCipher = rmp_serde::from_slice(&encrypted)?;
+        let KdfInfo::Pbkdf2ToChaCha20Poly1305 { rounds, .. } = encrypted.kdf_info else {
+            panic!("Invalid KDF info of a passphrase-encrypted store cipher");
+        };
+        assert_eq!(rounds, 500);
+
+        Ok(())
+    }
+
     #[test]
     fn exporting_store_cipher() -> Result<(), Error> {
         let passphrase = "it's a secret to everybody";
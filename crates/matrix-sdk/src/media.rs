@@ -23,6 +23,7 @@ use std::{fmt, fs::File, path::Path};
 
 use eyeball::SharedObservable;
 use futures_util::future::try_join;
+use matrix_sdk_base::event_cache_store::EventCacheStoreError;
 pub use matrix_sdk_base::media::*;
 use mime::Mime;
 use ruma::{
@@ -38,6 +39,7 @@ use ruma::{
 use tempfile::{Builder as TempFileBuilder, NamedTempFile, TempDir};
 #[cfg(not(target_arch = "wasm32"))]
 use tokio::{fs::File as TokioFile, io::AsyncWriteExt};
+use tracing::warn;
 
 use crate::{
     attachment::Thumbnail, futures::SendRequest, Client, Error, Result, TransmissionProgress,
@@ -477,7 +479,14 @@ impl Media {
         };
 
         if use_cache {
-            self.client.event_cache_store().add_media_content(request, content.clone()).await?;
+            match self.client.event_cache_store().add_media_content(request, content.clone()).await
+            {
+                // The content was downloaded, it can be returned even if it can't be cached.
+                Err(EventCacheStoreError::StorageFull) => {
+                    warn!("Failed to cache the media content, the storage is full");
+                }
+                result => result?,
+            }
         }
 
         Ok(content)